@@ -2,7 +2,7 @@
 use std::{fs, io::{BufRead, Write}};
 use crate::prelude::{HashType, log_msg, FAIL};
 use toml::map::Map;
-use crate::controller::mode::IndexMode;
+use crate::controller::mode::{IdType, IndexMode};
 use crate::structure::io::StructureFileFormat;
 
 #[derive(Debug, Clone, PartialEq)]
@@ -17,6 +17,14 @@ pub struct IndexConfig {
     pub input_format: StructureFileFormat,
     pub foldcomp_db: Option<String>,
     pub multiple_bin: Option<Vec<(usize, usize)>>,
+    pub value_bits: usize, // Width of numeric ids in id mode value vector
+    pub per_chain: bool, // Each chain is a lookup entry ({path}_{chain})
+    pub stop_ratio: f32, // Hashes in more than this fraction of structures are in {index}.stop. 0 if none
+    pub id_type: IdType, // IDs that aren't paths have their locations in {index}.paths
+    pub structure_root: Option<String>, // Input directory or archive that locations start with
+    // Hash types and bins indexed next to the main one. Id mode only.
+    // Set k (from 1) has its own table in {index}.hash{k}.offset and .value
+    pub extra_hash_sets: Vec<(HashType, usize, usize)>,
 }
 
 impl IndexConfig {
@@ -37,27 +45,58 @@ impl IndexConfig {
             input_format,
             foldcomp_db,
             multiple_bin,
+            value_bits: 16,
+            per_chain: false,
+            stop_ratio: 0.0,
+            id_type: IdType::RelPath,
+            structure_root: None,
+            extra_hash_sets: Vec::new(),
         }
     }
     pub fn from_toml(toml: &toml::Value) -> Self {
-        let hash_type = toml["hash_type"].as_str().unwrap();
-        let num_bin_dist = toml["num_bin_dist"].as_integer().unwrap() as usize;
-        let num_bin_angle = toml["num_bin_angle"].as_integer().unwrap() as usize;
-        let mode = toml["mode"].as_str().unwrap();
-        let mode = IndexMode::get_with_str(mode);
-        let grid_width = toml["grid_width"].as_float().unwrap() as f32;
-        let chunk_size = toml["chunk_size"].as_integer().unwrap() as usize;
-        let max_residue = toml["max_residue"].as_integer().unwrap() as usize;
-        let input_format = StructureFileFormat::get_with_string(toml["input_format"].as_str().unwrap());
-        let foldcomp_db = toml.get("foldcomp_db").map(|x| x.as_str().unwrap().to_string());
+        Self::try_from_toml(toml).unwrap_or_else(|e| panic!("{}", log_msg(FAIL, &e)))
+    }
+    // Same as from_toml but gives an error for missing or mistyped keys
+    pub fn try_from_toml(toml: &toml::Value) -> Result<Self, String> {
+        let invalid = |key: &str| format!("Invalid index config: {} is missing or has a wrong type", key);
+        let get = |key: &str| toml.get(key).ok_or_else(|| invalid(key));
+        let as_str = |key: &str, x: &toml::Value| x.as_str().map(|x| x.to_string()).ok_or_else(|| invalid(key));
+        let as_usize = |key: &str, x: &toml::Value| x.as_integer().map(|x| x as usize).ok_or_else(|| invalid(key));
+        let as_f32 = |key: &str, x: &toml::Value| x.as_float().map(|x| x as f32).ok_or_else(|| invalid(key));
+        let as_array = |key: &str, x: &toml::Value| x.as_array().cloned().ok_or_else(|| invalid(key));
+        let hash_type = as_str("hash_type", get("hash_type")?)?;
+        let num_bin_dist = as_usize("num_bin_dist", get("num_bin_dist")?)?;
+        let num_bin_angle = as_usize("num_bin_angle", get("num_bin_angle")?)?;
+        let mode = IndexMode::get_with_str(&as_str("mode", get("mode")?)?);
+        let grid_width = as_f32("grid_width", get("grid_width")?)?;
+        let chunk_size = as_usize("chunk_size", get("chunk_size")?)?;
+        let max_residue = as_usize("max_residue", get("max_residue")?)?;
+        let input_format = StructureFileFormat::get_with_string(&as_str("input_format", get("input_format")?)?);
+        let foldcomp_db = toml.get("foldcomp_db").map(|x| as_str("foldcomp_db", x)).transpose()?;
         let multiple_bin = toml.get("multiple_bin").map(|x| {
-            x.as_array().unwrap().iter().map(|y| {
-                let bin = y.as_array().unwrap();
-                (bin[0].as_integer().unwrap() as usize, bin[1].as_integer().unwrap() as usize)
-            }).collect()
-        });
-        Self {
-            hash_type: HashType::get_with_str(hash_type),
+            as_array("multiple_bin", x)?.iter().map(|y| {
+                match y.as_array().map(|bin| bin.as_slice()) {
+                    Some([dist, angle]) => Ok((as_usize("multiple_bin", dist)?, as_usize("multiple_bin", angle)?)),
+                    _ => Err(invalid("multiple_bin")),
+                }
+            }).collect::<Result<Vec<_>, String>>()
+        }).transpose()?;
+        // Indices built before value_bits was introduced use 16 bits
+        let value_bits = toml.get("value_bits").map(|x| as_usize("value_bits", x)).transpose()?.unwrap_or(16);
+        let per_chain = toml.get("per_chain").map(|x| x.as_bool().ok_or_else(|| invalid("per_chain"))).transpose()?.unwrap_or(false);
+        let stop_ratio = toml.get("stop_ratio").map(|x| as_f32("stop_ratio", x)).transpose()?.unwrap_or(0.0);
+        let id_type = toml.get("id_type").map(|x| as_str("id_type", x)).transpose()?
+            .map_or(IdType::RelPath, |x| IdType::get_with_str(&x));
+        let structure_root = toml.get("structure_root").map(|x| as_str("structure_root", x)).transpose()?;
+        let extra_hash_sets = toml.get("extra_hash_sets").map(|x| {
+            as_array("extra_hash_sets", x)?.iter().map(|y| Ok((
+                HashType::get_with_str(&as_str("extra_hash_sets", y.get("hash_type").ok_or_else(|| invalid("extra_hash_sets"))?)?),
+                as_usize("extra_hash_sets", y.get("num_bin_dist").ok_or_else(|| invalid("extra_hash_sets"))?)?,
+                as_usize("extra_hash_sets", y.get("num_bin_angle").ok_or_else(|| invalid("extra_hash_sets"))?)?,
+            ))).collect::<Result<Vec<_>, String>>()
+        }).transpose()?.unwrap_or_default();
+        Ok(Self {
+            hash_type: HashType::get_with_str(&hash_type),
             num_bin_dist,
             num_bin_angle,
             mode,
@@ -67,7 +106,13 @@ impl IndexConfig {
             input_format,
             foldcomp_db,
             multiple_bin,
-        }
+            value_bits,
+            per_chain,
+            stop_ratio,
+            id_type,
+            structure_root,
+            extra_hash_sets,
+        })
     }
     pub fn to_toml(&self) -> toml::Value {
         let mut map = Map::new();
@@ -89,8 +134,52 @@ impl IndexConfig {
                 }).collect()
             ));
         }
+        map.insert("value_bits".to_string(), toml::Value::Integer(self.value_bits as i64));
+        if self.per_chain {
+            map.insert("per_chain".to_string(), toml::Value::Boolean(true));
+        }
+        if self.stop_ratio > 0.0 {
+            // Written as given (0.1 instead of 0.10000000149011612)
+            map.insert("stop_ratio".to_string(), toml::Value::Float(self.stop_ratio.to_string().parse().unwrap()));
+        }
+        map.insert("id_type".to_string(), toml::Value::String(self.id_type.to_string()));
+        if let Some(structure_root) = &self.structure_root {
+            map.insert("structure_root".to_string(), toml::Value::String(structure_root.clone()));
+        }
+        if !self.extra_hash_sets.is_empty() {
+            map.insert("extra_hash_sets".to_string(), toml::Value::Array(
+                self.extra_hash_sets.iter().map(|(hash_type, num_bin_dist, num_bin_angle)| {
+                    let mut set = Map::new();
+                    set.insert("hash_type".to_string(), toml::Value::String(hash_type.to_string()));
+                    set.insert("num_bin_dist".to_string(), toml::Value::Integer(*num_bin_dist as i64));
+                    set.insert("num_bin_angle".to_string(), toml::Value::Integer(*num_bin_angle as i64));
+                    toml::Value::Table(set)
+                }).collect()
+            ));
+        }
         toml::Value::Table(map)
     }
+    // Indices are compatible if their hashes and numeric ids mean the same thing.
+    // chunk_size and max_residue only describe how the index was built.
+    pub fn is_compatible_with(&self, other: &IndexConfig) -> bool {
+        self.hash_type == other.hash_type
+            && self.num_bin_dist == other.num_bin_dist
+            && self.num_bin_angle == other.num_bin_angle
+            && self.mode == other.mode
+            && self.grid_width == other.grid_width
+            && self.input_format == other.input_format
+            && self.foldcomp_db == other.foldcomp_db
+            && self.multiple_bin == other.multiple_bin
+            && self.per_chain == other.per_chain
+            && self.stop_ratio == other.stop_ratio
+            && self.extra_hash_sets == other.extra_hash_sets
+    }
+    // Main hash set followed by the extra ones, in the order of their tables
+    pub fn hash_sets(&self) -> Vec<(HashType, usize, usize)> {
+        let mut hash_sets = vec![(self.hash_type, self.num_bin_dist, self.num_bin_angle)];
+        hash_sets.extend(self.extra_hash_sets.iter().cloned());
+        hash_sets
+    }
 }
 
 
@@ -186,13 +275,14 @@ pub fn write_query_config_to_file(path: &str, query_config: QueryConfig) {
 }
 
 pub fn read_index_config_from_file(path: &str) -> IndexConfig {
-    // Read file as read only, read from start to end including new line characters
-    let file = std::fs::File::open(path).expect(
-        &log_msg(FAIL, &format!("Config file not found: {}", path))
-    );
-    let reader = std::io::BufReader::new(file);
-    let toml = toml::from_str(&reader.lines().map(|x| format!("{}\n",x.unwrap())).collect::<String>()).unwrap();
-    IndexConfig::from_toml(&toml)
+    try_read_index_config_from_file(path).unwrap_or_else(|e| panic!("{}", log_msg(FAIL, &e)))
+}
+
+// Same as read_index_config_from_file but gives an error for a missing or malformed file
+pub fn try_read_index_config_from_file(path: &str) -> Result<IndexConfig, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("Unable to read config file {}: {}", path, e))?;
+    let toml = toml::from_str::<toml::Value>(&content).map_err(|e| format!("Invalid config file {}: {}", path, e))?;
+    IndexConfig::try_from_toml(&toml).map_err(|e| format!("{} in {}", e, path))
 }
 
 pub fn read_query_config_from_file(path: &str) -> QueryConfig {
@@ -259,17 +349,67 @@ mod tests {
     #[test]
     fn test_write_index_config_to_file() {
         let path = "data/index_config.toml";
-        let index_config = IndexConfig::new(
+        let mut index_config = IndexConfig::new(
             HashType::PDBTrRosetta, 10, 10,
             IndexMode::Big, 30.0, 65535, 4000,
             StructureFileFormat::FCZDB, Some("data/foldcomp_db".to_string()),
             Some(vec![(16, 4), (8, 3)])
         );
+        index_config.value_bits = 32;
+        index_config.per_chain = true;
+        index_config.stop_ratio = 0.05;
+        index_config.id_type = IdType::Pdb;
+        index_config.structure_root = Some("data/serine_peptidases_filtered".to_string());
+        index_config.extra_hash_sets = vec![(HashType::PointPairFeature, 16, 8), (HashType::PDBMotifSinCos, 8, 3)];
         write_index_config_to_file(path, index_config.clone());
         let index_config_read = read_index_config_from_file(path);
         assert_eq!(index_config, index_config_read);
     }
     
+    #[test]
+    fn test_index_config_compatibility() {
+        let index_config = IndexConfig::new(
+            HashType::PDBTrRosetta, 16, 4,
+            IndexMode::Id, 20.0, 65535, 4000,
+            StructureFileFormat::PDB, None, None
+        );
+        let mut other = index_config.clone();
+        other.chunk_size = 100;
+        other.max_residue = 50000;
+        assert!(index_config.is_compatible_with(&other));
+        other.num_bin_dist = 8;
+        assert!(!index_config.is_compatible_with(&other));
+    }
+
+    #[test]
+    fn test_read_index_config_without_value_bits() {
+        let path = "data/index_config_old.toml";
+        let mut index_config = IndexConfig::new(
+            HashType::PDBTrRosetta, 16, 4,
+            IndexMode::Id, 20.0, 65535, 4000,
+            StructureFileFormat::PDB, None, None
+        );
+        index_config.value_bits = 32;
+        let mut toml = index_config.to_toml();
+        toml.as_table_mut().unwrap().remove("value_bits");
+        std::fs::write(path, toml::to_string(&toml).unwrap()).unwrap();
+        let index_config_read = read_index_config_from_file(path);
+        assert_eq!(index_config_read.value_bits, 16);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_try_read_malformed_index_config() {
+        let path = "data/index_config_malformed.toml";
+        std::fs::write(path, "hash_type = \"pdbtr\"\nnum_bin_dist = \"16\"\n").unwrap();
+        let error = try_read_index_config_from_file(path).unwrap_err();
+        assert!(error.contains("num_bin_dist"));
+        std::fs::write(path, "not toml [").unwrap();
+        assert!(try_read_index_config_from_file(path).unwrap_err().starts_with("Invalid config file"));
+        std::fs::remove_file(path).unwrap();
+        assert!(try_read_index_config_from_file(path).is_err());
+    }
+
     #[test]
     fn test_write_query_config_to_file() {
         let path = "data/query_config.toml";
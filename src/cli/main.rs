@@ -6,13 +6,20 @@
 //! Main entry point for FoldDisco CLI
 
 // use crate::*;
-use folddisco::cli::{workflows::{build_index, benchmark, query_pdb}, *};
+use folddisco::cli::{workflows::{build_index, benchmark, query_pdb, remove, merge, stats, verify, convert, rebin, serve}, *};
 const HELP: &str = "\
 usage: folddisco <command> [<args>]
 
 subcommands:
   index     Create a new index table from multiple protein structures
   query     Query a motif from an index table
+  serve     Answer queries over HTTP with indices kept loaded
+  remove    Remove structures from an index table
+  merge     Merge multiple index tables into one
+  stats     Print statistics of an index table
+  verify    Check integrity of an index table
+  convert   Convert an index table between id and big mode
+  rebin     Coarsen the bins of an index table without reading structures
   benchmark Benchmark the performance of folddisco
 
 options:
@@ -28,6 +35,7 @@ fn parse_arg() -> Result<AppArgs, Box<dyn std::error::Error>> {
     {
         Some("index") => Ok(AppArgs::Index {
             pdb_container: args.opt_value_from_str(["-p", "--pdbs"])?,
+            path_list: args.opt_value_from_str("--list")?,
             hash_type: args.value_from_str(["-y", "--type"]).unwrap_or("default".into()),
             index_path: args.value_from_str(["-i", "--index"]).unwrap_or("folddisco_index".into()),
             num_threads: args.value_from_str(["-t", "--threads"]).unwrap_or(1),
@@ -35,12 +43,23 @@ fn parse_arg() -> Result<AppArgs, Box<dyn std::error::Error>> {
             num_bin_dist: args.value_from_str(["-d", "--distance"]).unwrap_or(0),
             num_bin_angle: args.value_from_str(["-a", "--angle"]).unwrap_or(0),
             multiple_bins: args.opt_value_from_str("--multiple-bins")?,
+            extra_hashes: args.opt_value_from_str("--extra-hashes")?,
             grid_width: args.value_from_str(["-g", "--grid"]).unwrap_or(20.0),
-            chunk_size: args.value_from_str(["-c", "--chunk"]).unwrap_or(65536),
+            chunk_size: args.value_from_str(["-c", "--chunk"]).unwrap_or(1000000),
             max_residue: args.value_from_str(["-n", "--residue"]).unwrap_or(50000),
             recursive: args.contains(["-r", "--recursive"]),
             mmap_on_disk: args.contains("--mmap-on-disk"),
             id_type: args.value_from_str("--id").unwrap_or("relpath".into()),
+            append: args.contains("--append"),
+            resume: args.contains("--resume"),
+            checkpoint_interval: args.value_from_str("--checkpoint-interval").unwrap_or(-1.0),
+            strict: args.contains("--strict"),
+            max_memory: args.value_from_str("--max-memory").unwrap_or(0.0),
+            per_chain: args.contains("--per-chain"),
+            stop_ratio: args.value_from_str("--stop-ratio").unwrap_or(0.0),
+            dry_run: args.contains("--dry-run"),
+            sample_ratio: args.value_from_str("--sample").unwrap_or(0.01),
+            coord_cache: args.contains("--coord-cache"),
             verbose: args.contains(["-v", "--verbose"]),
             help: args.contains(["-h", "--help"]),
         }),
@@ -84,6 +103,13 @@ fn parse_arg() -> Result<AppArgs, Box<dyn std::error::Error>> {
             header: args.contains("--header"),
             serial_query: args.contains("--serial-index"),
             output: args.value_from_str(["-o", "--output"]).unwrap_or("".into()),
+            output_format: args.value_from_str("--format").unwrap_or("tsv".into()),
+            superposition: args.contains("--superposition"),
+            write_hits: args.opt_value_from_str("--write-hits")?,
+            hit_environment: args.value_from_str("--hit-environment").unwrap_or(0.0),
+            hit_format: args.value_from_str("--hit-format").unwrap_or("pdb".into()),
+            structure_root: args.opt_value_from_str("--structure-root")?,
+            foldcomp_db: args.opt_value_from_str("--foldcomp-db")?,
             verbose: args.contains(["-v", "--verbose"]),
             help: args.contains(["-h", "--help"]),
         }),
@@ -104,6 +130,63 @@ fn parse_arg() -> Result<AppArgs, Box<dyn std::error::Error>> {
             header_answer: args.contains("--header-answer"),
             header_neutral: args.contains("--header-neutral"),
         }),
+        Some("remove") => Ok(AppArgs::Remove {
+            index_path: args.opt_value_from_str(["-i", "--index"])?,
+            id_list: args.opt_value_from_str(["-l", "--list"])?,
+            compact: args.contains("--compact"),
+            num_threads: args.value_from_str(["-t", "--threads"]).unwrap_or(1),
+            verbose: args.contains(["-v", "--verbose"]),
+            help: args.contains(["-h", "--help"]),
+        }),
+        Some("merge") => Ok(AppArgs::Merge {
+            index_paths: args.values_from_str(["-i", "--index"])?,
+            output_path: args.opt_value_from_str(["-o", "--output"])?,
+            num_threads: args.value_from_str(["-t", "--threads"]).unwrap_or(1),
+            verbose: args.contains(["-v", "--verbose"]),
+            help: args.contains(["-h", "--help"]),
+        }),
+        Some("stats") => Ok(AppArgs::Stats {
+            index_path: args.opt_value_from_str(["-i", "--index"])?,
+            top_n: args.value_from_str("--top").unwrap_or(20),
+            output: args.opt_value_from_str(["-o", "--output"])?,
+            verbose: args.contains(["-v", "--verbose"]),
+            help: args.contains(["-h", "--help"]),
+        }),
+        Some("verify") => Ok(AppArgs::Verify {
+            index_path: args.opt_value_from_str(["-i", "--index"])?,
+            verbose: args.contains(["-v", "--verbose"]),
+            help: args.contains(["-h", "--help"]),
+        }),
+        Some("convert") => Ok(AppArgs::Convert {
+            index_path: args.opt_value_from_str(["-i", "--index"])?,
+            output_path: args.opt_value_from_str(["-o", "--output"])?,
+            mode: args.opt_value_from_str(["-m", "--mode"])?,
+            binary_lookup: args.contains("--binary-lookup"),
+            num_threads: args.value_from_str(["-t", "--threads"]).unwrap_or(1),
+            verbose: args.contains(["-v", "--verbose"]),
+            help: args.contains(["-h", "--help"]),
+        }),
+        Some("serve") => Ok(AppArgs::Serve {
+            index_path: args.opt_value_from_str(["-i", "--index"])?,
+            host: args.value_from_str("--host").unwrap_or("127.0.0.1".into()),
+            port: args.value_from_str("--port").unwrap_or(8080),
+            socket: args.opt_value_from_str("--socket")?,
+            structure_root: args.opt_value_from_str("--structure-root")?,
+            foldcomp_db: args.opt_value_from_str("--foldcomp-db")?,
+            query_dir: args.opt_value_from_str("--query-dir")?,
+            threads: args.value_from_str(["-t", "--threads"]).unwrap_or(4),
+            verbose: args.contains(["-v", "--verbose"]),
+            help: args.contains(["-h", "--help"]),
+        }),
+        Some("rebin") => Ok(AppArgs::Rebin {
+            index_path: args.opt_value_from_str(["-i", "--index"])?,
+            output_path: args.opt_value_from_str(["-o", "--output"])?,
+            num_bin_dist: args.opt_value_from_str(["-d", "--distance"])?,
+            num_bin_angle: args.opt_value_from_str(["-a", "--angle"])?,
+            num_threads: args.value_from_str(["-t", "--threads"]).unwrap_or(1),
+            verbose: args.contains(["-v", "--verbose"]),
+            help: args.contains(["-h", "--help"]),
+        }),
         Some("test") => Ok(AppArgs::Test {
             index_path: args.value_from_str(["-i", "--index"])?,
             verbose: args.contains(["-v", "--verbose"]),
@@ -147,6 +230,62 @@ fn main() {
                 query_pdb::query_pdb(parsed_args);
             }
         }
+        AppArgs::Remove { help, .. } => {
+            if help {
+                print_logo();
+                eprintln!("{}", workflows::remove::HELP_REMOVE);
+            } else {
+                remove::remove(parsed_args);
+            }
+        }
+        AppArgs::Merge { help, .. } => {
+            if help {
+                print_logo();
+                eprintln!("{}", workflows::merge::HELP_MERGE);
+            } else {
+                merge::merge(parsed_args);
+            }
+        }
+        AppArgs::Stats { help, .. } => {
+            if help {
+                print_logo();
+                eprintln!("{}", workflows::stats::HELP_STATS);
+            } else {
+                stats::stats(parsed_args);
+            }
+        }
+        AppArgs::Verify { help, .. } => {
+            if help {
+                print_logo();
+                eprintln!("{}", workflows::verify::HELP_VERIFY);
+            } else {
+                verify::verify(parsed_args);
+            }
+        }
+        AppArgs::Convert { help, .. } => {
+            if help {
+                print_logo();
+                eprintln!("{}", workflows::convert::HELP_CONVERT);
+            } else {
+                convert::convert(parsed_args);
+            }
+        }
+        AppArgs::Serve { help, .. } => {
+            if help {
+                print_logo();
+                eprintln!("{}", workflows::serve::HELP_SERVE);
+            } else {
+                serve::serve(parsed_args);
+            }
+        }
+        AppArgs::Rebin { help, .. } => {
+            if help {
+                print_logo();
+                eprintln!("{}", workflows::rebin::HELP_REBIN);
+            } else {
+                rebin::rebin(parsed_args);
+            }
+        }
         AppArgs::Benchmark { .. } => {
             benchmark::benchmark(parsed_args);
         }
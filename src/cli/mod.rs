@@ -16,6 +16,7 @@ pub enum AppArgs {
     },
     Index {
         pdb_container: Option<String>,
+        path_list: Option<String>,
         hash_type: String,
         index_path: String,
         mode: String,
@@ -23,12 +24,23 @@ pub enum AppArgs {
         num_bin_dist: usize,
         num_bin_angle: usize,
         multiple_bins: Option<String>,
+        extra_hashes: Option<String>,
         grid_width: f32,
         chunk_size: usize,
         max_residue: usize,
         recursive: bool,
         mmap_on_disk: bool,
         id_type: String,
+        append: bool,
+        resume: bool,
+        checkpoint_interval: f64,
+        strict: bool,
+        max_memory: f64,
+        per_chain: bool,
+        stop_ratio: f32,
+        dry_run: bool,
+        sample_ratio: f32,
+        coord_cache: bool,
         verbose: bool,
         help: bool,
     },
@@ -77,6 +89,15 @@ pub enum AppArgs {
         header: bool,
         serial_query: bool,
         output: String,
+        output_format: String,
+        superposition: bool,
+        // Superposed hits written as structures
+        write_hits: Option<String>,
+        hit_environment: f32,
+        hit_format: String,
+        // Moved structures
+        structure_root: Option<String>,
+        foldcomp_db: Option<String>,
         verbose: bool,
         help: bool,
     },
@@ -101,6 +122,63 @@ pub enum AppArgs {
         header_answer: bool,
         header_neutral: bool,
     },
+    Merge {
+        index_paths: Vec<String>,
+        output_path: Option<String>,
+        num_threads: usize,
+        verbose: bool,
+        help: bool,
+    },
+    Remove {
+        index_path: Option<String>,
+        id_list: Option<String>,
+        compact: bool,
+        num_threads: usize,
+        verbose: bool,
+        help: bool,
+    },
+    Stats {
+        index_path: Option<String>,
+        top_n: usize,
+        output: Option<String>,
+        verbose: bool,
+        help: bool,
+    },
+    Verify {
+        index_path: Option<String>,
+        verbose: bool,
+        help: bool,
+    },
+    Convert {
+        index_path: Option<String>,
+        output_path: Option<String>,
+        mode: Option<String>,
+        binary_lookup: bool,
+        num_threads: usize,
+        verbose: bool,
+        help: bool,
+    },
+    Serve {
+        index_path: Option<String>,
+        host: String,
+        port: u16,
+        socket: Option<String>,
+        structure_root: Option<String>,
+        foldcomp_db: Option<String>,
+        query_dir: Option<String>,
+        threads: usize,
+        verbose: bool,
+        help: bool,
+    },
+    Rebin {
+        index_path: Option<String>,
+        output_path: Option<String>,
+        num_bin_dist: Option<usize>,
+        num_bin_angle: Option<usize>,
+        num_threads: usize,
+        verbose: bool,
+        help: bool,
+    },
     Test {
         index_path: String,
         verbose: bool,
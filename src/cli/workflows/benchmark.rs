@@ -4,7 +4,7 @@ use std::io::BufRead;
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 
 use crate::cli::*;
-use crate::index::lookup::load_lookup_from_file;
+use crate::index::lookup::load_lookup_table;
 use crate::prelude::*;
 
 use crate::cli::config::read_index_config_from_file;
@@ -86,8 +86,8 @@ pub fn benchmark(env: AppArgs) {
             let lookup_path = format!("{}.lookup", index_path);
             let config_path = format!("{}.type", index_path);
             let format = format.as_str();
-            let raw_lookup = load_lookup_from_file(&lookup_path);
-            let raw_lookup = raw_lookup.into_iter().map(|(id, _, _, _)| id).collect::<HashSet<_>>();
+            let raw_lookup = load_lookup_table(&index_path);
+            let raw_lookup = raw_lookup.iter().map(|(id, _, _, _)| id.to_string()).collect::<HashSet<_>>();
             let mut lookup = HashSet::with_capacity(raw_lookup.len());
             parse_path_set_as_set(&raw_lookup, &mut lookup, afdb_to_uniprot);
             let config = read_index_config_from_file(&config_path);
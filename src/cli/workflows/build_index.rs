@@ -9,14 +9,25 @@
 
 
 use std::path::PathBuf;
+use std::time::Duration;
 
-use crate::cli::config::{write_index_config_to_file, IndexConfig};
-use crate::controller::map::convert_sorted_hash_vec_to_simplemap;
+use crate::cli::config::{read_index_config_from_file, write_index_config_to_file, IndexConfig};
+use crate::controller::map::{convert_simplemap_to_hash_vec, convert_sorted_hash_vec_to_simplemap, SimpleHashMap};
 use crate::controller::mode::{parse_path_vec_by_id_type, IdType, IndexMode};
+use crate::controller::query::{get_hash_set_offset_value, get_offset_value_lookup_type};
 use crate::cli::*;
-use crate::controller::io::write_usize_vector_in_bits;
+use crate::controller::estimate::{estimate_index, write_index_estimate};
+use crate::controller::external_sort::{write_sorted_pairs_to_index, ExternalSorter};
+use crate::controller::io::{id_value_bits, read_id_values, split_path_vec_by_chain, write_id_values, write_skipped_report};
+use crate::index::checkpoint::IndexingPhase;
+use crate::index::coords::{CoordinateCache, CoordinateCacheWriter};
+use crate::index::indextable::load_big_index;
+use crate::index::lookup::{load_locations_from_file, refresh_binary_lookup, save_locations_to_file};
+use crate::index::stoplist::{apply_stop_list, load_stop_list_from_file, max_doc_freq, save_stop_list_to_file, StopList};
 use crate::prelude::*;
 use crate::structure::io::StructureFileFormat;
+use crate::structure::io::archive::is_tar_archive;
+use crate::utils::loader::{load_archive, load_path_list};
 use peak_alloc::PeakAlloc;
 
 #[cfg(feature= "foldcomp")]
@@ -28,20 +39,45 @@ use crate::structure::io::fcz::*;
 static PEAK_ALLOC: PeakAlloc = PeakAlloc;
 
 pub const HELP_INDEX: &str = "\
-usage: folddisco index -p <i:PDB_DIR>|<i:FOLDCOMP_DB> -i <o:INDEX_PATH> [OPTIONS]
+usage: folddisco index -p <i:PDB_DIR>|<i:TAR>|<i:FOLDCOMP_DB> -i <o:INDEX_PATH> [OPTIONS]
+       folddisco index --list <i:PATH_LIST> -i <o:INDEX_PATH> [OPTIONS]
 
 input/output:
- -p, --pdbs <PATH>                Directory or Foldcomp DB containing PDB files
+ -p, --pdbs <PATH>                Directory, tar archive (.tar, .tar.gz, .tgz) or Foldcomp DB containing PDB files.
+                                  Archives are read in place. Structures are stored as ARCHIVE:MEMBER
+ --list <PATH>                    File with one structure path per line instead of -p. - reads paths from stdin
  -i, --index <PATH>               Path to save the index table
  -r, --recursive                  Index PDB files in subdirectories recursively
 
 indexing parameters:
  -t, --threads <INT>              Number of threads to use [1]
  -n, --max-residue <INT>          Maximum number of residues in a PDB file [50000]
- --id <STR>                       ID type to use (pdb, uniprot, afdb, relpath, abspath) [relpath]
+ --id <STR>                       ID type to use (pdb, uniprot, afdb, basename, relpath, abspath) [relpath].
+                                  With IDs other than paths, locations are stored in INDEX_PATH.paths
+ --per-chain                      Index each chain as its own entry. IDs get the chain appended (e.g. 1abc_A)
+ --append                         Append structures to an existing index at -i instead of rebuilding it.
+                                  Hashing parameters, mode and ID type are taken from the existing index.
  -m, --mode <MODE>                Mode to index [id]
-    id: suitable for smaller dataset (N < 65536) with hashmap offset;
+    id: suitable for small to mid-sized dataset with hashmap offset.
+        Ids are stored in 16 bits up to 65536 structures and in 32 bits above;
     big: 8GB fixed-size offset table, suitable for large dataset
+ -c, --chunk <INT>                Maximum number of structures in one id mode index.
+                                  Larger inputs are split into INDEX_PATH_0, INDEX_PATH_1, ... [1000000]
+ --max-memory <FLOAT>             Id mode. Memory budget in GB for sorting hashes. Sorted runs are
+                                  spilled to INDEX_PATH.sort.run_N and merged. 0 sorts in memory [0]
+ --stop-ratio <FLOAT>             Id mode. Leave out hashes found in more than this fraction of structures.
+                                  Left out hashes are listed in INDEX_PATH.stop. 0 keeps all hashes [0]
+ --dry-run                        Hash a sample of the inputs and print estimated index sizes, peak memory
+                                  and time for id and big mode without writing the index
+ --sample <FLOAT>                 Fraction of inputs hashed with --dry-run. At least 100 structures [0.01]
+ --coord-cache                    Save coordinates of indexed structures to INDEX_PATH.coords.
+                                  Queries read candidates from it instead of the original files. Not with --resume
+ --strict                         Stop at the first structure that can't be indexed.
+                                  Otherwise such structures are left out and listed in INDEX_PATH.skipped
+ --checkpoint-interval <FLOAT>    Big mode. Save progress to INDEX_PATH.checkpoint every N minutes.
+                                  Each checkpoint copies the offsets and ids of the index. Off if negative [-1]
+ --resume                         Big mode. Continue an interrupted run from INDEX_PATH.checkpoint.
+                                  Inputs and parameters should be the same as the interrupted run.
 
 hashing parameters:
  -y, --type STR                   Hash type to use (default, pdb, trrosetta, ppf, 3di) [default]
@@ -49,6 +85,9 @@ hashing parameters:
  -a, --angle INT                  Number of angle bins [default, 4]
  --multiple-bins STR              Multiple bins for distance and angle (dist1-ang1,dist2-ang2 e.g. 16-4,8-3)
                                   While increasing sensitivity, this option increases the size of the index.
+ --extra-hashes STR               Id mode. Other hash types indexed next to -y, each in its own table
+                                  (type:dist-ang or type, e.g. ppf:16-8,pdb). Queries score each type
+                                  separately and add up the scores. Structures are read once per type
 
 general options:
  -v, --verbose                    Print verbose messages
@@ -61,6 +100,20 @@ folddisco index -p h_sapiens -i index/h_sapiens -t 12
 # Indexing big protein dataset
 folddisco index -p swissprot -i index/swissprot -t 64 -m big -v
 
+# Indexing structures in a tar archive or listed in a file or stdin
+folddisco index -p afdb_swissprot.tar -i index/swissprot -t 12
+find h_sapiens -name '*.cif.gz' | folddisco index --list - -i index/h_sapiens -t 12
+
+# Estimating index size and memory before indexing
+folddisco index -p swissprot -i index/swissprot -t 64 --dry-run --sample 0.001
+
+# Saving progress every hour and resuming an interrupted big mode run
+folddisco index -p swissprot -i index/swissprot -t 64 -m big -v --checkpoint-interval 60
+folddisco index -p swissprot -i index/swissprot -t 64 -m big -v --checkpoint-interval 60 --resume
+
+# Appending new structures to an existing index
+folddisco index -p new_models -i index/h_sapiens -t 12 --append
+
 # Indexing with custom hash type and parameters
 folddisco index -p h_sapiens -i index/h_sapiens -t 12 -y default -d 16 -a 4 # Default
 folddisco index -p h_sapiens -i index/h_sapiens -t 12 -y pdb -d 8 -a 3 # PDB
@@ -70,6 +123,7 @@ pub fn build_index(env: AppArgs) {
     match env {
         AppArgs::Index {
             pdb_container,
+            path_list,
             hash_type,
             index_path,
             num_threads,
@@ -78,32 +132,68 @@ pub fn build_index(env: AppArgs) {
             num_bin_dist,
             num_bin_angle,
             multiple_bins,
+            extra_hashes,
             grid_width,
             chunk_size,
             recursive,
             mmap_on_disk,
             id_type,
+            append,
+            resume,
+            checkpoint_interval,
+            strict,
+            max_memory,
+            per_chain,
+            stop_ratio,
+            dry_run,
+            sample_ratio,
+            coord_cache,
             verbose,
             help: _,
         } => {
             if verbose { print_logo(); }
             // Check if arguments are valid
-            if pdb_container.is_none() {
+            if pdb_container.is_none() && path_list.is_none() {
                 eprintln!("{}", HELP_INDEX);
                 std::process::exit(1);
             }
+            if pdb_container.is_some() && path_list.is_some() {
+                print_log_msg(FAIL, "-p and --list can't be used together");
+                std::process::exit(1);
+            }
+            if dry_run && append {
+                print_log_msg(FAIL, "--dry-run can't be used with --append");
+                std::process::exit(1);
+            }
+            if resume && (append || dry_run || IndexMode::get_with_str(mode.as_str()) != IndexMode::Big) {
+                print_log_msg(FAIL, "--resume is only supported in big mode (-m big) without --append or --dry-run");
+                std::process::exit(1);
+            }
+            if resume && coord_cache {
+                print_log_msg(FAIL, "--coord-cache can't be used with --resume. Coordinates are cached while hashing");
+                std::process::exit(1);
+            }
             // help is handled in the main function
-            let pdb_container_clone = pdb_container.clone();
+            let pdb_container_clone = pdb_container.clone().or(path_list.clone());
             #[cfg(feature = "foldcomp")]
-            let pdb_container_name: &'static str = Box::leak(pdb_container.clone().unwrap().into_boxed_str());
+            let pdb_container_name: &'static str = Box::leak(pdb_container.clone().unwrap_or_default().into_boxed_str());
             #[allow(unused_mut)]
             let mut input_format: StructureFileFormat = StructureFileFormat::PDB;
             // Load PDB files
-            let pdb_path_vec = if pdb_container.is_some() {
-                // Check if pdb_dir is a directory or db file
+            let pdb_path_vec = if let Some(path_list) = &path_list {
+                load_path_list(path_list)
+            } else if pdb_container.is_some() {
+                // Check if pdb_dir is a directory, tar archive or db file
                 // If not foldcomp, just load
                 #[cfg(not(feature = "foldcomp"))]
-                { load_path(&pdb_container.unwrap(), recursive) }
+                {
+                    let pdb_container = pdb_container.unwrap();
+                    if is_tar_archive(&pdb_container) {
+                        load_archive(&pdb_container)
+                    } else {
+                        load_path(&pdb_container, recursive)
+                    }
+                }
                 #[cfg(feature = "foldcomp")]
                 {
                     let pdb_container = pdb_container.unwrap();
@@ -111,6 +201,8 @@ pub fn build_index(env: AppArgs) {
                     let is_dir = PathBuf::from(&pdb_container).is_dir();
                     if is_dir {
                         load_path(&pdb_container, recursive)
+                    } else if is_tar_archive(&pdb_container) {
+                        load_archive(&pdb_container)
                     } else {
                         let lookup_vec = read_foldcomp_db_lookup(&pdb_container).expect(
                             &log_msg(FAIL, "Failed to read Foldcomp DB lookup")
@@ -127,12 +219,52 @@ pub fn build_index(env: AppArgs) {
                 eprintln!("{}", HELP_INDEX);
                 std::process::exit(1);
             };
-            
+
+            // Chains are listed before hashing so that each chain gets its own id.
+            // Appending follows the existing index
+            let pdb_path_vec = if per_chain && !append {
+                if input_format == StructureFileFormat::FCZDB {
+                    print_log_msg(FAIL, "--per-chain is not supported for Foldcomp DB input");
+                    std::process::exit(1);
+                }
+                if verbose { print_log_msg(INFO, "Splitting structures by chain"); }
+                split_path_vec_by_chain(&pdb_path_vec, num_threads)
+            } else {
+                pdb_path_vec
+            };
+
+            if append {
+                if extra_hashes.is_some() {
+                    print_log_msg(FAIL, "--extra-hashes can't be used with --append. Hash types are taken from the existing index");
+                    std::process::exit(1);
+                }
+                let pdb_container = pdb_container_clone.unwrap();
+                let foldcomp_db = (input_format == StructureFileFormat::FCZDB).then_some(pdb_container.as_str());
+                append_to_index(
+                    pdb_path_vec, foldcomp_db, &index_path,
+                    num_threads, mmap_on_disk, strict, verbose,
+                );
+                return;
+            }
+
             let index_mode = IndexMode::get_with_str(mode.as_str());
+            if !(0.0..=1.0).contains(&stop_ratio) {
+                print_log_msg(FAIL, "--stop-ratio should be between 0 and 1");
+                std::process::exit(1);
+            }
+            if index_mode == IndexMode::Big && stop_ratio > 0.0 {
+                print_log_msg(FAIL, "--stop-ratio is only supported in id mode");
+                std::process::exit(1);
+            }
+            let extra_hash_sets = extra_hashes.as_deref().map(parse_hash_sets).unwrap_or_default();
+            if !extra_hash_sets.is_empty() && (index_mode == IndexMode::Big || max_memory > 0.0) {
+                print_log_msg(FAIL, "--extra-hashes is only supported in id mode without --max-memory");
+                std::process::exit(1);
+            }
             if index_mode == IndexMode::Big && verbose {
                 print_log_msg(INFO, "Indexing in Big mode.");
             }
-            let chunk_size = if chunk_size > u16::max_value() as usize { u16::max_value() as usize } else { chunk_size };
+            let chunk_size = if chunk_size > u32::MAX as usize { u32::MAX as usize } else { chunk_size };
             // Overwrite chunk_size if index_mode is Big
             let chunk_size = if index_mode == IndexMode::Big { pdb_path_vec.len() } else { chunk_size };
             let num_chunks = if pdb_path_vec.len() <= chunk_size { 1 } else { (pdb_path_vec.len() as f64 / chunk_size as f64).ceil() as usize };
@@ -140,21 +272,59 @@ pub fn build_index(env: AppArgs) {
                 print_log_msg(
                     INFO,&format!(
                         "Indexing {} with {} threads and {} chunks",
-                        pdb_container_clone.unwrap_or("None".to_string()),
+                        pdb_container_clone.as_deref().unwrap_or("None"),
                         num_threads, num_chunks
                     )
                 );
             }
             let hash_type = HashType::get_with_str(hash_type.as_str());
             if verbose { print_log_msg(INFO, &format!("Hash type: {:?}", hash_type)); }
-            let pdb_path_chunks = pdb_path_vec.chunks(chunk_size);
-            let id_type = IdType::get_with_str(id_type.as_str());
-            
             let multiple_bins = if let Some(multiple_bins) = multiple_bins {
                 Some(parse_pairs(&multiple_bins))
             } else {
                 None
             };
+
+            if dry_run {
+                // Sampled in id mode so that the big mode offset table is not allocated
+                #[cfg(not(feature = "foldcomp"))]
+                let folddisco = FoldDisco::new(
+                    pdb_path_vec, hash_type, num_threads,
+                    num_bin_dist, num_bin_angle, index_path.clone(),
+                    grid_width, IndexMode::Id, multiple_bins,
+                    mmap_on_disk,
+                );
+                #[cfg(feature = "foldcomp")]
+                let folddisco = if input_format != StructureFileFormat::FCZDB {
+                    FoldDisco::new(
+                        pdb_path_vec, hash_type, num_threads,
+                        num_bin_dist, num_bin_angle, index_path.clone(),
+                        grid_width, IndexMode::Id, multiple_bins,
+                        mmap_on_disk,
+                    )
+                } else {
+                    FoldDisco::new_with_foldcomp_db(
+                        pdb_path_vec, hash_type, num_threads,
+                        num_bin_dist, num_bin_angle, index_path.clone(),
+                        grid_width, IndexMode::Id, pdb_container_name,
+                        multiple_bins, mmap_on_disk,
+                    )
+                };
+                if verbose { print_log_msg(INFO, "Dry run. Hashing a sample of the inputs"); }
+                let estimate = estimate_index(&folddisco, sample_ratio, chunk_size, max_memory);
+                write_index_estimate(&mut std::io::stdout().lock(), &estimate).unwrap_or_else(|_| panic!(
+                    "{}", log_msg(FAIL, "Failed to write the estimate")
+                ));
+                return;
+            }
+
+            let pdb_path_chunks = pdb_path_vec.chunks(chunk_size);
+            let id_type = IdType::get_with_str(id_type.as_str());
+            let structure_root = if path_list.is_none() {
+                get_structure_root(pdb_container_clone.as_deref(), &id_type, &input_format)
+            } else {
+                None
+            };
             
             pdb_path_chunks.into_iter().enumerate().for_each(|(i, pdb_path_vec)| {
                 // let pdb_container_name_inner: &'static str = pdb_container_name.clone();
@@ -176,7 +346,7 @@ pub fn build_index(env: AppArgs) {
                     mmap_on_disk,
                 );
                 #[cfg(feature = "foldcomp")]
-                let mut folddisco = if PathBuf::from(&pdb_container_name).is_dir() {
+                let mut folddisco = if input_format != StructureFileFormat::FCZDB {
                     FoldDisco::new(
                         pdb_path_vec.to_vec(), hash_type, num_threads, 
                         num_bin_dist, num_bin_angle, index_path.clone(), 
@@ -191,8 +361,27 @@ pub fn build_index(env: AppArgs) {
                         multiple_bins.clone(), mmap_on_disk,
                     )
                 };
+                folddisco.set_strict(strict);
+                start_coordinate_cache(&mut folddisco, &index_path, coord_cache, verbose);
                 
+                // Pairs are sorted on disk if a memory budget is given
+                let mut sorter = None;
                 match index_mode {
+                    IndexMode::Id if max_memory > 0.0 => {
+                        if verbose {
+                            print_log_msg(INFO, &format!("Collecting ids of the structures with {}GB memory budget", max_memory));
+                        }
+                        let mut external_sorter = ExternalSorter::new(
+                            &format!("{}.sort", index_path), (max_memory * 1073741824.0) as usize, num_threads
+                        );
+                        folddisco.collect_hash_runs(&mut external_sorter);
+                        if verbose {
+                            print_log_msg(INFO, &format!(
+                                "{} sorted runs written (Allocated {}MB)", external_sorter.num_runs(), PEAK_ALLOC.current_usage_as_mb()
+                            ));
+                        }
+                        sorter = Some(external_sorter);
+                    }
                     IndexMode::Id => {
                         if verbose {
                             print_log_msg(INFO, "Collecting ids of the structures"); 
@@ -207,53 +396,92 @@ pub fn build_index(env: AppArgs) {
                         }
                     }
                     IndexMode::Big => {
+                        if checkpoint_interval >= 0.0 {
+                            folddisco.set_checkpoint_interval(Duration::from_secs_f64(checkpoint_interval * 60.0));
+                        }
+                        let mut phase = IndexingPhase::Counting;
+                        if resume {
+                            phase = folddisco.resume_from_checkpoint().unwrap_or_else(|e| {
+                                print_log_msg(FAIL, &format!("Cannot resume indexing: {}", e));
+                                std::process::exit(1);
+                            });
+                            let completed_chunks = folddisco.resume_checkpoint.as_ref().unwrap().completed_chunks;
+                            print_log_msg(INFO, &format!(
+                                "Resuming {} phase after {} completed chunks", phase, completed_chunks
+                            ));
+                        }
                         if verbose {
                             print_log_msg(INFO, "Collecting ids of the structures");
-                            measure_time!(folddisco.collect_and_count());
-                            print_log_msg(INFO, 
-                                &format!("Hashes collected (Allocated {}MB)", PEAK_ALLOC.current_usage_as_mb())
-                            );
-                            measure_time!(folddisco.fold_disco_index.allocate_entries());
+                            if phase == IndexingPhase::Counting {
+                                measure_time!(folddisco.collect_and_count());
+                                folddisco.finish_coordinate_cache();
+                                print_log_msg(INFO, 
+                                    &format!("Hashes collected (Allocated {}MB)", PEAK_ALLOC.current_usage_as_mb())
+                                );
+                                measure_time!(folddisco.fold_disco_index.allocate_entries());
+                            }
                             measure_time!(folddisco.add_entries());
                             measure_time!(folddisco.fold_disco_index.finish_index());
                             measure_time!(folddisco.fold_disco_index.save_offset_to_file());
                         } else {
-                            folddisco.collect_and_count();
-                            folddisco.fold_disco_index.allocate_entries();
+                            if phase == IndexingPhase::Counting {
+                                folddisco.collect_and_count();
+                                folddisco.finish_coordinate_cache();
+                                folddisco.fold_disco_index.allocate_entries();
+                            }
                             folddisco.add_entries();
                             folddisco.fold_disco_index.finish_index();
                             folddisco.fold_disco_index.save_offset_to_file();
                         }
+                        folddisco.remove_checkpoint();
                     }
                 }
+                folddisco.finish_coordinate_cache();
+                report_skipped(&folddisco, &index_path, false);
+                folddisco.exclude_skipped();
                 if verbose { print_log_msg(INFO,
                     &format!("Hash sorted (Allocated {}MB)", PEAK_ALLOC.current_usage_as_mb())
                     // "Hash sorted"
                 ); }
                 folddisco.fill_numeric_id_vec();
 
+                let value_bits = id_value_bits(pdb_path_vec.len());
                 match index_mode {
                     IndexMode::Id => {
                         let offset_path = format!("{}.offset", index_path);
                         let value_path = format!("{}.value", index_path);
-                        if verbose {
-                            let (offset_map, value_vec) = measure_time!(convert_sorted_hash_vec_to_simplemap(folddisco.hash_id_vec));
+                        let max_doc_freq = (stop_ratio > 0.0).then(|| max_doc_freq(folddisco.path_vec.len(), stop_ratio));
+                        let mut stop_list = StopList::new();
+                        if let (None, Some(max_doc_freq)) = (&sorter, max_doc_freq) {
+                            apply_stop_list(&mut folddisco.hash_id_vec, &mut stop_list, max_doc_freq);
+                        }
+                        if let Some(sorter) = sorter {
+                            if verbose { print_log_msg(INFO, "Merging sorted runs"); }
+                            stop_list = write_sorted_pairs_to_index(
+                                sorter, &offset_path, &value_path, value_bits, hash_type, max_doc_freq
+                            ).unwrap_or_else(|_| panic!(
+                                "{}", log_msg(FAIL, "Failed to save index from sorted runs")
+                            ));
+                        } else if verbose {
+                            let (offset_map, value_vec) = measure_time!(convert_sorted_hash_vec_to_simplemap(std::mem::take(&mut folddisco.hash_id_vec)));
                             print_log_msg(INFO, &format!("Offset & values acquired (Allocated {}MB)", PEAK_ALLOC.current_usage_as_mb()));
-                            measure_time!(offset_map.dump_to_disk(&PathBuf::from(&offset_path)).expect(
-                               &log_msg(FAIL, "Failed to save offset table")
+                            measure_time!(offset_map.dump_to_disk(&PathBuf::from(&offset_path), hash_type).unwrap_or_else(|_| panic!(
+                                "{}", log_msg(FAIL, "Failed to save offset table")
+                            )));
+                            measure_time!(write_id_values(&value_path, &value_vec, value_bits, hash_type).unwrap_or_else(|_| panic!(
+                                "{}", log_msg(FAIL, "Failed to save values")
+                            )));
+                        } else {
+                            let (offset_map, value_vec) = convert_sorted_hash_vec_to_simplemap(std::mem::take(&mut folddisco.hash_id_vec));
+                            offset_map.dump_to_disk(&PathBuf::from(&offset_path), hash_type).unwrap_or_else(|_| panic!(
+                                "{}", log_msg(FAIL, "Failed to save offset table")
                             ));
-                            measure_time!(write_usize_vector_in_bits(&value_path, &value_vec, 16).expect(
-                                &log_msg(FAIL, "Failed to save values")
+                            write_id_values(&value_path, &value_vec, value_bits, hash_type).unwrap_or_else(|_| panic!(
+                                "{}", log_msg(FAIL, "Failed to save values")
                             ));
-                        } else {
-                            let (offset_map, value_vec) = convert_sorted_hash_vec_to_simplemap(folddisco.hash_id_vec);
-                            offset_map.dump_to_disk(&PathBuf::from(&offset_path)).expect(
-                                &log_msg(FAIL, "Failed to save offset table")
-                            );
-                            write_usize_vector_in_bits(&value_path, &value_vec, 16).expect(
-                                &log_msg(FAIL, "Failed to save values")
-                            );
                         }
+                        report_stopped(&stop_list, &index_path, verbose);
+                        save_extra_hash_tables(&folddisco, &index_path, &extra_hash_sets, value_bits, verbose);
                     }
                     IndexMode::Big => {}
                 }
@@ -270,21 +498,33 @@ pub fn build_index(env: AppArgs) {
                         Some(&folddisco.nres_vec), Some(&folddisco.plddt_vec)
                     );
                 }
+                // A binary lookup of a previous build is rewritten from the new lookup.
+                // Its tombstones are removed, as they would mark the new ids as deleted
+                refresh_binary_lookup(&index_path);
+                let _ = std::fs::remove_file(format!("{}.deleted", index_path));
+
+                save_locations(&folddisco.path_vec, &index_path, &id_type, &input_format);
 
                 let hash_type_path = format!("{}.type", index_path);
-                let chunk_size = pdb_path_vec.len();
+                let chunk_size = folddisco.path_vec.len();
                 #[cfg(not(feature = "foldcomp"))]
-                let index_config = IndexConfig::new(
+                let mut index_config = IndexConfig::new(
                     hash_type, num_bin_dist, num_bin_angle, index_mode.clone(),
                     grid_width, chunk_size, max_residue, input_format.clone(),
                     None, multiple_bins.clone(),
                 );
                 #[cfg(feature = "foldcomp")]
-                let index_config = IndexConfig::new(
+                let mut index_config = IndexConfig::new(
                     hash_type, num_bin_dist, num_bin_angle, index_mode.clone(),
                     grid_width, chunk_size, max_residue, input_format.clone(), 
                     Some(pdb_container_name.to_string()), multiple_bins.clone(),
                 );
+                index_config.value_bits = value_bits;
+                index_config.per_chain = per_chain;
+                index_config.stop_ratio = stop_ratio;
+                index_config.id_type = id_type;
+                index_config.structure_root = structure_root.clone();
+                index_config.extra_hash_sets = extra_hash_sets.clone();
                 write_index_config_to_file(&hash_type_path, index_config);
                 if verbose { print_log_msg(DONE, &format!("Indexing done for chunk {} - {}", i, index_path)); }
             });
@@ -297,6 +537,351 @@ pub fn build_index(env: AppArgs) {
     }
 }
 
+// Hash only the new structures and merge them with an existing index.
+// New numeric ids start after the last id of the existing lookup and
+// IDs are made with the id type of the existing index.
+// Merged index is written next to the original and renamed over it at the end.
+// `foldcomp_db` is the Foldcomp DB the structures are read from, None for structure files.
+fn append_to_index(
+    pdb_path_vec: Vec<String>, foldcomp_db: Option<&str>, index_path: &str,
+    num_threads: usize, mmap_on_disk: bool, strict: bool, verbose: bool,
+) {
+    let input_format = if foldcomp_db.is_some() { StructureFileFormat::FCZDB } else { StructureFileFormat::PDB };
+    if !PathBuf::from(format!("{}.offset", index_path)).is_file() {
+        if PathBuf::from(format!("{}_0.offset", index_path)).is_file() {
+            print_log_msg(FAIL, &format!("{} is chunked. Appending to chunked index is not supported", index_path));
+        } else {
+            print_log_msg(FAIL, &format!("Index to append not found: {}", index_path));
+        }
+        std::process::exit(1);
+    }
+    let (offset_path, value_path, lookup_path, hash_type_path) = get_offset_value_lookup_type(index_path.to_string());
+    let mut config = read_index_config_from_file(&hash_type_path);
+    if config.input_format != input_format {
+        print_log_msg(FAIL, &format!(
+            "Input format {} does not match the index ({})",
+            input_format.to_string(), config.input_format.to_string()
+        ));
+        std::process::exit(1);
+    }
+    if foldcomp_db.is_some() && config.foldcomp_db.as_deref() != foldcomp_db {
+        print_log_msg(FAIL, "Appending from a different Foldcomp DB is not supported");
+        std::process::exit(1);
+    }
+    if !config.extra_hash_sets.is_empty() {
+        print_log_msg(FAIL, "Appending to an index with extra hash sets is not supported");
+        std::process::exit(1);
+    }
+
+    let pdb_path_vec = if config.per_chain {
+        split_path_vec_by_chain(&pdb_path_vec, num_threads)
+    } else {
+        pdb_path_vec
+    };
+
+    let lookup = load_lookup_from_file(&lookup_path);
+    let num_existing = lookup.len();
+    let num_total = num_existing + pdb_path_vec.len();
+    // Value vector is widened if the appended index doesn't fit in 16 bits
+    let value_bits = id_value_bits(num_total);
+    if verbose {
+        print_log_msg(INFO, &format!(
+            "Appending {} structures to {} ({} existing, {:?}, {} mode)",
+            pdb_path_vec.len(), index_path, num_existing, config.hash_type, config.mode.to_string()
+        ));
+    }
+
+    let tmp_path = format!("{}.append", index_path);
+    #[cfg(not(feature = "foldcomp"))]
+    let mut folddisco = FoldDisco::new(
+        pdb_path_vec, config.hash_type, num_threads,
+        config.num_bin_dist, config.num_bin_angle, tmp_path.clone(),
+        config.grid_width, config.mode, config.multiple_bin.clone(),
+        mmap_on_disk,
+    );
+    #[cfg(feature = "foldcomp")]
+    let mut folddisco = if input_format != StructureFileFormat::FCZDB {
+        FoldDisco::new(
+            pdb_path_vec, config.hash_type, num_threads,
+            config.num_bin_dist, config.num_bin_angle, tmp_path.clone(),
+            config.grid_width, config.mode, config.multiple_bin.clone(),
+            mmap_on_disk,
+        )
+    } else {
+        let pdb_container_name: &'static str = Box::leak(foldcomp_db.unwrap().to_string().into_boxed_str());
+        FoldDisco::new_with_foldcomp_db(
+            pdb_path_vec, config.hash_type, num_threads,
+            config.num_bin_dist, config.num_bin_angle, tmp_path.clone(),
+            config.grid_width, config.mode, pdb_container_name,
+            config.multiple_bin.clone(), mmap_on_disk,
+        )
+    };
+    folddisco.set_id_offset(num_existing);
+    folddisco.set_strict(strict);
+    // Coordinates of the new structures are added if the index has a coordinate cache
+    let coords_path = format!("{}.coords", index_path);
+    let has_coord_cache = PathBuf::from(&coords_path).is_file();
+    if has_coord_cache {
+        if verbose { print_log_msg(INFO, "Adding new structures to the coordinate cache"); }
+        let coord_cache = CoordinateCache::open(&coords_path).unwrap_or_else(|_| panic!(
+            "{}", log_msg(FAIL, "Failed to open coordinate cache")
+        ));
+        let mut writer = CoordinateCacheWriter::create(&format!("{}.coords", tmp_path)).unwrap_or_else(|_| panic!(
+            "{}", log_msg(FAIL, "Failed to create coordinate cache")
+        ));
+        coord_cache.copy_to(&mut writer, |_| true).unwrap_or_else(|_| panic!(
+            "{}", log_msg(FAIL, "Failed to copy coordinate cache")
+        ));
+        folddisco.set_coordinate_cache_writer(writer);
+    }
+
+    match config.mode {
+        IndexMode::Id => {
+            let (offset_table, offset_mmap) = SimpleHashMap::load_from_disk(&PathBuf::from(&offset_path));
+            let offset_table = offset_table.unwrap_or_else(|_| panic!(
+                "{}", log_msg(FAIL, "Failed to load offset table")
+            ));
+            let (value_mmap, value_vec) = read_id_values(&value_path, config.value_bits).unwrap_or_else(|_| panic!(
+                "{}", log_msg(FAIL, "Failed to load values")
+            ));
+            if verbose { print_log_msg(INFO, "Collecting hashes of new structures"); }
+            folddisco.collect_hash_vec();
+            folddisco.finish_coordinate_cache();
+            folddisco.hash_id_vec.extend(convert_simplemap_to_hash_vec(&offset_table, value_vec, Some));
+            drop(offset_table);
+            drop(offset_mmap);
+            drop(value_mmap);
+            folddisco.sort_hash_vec();
+            // Hashes stopped in the existing index stay stopped. Others are checked against the new size
+            let mut stop_list = load_stop_list_from_file(&format!("{}.stop", index_path)).unwrap_or_default();
+            if config.stop_ratio > 0.0 {
+                let max_doc_freq = max_doc_freq(num_existing + folddisco.path_vec.len(), config.stop_ratio);
+                apply_stop_list(&mut folddisco.hash_id_vec, &mut stop_list, max_doc_freq);
+            }
+            report_stopped(&stop_list, &tmp_path, verbose);
+            let hash_id_vec = std::mem::take(&mut folddisco.hash_id_vec);
+            let (offset_map, value_vec) = convert_sorted_hash_vec_to_simplemap(hash_id_vec);
+            offset_map.dump_to_disk(&PathBuf::from(format!("{}.offset", tmp_path)), config.hash_type).unwrap_or_else(|_| panic!(
+                "{}", log_msg(FAIL, "Failed to save offset table")
+            ));
+            write_id_values(&format!("{}.value", tmp_path), &value_vec, value_bits, config.hash_type).unwrap_or_else(|_| panic!(
+                "{}", log_msg(FAIL, "Failed to save values")
+            ));
+        }
+        IndexMode::Big => {
+            let (big_index, big_offset_mmap) = load_big_index(index_path);
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(num_threads)
+                .build()
+                .unwrap_or_else(|_| panic!(
+                    "{}", log_msg(FAIL, "Failed to build thread pool for copying entries")
+                ));
+            // Existing entries go first so that new ids are always larger
+            if verbose { print_log_msg(INFO, "Counting entries of existing index"); }
+            pool.install(|| folddisco.fold_disco_index.count_entries_from_index(&big_index, Some));
+            folddisco.collect_and_count();
+            folddisco.finish_coordinate_cache();
+            folddisco.fold_disco_index.allocate_entries();
+            if verbose { print_log_msg(INFO, "Copying entries of existing index"); }
+            pool.install(|| folddisco.fold_disco_index.add_entries_from_index(&big_index, Some));
+            folddisco.add_entries();
+            folddisco.fold_disco_index.finish_index();
+            folddisco.fold_disco_index.save_offset_to_file();
+            drop(pool);
+            drop(big_index);
+            drop(big_offset_mmap);
+        }
+    }
+    report_skipped(&folddisco, index_path, true);
+    folddisco.exclude_skipped();
+    folddisco.fill_numeric_id_vec();
+    let num_total = num_existing + folddisco.path_vec.len();
+
+    // Existing lookup followed by the new structures
+    let mut id_vec = Vec::with_capacity(num_total);
+    let mut numeric_id_vec = Vec::with_capacity(num_total);
+    let mut nres_vec = Vec::with_capacity(num_total);
+    let mut plddt_vec = Vec::with_capacity(num_total);
+    for (id, nid, nres, plddt) in lookup.into_iter() {
+        id_vec.push(id);
+        numeric_id_vec.push(nid);
+        nres_vec.push(nres);
+        plddt_vec.push(plddt);
+    }
+    id_vec.extend(parse_path_vec_by_id_type(&folddisco.path_vec, &config.id_type));
+    numeric_id_vec.extend(folddisco.numeric_id_vec.iter());
+    nres_vec.extend(folddisco.nres_vec.iter());
+    plddt_vec.extend(folddisco.plddt_vec.iter());
+    save_lookup_to_file(
+        &format!("{}.lookup", tmp_path), &id_vec, &numeric_id_vec,
+        Some(&nres_vec), Some(&plddt_vec)
+    );
+    // Locations of the new structures follow the existing ones. An index without
+    // {index_path}.paths had the locations in its IDs
+    if !config.id_type.is_path() {
+        let mut location_vec = load_locations_from_file(&format!("{}.paths", index_path)).unwrap_or_else(
+            || id_vec[..num_existing].to_vec()
+        );
+        location_vec.extend(get_locations(&folddisco.path_vec, &input_format));
+        save_locations_to_file(&format!("{}.paths", tmp_path), &location_vec);
+    }
+    config.chunk_size = num_total;
+    config.value_bits = value_bits;
+    let config_id_type = config.id_type;
+    write_index_config_to_file(&format!("{}.type", tmp_path), config);
+    drop(folddisco);
+
+    if !config_id_type.is_path() {
+        std::fs::rename(format!("{}.paths", tmp_path), format!("{}.paths", index_path)).unwrap_or_else(|_| panic!(
+            "{}", log_msg(FAIL, &format!("Failed to replace {}.paths", index_path))
+        ));
+    }
+    if has_coord_cache {
+        std::fs::rename(format!("{}.coords", tmp_path), &coords_path).unwrap_or_else(|_| panic!(
+            "{}", log_msg(FAIL, &format!("Failed to replace {}", coords_path))
+        ));
+    }
+    if PathBuf::from(format!("{}.stop", tmp_path)).is_file() {
+        std::fs::rename(format!("{}.stop", tmp_path), format!("{}.stop", index_path)).unwrap_or_else(|_| panic!(
+            "{}", log_msg(FAIL, &format!("Failed to replace {}.stop", index_path))
+        ));
+    } else {
+        let _ = std::fs::remove_file(format!("{}.stop", index_path));
+    }
+    for ext in ["offset", "value", "lookup", "type"] {
+        std::fs::rename(format!("{}.{}", tmp_path, ext), format!("{}.{}", index_path, ext)).unwrap_or_else(|_| panic!(
+            "{}", log_msg(FAIL, &format!("Failed to replace {}.{}", index_path, ext))
+        ));
+    }
+    refresh_binary_lookup(index_path);
+    if verbose { print_log_msg(DONE, &format!("Appended. {} now has {} structures", index_path, num_total)); }
+}
+
+// Root that structure locations start with. Queries replace it with --structure-root.
+// Path lists have no common root and Foldcomp DBs are moved with --foldcomp-db instead.
+fn get_structure_root(pdb_container: Option<&str>, id_type: &IdType, input_format: &StructureFileFormat) -> Option<String> {
+    let pdb_container = pdb_container?.trim_end_matches('/');
+    if *input_format == StructureFileFormat::FCZDB {
+        return None;
+    }
+    match id_type {
+        // Absolute path IDs are canonicalized
+        IdType::AbsPath => std::fs::canonicalize(pdb_container).ok().map(|path| path.to_string_lossy().into_owned()),
+        IdType::RelPath | IdType::Other => Some(pdb_container.to_string()),
+        _ => Some(get_absolute_path(pdb_container)),
+    }
+}
+
+fn get_absolute_path(path: &str) -> String {
+    std::path::absolute(path).map(|path| path.to_string_lossy().into_owned()).unwrap_or_else(|_| path.to_string())
+}
+
+// Paths are made absolute so that the index can be queried from any directory
+fn get_locations(path_vec: &[String], input_format: &StructureFileFormat) -> Vec<String> {
+    path_vec.iter().map(|path| {
+        if *input_format == StructureFileFormat::FCZDB { path.clone() } else { get_absolute_path(path) }
+    }).collect()
+}
+
+// Write {index_path}.paths with the location of each structure if IDs are not paths.
+// A file of a previous build is removed otherwise.
+fn save_locations(path_vec: &[String], index_path: &str, id_type: &IdType, input_format: &StructureFileFormat) {
+    let paths_path = format!("{}.paths", index_path);
+    if id_type.is_path() {
+        let _ = std::fs::remove_file(&paths_path);
+        return;
+    }
+    save_locations_to_file(&paths_path, &get_locations(path_vec, input_format));
+}
+
+// Write {index_path}.coords while hashing if requested. A cache of a previous build is
+// removed otherwise, as its records would not match the new ids.
+fn start_coordinate_cache(folddisco: &mut FoldDisco, index_path: &str, coord_cache: bool, verbose: bool) {
+    let coords_path = format!("{}.coords", index_path);
+    if !coord_cache {
+        let _ = std::fs::remove_file(&coords_path);
+        return;
+    }
+    if verbose { print_log_msg(INFO, &format!("Saving coordinates to {}", coords_path)); }
+    let writer = CoordinateCacheWriter::create(&coords_path).unwrap_or_else(|_| panic!(
+        "{}", log_msg(FAIL, "Failed to create coordinate cache")
+    ));
+    folddisco.set_coordinate_cache_writer(writer);
+}
+
+// Write {index_path}.stop listing hashes left out of the index with their document frequency.
+// A stop-list of a previous build is removed if nothing was stopped.
+fn report_stopped(stop_list: &StopList, index_path: &str, verbose: bool) {
+    let stop_path = format!("{}.stop", index_path);
+    if stop_list.is_empty() {
+        let _ = std::fs::remove_file(&stop_path);
+        return;
+    }
+    save_stop_list_to_file(&stop_path, stop_list);
+    if verbose {
+        print_log_msg(INFO, &format!("{} frequent hashes left out. See {}", stop_list.len(), stop_path));
+    }
+}
+
+// Write {index_path}.skipped listing structures left out of the index with reasons.
+// A report of a previous build is removed if nothing was skipped. When appending,
+// the report keeps the structures skipped earlier and the new ones are added to it.
+fn report_skipped(folddisco: &FoldDisco, index_path: &str, append: bool) {
+    let skipped_path = format!("{}.skipped", index_path);
+    if folddisco.skipped.is_empty() {
+        if !append {
+            let _ = std::fs::remove_file(&skipped_path);
+        }
+        return;
+    }
+    write_skipped_report(&skipped_path, &folddisco.skipped, append).unwrap_or_else(|_| panic!(
+        "{}", log_msg(FAIL, "Failed to write skipped structures")
+    ));
+    print_log_msg(WARN, &format!(
+        "{} structures skipped. See {} for reasons", folddisco.skipped.len(), skipped_path
+    ));
+}
+
+// Hash types with optional bins (ppf:16-8,pdb). Types without bins use their defaults
+fn parse_hash_sets(input: &str) -> Vec<(HashType, usize, usize)> {
+    input.split(',').filter(|set| !set.trim().is_empty()).map(|set| {
+        let (hash_type, bins) = match set.trim().split_once(':') {
+            Some((hash_type, bins)) => (hash_type, parse_pairs(bins).first().copied()),
+            None => (set.trim(), Some((0, 0))),
+        };
+        match (HashType::get_with_str(hash_type), bins) {
+            (HashType::Other, _) | (_, None) => {
+                print_log_msg(FAIL, &format!("Invalid hash set in --extra-hashes: {}", set));
+                std::process::exit(1);
+            }
+            (hash_type, Some((num_bin_dist, num_bin_angle))) => (hash_type, num_bin_dist, num_bin_angle),
+        }
+    }).collect()
+}
+
+// Hash the structures of an id mode chunk with each extra hash set into {index}.hash{k}.*
+fn save_extra_hash_tables(
+    folddisco: &FoldDisco, index_path: &str, extra_hash_sets: &[(HashType, usize, usize)],
+    value_bits: usize, verbose: bool,
+) {
+    for (k, &(hash_type, num_bin_dist, num_bin_angle)) in extra_hash_sets.iter().enumerate() {
+        if verbose {
+            print_log_msg(INFO, &format!(
+                "Hashing with {} ({}-{} bins) for extra hash set {}", hash_type.to_string(), num_bin_dist, num_bin_angle, k + 1
+            ));
+        }
+        let hash_id_vec = folddisco.collect_hash_vec_for(hash_type, num_bin_dist, num_bin_angle);
+        let (offset_path, value_path) = get_hash_set_offset_value(index_path, k + 1);
+        let (offset_map, value_vec) = convert_sorted_hash_vec_to_simplemap(hash_id_vec);
+        offset_map.dump_to_disk(&PathBuf::from(&offset_path), hash_type).unwrap_or_else(|_| panic!(
+            "{}", log_msg(FAIL, "Failed to save offset table")
+        ));
+        write_id_values(&value_path, &value_vec, value_bits, hash_type).unwrap_or_else(|_| panic!(
+            "{}", log_msg(FAIL, "Failed to save values")
+        ));
+    }
+}
+
 fn parse_pairs(input: &str) -> Vec<(usize, usize)> {
     input
         .split(',')
@@ -318,50 +903,473 @@ fn parse_pairs(input: &str) -> Vec<(usize, usize)> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::cli::workflows::test_index_args;
     #[test]
     fn test_build_index() {
-        let env = AppArgs::Index {
-            pdb_container: Some("data/serine_peptidases_filtered".to_string()),
-            hash_type: "pdbtr".to_string(),
-            index_path: "data/serine_peptidases_pdbtr_small".to_string(),
-            mode: "id".to_string(),
-            num_threads: 8,
-            num_bin_dist: 16,
-            num_bin_angle: 4,
-            multiple_bins: None,
-            grid_width: 20.0,
-            chunk_size: 65536,
-            max_residue: 3000,
-            recursive: true,
-            mmap_on_disk: false,
-            id_type: "relpath".to_string(),
-            verbose: true,
-            help: false,
+        let mut env = test_index_args("data/serine_peptidases_filtered", "data/serine_peptidases_pdbtr_small", "pdbtr");
+        if let AppArgs::Index { num_threads, recursive, verbose, .. } = &mut env {
+            (*num_threads, *recursive, *verbose) = (8, true, true);
+        }
+        build_index(env);
+    }
+    #[test]
+    fn test_append_to_index() {
+        let make_env = |pdb_container: &str, index_path: &str, append: bool| {
+            let mut env = test_index_args(pdb_container, index_path, "pdbtr");
+            if let AppArgs::Index { append: env_append, .. } = &mut env {
+                *env_append = append;
+            }
+            env
+        };
+        build_index(make_env("data/homeobox", "data/append_test_homeobox", false));
+        build_index(make_env("data/cys_his_asp", "data/append_test_cys_his_asp", false));
+        build_index(make_env("data/homeobox", "data/append_test", false));
+        build_index(make_env("data/cys_his_asp", "data/append_test", true));
+
+        let lookup = load_lookup_from_file("data/append_test.lookup");
+        assert_eq!(lookup.len(), 14);
+        for (i, entry) in lookup.iter().enumerate() {
+            assert_eq!(entry.1, i);
+        }
+        assert!(lookup[..4].iter().all(|x| x.0.starts_with("data/homeobox")));
+        assert!(lookup[4..].iter().all(|x| x.0.starts_with("data/cys_his_asp")));
+        let config = read_index_config_from_file("data/append_test.type");
+        assert_eq!(config.chunk_size, 14);
+
+        // Every posting of both separate indices should be in the appended one
+        let value_len = |prefix: &str| {
+            let (_mmap, values) = read_id_values(&format!("{}.value", prefix), 16).unwrap();
+            values.len()
+        };
+        assert_eq!(
+            value_len("data/append_test"),
+            value_len("data/append_test_homeobox") + value_len("data/append_test_cys_his_asp")
+        );
+        let (offset_table, _mmap) = SimpleHashMap::load_from_disk(&PathBuf::from("data/append_test.offset"));
+        let offset_table = offset_table.unwrap();
+        let (_value_mmap, values) = read_id_values("data/append_test.value", 16).unwrap();
+        let hash_vec = convert_simplemap_to_hash_vec(&offset_table, values, Some);
+        assert!(hash_vec.iter().any(|(_, id)| *id >= 4));
+        assert!(hash_vec.iter().all(|(_, id)| *id < 14));
+        drop(offset_table);
+        for prefix in ["data/append_test", "data/append_test_homeobox", "data/append_test_cys_his_asp"] {
+            for ext in ["offset", "value", "lookup", "type"] {
+                std::fs::remove_file(format!("{}.{}", prefix, ext)).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_build_index_with_stop_ratio() {
+        let make_env = |pdb_container: &str, index_path: &str, append: bool, max_memory: f64, stop_ratio: f32| {
+            let mut env = test_index_args(pdb_container, index_path, "pdbtr");
+            if let AppArgs::Index { append: env_append, max_memory: env_max_memory, stop_ratio: env_stop_ratio, .. } = &mut env {
+                (*env_append, *env_max_memory, *env_stop_ratio) = (append, max_memory, stop_ratio);
+            }
+            env
+        };
+        let load_hash_vec = |prefix: &str| {
+            let (offset_table, _mmap) = SimpleHashMap::load_from_disk(&PathBuf::from(format!("{}.offset", prefix)));
+            let (_value_mmap, values) = read_id_values(&format!("{}.value", prefix), 16).unwrap();
+            let mut hash_vec = convert_simplemap_to_hash_vec(&offset_table.unwrap(), values, Some);
+            hash_vec.sort();
+            hash_vec
         };
+        // 10 structures. Hashes in more than 3 of them are stopped
+        build_index(make_env("data/cys_his_asp", "data/stop_test_full", false, 0.0, 0.0));
+        build_index(make_env("data/cys_his_asp", "data/stop_test", false, 0.0, 0.3));
+        build_index(make_env("data/cys_his_asp", "data/stop_test_sorted", false, 0.00001, 0.3));
+        assert!(!PathBuf::from("data/stop_test_full.stop").exists());
+        assert_eq!(read_index_config_from_file("data/stop_test.type").stop_ratio, 0.3);
+
+        let mut expected_stop_list = StopList::new();
+        for hash_id in load_hash_vec("data/stop_test_full") {
+            *expected_stop_list.entry(hash_id.0).or_insert(0) += 1;
+        }
+        expected_stop_list.retain(|_, doc_freq| *doc_freq > 3);
+        assert!(!expected_stop_list.is_empty());
+        let stop_list = load_stop_list_from_file("data/stop_test.stop").unwrap();
+        assert_eq!(stop_list, expected_stop_list);
+        let hash_vec = load_hash_vec("data/stop_test");
+        assert_eq!(hash_vec, load_hash_vec("data/stop_test_full").into_iter().filter(
+            |(hash, _)| !stop_list.contains_key(hash)
+        ).collect::<Vec<_>>());
+        // Sorting on disk gives the same index
+        assert_eq!(load_hash_vec("data/stop_test_sorted"), hash_vec);
+        assert_eq!(load_stop_list_from_file("data/stop_test_sorted.stop").unwrap(), stop_list);
+
+        // Stopped hashes stay stopped and count the appended structures
+        build_index(make_env("data/homeobox", "data/stop_test", true, 0.0, 0.0));
+        let appended_stop_list = load_stop_list_from_file("data/stop_test.stop").unwrap();
+        assert!(stop_list.iter().all(|(hash, doc_freq)| appended_stop_list[hash] >= *doc_freq));
+        let mut doc_freq = StopList::new();
+        for (hash, _) in load_hash_vec("data/stop_test") {
+            assert!(!appended_stop_list.contains_key(&hash));
+            *doc_freq.entry(hash).or_insert(0) += 1;
+        }
+        assert!(doc_freq.values().all(|&count| count <= max_doc_freq(14, 0.3)));
+
+        for prefix in ["data/stop_test_full", "data/stop_test", "data/stop_test_sorted"] {
+            for ext in ["offset", "value", "lookup", "type", "stop"] {
+                let _ = std::fs::remove_file(format!("{}.{}", prefix, ext));
+            }
+        }
+    }
+
+    #[test]
+    fn test_build_index_with_extra_hashes() {
+        let make_env = |hash_type: &str, index_path: &str, extra_hashes: Option<&str>| {
+            let mut env = test_index_args("data/cys_his_asp", index_path, hash_type);
+            if let AppArgs::Index { num_bin_angle, extra_hashes: env_extra_hashes, .. } = &mut env {
+                *num_bin_angle = 8;
+                *env_extra_hashes = extra_hashes.map(|x| x.to_string());
+            }
+            env
+        };
+        let load_hash_vec = |offset_path: &str, value_path: &str| {
+            let (offset_table, _mmap) = SimpleHashMap::load_from_disk(&PathBuf::from(offset_path));
+            let (_value_mmap, values) = read_id_values(value_path, 16).unwrap();
+            let mut hash_vec = convert_simplemap_to_hash_vec(&offset_table.unwrap(), values, Some);
+            hash_vec.sort();
+            hash_vec
+        };
+        build_index(make_env("ppf", "data/extra_hash_test_ppf", None));
+        build_index(make_env("pdb", "data/extra_hash_test_pdb", None));
+        build_index(make_env("pdb", "data/extra_hash_test", Some("ppf:16-8,pdb")));
+        let config = read_index_config_from_file("data/extra_hash_test.type");
+        assert_eq!(config.extra_hash_sets, vec![(HashType::PointPairFeature, 16, 8), (HashType::PDBMotifSinCos, 0, 0)]);
+        assert_eq!(config.hash_sets().len(), 3);
+
+        // Each extra table is the table of an index of its own hash type
+        let (offset_path, value_path) = get_hash_set_offset_value("data/extra_hash_test", 1);
+        assert_eq!(
+            load_hash_vec(&offset_path, &value_path),
+            load_hash_vec("data/extra_hash_test_ppf.offset", "data/extra_hash_test_ppf.value")
+        );
+        assert_eq!(
+            load_hash_vec("data/extra_hash_test.offset", "data/extra_hash_test.value"),
+            load_hash_vec("data/extra_hash_test_pdb.offset", "data/extra_hash_test_pdb.value")
+        );
+        let (offset_path, _) = get_hash_set_offset_value("data/extra_hash_test", 2);
+        let (offset_table, _mmap) = SimpleHashMap::load_from_disk(&PathBuf::from(offset_path));
+        assert!(!offset_table.unwrap().is_empty());
+
+        for prefix in ["data/extra_hash_test_ppf", "data/extra_hash_test_pdb", "data/extra_hash_test"] {
+            for ext in ["offset", "value", "lookup", "type", "hash1.offset", "hash1.value", "hash2.offset", "hash2.value"] {
+                let _ = std::fs::remove_file(format!("{}.{}", prefix, ext));
+            }
+        }
+    }
+
+    #[test]
+    fn test_skip_unreadable_structures() {
+        let input_dir = "data/skip_test_input";
+        let index_path = "data/skip_test";
+        std::fs::create_dir_all(input_dir).unwrap();
+        for name in ["1akha-.pdb", "1b72a-.pdb"] {
+            std::fs::copy(format!("data/homeobox/{}", name), format!("{}/{}", input_dir, name)).unwrap();
+        }
+        std::fs::write(format!("{}/empty.pdb", input_dir), "").unwrap();
+        std::fs::write(format!("{}/truncated.pdb", input_dir), "ATOM      1  N   ILE A  77      14.206\n").unwrap();
+        // Multi-byte character where the coordinates start makes the parser panic
+        std::fs::write(
+            format!("{}/corrupt.pdb", input_dir),
+            "ATOM      1  N   ILE A  77   \u{e9}4.206  47.471   5.277  1.00 45.79           N  \n",
+        ).unwrap();
+        std::fs::write(
+            format!("{}/no_backbone.pdb", input_dir),
+            "ATOM      4  O   ILE A  77      13.514  44.686   7.120  1.00 48.81           O  \n\
+             ATOM      5  O   ILE A  78      13.514  44.686   8.120  1.00 48.81           O  \n",
+        ).unwrap();
+        build_index(test_index_args(input_dir, index_path, "pdbtr"));
+        let lookup = load_lookup_from_file(&format!("{}.lookup", index_path));
+        assert_eq!(lookup.len(), 2);
+        assert!(lookup.iter().enumerate().all(|(i, entry)| entry.1 == i && entry.2 > 0));
+        let (_mmap, values) = read_id_values(&format!("{}.value", index_path), 16).unwrap();
+        assert!((0..values.len()).all(|i| values.get(i) < 2));
+
+        let report = std::fs::read_to_string(format!("{}.skipped", index_path)).unwrap();
+        let reasons: std::collections::HashMap<&str, &str> = report.lines()
+            .map(|line| line.split_once('\t').unwrap()).collect();
+        assert_eq!(reasons.len(), 4);
+        assert_eq!(reasons[format!("{}/empty.pdb", input_dir).as_str()], "No residues");
+        assert_eq!(reasons[format!("{}/truncated.pdb", input_dir).as_str()], "No residues");
+        assert_eq!(reasons[format!("{}/no_backbone.pdb", input_dir).as_str()], "No residue with N and CA atoms");
+        assert!(reasons[format!("{}/corrupt.pdb", input_dir).as_str()].starts_with("Failed to parse structure: "));
+
+        // Appending adds to the report and keeps it when nothing new is skipped
+        let append_dir = "data/skip_test_append";
+        let append = |names: &[&str]| {
+            let _ = std::fs::remove_dir_all(append_dir);
+            std::fs::create_dir_all(append_dir).unwrap();
+            for name in names {
+                std::fs::copy(format!("data/homeobox/{}", name), format!("{}/{}", append_dir, name)).unwrap();
+            }
+            let mut env = test_index_args(append_dir, index_path, "pdbtr");
+            if let AppArgs::Index { append, .. } = &mut env {
+                *append = true;
+            }
+            env
+        };
+        let env = append(&["1b72b-.pdb"]);
+        std::fs::write(format!("{}/empty.pdb", append_dir), "").unwrap();
         build_index(env);
+        let report = std::fs::read_to_string(format!("{}.skipped", index_path)).unwrap();
+        assert_eq!(report.lines().count(), 5);
+        assert!(report.contains(&format!("{}/empty.pdb\tNo residues", input_dir)));
+        assert!(report.contains(&format!("{}/empty.pdb\tNo residues", append_dir)));
+        build_index(append(&["1ba5--.pdb"]));
+        assert_eq!(std::fs::read_to_string(format!("{}.skipped", index_path)).unwrap(), report);
+        assert_eq!(load_lookup_from_file(&format!("{}.lookup", index_path)).len(), 4);
+
+        std::fs::remove_dir_all(append_dir).unwrap();
+        std::fs::remove_dir_all(input_dir).unwrap();
+        for ext in ["offset", "value", "lookup", "type", "skipped"] {
+            std::fs::remove_file(format!("{}.{}", index_path, ext)).unwrap();
+        }
     }
+
+    #[test]
+    fn test_index_from_archive_and_list() {
+        use crate::controller::io::read_structure_from_path;
+        use std::io::Write;
+        let make_env = |pdb_container: Option<&str>, path_list: Option<&str>, index_path: &str| {
+            let mut env = test_index_args("", index_path, "pdbtr");
+            if let AppArgs::Index { pdb_container: env_pdb_container, path_list: env_path_list, .. } = &mut env {
+                *env_pdb_container = pdb_container.map(|x| x.to_string());
+                *env_path_list = path_list.map(|x| x.to_string());
+            }
+            env
+        };
+        let pdb_paths = load_path("data/homeobox", false);
+        std::fs::write("data/archive_input_test.txt", pdb_paths.join("\n")).unwrap();
+        // Plain members in a .tar and gzipped members in a .tar.gz
+        let mut tar_builder = tar::Builder::new(std::fs::File::create("data/archive_input_test.tar").unwrap());
+        let mut tgz_builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            std::fs::File::create("data/archive_input_test.tar.gz").unwrap(), flate2::Compression::default()
+        ));
+        for pdb_path in pdb_paths.iter() {
+            let name = PathBuf::from(pdb_path).file_name().unwrap().to_str().unwrap().to_string();
+            tar_builder.append_path_with_name(pdb_path, &name).unwrap();
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&std::fs::read(pdb_path).unwrap()).unwrap();
+            let data = encoder.finish().unwrap();
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tgz_builder.append_data(&mut header, format!("{}.gz", name), &data[..]).unwrap();
+        }
+        tar_builder.into_inner().unwrap();
+        tgz_builder.into_inner().unwrap().finish().unwrap();
+
+        build_index(make_env(None, Some("data/archive_input_test.txt"), "data/archive_test_list"));
+        build_index(make_env(Some("data/archive_input_test.tar"), None, "data/archive_test_tar"));
+        build_index(make_env(Some("data/archive_input_test.tar.gz"), None, "data/archive_test_tgz"));
+
+        // Same structures in the same order give the same postings
+        for ext in ["offset", "value"] {
+            let expected = std::fs::read(format!("data/archive_test_list.{}", ext)).unwrap();
+            assert_eq!(std::fs::read(format!("data/archive_test_tar.{}", ext)).unwrap(), expected);
+            assert_eq!(std::fs::read(format!("data/archive_test_tgz.{}", ext)).unwrap(), expected);
+        }
+        // Lookup entries point into the archive and can be read back for retrieval
+        let lookup = load_lookup_from_file("data/archive_test_tgz.lookup");
+        assert_eq!(lookup.len(), pdb_paths.len());
+        for (entry, pdb_path) in lookup.iter().zip(pdb_paths.iter()) {
+            assert!(entry.0.starts_with("data/archive_input_test.tar.gz:") && entry.0.ends_with(".pdb.gz"));
+            let from_archive = read_structure_from_path(&entry.0).unwrap().to_compact();
+            let from_file = read_structure_from_path(pdb_path).unwrap().to_compact();
+            assert_eq!(from_archive.num_residues, from_file.num_residues);
+            assert_eq!(from_archive.ca_vector.x, from_file.ca_vector.x);
+        }
+
+        for prefix in ["data/archive_test_list", "data/archive_test_tar", "data/archive_test_tgz"] {
+            for ext in ["offset", "value", "lookup", "type"] {
+                std::fs::remove_file(format!("{}.{}", prefix, ext)).unwrap();
+            }
+        }
+        for path in ["data/archive_input_test.txt", "data/archive_input_test.tar", "data/archive_input_test.tar.gz"] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_index_per_chain() {
+        use crate::controller::io::read_structure_from_path;
+        use crate::controller::mode::parse_path_by_id_type;
+        let index_path = "data/per_chain_test";
+        std::fs::write(
+            "data/per_chain_input_test.txt", "query/4CHA.pdb\ndata/homeobox/1b72b-.pdb\ndata/homeobox/1ba5--.pdb\n"
+        ).unwrap();
+        let mut env = test_index_args("", index_path, "pdbtr");
+        if let AppArgs::Index { pdb_container, path_list, per_chain, .. } = &mut env {
+            *pdb_container = None;
+            *path_list = Some("data/per_chain_input_test.txt".to_string());
+            *per_chain = true;
+        }
+        build_index(env);
+        let lookup = load_lookup_from_file(&format!("{}.lookup", index_path));
+        let ids = lookup.iter().map(|entry| entry.0.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, vec![
+            "query/4CHA.pdb_A", "query/4CHA.pdb_B", "query/4CHA.pdb_C",
+            "query/4CHA.pdb_E", "query/4CHA.pdb_F", "query/4CHA.pdb_G",
+            "data/homeobox/1b72b-.pdb_B", "data/homeobox/1ba5--.pdb",
+        ]);
+        assert!(read_index_config_from_file(&format!("{}.type", index_path)).per_chain);
+        assert_eq!(parse_path_by_id_type("query/4CHA.pdb_A", &IdType::Pdb), "4CHA_A");
+
+        // nres of each entry is the size of the chain and retrieval reads only that chain
+        let whole = read_structure_from_path("query/4CHA.pdb").unwrap().to_compact();
+        let mut total_nres = 0;
+        for entry in lookup[..6].iter() {
+            let chain = read_structure_from_path(&entry.0).unwrap().to_compact();
+            assert_eq!(chain.num_chains, 1);
+            assert_eq!(chain.chains[0], *entry.0.as_bytes().last().unwrap());
+            assert_eq!(entry.2, chain.num_residues);
+            total_nres += entry.2;
+        }
+        assert_eq!(total_nres, whole.num_residues);
+
+        for ext in ["offset", "value", "lookup", "type"] {
+            std::fs::remove_file(format!("{}.{}", index_path, ext)).unwrap();
+        }
+        std::fs::remove_file("data/per_chain_input_test.txt").unwrap();
+    }
+
+    #[test]
+    fn test_build_index_with_coord_cache() {
+        use crate::controller::io::read_structure_from_path;
+        use crate::index::lookup::convert_lookup_to_binary;
+        use crate::index::tombstone::save_tombstone_to_file;
+        let index_path = "data/coord_cache_test";
+        let make_env = |pdb_container: &str, append: bool| {
+            let mut env = test_index_args(pdb_container, index_path, "pdbtr");
+            if let AppArgs::Index { append: env_append, coord_cache, .. } = &mut env {
+                (*env_append, *coord_cache) = (append, !append);
+            }
+            env
+        };
+        build_index(make_env("data/cys_his_asp", false));
+        // Appending keeps the cache in sync with the lookup
+        build_index(make_env("data/homeobox", true));
+
+        let lookup = load_lookup_from_file(&format!("{}.lookup", index_path));
+        let coord_cache = CoordinateCache::open(&format!("{}.coords", index_path)).unwrap();
+        assert_eq!(coord_cache.len(), lookup.len());
+        assert_eq!(lookup.len(), 14);
+        for (id, nid, nres, _) in lookup.iter() {
+            let cached = coord_cache.get(*nid).unwrap();
+            let expected = read_structure_from_path(id).unwrap().to_compact();
+            assert_eq!(cached.num_residues, *nres);
+            assert_eq!(cached.residue_serial, expected.residue_serial);
+            assert_eq!(cached.ca_vector.x, expected.ca_vector.x);
+            assert_eq!(cached.cb_vector.z, expected.cb_vector.z);
+        }
+
+        // A fresh build over the index rewrites its binary lookup as well and drops its tombstones
+        convert_lookup_to_binary(index_path);
+        save_tombstone_to_file(&format!("{}.deleted", index_path), &[true; 14]);
+        build_index(make_env("data/homeobox", false));
+        assert!(!PathBuf::from(format!("{}.deleted", index_path)).exists());
+        let lookup = load_lookup_from_file(&format!("{}.lookup", index_path));
+        let binary_lookup = load_lookup_table(index_path);
+        assert!(matches!(binary_lookup, LookupTable::Binary(_)));
+        assert_eq!(binary_lookup.len(), lookup.len());
+        assert!(binary_lookup.iter().zip(lookup.iter()).all(|((id, nid, _, _), (expected_id, expected_nid, _, _))| {
+            id == expected_id && nid == *expected_nid
+        }));
+
+        for ext in ["offset", "value", "lookup", "blookup", "type", "coords"] {
+            std::fs::remove_file(format!("{}.{}", index_path, ext)).unwrap();
+        }
+
+        // Big mode caches the structures while counting hashes. PDBMotif keeps its offset table small
+        let make_big_env = |pdb_container: &str, append: bool| {
+            let mut env = make_env(pdb_container, append);
+            if let AppArgs::Index { mode, hash_type, .. } = &mut env {
+                (*mode, *hash_type) = ("big".to_string(), "orig_pdb".to_string());
+            }
+            env
+        };
+        build_index(make_big_env("data/cys_his_asp", false));
+        build_index(make_big_env("data/homeobox", true));
+        let lookup = load_lookup_from_file(&format!("{}.lookup", index_path));
+        let coord_cache = CoordinateCache::open(&format!("{}.coords", index_path)).unwrap();
+        assert_eq!(coord_cache.len(), lookup.len());
+        for (id, nid, nres, _) in lookup.iter() {
+            let cached = coord_cache.get(*nid).unwrap();
+            let expected = read_structure_from_path(id).unwrap().to_compact();
+            assert_eq!(cached.num_residues, *nres);
+            assert_eq!(cached.ca_vector.y, expected.ca_vector.y);
+        }
+        for ext in ["offset", "value", "lookup", "type", "coords"] {
+            std::fs::remove_file(format!("{}.{}", index_path, ext)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_build_index_with_stable_ids() {
+        use crate::controller::io::read_structure_from_path;
+        use crate::controller::locate::StructureLocator;
+        let input_dir = "data/relocate_test_input";
+        let moved_dir = "data/relocate_test_moved";
+        let index_path = "data/relocate_test";
+        let _ = std::fs::remove_dir_all(moved_dir);
+        std::fs::create_dir_all(input_dir).unwrap();
+        for name in ["1akha-.pdb", "1b72b-.pdb"] {
+            std::fs::copy(format!("data/homeobox/{}", name), format!("{}/{}", input_dir, name)).unwrap();
+        }
+        let make_env = |pdb_container: &str, id_type: &str, append: bool| {
+            let mut env = test_index_args(pdb_container, index_path, "pdbtr");
+            if let AppArgs::Index { id_type: env_id_type, append: env_append, .. } = &mut env {
+                (*env_id_type, *env_append) = (id_type.to_string(), append);
+            }
+            env
+        };
+        build_index(make_env(input_dir, "pdb", false));
+        let config = read_index_config_from_file(&format!("{}.type", index_path));
+        assert_eq!(config.id_type, IdType::Pdb);
+        let structure_root = config.structure_root.clone().unwrap();
+        assert!(PathBuf::from(&structure_root).is_absolute() && structure_root.ends_with(input_dir));
+        let locations = load_locations_from_file(&format!("{}.paths", index_path)).unwrap();
+        assert_eq!(locations.len(), 2);
+        assert!(locations.iter().all(|location| location.starts_with(&structure_root)));
+
+        // Appending follows the id type of the index
+        build_index(make_env("data/homeobox", "relpath", true));
+        let lookup = load_lookup_from_file(&format!("{}.lookup", index_path));
+        let locations = load_locations_from_file(&format!("{}.paths", index_path)).unwrap();
+        assert_eq!(locations.len(), lookup.len());
+        assert!(lookup.iter().all(|entry| !entry.0.contains('/')));
+
+        // Moved structures are found with the new root
+        std::fs::rename(input_dir, moved_dir).unwrap();
+        let config = read_index_config_from_file(&format!("{}.type", index_path));
+        let locator = StructureLocator::from_index(index_path, &config, Some(moved_dir));
+        for (id, nid, nres, _) in lookup[..2].iter() {
+            let location = locator.locate(*nid, id);
+            assert!(location.starts_with(moved_dir));
+            assert_eq!(read_structure_from_path(&location).unwrap().to_compact().num_residues, *nres);
+        }
+        let locator = StructureLocator::from_index(index_path, &config, None);
+        assert_eq!(locator.locate(lookup[2].1, &lookup[2].0), locations[2]);
+
+        for ext in ["offset", "value", "lookup", "type", "paths"] {
+            std::fs::remove_file(format!("{}.{}", index_path, ext)).unwrap();
+        }
+        std::fs::remove_dir_all(moved_dir).unwrap();
+    }
+
     #[test]
     fn test_build_index_of_foldcomp_db() {
         #[cfg(feature = "foldcomp")]
         {
-            let env = AppArgs::Index {
-                pdb_container: Some("data/foldcomp/example_db".to_string()),
-                hash_type: "pdbtr".to_string(),
-                index_path: "data/example_db_folddisco_db".to_string(),
-                mode: "id".to_string(),
-                num_threads: 8,
-                num_bin_dist: 16,
-                num_bin_angle: 4,
-                multiple_bins: Some("16-4,8-3".to_string()),
-                grid_width: 20.0,
-                chunk_size: 65536,
-                max_residue: 50000,
-                recursive: true,
-                mmap_on_disk: false,
-                id_type: "relpath".to_string(),
-                verbose: true,
-                help: false,
-            };
+            let mut env = test_index_args("data/foldcomp/example_db", "data/example_db_folddisco_db", "pdbtr");
+            if let AppArgs::Index { multiple_bins, max_residue, recursive, verbose, .. } = &mut env {
+                *multiple_bins = Some("16-4,8-3".to_string());
+                (*max_residue, *recursive, *verbose) = (50000, true, true);
+            }
             build_index(env);
         }
     }
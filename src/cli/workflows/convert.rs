@@ -0,0 +1,293 @@
+//! Workflow for converting an index between id mode and big mode.
+//! Postings are read from the existing index and written in the layout of the
+//! other mode. Structures are not read again. Lookup, config and sidecar files
+//! are copied so that the converted index answers queries the same way.
+//! With --binary-lookup, the text lookup is converted to the binary lookup
+//! that queries map instead of parsing the text lookup.
+
+use std::path::PathBuf;
+
+use rayon::prelude::*;
+
+use crate::cli::*;
+use crate::cli::config::{read_index_config_from_file, write_index_config_to_file};
+use crate::controller::io::{id_value_bits, read_id_values, write_id_values};
+use crate::controller::map::{convert_simplemap_to_hash_vec, convert_sorted_hash_vec_to_simplemap, SimpleHashMap};
+use crate::controller::mode::IndexMode;
+use crate::controller::query::{check_and_get_indices, get_offset_value_lookup_type};
+use crate::index::lookup::convert_lookup_to_binary;
+use crate::index::indextable::{load_big_index, FolddiscoIndex};
+use crate::prelude::*;
+
+pub const HELP_CONVERT: &str = "\
+usage: folddisco convert -i <i:INDEX_PATH> -o <o:INDEX_PATH> -m <MODE> [OPTIONS]
+       folddisco convert -i <i:INDEX_PATH> --binary-lookup
+
+input/output:
+ -i, --index <PATH>               Index to convert
+ -o, --output <PATH>              Path to save the converted index
+ -m, --mode <MODE>                Mode of the converted index (id, big)
+ --binary-lookup                  Write the lookup of the index (all chunks) as {index}.blookup in place.
+                                  Queries map it lazily instead of loading the text lookup
+
+options:
+ -t, --threads <INT>              Number of threads to use [1]
+ -v, --verbose                    Print verbose messages
+ -h, --help                       Print this help menu
+
+examples:
+# Index outgrew id mode
+folddisco convert -i index/afdb_part -o index/afdb_part_big -m big -t 12
+
+# Small big mode index for testing
+folddisco convert -i index/h_sapiens_big -o index/h_sapiens_id -m id -t 12
+
+# Binary lookup for a large index
+folddisco convert -i index/afdb --binary-lookup
+";
+
+// Files next to the index that don't depend on the mode
+pub const SIDECAR_EXTENSIONS: [&str; 6] = ["lookup", "blookup", "deleted", "skipped", "coords", "paths"];
+
+pub fn convert(env: AppArgs) {
+    match env {
+        AppArgs::Convert {
+            index_path,
+            output_path,
+            mode,
+            binary_lookup,
+            num_threads,
+            verbose,
+            help: _,
+        } => {
+            if binary_lookup && index_path.is_some() {
+                for index_prefix in check_and_get_indices(index_path, verbose) {
+                    let num_entries = convert_lookup_to_binary(&index_prefix);
+                    if verbose {
+                        print_log_msg(DONE, &format!("Wrote {}.blookup with {} entries", index_prefix, num_entries));
+                    }
+                }
+                return;
+            }
+            if index_path.is_none() || output_path.is_none() || mode.is_none() {
+                eprintln!("{}", HELP_CONVERT);
+                std::process::exit(1);
+            }
+            let index_path = index_path.unwrap();
+            let output_path = output_path.unwrap();
+            let mode = match mode.unwrap().as_str() {
+                "id" => IndexMode::Id,
+                "big" => IndexMode::Big,
+                other => {
+                    print_log_msg(FAIL, &format!("Unknown mode: {}. Use id or big", other));
+                    std::process::exit(1);
+                }
+            };
+            if !PathBuf::from(format!("{}.type", index_path)).is_file() {
+                if PathBuf::from(format!("{}_0.type", index_path)).is_file() {
+                    print_log_msg(FAIL, &format!("{} is chunked. Merge the chunks before converting", index_path));
+                } else {
+                    print_log_msg(FAIL, &format!("Index not found: {}", index_path));
+                }
+                std::process::exit(1);
+            }
+            if index_path == output_path {
+                print_log_msg(FAIL, "Output path should be different from the input index");
+                std::process::exit(1);
+            }
+            convert_index(&index_path, &output_path, mode, num_threads, verbose);
+        }
+        _ => {
+            eprintln!("{}", HELP_CONVERT);
+            std::process::exit(1);
+        }
+    }
+}
+
+pub fn convert_index(index_path: &str, output_path: &str, mode: IndexMode, num_threads: usize, verbose: bool) {
+    let (offset_path, value_path, lookup_path, hash_type_path) = get_offset_value_lookup_type(index_path.to_string());
+    let mut config = read_index_config_from_file(&hash_type_path);
+    if config.mode == mode {
+        print_log_msg(FAIL, &format!("{} is already in {} mode", index_path, mode.to_string()));
+        std::process::exit(1);
+    }
+    if !config.extra_hash_sets.is_empty() {
+        print_log_msg(FAIL, "Indices with extra hash sets are only supported in id mode");
+        std::process::exit(1);
+    }
+    if mode == IndexMode::Big && config.stop_ratio > 0.0 {
+        print_log_msg(FAIL, "Index with a stop-list can't be converted to big mode");
+        std::process::exit(1);
+    }
+    let num_structures = load_lookup_from_file(&lookup_path).len();
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build()
+        .unwrap_or_else(|_| panic!("{}", log_msg(FAIL, "Failed to build thread pool for converting")));
+    if verbose {
+        print_log_msg(INFO, &format!(
+            "Converting {} ({} structures) from {} mode to {} mode",
+            index_path, num_structures, config.mode.to_string(), mode.to_string()
+        ));
+    }
+
+    match mode {
+        IndexMode::Big => {
+            let mut hash_id_vec = load_id_index_pairs(&offset_path, &value_path, config.value_bits);
+            // Big mode stores differences of ids, so ids should increase within each posting list
+            pool.install(|| hash_id_vec.par_sort_unstable());
+            save_big_index(&hash_id_vec, output_path, config.hash_type, &pool, verbose);
+        }
+        IndexMode::Id => {
+            if verbose { print_log_msg(INFO, "Collecting entries"); }
+            let hash_id_vec = load_big_index_pairs(index_path, &pool);
+            config.value_bits = id_value_bits(num_structures);
+            save_id_index(hash_id_vec, output_path, config.hash_type, config.value_bits);
+        }
+    }
+    drop(pool);
+
+    let mut sidecars = SIDECAR_EXTENSIONS.to_vec();
+    if mode == IndexMode::Id {
+        sidecars.push("stop");
+    }
+    copy_sidecars(index_path, output_path, &sidecars);
+    config.mode = mode;
+    config.chunk_size = num_structures;
+    write_index_config_to_file(&format!("{}.type", output_path), config);
+    if verbose {
+        print_log_msg(DONE, &format!("Converted {} into {}", index_path, output_path));
+    }
+}
+
+// (hash, id) pairs of an id mode index. Pairs of the same hash are next to each other
+pub fn load_id_index_pairs(offset_path: &str, value_path: &str, value_bits: usize) -> Vec<(u32, usize)> {
+    let (offset_table, offset_mmap) = SimpleHashMap::load_from_disk(&PathBuf::from(offset_path));
+    let offset_table = offset_table.unwrap_or_else(|_| panic!(
+        "{}", log_msg(FAIL, "Failed to load offset table")
+    ));
+    let (value_mmap, value_vec) = read_id_values(value_path, value_bits).unwrap_or_else(|_| panic!(
+        "{}", log_msg(FAIL, "Failed to load values")
+    ));
+    let hash_id_vec = convert_simplemap_to_hash_vec(&offset_table, value_vec, Some);
+    drop(offset_table);
+    drop(offset_mmap);
+    drop(value_mmap);
+    hash_id_vec
+}
+
+// (hash, id) pairs of a big mode index, sorted
+pub fn load_big_index_pairs(index_path: &str, pool: &rayon::ThreadPool) -> Vec<(u32, usize)> {
+    let (big_index, big_offset_mmap) = load_big_index(index_path);
+    // Hashes are visited in order, so the pairs come out sorted by hash
+    let hash_id_vec = pool.install(|| {
+        (0..big_index.total_hashes()).into_par_iter().flat_map_iter(|hash| {
+            let entries = if big_index.get_raw_entries(hash).is_empty() {
+                Vec::new()
+            } else {
+                big_index.get_entries(hash as u32)
+            };
+            entries.into_iter().map(move |id| (hash as u32, id))
+        }).collect()
+    });
+    drop(big_index);
+    drop(big_offset_mmap);
+    hash_id_vec
+}
+
+// Write sorted (hash, id) pairs as the offset and value files of a big mode index
+pub fn save_big_index(
+    hash_id_vec: &[(u32, usize)], output_path: &str, hash_type: HashType, pool: &rayon::ThreadPool, verbose: bool
+) {
+    let total_hashes = 2usize.pow(hash_type.encoding_bits() as u32);
+    let big_index = FolddiscoIndex::new(total_hashes, output_path.to_string(), false, hash_type);
+    // Each posting list is written by one thread
+    let posting_lists = hash_id_vec.chunk_by(|a, b| a.0 == b.0).collect::<Vec<_>>();
+    if verbose { print_log_msg(INFO, "Counting entries"); }
+    pool.install(|| posting_lists.par_iter().for_each(|postings| {
+        postings.iter().for_each(|&(hash, id)| big_index.count_single_entry(hash, id));
+    }));
+    big_index.allocate_entries();
+    if verbose { print_log_msg(INFO, "Adding entries"); }
+    pool.install(|| posting_lists.par_iter().for_each_init(
+        || Vec::with_capacity(8),
+        |bit_container, postings| {
+            postings.iter().for_each(|&(hash, id)| big_index.add_single_entry(hash, id, bit_container));
+        }
+    ));
+    big_index.finish_index();
+    big_index.save_offset_to_file();
+}
+
+// Write (hash, id) pairs sorted by hash as the offset and value files of an id mode index
+pub fn save_id_index(hash_id_vec: Vec<(u32, usize)>, output_path: &str, hash_type: HashType, value_bits: usize) {
+    let (offset_map, value_vec) = convert_sorted_hash_vec_to_simplemap(hash_id_vec);
+    offset_map.dump_to_disk(&PathBuf::from(format!("{}.offset", output_path)), hash_type).unwrap_or_else(|_| panic!(
+        "{}", log_msg(FAIL, "Failed to save offset table")
+    ));
+    write_id_values(&format!("{}.value", output_path), &value_vec, value_bits, hash_type).unwrap_or_else(|_| panic!(
+        "{}", log_msg(FAIL, "Failed to save values")
+    ));
+}
+
+// Copy {index_path}.{ext} to {output_path}.{ext} for the extensions that exist
+pub fn copy_sidecars(index_path: &str, output_path: &str, extensions: &[&str]) {
+    for ext in extensions {
+        let path = format!("{}.{}", index_path, ext);
+        if PathBuf::from(&path).is_file() {
+            std::fs::copy(&path, format!("{}.{}", output_path, ext)).unwrap_or_else(|_| panic!(
+                "{}", log_msg(FAIL, &format!("Failed to copy {}", path))
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::workflows::build_index::build_index;
+    use crate::cli::workflows::test_index_args;
+
+    #[test]
+    fn test_convert_index() {
+        let index_path = "data/convert_test";
+        build_index(test_index_args("data/homeobox", index_path, "pdb"));
+        let load_hash_vec = |prefix: &str| {
+            let (offset_table, _mmap) = SimpleHashMap::load_from_disk(&PathBuf::from(format!("{}.offset", prefix)));
+            let config = read_index_config_from_file(&format!("{}.type", prefix));
+            let (_value_mmap, values) = read_id_values(&format!("{}.value", prefix), config.value_bits).unwrap();
+            let mut hash_vec = convert_simplemap_to_hash_vec(&offset_table.unwrap(), values, Some);
+            hash_vec.sort();
+            hash_vec
+        };
+        let expected = load_hash_vec(index_path);
+
+        convert_index(index_path, "data/convert_test_big", IndexMode::Big, 2, false);
+        let config = read_index_config_from_file("data/convert_test_big.type");
+        assert_eq!(config.mode, IndexMode::Big);
+        assert_eq!(config.hash_type, HashType::PDBMotifSinCos);
+        assert_eq!(std::fs::read("data/convert_test_big.lookup").unwrap(), std::fs::read("data/convert_test.lookup").unwrap());
+        let (big_index, _mmap) = load_big_index("data/convert_test_big");
+        let mut big_hash_vec = Vec::new();
+        for hash in 0..big_index.total_hashes() {
+            if !big_index.get_raw_entries(hash).is_empty() {
+                big_hash_vec.extend(big_index.get_entries(hash as u32).into_iter().map(|id| (hash as u32, id)));
+            }
+        }
+        assert_eq!(big_hash_vec, expected);
+        drop(big_index);
+
+        // Back to id mode gives the same postings
+        convert_index("data/convert_test_big", "data/convert_test_id", IndexMode::Id, 2, false);
+        let config = read_index_config_from_file("data/convert_test_id.type");
+        assert_eq!(config.mode, IndexMode::Id);
+        assert_eq!(config.value_bits, 16);
+        assert_eq!(load_hash_vec("data/convert_test_id"), expected);
+
+        for prefix in [index_path, "data/convert_test_big", "data/convert_test_id"] {
+            for ext in ["offset", "value", "lookup", "type"] {
+                std::fs::remove_file(format!("{}.{}", prefix, ext)).unwrap();
+            }
+        }
+    }
+}
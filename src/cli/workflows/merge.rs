@@ -0,0 +1,491 @@
+//! Workflow for merging multiple indices into one.
+//! Inputs can be chunks of one index (`index_0`, `index_1`, ...) or indices
+//! built separately from disjoint structure sets with the same parameters.
+//! Numeric ids are renumbered in input order and structures marked as
+//! deleted are dropped. Hashes stopped in any input stay stopped in the
+//! merged index, so inputs can't be merged if such a hash is too rare in the
+//! merged index to be stopped.
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use rayon::slice::ParallelSliceMut;
+
+use crate::cli::*;
+use crate::cli::config::{read_index_config_from_file, write_index_config_to_file, IndexConfig};
+use crate::controller::io::{id_value_bits, read_id_values, write_id_values};
+use crate::controller::map::{convert_simplemap_to_hash_vec, convert_sorted_hash_vec_to_simplemap, SimpleHashMap};
+use crate::controller::mode::IndexMode;
+use crate::controller::query::{check_and_get_indices, get_hash_set_offset_value, get_offset_value_lookup_type};
+use crate::index::coords::{CoordinateCache, CoordinateCacheWriter};
+use crate::index::indextable::{load_big_index, FolddiscoIndex};
+use crate::index::lookup::{convert_lookup_to_binary, load_locations_from_file, save_locations_to_file};
+use crate::index::stoplist::{apply_stop_list, load_stop_list_from_file, max_doc_freq, save_stop_list_to_file, StopList};
+use crate::index::tombstone::load_tombstone_from_file;
+use crate::prelude::*;
+
+pub const HELP_MERGE: &str = "\
+usage: folddisco merge -i <i:INDEX_PATH> -i <i:INDEX_PATH> ... -o <o:INDEX_PATH> [OPTIONS]
+
+input/output:
+ -i, --index <PATH>               Index to merge. Repeat for multiple indices.
+                                  Chunked indices (PATH_0, PATH_1, ...) are expanded
+ -o, --output <PATH>              Path to save the merged index
+
+options:
+ -t, --threads <INT>              Number of threads to use [1]
+ -v, --verbose                    Print verbose messages
+ -h, --help                       Print this help menu
+
+examples:
+# Merge chunks of an index into one index
+folddisco merge -i index/afdb -o index/afdb_merged -t 12
+
+# Merge indices built on different machines
+folddisco merge -i index/part1 -i index/part2 -o index/all -t 12
+";
+
+pub fn merge(env: AppArgs) {
+    match env {
+        AppArgs::Merge {
+            index_paths,
+            output_path,
+            num_threads,
+            verbose,
+            help: _,
+        } => {
+            if index_paths.is_empty() || output_path.is_none() {
+                eprintln!("{}", HELP_MERGE);
+                std::process::exit(1);
+            }
+            let output_path = output_path.unwrap();
+            let input_paths = index_paths.into_iter().flat_map(
+                |index_path| check_and_get_indices(Some(index_path), verbose)
+            ).collect::<Vec<_>>();
+            for input_path in input_paths.iter() {
+                if !PathBuf::from(format!("{}.type", input_path)).is_file() {
+                    print_log_msg(FAIL, &format!("Index not found: {}", input_path));
+                    std::process::exit(1);
+                }
+            }
+            if input_paths.contains(&output_path) {
+                print_log_msg(FAIL, "Output path should be different from the input indices");
+                std::process::exit(1);
+            }
+            if verbose {
+                print_log_msg(INFO, &format!("Merging {} indices into {}", input_paths.len(), output_path));
+            }
+            merge_indices(&input_paths, &output_path, num_threads, verbose);
+        }
+        _ => {
+            eprintln!("{}", HELP_MERGE);
+            std::process::exit(1);
+        }
+    }
+}
+
+pub fn merge_indices(input_paths: &[String], output_path: &str, num_threads: usize, verbose: bool) {
+    // All inputs should be built with the same parameters
+    let configs = input_paths.iter().map(|input_path| {
+        read_index_config_from_file(&format!("{}.type", input_path))
+    }).collect::<Vec<IndexConfig>>();
+    let mut config = configs[0].clone();
+    for (input_path, other) in input_paths.iter().zip(configs.iter()).skip(1) {
+        if !config.is_compatible_with(other) {
+            print_log_msg(FAIL, &format!(
+                "{} was built with different parameters from {}", input_path, input_paths[0]
+            ));
+            std::process::exit(1);
+        }
+    }
+
+    // Old numeric ids of each input -> merged numeric ids. Deleted structures are dropped
+    let mut id_vec = Vec::new();
+    let mut numeric_id_vec = Vec::new();
+    let mut nres_vec = Vec::new();
+    let mut plddt_vec = Vec::new();
+    let mut id_maps: Vec<Vec<Option<usize>>> = Vec::with_capacity(input_paths.len());
+    for input_path in input_paths.iter() {
+        let lookup = load_lookup_from_file(&format!("{}.lookup", input_path));
+        let deleted = load_tombstone_from_file(&format!("{}.deleted", input_path), lookup.len());
+        let mut id_map = Vec::with_capacity(lookup.len());
+        for (pos, (id, _, nres, plddt)) in lookup.into_iter().enumerate() {
+            if deleted.as_ref().is_some_and(|deleted| deleted[pos]) {
+                id_map.push(None);
+                continue;
+            }
+            id_map.push(Some(id_vec.len()));
+            numeric_id_vec.push(id_vec.len());
+            id_vec.push(id);
+            nres_vec.push(nres);
+            plddt_vec.push(plddt);
+        }
+        id_maps.push(id_map);
+    }
+    let num_total = id_vec.len();
+    let value_bits = id_value_bits(num_total);
+    let mut seen = HashSet::with_capacity(num_total);
+    let num_duplicates = id_vec.iter().filter(|id| !seen.insert(id.as_str())).count();
+    if num_duplicates > 0 {
+        print_log_msg(WARN, &format!("{} IDs appear in more than one index", num_duplicates));
+    }
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build()
+        .unwrap_or_else(|_| panic!("{}", log_msg(FAIL, "Failed to build thread pool for merging")));
+    match config.mode {
+        IndexMode::Id => {
+            let mut hash_id_vec = Vec::new();
+            for ((input_path, id_map), input_config) in input_paths.iter().zip(id_maps.iter()).zip(configs.iter()) {
+                if verbose { print_log_msg(INFO, &format!("Collecting entries of {}", input_path)); }
+                let (offset_path, value_path, _, _) = get_offset_value_lookup_type(input_path.clone());
+                let (offset_table, offset_mmap) = SimpleHashMap::load_from_disk(&PathBuf::from(&offset_path));
+                let offset_table = offset_table.unwrap_or_else(|_| panic!(
+                    "{}", log_msg(FAIL, "Failed to load offset table")
+                ));
+                let (value_mmap, value_vec) = read_id_values(&value_path, input_config.value_bits).unwrap_or_else(|_| panic!(
+                    "{}", log_msg(FAIL, "Failed to load values")
+                ));
+                hash_id_vec.extend(convert_simplemap_to_hash_vec(&offset_table, value_vec, |id| id_map[id]));
+                drop(offset_table);
+                drop(offset_mmap);
+                drop(value_mmap);
+            }
+            pool.install(|| hash_id_vec.par_sort_unstable_by(|a, b| a.0.cmp(&b.0)));
+            let stop_lists = input_paths.iter().map(|input_path| {
+                load_stop_list_from_file(&format!("{}.stop", input_path)).unwrap_or_default()
+            });
+            let max_doc_freq = if config.stop_ratio > 0.0 { max_doc_freq(num_total, config.stop_ratio) } else { usize::MAX };
+            let stop_list = merge_stop_lists(stop_lists, &mut hash_id_vec, max_doc_freq).unwrap_or_else(|e| {
+                print_log_msg(FAIL, &e);
+                std::process::exit(1);
+            });
+            let stop_path = format!("{}.stop", output_path);
+            if stop_list.is_empty() {
+                let _ = std::fs::remove_file(&stop_path);
+            } else {
+                save_stop_list_to_file(&stop_path, &stop_list);
+            }
+            let (offset_map, value_vec) = convert_sorted_hash_vec_to_simplemap(hash_id_vec);
+            offset_map.dump_to_disk(&PathBuf::from(format!("{}.offset", output_path)), config.hash_type).unwrap_or_else(|_| panic!(
+                "{}", log_msg(FAIL, "Failed to save offset table")
+            ));
+            write_id_values(&format!("{}.value", output_path), &value_vec, value_bits, config.hash_type).unwrap_or_else(|_| panic!(
+                "{}", log_msg(FAIL, "Failed to save values")
+            ));
+            // Extra hash sets are merged with the same id maps
+            for (k, (hash_type, _, _)) in config.extra_hash_sets.iter().enumerate() {
+                let mut hash_id_vec = Vec::new();
+                for ((input_path, id_map), input_config) in input_paths.iter().zip(id_maps.iter()).zip(configs.iter()) {
+                    let (offset_path, value_path) = get_hash_set_offset_value(input_path, k + 1);
+                    let (offset_table, offset_mmap) = SimpleHashMap::load_from_disk(&PathBuf::from(&offset_path));
+                    let offset_table = offset_table.unwrap_or_else(|_| panic!(
+                        "{}", log_msg(FAIL, "Failed to load offset table")
+                    ));
+                    let (value_mmap, value_vec) = read_id_values(&value_path, input_config.value_bits).unwrap_or_else(|_| panic!(
+                        "{}", log_msg(FAIL, "Failed to load values")
+                    ));
+                    hash_id_vec.extend(convert_simplemap_to_hash_vec(&offset_table, value_vec, |id| id_map[id]));
+                    drop(offset_table);
+                    drop(offset_mmap);
+                    drop(value_mmap);
+                }
+                pool.install(|| hash_id_vec.par_sort_unstable_by(|a, b| a.0.cmp(&b.0)));
+                let (offset_path, value_path) = get_hash_set_offset_value(output_path, k + 1);
+                let (offset_map, value_vec) = convert_sorted_hash_vec_to_simplemap(hash_id_vec);
+                offset_map.dump_to_disk(&PathBuf::from(&offset_path), *hash_type).unwrap_or_else(|_| panic!(
+                    "{}", log_msg(FAIL, "Failed to save offset table")
+                ));
+                write_id_values(&value_path, &value_vec, value_bits, *hash_type).unwrap_or_else(|_| panic!(
+                    "{}", log_msg(FAIL, "Failed to save values")
+                ));
+            }
+        }
+        IndexMode::Big => {
+            // Inputs are visited in order so that merged ids increase within each posting list
+            let big_indices = input_paths.iter().map(|input_path| load_big_index(input_path)).collect::<Vec<_>>();
+            let merged = FolddiscoIndex::new(big_indices[0].0.total_hashes(), output_path.to_string(), false, config.hash_type);
+            for ((big_index, _), id_map) in big_indices.iter().zip(id_maps.iter()) {
+                pool.install(|| merged.count_entries_from_index(big_index, |id| id_map[id]));
+            }
+            merged.allocate_entries();
+            for ((big_index, _), id_map) in big_indices.iter().zip(id_maps.iter()) {
+                pool.install(|| merged.add_entries_from_index(big_index, |id| id_map[id]));
+            }
+            merged.finish_index();
+            merged.save_offset_to_file();
+        }
+    }
+    drop(pool);
+    merge_coordinate_caches(input_paths, &id_maps, output_path, verbose);
+    merge_locations(input_paths, &configs, &id_maps, &id_vec, output_path);
+    if configs.iter().any(|other| other.structure_root != config.structure_root) {
+        print_log_msg(WARN, "Indices were built from different structure roots. --structure-root finds their structures by file name");
+        config.structure_root = None;
+    }
+
+    save_lookup_to_file(
+        &format!("{}.lookup", output_path), &id_vec, &numeric_id_vec,
+        Some(&nres_vec), Some(&plddt_vec)
+    );
+    // Merged index gets a binary lookup if any input has one
+    if input_paths.iter().any(|input_path| PathBuf::from(format!("{}.blookup", input_path)).is_file()) {
+        convert_lookup_to_binary(output_path);
+    } else {
+        let _ = std::fs::remove_file(format!("{}.blookup", output_path));
+    }
+    config.chunk_size = num_total;
+    config.value_bits = value_bits;
+    config.max_residue = configs.iter().map(|x| x.max_residue).max().unwrap();
+    write_index_config_to_file(&format!("{}.type", output_path), config);
+    if verbose {
+        print_log_msg(DONE, &format!("Merged {} structures into {}", num_total, output_path));
+    }
+}
+
+// Stop-list of the merged index. Postings of a hash stopped in an input are gone, so the
+// hash stays stopped with its document frequency over all inputs. That is an error if the
+// frequency is not above max_doc_freq. Frequencies in the inputs count deleted structures
+fn merge_stop_lists(
+    stop_lists: impl IntoIterator<Item = StopList>, hash_id_vec: &mut Vec<(u32, usize)>, max_doc_freq: usize,
+) -> Result<StopList, String> {
+    let mut stop_list = StopList::new();
+    for input_stop_list in stop_lists {
+        for (hash, doc_freq) in input_stop_list {
+            *stop_list.entry(hash).or_insert(0) += doc_freq;
+        }
+    }
+    apply_stop_list(hash_id_vec, &mut stop_list, max_doc_freq);
+    let num_rare = stop_list.values().filter(|&&doc_freq| doc_freq <= max_doc_freq).count();
+    if num_rare > 0 {
+        return Err(format!(
+            "{} hashes stopped in some of the indices are too rare to be stopped in the merged index. \
+            Build the merged index from the structures instead", num_rare
+        ));
+    }
+    Ok(stop_list)
+}
+
+// Locations are written to {output_path}.paths if any input has IDs that are not paths.
+// Structures of the other inputs are located by their IDs
+fn merge_locations(
+    input_paths: &[String], configs: &[IndexConfig], id_maps: &[Vec<Option<usize>>],
+    id_vec: &[String], output_path: &str,
+) {
+    let paths_path = format!("{}.paths", output_path);
+    let _ = std::fs::remove_file(&paths_path);
+    if configs.iter().all(|config| config.id_type.is_path()) {
+        return;
+    }
+    let mut location_vec = Vec::with_capacity(id_vec.len());
+    for (input_path, id_map) in input_paths.iter().zip(id_maps.iter()) {
+        let locations = load_locations_from_file(&format!("{}.paths", input_path));
+        for (old_id, new_id) in id_map.iter().enumerate() {
+            if let Some(new_id) = new_id {
+                let location = locations.as_ref().and_then(|locations| locations.get(old_id));
+                location_vec.push(location.unwrap_or(&id_vec[*new_id]).clone());
+            }
+        }
+    }
+    save_locations_to_file(&paths_path, &location_vec);
+}
+
+// Coordinate caches are merged only if every input has one
+fn merge_coordinate_caches(input_paths: &[String], id_maps: &[Vec<Option<usize>>], output_path: &str, verbose: bool) {
+    let coords_path = format!("{}.coords", output_path);
+    let _ = std::fs::remove_file(&coords_path);
+    let num_cached = input_paths.iter().filter(|input_path| {
+        PathBuf::from(format!("{}.coords", input_path)).is_file()
+    }).count();
+    if num_cached == 0 {
+        return;
+    }
+    if num_cached < input_paths.len() {
+        print_log_msg(WARN, &format!(
+            "Only {} of {} indices have a coordinate cache. Merged index has no coordinate cache",
+            num_cached, input_paths.len()
+        ));
+        return;
+    }
+    if verbose { print_log_msg(INFO, "Merging coordinate caches"); }
+    let mut writer = CoordinateCacheWriter::create(&coords_path).unwrap_or_else(|_| panic!(
+        "{}", log_msg(FAIL, "Failed to create coordinate cache")
+    ));
+    for (input_path, id_map) in input_paths.iter().zip(id_maps.iter()) {
+        let coord_cache = CoordinateCache::open(&format!("{}.coords", input_path)).unwrap_or_else(|_| panic!(
+            "{}", log_msg(FAIL, &format!("Failed to open coordinate cache of {}", input_path))
+        ));
+        coord_cache.copy_to(&mut writer, |id| id_map[id].is_some()).unwrap_or_else(|_| panic!(
+            "{}", log_msg(FAIL, "Failed to copy coordinate cache")
+        ));
+    }
+    writer.finish().unwrap_or_else(|_| panic!("{}", log_msg(FAIL, "Failed to write coordinate cache")));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::workflows::build_index::build_index;
+    use crate::cli::workflows::test_index_args;
+    use crate::index::tombstone::save_tombstone_to_file;
+
+    fn index_env(pdb_container: &str, index_path: &str, chunk_size: usize) -> AppArgs {
+        let mut env = test_index_args(pdb_container, index_path, "pdbtr");
+        if let AppArgs::Index { chunk_size: env_chunk_size, .. } = &mut env {
+            *env_chunk_size = chunk_size;
+        }
+        env
+    }
+
+    fn load_hash_vec(index_path: &str) -> Vec<(u32, usize)> {
+        let (offset_table, _offset_mmap) = SimpleHashMap::load_from_disk(&PathBuf::from(format!("{}.offset", index_path)));
+        let (_value_mmap, value_vec) = read_id_values(&format!("{}.value", index_path), 16).unwrap();
+        let mut hash_vec = convert_simplemap_to_hash_vec(&offset_table.unwrap(), value_vec, Some);
+        hash_vec.sort();
+        hash_vec
+    }
+
+    #[test]
+    fn test_merge_chunks() {
+        // 10 structures in chunks of 4: merge_test_0, merge_test_1, merge_test_2
+        build_index(index_env("data/cys_his_asp", "data/merge_test", 4));
+        build_index(index_env("data/cys_his_asp", "data/merge_test_single", 65536));
+        merge(AppArgs::Merge {
+            index_paths: vec!["data/merge_test".to_string()],
+            output_path: Some("data/merge_test_merged".to_string()),
+            num_threads: 2,
+            verbose: false,
+            help: false,
+        });
+        let merged_lookup = load_lookup_from_file("data/merge_test_merged.lookup");
+        let single_lookup = load_lookup_from_file("data/merge_test_single.lookup");
+        assert_eq!(merged_lookup, single_lookup);
+        assert_eq!(load_hash_vec("data/merge_test_merged"), load_hash_vec("data/merge_test_single"));
+        let config = read_index_config_from_file("data/merge_test_merged.type");
+        assert_eq!(config.chunk_size, 10);
+        assert_eq!(config.value_bits, 16);
+
+        for prefix in ["data/merge_test_0", "data/merge_test_1", "data/merge_test_2", "data/merge_test_single", "data/merge_test_merged"] {
+            for ext in ["offset", "value", "lookup", "type"] {
+                std::fs::remove_file(format!("{}.{}", prefix, ext)).unwrap();
+            }
+        }
+    }
+
+    fn merge_env(index_paths: &[&str], output_path: &str) -> AppArgs {
+        AppArgs::Merge {
+            index_paths: index_paths.iter().map(|index_path| index_path.to_string()).collect(),
+            output_path: Some(output_path.to_string()),
+            num_threads: 2,
+            verbose: false,
+            help: false,
+        }
+    }
+
+    #[test]
+    fn test_merge_separate_indices_with_tombstones() {
+        let (first, second, merged) = ("data/merge_sep_test_a", "data/merge_sep_test_b", "data/merge_sep_test_merged");
+        build_index(index_env("data/cys_his_asp", first, 65536));
+        build_index(index_env("data/homeobox", second, 65536));
+        let first_lookup = load_lookup_from_file(&format!("{}.lookup", first));
+        let second_lookup = load_lookup_from_file(&format!("{}.lookup", second));
+        // Structure 1 of the first index is deleted. Later structures move up by one
+        let mut deleted = vec![false; first_lookup.len()];
+        deleted[1] = true;
+        save_tombstone_to_file(&format!("{}.deleted", first), &deleted);
+        merge(merge_env(&[first, second], merged));
+
+        let new_id = |index: usize, id: usize| if index == 0 { id - (id > 1) as usize } else { id + first_lookup.len() - 1 };
+        let merged_lookup = load_lookup_from_file(&format!("{}.lookup", merged));
+        assert_eq!(merged_lookup.len(), first_lookup.len() + second_lookup.len() - 1);
+        for (index, lookup) in [&first_lookup, &second_lookup].into_iter().enumerate() {
+            for (id, nid, _, _) in lookup.iter().filter(|(_, nid, _, _)| index == 1 || *nid != 1) {
+                assert_eq!(&merged_lookup[new_id(index, *nid)].0, id);
+            }
+        }
+        let mut expected = load_hash_vec(first).into_iter().filter(|(_, id)| *id != 1)
+            .map(|(hash, id)| (hash, new_id(0, id)))
+            .chain(load_hash_vec(second).into_iter().map(|(hash, id)| (hash, new_id(1, id))))
+            .collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(load_hash_vec(merged), expected);
+
+        std::fs::remove_file(format!("{}.deleted", first)).unwrap();
+        for prefix in [first, second, merged] {
+            for ext in ["offset", "value", "lookup", "type"] {
+                std::fs::remove_file(format!("{}.{}", prefix, ext)).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_merge_big_indices() {
+        // PDBMotif keeps the offset tables of big indices small
+        let build = |pdb_container: &str, index_path: &str| {
+            let mut env = test_index_args(pdb_container, index_path, "orig_pdb");
+            if let AppArgs::Index { mode, .. } = &mut env {
+                *mode = "big".to_string();
+            }
+            build_index(env)
+        };
+        let (first, second, merged) = ("data/merge_big_test_a", "data/merge_big_test_b", "data/merge_big_test_merged");
+        build("data/cys_his_asp", first);
+        build("data/homeobox", second);
+        let num_first = load_lookup_from_file(&format!("{}.lookup", first)).len();
+        let num_second = load_lookup_from_file(&format!("{}.lookup", second)).len();
+        let mut deleted = vec![false; num_second];
+        deleted[0] = true;
+        save_tombstone_to_file(&format!("{}.deleted", second), &deleted);
+        merge(merge_env(&[first, second], merged));
+        assert_eq!(load_lookup_from_file(&format!("{}.lookup", merged)).len(), num_first + num_second - 1);
+
+        // Postings of the second index follow those of the first without its deleted structure
+        let (first_index, _first_mmap) = load_big_index(first);
+        let (second_index, _second_mmap) = load_big_index(second);
+        let (merged_index, _merged_mmap) = load_big_index(merged);
+        let mut num_postings = 0;
+        for hash in 0..merged_index.total_hashes() as u32 {
+            if first_index.get_entry_count(hash) + second_index.get_entry_count(hash) == 0 {
+                assert_eq!(merged_index.get_entry_count(hash), 0);
+                continue;
+            }
+            let expected = first_index.get_entries(hash).into_iter()
+                .chain(second_index.get_entries(hash).into_iter().filter(|&id| id != 0).map(|id| id + num_first - 1))
+                .collect::<Vec<_>>();
+            assert_eq!(merged_index.get_entries(hash), expected);
+            num_postings += expected.len();
+        }
+        assert!(num_postings > 0);
+
+        drop((first_index, second_index, merged_index));
+        std::fs::remove_file(format!("{}.deleted", second)).unwrap();
+        for prefix in [first, second, merged] {
+            for ext in ["offset", "value", "lookup", "type"] {
+                std::fs::remove_file(format!("{}.{}", prefix, ext)).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_merge_stop_lists() {
+        // Hash 3 is stopped in the first input and hash 5 in both. The second input has
+        // the postings of hash 3 and 7
+        let first = StopList::from([(3, 4), (5, 6)]);
+        let second = StopList::from([(5, 5)]);
+        let hash_id_vec = vec![(1, 0), (3, 10), (3, 11), (7, 10), (7, 11), (7, 12)];
+
+        let mut merged_vec = hash_id_vec.clone();
+        let stop_list = merge_stop_lists([first.clone(), second.clone()], &mut merged_vec, 5).unwrap();
+        assert_eq!(stop_list, StopList::from([(3, 6), (5, 11)]));
+        assert_eq!(merged_vec, vec![(1, 0), (7, 10), (7, 11), (7, 12)]);
+        let mut merged_vec = hash_id_vec.clone();
+        let stop_list = merge_stop_lists([first.clone(), second.clone()], &mut merged_vec, 2).unwrap();
+        assert_eq!(stop_list, StopList::from([(3, 6), (5, 11), (7, 3)]));
+        assert_eq!(merged_vec, vec![(1, 0)]);
+
+        // Hash 3 is in 6 structures of the merged index, which keeps hashes in up to 6
+        let mut merged_vec = hash_id_vec.clone();
+        assert!(merge_stop_lists([first, second], &mut merged_vec, 6).unwrap_err().starts_with("1 hashes"));
+    }
+}
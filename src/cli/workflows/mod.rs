@@ -9,3 +9,46 @@
 pub mod build_index;
 pub mod query_pdb;
 pub mod benchmark;
+pub mod remove;
+pub mod merge;
+pub mod stats;
+pub mod verify;
+pub mod convert;
+pub mod rebin;
+pub mod serve;
+
+// Index arguments shared by the tests. Enum variants have no struct update syntax,
+// so other fields are set in place with `if let AppArgs::Index { .. } = &mut args`
+#[cfg(test)]
+pub fn test_index_args(pdb_container: &str, index_path: &str, hash_type: &str) -> crate::cli::AppArgs {
+    crate::cli::AppArgs::Index {
+        pdb_container: Some(pdb_container.to_string()),
+        path_list: None,
+        hash_type: hash_type.to_string(),
+        index_path: index_path.to_string(),
+        mode: "id".to_string(),
+        num_threads: 4,
+        num_bin_dist: 16,
+        num_bin_angle: 4,
+        multiple_bins: None,
+        extra_hashes: None,
+        grid_width: 20.0,
+        chunk_size: 65536,
+        max_residue: 3000,
+        recursive: false,
+        mmap_on_disk: false,
+        id_type: "relpath".to_string(),
+        append: false,
+        resume: false,
+        checkpoint_interval: -1.0,
+        strict: false,
+        max_memory: 0.0,
+        per_chain: false,
+        stop_ratio: 0.0,
+        dry_run: false,
+        sample_ratio: 0.01,
+        coord_cache: false,
+        verbose: false,
+        help: false,
+    }
+}
@@ -7,35 +7,19 @@
 // When querying PDB files, we need index table and query file.
 
 use std::io::BufRead;
-use std::path::PathBuf;
 
-use memmap2::{Mmap, MmapMut};
 use rayon::prelude::*;
 
-use crate::cli::config::{read_index_config_from_file, IndexConfig};
-use crate::controller::filter::{MatchFilter, StructureFilter};
-use crate::controller::map::SimpleHashMap;
-use crate::controller::mode::{IndexMode, QueryMode};
+use crate::controller::mode::QueryMode;
 use crate::cli::*;
-use crate::controller::io::{read_compact_structure, read_u16_vector};
-use crate::controller::query::{
-    check_and_get_indices, get_offset_value_lookup_type, make_query_map, parse_threshold_string
-};
-use crate::controller::count_query::{count_query_bigmode, count_query_idmode};
+use crate::controller::export::write_hits;
+use crate::controller::query::parse_threshold_string;
 use crate::controller::result::{
     convert_structure_query_result_to_match_query_results, 
-    sort_and_print_match_query_result, sort_and_print_structure_query_result, StructureResult
+    sort_and_print_match_query_result, sort_and_print_structure_query_result, MatchResult, OutputFormat, ResultFormat,
 };
-use crate::controller::retrieve::retrieval_wrapper;
-use crate::index::indextable::{load_big_index, FolddiscoIndex};
-use crate::index::lookup::load_lookup_from_file;
+use crate::controller::search::{Index, QueryBuilder, QueryOptions};
 use crate::prelude::*;
-
-#[cfg(feature = "foldcomp")]
-use crate::controller::retrieve::retrieval_wrapper_for_foldcompdb;
-#[cfg(feature = "foldcomp")]
-use crate::structure::io::fcz::FoldcompDbReader;
-#[cfg(feature = "foldcomp")]
 use crate::structure::io::StructureFileFormat;
 
 pub const HELP_QUERY: &str = "\
@@ -46,6 +30,9 @@ input/output:
  -q, --query <STR>                Query string that specifies residues or a text file containing query
  -i, --index <PATH>               Path of index table to load [REQUIRED]
  -o, --output <PATH>              Output file path [stdout]
+ --structure-root <PATH>          Read structures from this directory or archive instead of the one
+                                  they were indexed from. Use when the index or structures were moved
+ --foldcomp-db <PATH>             Read structures from this Foldcomp DB instead of the indexed one
  
 search parameters:
  -t, --threads <INT>              Number of threads [1]
@@ -82,6 +69,13 @@ display options:
  --sort-by-score                  Sort output by score
  --sort-by-rmsd                   Sort output by RMSD. Not working with --skip-match
  --skip-ca-match                  Print matching residues before C-alpha distance check
+ --format <STR>                   Output format: tsv, json or jsonl. JSON records pair query and matching residues.
+                                  Use jsonl to print multiple queries to stdout [tsv]
+ --superposition                  Print rotation and translation superposing each match onto the query
+ --write-hits <DIR>               Write matched residues of each hit superposed onto the query to this directory,
+                                  and the top 10 hits as models of one file
+ --hit-environment <FLOAT>        Also write residues within given distance in Angstroms of matched residues [0.0]
+ --hit-format <STR>               Format of written hits: pdb or cif [pdb]
 
 general options:
  -v, --verbose                    Print verbose messages
@@ -110,7 +104,6 @@ folddisco query -q query/zinc_finger.txt -i index/h_sapiens_folddisco -t 6 --con
 folddisco query -q query/zinc_finger.txt -i index/h_sapiens_folddisco -t 6 --covered-node 3 --top 1000 --per-structure --skip-match
 ";
 
-pub const MIN_CONNECTED_COMPONENT_SIZE: usize = 2;
 pub const MAX_NUM_LINES_FOR_WEB: usize = 1000;
 
 pub fn query_pdb(env: AppArgs) {
@@ -151,6 +144,13 @@ pub fn query_pdb(env: AppArgs) {
             header,
             serial_query,
             output,
+            output_format,
+            superposition,
+            write_hits: hit_dir,
+            hit_environment,
+            hit_format,
+            structure_root,
+            foldcomp_db,
             verbose,
             help: _,
         } => {
@@ -181,6 +181,20 @@ pub fn query_pdb(env: AppArgs) {
                 }
                 _ => {}
             }
+            let output_format = OutputFormat::get_with_str(&output_format).unwrap_or_else(|| {
+                print_log_msg(FAIL, &format!("Unknown output format: {}. Use tsv, json or jsonl", output_format));
+                std::process::exit(1);
+            });
+            let hit_format = match StructureFileFormat::get_with_string(&hit_format) {
+                format @ (StructureFileFormat::PDB | StructureFileFormat::CIF) => format,
+                _ => {
+                    print_log_msg(FAIL, &format!("Unknown hit format: {}. Use pdb or cif", hit_format));
+                    std::process::exit(1);
+                }
+            };
+            if hit_dir.is_some() && skip_match {
+                print_log_msg(WARN, "No matching residues to write with --skip-match. --write-hits is ignored");
+            }
 
             // Print query information
             if verbose {
@@ -192,34 +206,36 @@ pub fn query_pdb(env: AppArgs) {
                 }
                 // NOTE: If needed, print filter information
             }
-            // Get index paths
-            let index_paths = check_and_get_indices(index_path.clone(), verbose);
+            // Set thread pool. The global pool can be set only once; later calls in the same process keep it
+            if rayon::ThreadPoolBuilder::new().num_threads(threads).build_global().is_err() && verbose {
+                print_log_msg(WARN, &format!("Thread pool already initialized with {} threads", rayon::current_num_threads()));
+            }
+
+            #[cfg(not(feature = "foldcomp"))]
+            let foldcomp_db = foldcomp_db.and_then(|_| {
+                print_log_msg(WARN, "Built without Foldcomp support. --foldcomp-db is ignored");
+                None::<String>
+            });
+            let index_path = index_path.unwrap();
+            let index = Index::open_with_locations(&index_path, structure_root.as_deref(), foldcomp_db.as_deref())
+                .unwrap_or_else(|e| {
+                    print_log_msg(FAIL, &e);
+                    std::process::exit(1);
+                });
             if verbose {
-                print_log_msg(INFO, &format!("Found {} index file(s). Querying with {} threads", index_paths.len(), threads));
+                print_log_msg(INFO, &format!(
+                    "Loaded {} index file(s) with {} structures. Querying with {} threads",
+                    index.num_chunks(), index.num_structures(), threads
+                ));
             }
-            
-            let mut use_big_index = false;
-            if index_paths.len() == 1 {
-                // Check index mode is Big
-                let (_, _, _, hash_type_path) = get_offset_value_lookup_type(index_paths[0].clone());
-                let config = read_index_config_from_file(&hash_type_path);
-                if config.mode == IndexMode::Big {
-                    use_big_index = true;
+            // Fail before searching if structures were moved without --structure-root
+            if !skip_match {
+                if let Err(e) = index.check_structure_locations() {
+                    print_log_msg(FAIL, &e);
+                    std::process::exit(1);
                 }
             }
-            // Load big index here
-            
-            let index_prefix = index_paths[0].clone();
-            let (big_index, big_offset_mmap) = if use_big_index {
-                load_big_index(&index_prefix)
-            } else {
-                (FolddiscoIndex::new(0, "".to_string(), false),
-                 MmapMut::map_anon(0).unwrap().make_read_only().unwrap())
-            };
 
-            // Set thread pool
-            let _pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build_global().unwrap();
-            
             let queries = if query_string.ends_with(".txt") || query_string.ends_with(".tsv") {
                 // Read file and get path, query, output by line
                 let mut queries: Vec<(String, String, String)> = Vec::new();
@@ -239,370 +255,93 @@ pub fn query_pdb(env: AppArgs) {
             } else {
                 vec![(pdb_path.clone(), query_string.clone(), output.clone())]
             };
+            // Each query writes its own array, so only one of them can go to stdout as json
+            let num_stdout_queries = queries.iter().filter(|(_, _, output_path)| output_path.is_empty()).count();
+            if output_format == OutputFormat::Json && num_stdout_queries > 1 {
+                print_log_msg(FAIL, "JSON output of multiple queries can't be printed as one document. Use --format jsonl or give each query an output file");
+                std::process::exit(1);
+            }
 
-            let dist_thresholds = parse_threshold_string(dist_threshold.clone());
-            let angle_thresholds = parse_threshold_string(angle_threshold.clone());
-            
-            let loaded_index_vec = index_paths.into_par_iter().map(|index_path| {
-                let (offset_path, value_path, lookup_path, hash_type_path) = get_offset_value_lookup_type(index_path);
-                let config = read_index_config_from_file(&hash_type_path);
-                let (offset_table, offset_mmap) = if verbose && !use_big_index { measure_time!(
-                    SimpleHashMap::load_from_disk(&PathBuf::from(&offset_path))
-                ) } else if !use_big_index {
-                    SimpleHashMap::load_from_disk(&PathBuf::from(&offset_path))
-                } else {
-                    let anon_mmap: Mmap = MmapMut::map_anon(0).unwrap().make_read_only().unwrap();
-                    (Ok(SimpleHashMap::new(0)), anon_mmap)
-                };
-                let offset_table = offset_table.unwrap();
-                let lookup = if verbose {
-                    measure_time!(load_lookup_from_file(&lookup_path))
-                } else {
-                    load_lookup_from_file(&lookup_path)
-                };
-                (offset_table, offset_mmap, lookup, config, value_path)
-            }).collect::<Vec<(SimpleHashMap, Mmap, Vec<(String, usize, usize, f32)>, IndexConfig, String)>>();
-            
-            // Load foldcomp db 
-            #[cfg(feature = "foldcomp")]
-            let config = &loaded_index_vec[0].3;
-            #[cfg(feature = "foldcomp")]
-            let using_foldcomp = config.foldcomp_db.is_some() && config.input_format == StructureFileFormat::FCZDB;
-
-            #[cfg(feature = "foldcomp")]
-            let foldcomp_db_reader = match config.input_format {
-                StructureFileFormat::FCZDB => {
-                    if !skip_match {
-                        let foldcomp_db_path = config.foldcomp_db.clone().unwrap();
-                        if verbose {
-                            measure_time!(FoldcompDbReader::new(foldcomp_db_path.as_str()))
-                        } else {
-                            FoldcompDbReader::new(foldcomp_db_path.as_str())
-                        }
-                    } else {
-                        FoldcompDbReader::empty()
-                    }
-                },
-                _ => FoldcompDbReader::empty(),
+            let options = QueryOptions {
+                dist_thresholds: parse_threshold_string(dist_threshold),
+                angle_thresholds: parse_threshold_string(angle_threshold),
+                ca_dist_threshold, sampling_count, sampling_ratio, freq_filter, length_penalty, skip_match, serial_query,
+                total_match_count, covered_node_count, covered_node_ratio, covered_edge_count, covered_edge_ratio,
+                max_matching_node_count, max_matching_node_ratio, idf_score_cutoff, connected_node_count,
+                connected_node_ratio, num_res_cutoff, plddt_cutoff, rmsd_cutoff, top_n, sort_by_score, skip_ca_match,
+                verbose,
             };
 
-            // #[cfg(not(feature = "foldcomp"))]
-            // let using_foldcomp = false;
-
             // Iterate over queries
-            queries.into_par_iter().for_each(|(pdb_path, query_string, output_path)| {
-                let (query_structure, _) = read_compact_structure(&pdb_path).expect(
-                    &log_msg(FAIL, &format!("Failed to read structure: {}", &pdb_path))
-                );
-                
-                let (query_residues, aa_substitutions) = parse_query_string(&query_string, query_structure.chains[0]);
-                
-                let _residue_count = if query_residues.is_empty() {
-                    query_structure.num_residues
-                } else {
-                    query_residues.len()
-                };
-                let query_string = if query_residues.is_empty() {
+            let num_queries = queries.len();
+            queries.into_par_iter().enumerate().for_each(|(query_number, (pdb_path, query_string, output_path))| {
+                let query = QueryBuilder::new(&pdb_path).residues(&query_string).options(options.clone()).build()
+                    .unwrap_or_else(|e| {
+                        print_log_msg(FAIL, &e);
+                        std::process::exit(1);
+                    });
+                let query_string = if query.residues().is_empty() {
                     query_string
                 } else {
-                    let mut query_residues = query_residues.clone();
+                    let mut query_residues = query.residues().to_vec();
                     query_residues.sort();
                     res_chain_to_string(&query_residues)
                 };
+                let result_format = ResultFormat {
+                    output_format, query_residues: query.residue_labels(), superposition,
+                };
 
-                // Get query map for each query in all indices
-                let mut queried_from_indices: Vec<(usize, StructureResult)> = loaded_index_vec.par_iter().map(
-                    |(offset_table, _offset_mmap, lookup, config, value_path)| {
-                        let hash_type = config.hash_type;
-                        let num_bin_dist = config.num_bin_dist;
-                        let num_bin_angle = config.num_bin_angle;
-                        let mode = config.mode;
-                        let dist_cutoff = config.grid_width;
-                        let multiple_bin = &config.multiple_bin;
-                        let (pdb_query_map, query_indices, aa_dist_map ) = if verbose { 
-                            measure_time!(make_query_map(
-                                &pdb_path, &query_residues, hash_type, num_bin_dist, num_bin_angle, multiple_bin,
-                                &dist_thresholds, &angle_thresholds, &aa_substitutions, dist_cutoff, serial_query,
-                            ))
-                        } else {
-                            make_query_map(
-                                &pdb_path, &query_residues, hash_type, num_bin_dist, num_bin_angle, multiple_bin,
-                                &dist_thresholds, &angle_thresholds, &aa_substitutions, dist_cutoff, serial_query,
-                            )
-                        };
-                        let pdb_query = pdb_query_map.keys().cloned().collect::<Vec<_>>();
-                        // Make filters out of filtering parameters
-                        let structure_filter = StructureFilter::new(
-                            total_match_count, covered_node_count, covered_node_ratio, covered_edge_count, covered_edge_ratio,
-                            idf_score_cutoff, num_res_cutoff, plddt_cutoff, 
-                            max_matching_node_count, max_matching_node_ratio, rmsd_cutoff,
-                            _residue_count, _residue_count * (_residue_count - 1)
-                        );
-
-                        match mode {
-                            IndexMode::Id => {
-                                let (value_mmap, value_vec) = if verbose { measure_time!(read_u16_vector(&value_path).expect(
-                                    &log_msg(FAIL, &format!("Failed to load value vector: {}", &value_path))
-                                )) } else {
-                                    read_u16_vector(&value_path).expect(&log_msg(FAIL, &format!("Failed to load value vector: {}", &value_path)))
-                                };
-                                let query_count_map = if verbose { measure_time!(count_query_idmode(
-                                    &pdb_query, &pdb_query_map, &offset_table, value_vec, &lookup, 
-                                    sampling_ratio, sampling_count, freq_filter, length_penalty
-                                ))} else {
-                                    count_query_idmode(
-                                        &pdb_query, &pdb_query_map, &offset_table, value_vec, &lookup,
-                                        sampling_ratio, sampling_count, freq_filter, length_penalty
-                                    )
-                                };
-
-                                let mut query_count_vec: Vec<(usize, StructureResult)> = query_count_map.into_par_iter().filter(|(_k, v)| {
-                                    structure_filter.filter_before_matching(v)
-                                }).collect();
-                                if verbose {
-                                    print_log_msg(INFO, &format!("Found {} structures from inverted index", query_count_vec.len()));
-                                }
-                                // 
-                                if verbose {
-                                    measure_time!(query_count_vec.par_sort_by(|a, b| b.1.idf.partial_cmp(&a.1.idf).unwrap()));
-                                } else {
-                                    query_count_vec.par_sort_by(|a, b| b.1.idf.partial_cmp(&a.1.idf).unwrap());
-                                }
-                                // Apply top N filter if top_n is not usize::MAX
-                                if top_n != usize::MAX {
-                                    if verbose {
-                                        print_log_msg(INFO, &format!("Limiting result to top {} structures", top_n));
-                                    }
-                                    query_count_vec.truncate(top_n);
-                                }
-                                // IF retrieve is true, retrieve matching residues
-                                if !skip_match {
-                                    if verbose {
-                                        measure_time!(query_count_vec.par_iter_mut().for_each(|(_, v)| {
-                                            #[cfg(not(feature = "foldcomp"))]
-                                            let retrieval_result = retrieval_wrapper(
-                                                &v.id, MIN_CONNECTED_COMPONENT_SIZE, &pdb_query,
-                                                hash_type, num_bin_dist, num_bin_angle, multiple_bin, dist_cutoff,
-                                                &pdb_query_map, &query_structure, &query_indices,
-                                                &aa_dist_map, ca_dist_threshold,
-                                            );
-                                            #[cfg(feature = "foldcomp")]
-                                            let retrieval_result = if using_foldcomp {
-                                                retrieval_wrapper_for_foldcompdb(
-                                                    &v.id, MIN_CONNECTED_COMPONENT_SIZE, &pdb_query,
-                                                    hash_type, num_bin_dist, num_bin_angle, multiple_bin, dist_cutoff,
-                                                    &pdb_query_map, &query_structure, &query_indices,
-                                                    &aa_dist_map, ca_dist_threshold, &foldcomp_db_reader
-                                                )
-                                            } else {
-                                                retrieval_wrapper(
-                                                    &v.id, MIN_CONNECTED_COMPONENT_SIZE, &pdb_query,
-                                                    hash_type, num_bin_dist, num_bin_angle, multiple_bin, dist_cutoff,
-                                                    &pdb_query_map, &query_structure, &query_indices,
-                                                    &aa_dist_map, ca_dist_threshold,
-                                                )
-                                            };
-                                            v.matching_residues = retrieval_result.0;
-                                            v.matching_residues_processed = retrieval_result.1;
-                                            v.max_matching_node_count = retrieval_result.2;
-                                            v.min_rmsd_with_max_match = retrieval_result.3;
-                                        }));
-                                    } else {
-                                        query_count_vec.par_iter_mut().for_each(|(_, v)| {
-                                            #[cfg(not(feature = "foldcomp"))]
-                                            let retrieval_result = retrieval_wrapper(
-                                                &v.id, MIN_CONNECTED_COMPONENT_SIZE, &pdb_query,
-                                                hash_type, num_bin_dist, num_bin_angle, multiple_bin, dist_cutoff,
-                                                &pdb_query_map, &query_structure, &query_indices,
-                                                &aa_dist_map, ca_dist_threshold,
-                                            );
-                                            #[cfg(feature = "foldcomp")]
-                                            let retrieval_result = if using_foldcomp {
-                                                retrieval_wrapper_for_foldcompdb(
-                                                    &v.id, MIN_CONNECTED_COMPONENT_SIZE, &pdb_query,
-                                                    hash_type, num_bin_dist, num_bin_angle, multiple_bin, dist_cutoff,
-                                                    &pdb_query_map, &query_structure, &query_indices,
-                                                    &aa_dist_map, ca_dist_threshold, &foldcomp_db_reader
-                                                )
-                                            } else {
-                                                retrieval_wrapper(
-                                                    &v.id, MIN_CONNECTED_COMPONENT_SIZE, &pdb_query,
-                                                    hash_type, num_bin_dist, num_bin_angle, multiple_bin, dist_cutoff,
-                                                    &pdb_query_map, &query_structure, &query_indices,
-                                                    &aa_dist_map, ca_dist_threshold,
-                                                )
-                                            };
-                                            v.matching_residues = retrieval_result.0;
-                                            v.matching_residues_processed = retrieval_result.1;
-                                            v.max_matching_node_count = retrieval_result.2;
-                                            v.min_rmsd_with_max_match = retrieval_result.3;
-                                        });
-                                    }
-                                    
-                                    // Filter query_count_vec with reasonable retrieval results
-                                    query_count_vec.retain(|(_, v)| structure_filter.filter_after_matching(v));
-                                    drop(value_mmap);
-                                    return query_count_vec;
-                                }
-                                drop(value_mmap);
-                                query_count_vec
-                            },
-                            IndexMode::Big => {
-
-                                let query_count_map = if verbose { measure_time!(count_query_bigmode(
-                                    &pdb_query, &pdb_query_map, &big_index, &lookup, 
-                                    sampling_ratio, sampling_count, freq_filter, length_penalty
-                                )) } else {
-                                    count_query_bigmode(
-                                        &pdb_query, &pdb_query_map, &big_index, &lookup,
-                                        sampling_ratio, sampling_count, freq_filter, length_penalty
-                                    )
-                                };
-                                let mut query_count_vec: Vec<(usize, StructureResult)> = query_count_map.into_par_iter().filter(|(_k, v)| {
-                                    structure_filter.filter_before_matching(v)
-                                }).collect();
-
-                                if verbose {
-                                    print_log_msg(INFO, &format!("Found {} structures from inverted index", query_count_vec.len()));
-                                }
-
-                                // 
-                                if verbose {
-                                    measure_time!(query_count_vec.par_sort_by(|a, b| b.1.idf.partial_cmp(&a.1.idf).unwrap()));
-                                } else {
-                                    query_count_vec.par_sort_by(|a, b| b.1.idf.partial_cmp(&a.1.idf).unwrap());
-                                }
-                                // Apply top N filter if top_n is not usize::MAX
-                                if top_n != usize::MAX {
-                                    if verbose {
-                                        print_log_msg(INFO, &format!("Limiting result to top {} structures", top_n));
-                                    }
-                                    query_count_vec.truncate(top_n);
-                                }
-                                
-                                // IF retrieve is true, retrieve matching residues
-                                if !skip_match {
-                                    if verbose {
-                                        measure_time!(query_count_vec.par_iter_mut().for_each(|(_, v)| {
-                                            #[cfg(not(feature = "foldcomp"))]
-                                            let retrieval_result = retrieval_wrapper(
-                                                &v.id, MIN_CONNECTED_COMPONENT_SIZE, &pdb_query,
-                                                hash_type, num_bin_dist, num_bin_angle, multiple_bin, dist_cutoff,
-                                                &pdb_query_map, &query_structure, &query_indices,
-                                                &aa_dist_map, ca_dist_threshold,
-                                            );
-                                            #[cfg(feature = "foldcomp")]
-                                            let retrieval_result = if using_foldcomp {
-                                                retrieval_wrapper_for_foldcompdb(
-                                                    &v.id, MIN_CONNECTED_COMPONENT_SIZE, &pdb_query,
-                                                    hash_type, num_bin_dist, num_bin_angle, multiple_bin, dist_cutoff,
-                                                    &pdb_query_map, &query_structure, &query_indices,
-                                                    &aa_dist_map, ca_dist_threshold, &foldcomp_db_reader
-                                                )
-                                            } else {
-                                                retrieval_wrapper(
-                                                    &v.id, MIN_CONNECTED_COMPONENT_SIZE, &pdb_query,
-                                                    hash_type, num_bin_dist, num_bin_angle, multiple_bin, dist_cutoff,
-                                                    &pdb_query_map, &query_structure, &query_indices,
-                                                    &aa_dist_map, ca_dist_threshold,
-                                                )
-                                            };
-                                            v.matching_residues = retrieval_result.0;
-                                            v.matching_residues_processed = retrieval_result.1;
-                                            v.max_matching_node_count = retrieval_result.2;
-                                            v.min_rmsd_with_max_match = retrieval_result.3;
-                                        }));
-                                    } else {
-                                        query_count_vec.par_iter_mut().for_each(|(_, v)| {
-                                            #[cfg(not(feature = "foldcomp"))]
-                                            let retrieval_result = retrieval_wrapper(
-                                                &v.id, MIN_CONNECTED_COMPONENT_SIZE, &pdb_query,
-                                                hash_type, num_bin_dist, num_bin_angle, multiple_bin, dist_cutoff,
-                                                &pdb_query_map, &query_structure, &query_indices,
-                                                &aa_dist_map, ca_dist_threshold,
-                                            );
-                                            #[cfg(feature = "foldcomp")]
-                                            let retrieval_result = if using_foldcomp {
-                                                retrieval_wrapper_for_foldcompdb(
-                                                    &v.id, MIN_CONNECTED_COMPONENT_SIZE, &pdb_query,
-                                                    hash_type, num_bin_dist, num_bin_angle, multiple_bin, dist_cutoff,
-                                                    &pdb_query_map, &query_structure, &query_indices,
-                                                    &aa_dist_map, ca_dist_threshold, &foldcomp_db_reader
-                                                )
-                                            } else {
-                                                retrieval_wrapper(
-                                                    &v.id, MIN_CONNECTED_COMPONENT_SIZE, &pdb_query,
-                                                    hash_type, num_bin_dist, num_bin_angle, multiple_bin, dist_cutoff,
-                                                    &pdb_query_map, &query_structure, &query_indices,
-                                                    &aa_dist_map, ca_dist_threshold,
-                                                )
-                                            };
-                                            v.matching_residues = retrieval_result.0;
-                                            v.matching_residues_processed = retrieval_result.1;
-                                            v.max_matching_node_count = retrieval_result.2;
-                                            v.min_rmsd_with_max_match = retrieval_result.3;
-                                        });
-                                    }
-
-                                    // Filter query_count_vec with reasonable retrieval results
-                                    query_count_vec.retain(|(_, v)| v.matching_residues.len() > 0);
-                                    return query_count_vec;
-                                }
-                                query_count_vec
-                            },
-                        } // match mode
-                    }
-                ).reduce(|| Vec::new(), |mut acc, x| {
-                    acc.extend(x);
-                    acc
+                // Hits of each query go to a numbered subdirectory if there are many queries
+                let hit_dir = hit_dir.as_ref().map(|dir| match num_queries {
+                    1 => dir.clone(),
+                    _ => format!("{}/{}", dir, query_number + 1),
                 });
-                drop(query_residues);
-                let match_filter= MatchFilter::new(
-                    connected_node_count, connected_node_ratio, idf_score_cutoff,
-                    rmsd_cutoff, _residue_count,
-                );
+                let export_hits = |hits: &[(usize, MatchResult)]| {
+                    if let (Some(hit_dir), false) = (&hit_dir, skip_match) {
+                        let read_hit = |nid: usize, hit: &MatchResult| index.read_structure(nid, hit.id);
+                        write_hits(hit_dir, hits, hit_environment, &hit_format, read_hit, verbose).unwrap_or_else(|e| {
+                            print_log_msg(FAIL, &e);
+                            std::process::exit(1);
+                        });
+                    }
+                };
 
                 match query_mode {
                     QueryMode::PerMatchDefault | QueryMode::PerMatchSortByScore => {
-                        let mut match_results = convert_structure_query_result_to_match_query_results(
-                            &queried_from_indices, skip_ca_match
-                        );
-                        match_results.retain(|(_, v)| match_filter.filter(v));
+                        let mut match_results = index.search_matches(&query);
                         sort_and_print_match_query_result(
                             &mut match_results, top_n, 
-                            &output_path, &query_string, header, verbose
+                            &output_path, &query_string, &result_format, header, verbose
                         );
+                        export_hits(&match_results);
                     }
                     QueryMode::Web => {
-                        let mut match_results = convert_structure_query_result_to_match_query_results(
-                            &queried_from_indices, skip_ca_match
-                        );
-                        match_results.retain(|(_, v)| match_filter.filter(v));
+                        let mut match_results = index.search_matches(&query);
                         sort_and_print_match_query_result(
                             &mut match_results, MAX_NUM_LINES_FOR_WEB,
-                            &output_path, &query_string, header, verbose
+                            &output_path, &query_string, &result_format, header, verbose
                         );
+                        export_hits(&match_results);
                     }
                     QueryMode::PerStructureSortByRmsd => {
+                        let mut structure_results = index.search_structures(&query);
                         sort_and_print_structure_query_result(
-                            &mut queried_from_indices,  true, &output_path, 
-                            &query_string, header, verbose
+                            &mut structure_results,  true, &output_path, 
+                            &query_string, &result_format, header, verbose
                         );
+                        export_hits(&convert_structure_query_result_to_match_query_results(&structure_results, skip_ca_match));
                     }
                     QueryMode::PerStructureSortByScore | QueryMode::SkipMatch => {
+                        let mut structure_results = index.search_structures(&query);
                         sort_and_print_structure_query_result(
-                            &mut queried_from_indices, false, &output_path, 
-                            &query_string, header, verbose
+                            &mut structure_results, false, &output_path, 
+                            &query_string, &result_format, header, verbose
                         );
+                        export_hits(&convert_structure_query_result_to_match_query_results(&structure_results, skip_ca_match));
                     }
                     _ => {}
                 }
-                drop(queried_from_indices);
-                drop(query_structure);
             }); // queries
-            drop(loaded_index_vec);
-            drop(big_offset_mmap);
-            drop(big_index);
         }, // AppArgs::Query
         _ => {
             eprintln!("{}", HELP_QUERY);
@@ -668,6 +407,13 @@ mod tests {
             header: true,
             serial_query: false,
             output: String::from(""),
+            output_format: String::from("tsv"),
+            superposition: false,
+            write_hits: None,
+            hit_environment: 0.0,
+            hit_format: String::from("pdb"),
+            structure_root: None,
+            foldcomp_db: None,
             verbose: true,
             help: false,
         };
@@ -717,6 +463,13 @@ mod tests {
                 header: true,
                 serial_query: false,
                 output: String::from(""),
+                output_format: String::from("tsv"),
+                superposition: false,
+                write_hits: None,
+                hit_environment: 0.0,
+                hit_format: String::from("pdb"),
+                structure_root: None,
+                foldcomp_db: None,
                 verbose: true,
                 help: false,
             };
@@ -766,6 +519,13 @@ mod tests {
             header: true,
             serial_query: false,
             output: String::from(""),
+            output_format: String::from("jsonl"),
+            superposition: false,
+            write_hits: None,
+            hit_environment: 0.0,
+            hit_format: String::from("pdb"),
+            structure_root: None,
+            foldcomp_db: None,
             verbose: true,
             help: false,
         };
@@ -0,0 +1,275 @@
+//! Workflow for coarsening the distance and angle bins of an existing index.
+//! Every hash is moved to the hash of the centres of its bins with fewer bins
+//! and the posting lists that end up on the same hash are merged. Structures
+//! are not read again, so rebinning an index of AFDB takes as long as reading
+//! its postings. Only numbers of bins whose bins are unions of the old ones are
+//! accepted, so that hashes are the same as indexing the structures again.
+
+use std::path::PathBuf;
+
+use rayon::prelude::*;
+
+use crate::cli::*;
+use crate::cli::config::{read_index_config_from_file, write_index_config_to_file};
+use crate::cli::workflows::convert::{
+    copy_sidecars, load_big_index_pairs, load_id_index_pairs, save_big_index, save_id_index, SIDECAR_EXTENSIONS
+};
+use crate::controller::mode::IndexMode;
+use crate::controller::query::get_offset_value_lookup_type;
+use crate::geometry::{hybrid, pdb_motif, pdb_tr};
+use crate::prelude::*;
+use crate::utils::convert::{NBIN_DIST, NBIN_SIN_COS};
+
+pub const HELP_REBIN: &str = "\
+usage: folddisco rebin -i <i:INDEX_PATH> -o <o:INDEX_PATH> [-d <NUM_BIN_DIST>] [-a <NUM_BIN_ANGLE>] [OPTIONS]
+
+input/output:
+ -i, --index <PATH>               Index to rebin
+ -o, --output <PATH>              Path to save the rebinned index
+
+binning:
+ -d, --distance <INT>             Number of distance bins. Not more than the index [same as index]
+ -a, --angle <INT>                Number of angle bins. Not more than the index [same as index]
+                                  (index bins - 1) should be an odd multiple of (bins - 1),
+                                  e.g. 16 -> 6 or 4, and 4 -> 2
+
+options:
+ -t, --threads <INT>              Number of threads to use [1]
+ -v, --verbose                    Print verbose messages
+ -h, --help                       Print this help menu
+
+Supported hash types: PDBMotif, PDBMotifSinCos, PDBTrRosetta, Hybrid.
+Indices built with --multiple-bins, --extra-hashes or --stop-ratio can't be rebinned.
+
+examples:
+# Coarser bins of the default hash (16 distance, 4 angle bins)
+folddisco rebin -i index/afdb -o index/afdb_6_2 -d 6 -a 2 -t 12
+";
+
+pub fn rebin(env: AppArgs) {
+    match env {
+        AppArgs::Rebin {
+            index_path,
+            output_path,
+            num_bin_dist,
+            num_bin_angle,
+            num_threads,
+            verbose,
+            help: _,
+        } => {
+            if index_path.is_none() || output_path.is_none() || (num_bin_dist.is_none() && num_bin_angle.is_none()) {
+                eprintln!("{}", HELP_REBIN);
+                std::process::exit(1);
+            }
+            let index_path = index_path.unwrap();
+            let output_path = output_path.unwrap();
+            if !PathBuf::from(format!("{}.type", index_path)).is_file() {
+                if PathBuf::from(format!("{}_0.type", index_path)).is_file() {
+                    print_log_msg(FAIL, &format!("{} is chunked. Merge the chunks before rebinning", index_path));
+                } else {
+                    print_log_msg(FAIL, &format!("Index not found: {}", index_path));
+                }
+                std::process::exit(1);
+            }
+            if index_path == output_path {
+                print_log_msg(FAIL, "Output path should be different from the input index");
+                std::process::exit(1);
+            }
+            rebin_index(&index_path, &output_path, num_bin_dist, num_bin_angle, num_threads, verbose);
+        }
+        _ => {
+            eprintln!("{}", HELP_REBIN);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Numbers of bins that perfect_hash of the hash type uses for the given numbers.
+// 0 falls back to the default and larger numbers are capped to what fits in the hash.
+// None for hash types that can't be rebinned
+fn effective_num_bins(hash_type: HashType, nbin_dist: usize, nbin_angle: usize) -> Option<(usize, usize)> {
+    let (default_dist, default_angle, max_dist, max_angle) = match hash_type {
+        HashType::PDBMotif => (pdb_motif::NBIN_DIST as usize, pdb_motif::NBIN_ANGLE as usize, 32, 32),
+        HashType::PDBMotifSinCos => (NBIN_DIST as usize, NBIN_SIN_COS as usize, 16, 16),
+        HashType::PDBTrRosetta => (pdb_tr::PDBTR_NBIN_DIST as usize, pdb_tr::PDBTR_NBIN_SIN_COS as usize, 16, 4),
+        HashType::Hybrid => (hybrid::HYBRID_NBIN_DIST as usize, hybrid::HYBRID_NBIN_SIN_COS as usize, 16, 4),
+        _ => return None,
+    };
+    let resolve = |nbin: usize, default: usize, max: usize| if nbin == 0 { default } else { nbin.min(max) };
+    Some((resolve(nbin_dist, default_dist, max_dist), resolve(nbin_angle, default_angle, max_angle)))
+}
+
+// Values go to the nearest of num_bin evenly spaced bin centres from min to max.
+// New bins are unions of old ones if the new centres are old centres and the edges
+// between new bins are edges between old bins, i.e. if (num_bin - 1) is an odd
+// multiple of (new_num_bin - 1). Otherwise a feature can end up in another bin
+// than when the structures are indexed with new_num_bin bins.
+fn bins_nest(num_bin: usize, new_num_bin: usize) -> bool {
+    (num_bin - 1).is_multiple_of(new_num_bin - 1) && ((num_bin - 1) / (new_num_bin - 1)) % 2 == 1
+}
+
+pub fn rebin_index(
+    index_path: &str, output_path: &str, num_bin_dist: Option<usize>, num_bin_angle: Option<usize>,
+    num_threads: usize, verbose: bool,
+) {
+    let (offset_path, value_path, _lookup_path, hash_type_path) = get_offset_value_lookup_type(index_path.to_string());
+    let mut config = read_index_config_from_file(&hash_type_path);
+    if config.multiple_bin.is_some() {
+        print_log_msg(FAIL, "Index built with multiple bins can't be rebinned");
+        std::process::exit(1);
+    }
+    if !config.extra_hash_sets.is_empty() {
+        print_log_msg(FAIL, "Index with extra hash sets can't be rebinned");
+        std::process::exit(1);
+    }
+    if config.stop_ratio > 0.0 {
+        print_log_msg(FAIL, "Index with a stop-list can't be rebinned. Postings of the left out hashes are missing");
+        std::process::exit(1);
+    }
+    let (old_dist, old_angle) = effective_num_bins(
+        config.hash_type, config.num_bin_dist, config.num_bin_angle
+    ).unwrap_or_else(|| {
+        print_log_msg(FAIL, &format!("Rebinning is not supported for {}", config.hash_type.to_string()));
+        std::process::exit(1);
+    });
+    let new_dist = num_bin_dist.unwrap_or(old_dist);
+    let new_angle = num_bin_angle.unwrap_or(old_angle);
+    if new_dist < 2 || new_angle < 2 || new_dist > old_dist || new_angle > old_angle {
+        print_log_msg(FAIL, &format!(
+            "Bins should be between 2 and the bins of the index ({} distance, {} angle)", old_dist, old_angle
+        ));
+        std::process::exit(1);
+    }
+    for (kind, old, new) in [("distance", old_dist, new_dist), ("angle", old_angle, new_angle)] {
+        if !bins_nest(old, new) {
+            let allowed = (2..=old).filter(|&n| bins_nest(old, n)).map(|n| n.to_string()).collect::<Vec<_>>();
+            print_log_msg(FAIL, &format!(
+                "{} {} bins can't be made from the {} bins of the index without indexing again. Use one of {}",
+                new, kind, old, allowed.join(", ")
+            ));
+            std::process::exit(1);
+        }
+    }
+    if (new_dist, new_angle) == (old_dist, old_angle) {
+        print_log_msg(FAIL, &format!("{} already has {} distance and {} angle bins", index_path, old_dist, old_angle));
+        std::process::exit(1);
+    }
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build()
+        .unwrap_or_else(|_| panic!("{}", log_msg(FAIL, "Failed to build thread pool for rebinning")));
+    if verbose {
+        print_log_msg(INFO, &format!(
+            "Rebinning {} from {}-{} to {}-{} (distance-angle bins)",
+            index_path, old_dist, old_angle, new_dist, new_angle
+        ));
+    }
+
+    let mut hash_id_vec = match config.mode {
+        IndexMode::Id => load_id_index_pairs(&offset_path, &value_path, config.value_bits),
+        IndexMode::Big => load_big_index_pairs(index_path, &pool),
+    };
+    let num_old_hashes = hash_id_vec.chunk_by(|a, b| a.0 == b.0).count();
+    let hash_type = config.hash_type;
+    pool.install(|| {
+        hash_id_vec.par_iter_mut().for_each(|(hash, _)| {
+            *hash = GeometricHash::from_u32(*hash, hash_type)
+                .rebin(old_dist, old_angle, new_dist, new_angle).unwrap().as_u32();
+        });
+        hash_id_vec.par_sort_unstable();
+    });
+    // A structure is listed once in a merged posting list
+    hash_id_vec.dedup();
+    if verbose {
+        let num_new_hashes = hash_id_vec.chunk_by(|a, b| a.0 == b.0).count();
+        print_log_msg(INFO, &format!(
+            "Merged {} hashes into {} ({} postings)", num_old_hashes, num_new_hashes, hash_id_vec.len()
+        ));
+    }
+    match config.mode {
+        IndexMode::Id => save_id_index(hash_id_vec, output_path, hash_type, config.value_bits),
+        IndexMode::Big => save_big_index(&hash_id_vec, output_path, hash_type, &pool, verbose),
+    }
+    drop(pool);
+
+    copy_sidecars(index_path, output_path, &SIDECAR_EXTENSIONS);
+    config.num_bin_dist = new_dist;
+    config.num_bin_angle = new_angle;
+    write_index_config_to_file(&format!("{}.type", output_path), config);
+    if verbose {
+        print_log_msg(DONE, &format!("Rebinned {} into {}", index_path, output_path));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::workflows::build_index::build_index;
+    use crate::cli::workflows::test_index_args;
+    use crate::cli::workflows::convert::convert_index;
+    use crate::utils::convert::{MAX_DIST, MIN_DIST};
+
+    #[test]
+    fn test_rebin_index() {
+        let index_path = "data/rebin_test";
+        build_index(test_index_args("data/homeobox", index_path, "pdb"));
+        let mut expected = load_id_index_pairs("data/rebin_test.offset", "data/rebin_test.value", 16).into_iter().map(
+            |(hash, id)| {
+                let hash = GeometricHash::from_u32(hash, HashType::PDBMotifSinCos).rebin(16, 4, 6, 2).unwrap();
+                (hash.as_u32(), id)
+            }
+        ).collect::<Vec<_>>();
+        expected.sort();
+        expected.dedup();
+
+        rebin_index(index_path, "data/rebin_test_6_2", Some(6), Some(2), 2, false);
+        let config = read_index_config_from_file("data/rebin_test_6_2.type");
+        assert_eq!((config.num_bin_dist, config.num_bin_angle), (6, 2));
+        assert_eq!(std::fs::read("data/rebin_test_6_2.lookup").unwrap(), std::fs::read("data/rebin_test.lookup").unwrap());
+        let mut rebinned = load_id_index_pairs("data/rebin_test_6_2.offset", "data/rebin_test_6_2.value", 16);
+        rebinned.sort();
+        assert_eq!(rebinned, expected);
+
+        // Big mode gives the same postings
+        convert_index(index_path, "data/rebin_test_big", IndexMode::Big, 2, false);
+        rebin_index("data/rebin_test_big", "data/rebin_test_big_6_2", Some(6), Some(2), 2, false);
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        assert_eq!(load_big_index_pairs("data/rebin_test_big_6_2", &pool), expected);
+
+        for prefix in [index_path, "data/rebin_test_6_2", "data/rebin_test_big", "data/rebin_test_big_6_2"] {
+            for ext in ["offset", "value", "lookup", "type"] {
+                std::fs::remove_file(format!("{}.{}", prefix, ext)).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_rebin_matches_fresh_hash() {
+        assert!(bins_nest(16, 16) && bins_nest(16, 6) && bins_nest(16, 4) && bins_nest(16, 2) && bins_nest(4, 2));
+        assert!(!bins_nest(16, 8) && !bins_nest(16, 11) && !bins_nest(4, 3));
+
+        // Features within the range of the bins, hashed with the old bins and rebinned
+        // or hashed with the new bins directly
+        let features = (0..2000).map(|i| {
+            let x = i as f32;
+            vec![
+                (i % 20) as f32, ((i / 20) % 20) as f32, MIN_DIST + (x * 0.0137) % (MAX_DIST - MIN_DIST),
+                MIN_DIST + (x * 0.0291) % (MAX_DIST - MIN_DIST), (x * 0.0113) % 6.28 - 3.14,
+                (x * 0.0071) % 6.28 - 3.14, (x * 0.0197) % 6.28 - 3.14,
+            ]
+        }).collect::<Vec<_>>();
+        let num_mismatches = |hash_type: HashType, old: (usize, usize), new: (usize, usize)| {
+            features.iter().filter(|feature| {
+                let rebinned = GeometricHash::from_u32(
+                    GeometricHash::perfect_hash_as_u32(feature, hash_type, old.0, old.1), hash_type
+                ).rebin(old.0, old.1, new.0, new.1).unwrap().as_u32();
+                rebinned != GeometricHash::perfect_hash_as_u32(feature, hash_type, new.0, new.1)
+            }).count()
+        };
+        for hash_type in [HashType::PDBMotifSinCos, HashType::PDBTrRosetta] {
+            assert_eq!(num_mismatches(hash_type, (16, 4), (6, 2)), 0);
+            assert_eq!(num_mismatches(hash_type, (16, 4), (4, 4)), 0);
+            assert!(num_mismatches(hash_type, (16, 4), (8, 4)) > 0);
+        }
+    }
+}
@@ -0,0 +1,352 @@
+//! Workflow for removing structures from an existing index.
+//! Removed structures are marked in a tombstone sidecar ({index}.deleted)
+//! and skipped while querying. Compaction rewrites the posting lists and
+//! the lookup without them.
+
+use std::collections::HashSet;
+use std::io::BufRead;
+use std::path::PathBuf;
+
+use rayon::slice::ParallelSliceMut;
+
+use crate::cli::*;
+use crate::cli::config::{read_index_config_from_file, write_index_config_to_file};
+use crate::controller::io::{id_value_bits, read_id_values, write_id_values};
+use crate::controller::map::{convert_simplemap_to_hash_vec, convert_sorted_hash_vec_to_simplemap, SimpleHashMap};
+use crate::controller::mode::IndexMode;
+use crate::controller::query::{check_and_get_indices, get_hash_set_offset_value, get_offset_value_lookup_type};
+use crate::index::coords::{CoordinateCache, CoordinateCacheWriter};
+use crate::index::indextable::{load_big_index, FolddiscoIndex};
+use crate::index::lookup::{load_locations_from_file, refresh_binary_lookup, save_locations_to_file};
+use crate::index::tombstone::{load_tombstone_from_file, save_tombstone_to_file};
+use crate::prelude::*;
+
+pub const HELP_REMOVE: &str = "\
+usage: folddisco remove -i <i:INDEX_PATH> -l <i:ID_LIST> [OPTIONS]
+
+input:
+ -i, --index <PATH>               Path of the index table
+ -l, --list <PATH>                File with IDs to remove, one per line (first tab-separated column).
+                                  IDs are matched against the IDs in the lookup of the index
+
+options:
+ --compact                        Rewrite posting lists and lookup without removed structures.
+                                  Numeric ids are reassigned. Can be used without -l to compact
+                                  structures removed earlier
+ -t, --threads <INT>              Number of threads to use for compaction [1]
+ -v, --verbose                    Print verbose messages
+ -h, --help                       Print this help menu
+
+examples:
+# Mark structures as deleted. They are skipped in queries from now on
+folddisco remove -i index/h_sapiens -l obsolete.txt
+
+# Mark and physically remove them from the index
+folddisco remove -i index/h_sapiens -l obsolete.txt --compact -t 12
+";
+
+pub fn remove(env: AppArgs) {
+    match env {
+        AppArgs::Remove {
+            index_path,
+            id_list,
+            compact,
+            num_threads,
+            verbose,
+            help: _,
+        } => {
+            if index_path.is_none() || (id_list.is_none() && !compact) {
+                eprintln!("{}", HELP_REMOVE);
+                std::process::exit(1);
+            }
+            let ids_to_remove = match id_list {
+                Some(id_list) => read_id_list(&id_list),
+                None => HashSet::new(),
+            };
+            let index_paths = check_and_get_indices(index_path, verbose);
+            if !PathBuf::from(format!("{}.lookup", index_paths[0])).is_file() {
+                print_log_msg(FAIL, &format!("Index not found: {}", index_paths[0]));
+                std::process::exit(1);
+            }
+
+            let mut num_marked = 0usize;
+            for index_prefix in index_paths.iter() {
+                num_marked += mark_deleted(index_prefix, &ids_to_remove, verbose);
+            }
+            if num_marked < ids_to_remove.len() {
+                print_log_msg(WARN, &format!(
+                    "{} of {} IDs were not found in the index", ids_to_remove.len() - num_marked, ids_to_remove.len()
+                ));
+            }
+            if compact {
+                for index_prefix in index_paths.iter() {
+                    compact_index(index_prefix, num_threads, verbose);
+                }
+            }
+            if verbose {
+                print_log_msg(DONE, &format!("Removed {} structures", num_marked));
+            }
+        }
+        _ => {
+            eprintln!("{}", HELP_REMOVE);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn read_id_list(path: &str) -> HashSet<String> {
+    let file = std::fs::File::open(path).unwrap_or_else(|_| panic!(
+        "{}", log_msg(FAIL, &format!("Failed to open ID list: {}", path))
+    ));
+    let reader = std::io::BufReader::new(file);
+    reader.lines().filter_map(|line| {
+        let line = line.unwrap_or_else(|_| panic!("{}", log_msg(FAIL, "Failed to read line")));
+        let id = line.split('\t').next().unwrap_or("").trim().to_string();
+        if id.is_empty() { None } else { Some(id) }
+    }).collect()
+}
+
+// Set tombstone bits of the given IDs. Returns the number of newly marked structures
+fn mark_deleted(index_prefix: &str, ids_to_remove: &HashSet<String>, verbose: bool) -> usize {
+    let lookup_path = format!("{}.lookup", index_prefix);
+    let deleted_path = format!("{}.deleted", index_prefix);
+    let lookup = load_lookup_from_file(&lookup_path);
+    let mut deleted = load_tombstone_from_file(&deleted_path, lookup.len()).unwrap_or(vec![false; lookup.len()]);
+    let mut num_marked = 0usize;
+    for (pos, (id, _, _, _)) in lookup.iter().enumerate() {
+        if ids_to_remove.contains(id) && !deleted[pos] {
+            deleted[pos] = true;
+            num_marked += 1;
+        }
+    }
+    if num_marked > 0 {
+        save_tombstone_to_file(&deleted_path, &deleted);
+        if verbose {
+            print_log_msg(INFO, &format!("Marked {} structures as deleted in {}", num_marked, index_prefix));
+        }
+    }
+    num_marked
+}
+
+// Rewrite the index without structures marked in the tombstone file
+fn compact_index(index_prefix: &str, num_threads: usize, verbose: bool) {
+    let deleted_path = format!("{}.deleted", index_prefix);
+    let (offset_path, value_path, lookup_path, hash_type_path) = get_offset_value_lookup_type(index_prefix.to_string());
+    let lookup = load_lookup_from_file(&lookup_path);
+    let deleted = match load_tombstone_from_file(&deleted_path, lookup.len()) {
+        Some(deleted) => deleted,
+        None => {
+            if verbose { print_log_msg(INFO, &format!("Nothing to compact in {}", index_prefix)); }
+            return;
+        }
+    };
+    let mut config = read_index_config_from_file(&hash_type_path);
+
+    // Old numeric id -> new numeric id. Deleted ids are dropped
+    let mut id_map: Vec<Option<usize>> = Vec::with_capacity(lookup.len());
+    let mut num_kept = 0usize;
+    for &is_deleted in deleted.iter() {
+        if is_deleted {
+            id_map.push(None);
+        } else {
+            id_map.push(Some(num_kept));
+            num_kept += 1;
+        }
+    }
+    if verbose {
+        print_log_msg(INFO, &format!(
+            "Compacting {}: {} of {} structures are kept", index_prefix, num_kept, lookup.len()
+        ));
+    }
+
+    let tmp_path = format!("{}.compact", index_prefix);
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build()
+        .unwrap_or_else(|_| panic!("{}", log_msg(FAIL, "Failed to build thread pool for compaction")));
+    match config.mode {
+        IndexMode::Id => {
+            compact_id_table(
+                (&offset_path, &value_path), (&format!("{}.offset", tmp_path), &format!("{}.value", tmp_path)),
+                config.hash_type, config.value_bits, id_value_bits(num_kept), &id_map, &pool,
+            );
+            // Extra hash sets share the numeric ids of the primary table
+            for (k, (hash_type, _, _)) in config.extra_hash_sets.iter().enumerate() {
+                let (extra_offset_path, extra_value_path) = get_hash_set_offset_value(index_prefix, k + 1);
+                let (tmp_offset_path, tmp_value_path) = get_hash_set_offset_value(&tmp_path, k + 1);
+                compact_id_table(
+                    (&extra_offset_path, &extra_value_path), (&tmp_offset_path, &tmp_value_path),
+                    *hash_type, config.value_bits, id_value_bits(num_kept), &id_map, &pool,
+                );
+            }
+        }
+        IndexMode::Big => {
+            let (big_index, big_offset_mmap) = load_big_index(index_prefix);
+            let compacted = FolddiscoIndex::new(big_index.total_hashes(), tmp_path.clone(), false, config.hash_type);
+            pool.install(|| compacted.count_entries_from_index(&big_index, |id| id_map[id]));
+            compacted.allocate_entries();
+            pool.install(|| compacted.add_entries_from_index(&big_index, |id| id_map[id]));
+            compacted.finish_index();
+            compacted.save_offset_to_file();
+            drop(compacted);
+            drop(big_index);
+            drop(big_offset_mmap);
+        }
+    }
+    drop(pool);
+
+    let mut id_vec = Vec::with_capacity(num_kept);
+    let mut numeric_id_vec = Vec::with_capacity(num_kept);
+    let mut nres_vec = Vec::with_capacity(num_kept);
+    let mut plddt_vec = Vec::with_capacity(num_kept);
+    for ((id, _, nres, plddt), new_id) in lookup.into_iter().zip(id_map.iter()) {
+        if let Some(new_id) = new_id {
+            id_vec.push(id);
+            numeric_id_vec.push(*new_id);
+            nres_vec.push(nres);
+            plddt_vec.push(plddt);
+        }
+    }
+    save_lookup_to_file(
+        &format!("{}.lookup", tmp_path), &id_vec, &numeric_id_vec,
+        Some(&nres_vec), Some(&plddt_vec)
+    );
+    config.chunk_size = num_kept;
+    config.value_bits = id_value_bits(num_kept);
+    let num_extra_hash_sets = config.extra_hash_sets.len();
+    write_index_config_to_file(&format!("{}.type", tmp_path), config);
+    let mut exts = vec!["offset", "value", "lookup", "type"].into_iter().map(String::from).collect::<Vec<_>>();
+    for k in 1..=num_extra_hash_sets {
+        exts.push(format!("hash{}.offset", k));
+        exts.push(format!("hash{}.value", k));
+    }
+    let coords_path = format!("{}.coords", index_prefix);
+    if PathBuf::from(&coords_path).is_file() {
+        let coord_cache = CoordinateCache::open(&coords_path).unwrap_or_else(|_| panic!(
+            "{}", log_msg(FAIL, "Failed to open coordinate cache")
+        ));
+        let mut writer = CoordinateCacheWriter::create(&format!("{}.coords", tmp_path)).unwrap_or_else(|_| panic!(
+            "{}", log_msg(FAIL, "Failed to create coordinate cache")
+        ));
+        coord_cache.copy_to(&mut writer, |id| id_map[id].is_some()).unwrap_or_else(|_| panic!(
+            "{}", log_msg(FAIL, "Failed to copy coordinate cache")
+        ));
+        writer.finish().unwrap_or_else(|_| panic!("{}", log_msg(FAIL, "Failed to write coordinate cache")));
+        exts.push("coords".to_string());
+    }
+    if let Some(locations) = load_locations_from_file(&format!("{}.paths", index_prefix)) {
+        let kept = locations.into_iter().zip(id_map.iter()).filter_map(
+            |(location, new_id)| new_id.map(|_| location)
+        ).collect::<Vec<_>>();
+        save_locations_to_file(&format!("{}.paths", tmp_path), &kept);
+        exts.push("paths".to_string());
+    }
+
+    for ext in exts {
+        std::fs::rename(format!("{}.{}", tmp_path, ext), format!("{}.{}", index_prefix, ext)).unwrap_or_else(|_| panic!(
+            "{}", log_msg(FAIL, &format!("Failed to replace {}.{}", index_prefix, ext))
+        ));
+    }
+    std::fs::remove_file(&deleted_path).unwrap_or_else(|_| panic!(
+        "{}", log_msg(FAIL, &format!("Failed to remove {}", deleted_path))
+    ));
+    refresh_binary_lookup(index_prefix);
+}
+
+// Rewrite an id mode table with the numeric ids mapped by id_map
+fn compact_id_table(
+    (offset_path, value_path): (&str, &str), (out_offset_path, out_value_path): (&str, &str),
+    hash_type: HashType, value_bits: usize, out_value_bits: usize, id_map: &[Option<usize>], pool: &rayon::ThreadPool,
+) {
+    let (offset_table, offset_mmap) = SimpleHashMap::load_from_disk(&PathBuf::from(offset_path));
+    let offset_table = offset_table.unwrap_or_else(|_| panic!(
+        "{}", log_msg(FAIL, "Failed to load offset table")
+    ));
+    let (value_mmap, value_vec) = read_id_values(value_path, value_bits).unwrap_or_else(|_| panic!(
+        "{}", log_msg(FAIL, "Failed to load values")
+    ));
+    let mut hash_id_vec = convert_simplemap_to_hash_vec(&offset_table, value_vec, |id| id_map[id]);
+    drop(offset_table);
+    drop(offset_mmap);
+    drop(value_mmap);
+    pool.install(|| hash_id_vec.par_sort_unstable_by(|a, b| a.0.cmp(&b.0)));
+    let (offset_map, value_vec) = convert_sorted_hash_vec_to_simplemap(hash_id_vec);
+    offset_map.dump_to_disk(&PathBuf::from(out_offset_path), hash_type).unwrap_or_else(|_| panic!(
+        "{}", log_msg(FAIL, "Failed to save offset table")
+    ));
+    write_id_values(out_value_path, &value_vec, out_value_bits, hash_type).unwrap_or_else(|_| panic!(
+        "{}", log_msg(FAIL, "Failed to save values")
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::workflows::build_index::build_index;
+    use crate::cli::workflows::test_index_args;
+    use crate::index::lookup::{convert_lookup_to_binary, load_lookup_table, LookupTable};
+
+    fn build_test_index(index_path: &str) {
+        build_index(test_index_args("data/homeobox", index_path, "pdbtr"));
+    }
+
+    #[test]
+    fn test_remove_and_compact() {
+        let index_path = "data/remove_test";
+        let list_path = "data/remove_test.txt";
+        build_test_index(index_path);
+        let lookup = load_lookup_from_file(&format!("{}.lookup", index_path));
+        assert_eq!(lookup.len(), 4);
+        let removed = lookup[1].0.clone();
+        std::fs::write(list_path, format!("{}\nnot_in_index\n", removed)).unwrap();
+
+        let (offset_table, _offset_mmap) = SimpleHashMap::load_from_disk(&PathBuf::from(format!("{}.offset", index_path)));
+        let (_value_mmap, value_vec) = read_id_values(&format!("{}.value", index_path), 16).unwrap();
+        let original = convert_simplemap_to_hash_vec(&offset_table.unwrap(), value_vec, Some);
+        convert_lookup_to_binary(index_path);
+
+        // Tombstone only
+        remove(AppArgs::Remove {
+            index_path: Some(index_path.to_string()),
+            id_list: Some(list_path.to_string()),
+            compact: false, num_threads: 1, verbose: false, help: false,
+        });
+        let deleted = load_tombstone_from_file(&format!("{}.deleted", index_path), 4).unwrap();
+        assert_eq!(deleted, vec![false, true, false, false]);
+        assert_eq!(load_lookup_from_file(&format!("{}.lookup", index_path)).len(), 4);
+
+        // Compaction of previously removed structures
+        remove(AppArgs::Remove {
+            index_path: Some(index_path.to_string()),
+            id_list: None,
+            compact: true, num_threads: 2, verbose: false, help: false,
+        });
+        assert!(!PathBuf::from(format!("{}.deleted", index_path)).exists());
+        let compacted_lookup = load_lookup_from_file(&format!("{}.lookup", index_path));
+        assert_eq!(compacted_lookup.len(), 3);
+        assert!(compacted_lookup.iter().all(|x| x.0 != removed));
+        for (i, entry) in compacted_lookup.iter().enumerate() {
+            assert_eq!(entry.1, i);
+        }
+        // Binary lookup follows the compacted lookup
+        let binary_lookup = load_lookup_table(index_path);
+        assert!(matches!(binary_lookup, LookupTable::Binary(_)));
+        assert!(binary_lookup.iter().eq(LookupTable::Text(compacted_lookup).iter()));
+        let (offset_table, _offset_mmap) = SimpleHashMap::load_from_disk(&PathBuf::from(format!("{}.offset", index_path)));
+        let (_value_mmap, value_vec) = read_id_values(&format!("{}.value", index_path), 16).unwrap();
+        let mut compacted = convert_simplemap_to_hash_vec(&offset_table.unwrap(), value_vec, Some);
+        let mut expected = original.into_iter().filter_map(|(hash, id)| match id {
+            0 => Some((hash, 0)),
+            1 => None,
+            _ => Some((hash, id - 1)),
+        }).collect::<Vec<_>>();
+        compacted.sort();
+        expected.sort();
+        assert_eq!(compacted, expected);
+
+        std::fs::remove_file(list_path).unwrap();
+        for ext in ["offset", "value", "lookup", "blookup", "type"] {
+            std::fs::remove_file(format!("{}.{}", index_path, ext)).unwrap();
+        }
+    }
+}
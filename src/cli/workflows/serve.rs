@@ -0,0 +1,682 @@
+//! Workflow for answering queries from a process that keeps indices loaded.
+//! Offset tables, values, lookups and coordinate caches are mapped once at
+//! start-up. Requests are HTTP/1.1 over TCP or a Unix socket with a JSON body
+//! and are handled concurrently on the rayon pool of the server.
+//!
+//! Routes:
+//!   GET  /health   {"status": "ok"}
+//!   GET  /indices  Served indices with their number of structures and hash type
+//!   POST /query    Query with the options of `folddisco query` as a JSON object
+
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::cli::*;
+use crate::controller::search::{Index, Query, QueryBuilder, QueryOptions};
+use crate::json_object;
+use crate::prelude::*;
+use crate::utils::json::{parse_json, JsonValue};
+
+pub const HELP_SERVE: &str = "\
+usage: folddisco serve -i <i:INDEX_PATH>[,<i:INDEX_PATH>...] [--port <PORT> | --socket <PATH>] [OPTIONS]
+
+input:
+ -i, --index <PATH>               Indices to serve, separated by comma. Chunks of an index are served together
+ --structure-root <PATH>          Read structures from this directory or archive instead of the indexed one
+ --foldcomp-db <PATH>             Read structures from this Foldcomp DB instead of the indexed one
+ --query-dir <PATH>               Directory of query structures that requests can name with pdb.
+                                  Without it, query structures are only accepted as pdb_content
+
+server:
+ --host <STR>                     Address to listen on [127.0.0.1]
+ --port <INT>                     TCP port to listen on [8080]
+ --socket <PATH>                  Listen on a Unix socket instead of TCP
+
+options:
+ -t, --threads <INT>              Number of threads for handling requests [4]
+ -v, --verbose                    Print a line per request
+ -h, --help                       Print this help menu
+
+requests:
+ GET  /health                     {\"status\": \"ok\"}
+ GET  /indices                    Served indices
+ POST /query                      JSON object with the query. Response is a JSON object with the hits
+   pdb / pdb_content              Path of the query structure under --query-dir, or its PDB/mmCIF text
+   query                          Query residues as in `folddisco query -q` [whole structure]
+   index                          Index to search. Required when serving more than one index
+   distance, angle, ca_distance, sampling_count, sampling_ratio, freq_filter, length_penalty,
+   skip_match, serial_index, total_match, covered_node, covered_node_ratio, covered_edge,
+   covered_edge_ratio, max_node, max_node_ratio, score, connected_node, connected_node_ratio,
+   num_residue, plddt, rmsd, top, per_structure, sort_by_score, skip_ca_match, superposition
+                                  Same as the options of `folddisco query`
+
+examples:
+folddisco serve -i index/h_sapiens_folddisco -t 8 --port 8080 --query-dir query
+curl -s localhost:8080/query -d '{\"pdb\": \"4CHA.pdb\", \"query\": \"B57,B102,C195\", \"top\": 10}'
+
+folddisco serve -i index/h_sapiens_folddisco,index/e_coli_folddisco --socket /tmp/folddisco.sock --query-dir query
+curl -s --unix-socket /tmp/folddisco.sock localhost/query \\
+  -d '{\"index\": \"index/e_coli_folddisco\", \"pdb\": \"4CHA.pdb\", \"query\": \"B57,B102,C195\"}'
+";
+
+// Larger requests are rejected with 413. Query structures given inline are the largest
+// bodies, and a query structure is far smaller than this even as mmCIF
+const MAX_BODY_SIZE: usize = 8 * 1024 * 1024;
+// Time to wait for a client to send its request or take the response.
+// A stalled client would hold one of the threads otherwise
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+// Connections waiting or being handled per thread. More are answered with 503
+// right away instead of queueing behind slow clients without a bound
+const MAX_CONNECTIONS_PER_THREAD: usize = 8;
+
+pub fn serve(env: AppArgs) {
+    match env {
+        AppArgs::Serve {
+            index_path,
+            host,
+            port,
+            socket,
+            structure_root,
+            foldcomp_db,
+            query_dir,
+            threads,
+            verbose,
+            help: _,
+        } => {
+            if index_path.is_none() {
+                eprintln!("{}", HELP_SERVE);
+                std::process::exit(1);
+            }
+            let index_names = index_path.unwrap().split(',').map(|x| x.trim().to_string())
+                .filter(|x| !x.is_empty()).collect::<Vec<_>>();
+            let indices = index_names.iter().map(|name| {
+                let index = Index::open_with_locations(name, structure_root.as_deref(), foldcomp_db.as_deref())
+                    .unwrap_or_else(|e| {
+                        print_log_msg(FAIL, &e);
+                        std::process::exit(1);
+                    });
+                if verbose {
+                    print_log_msg(INFO, &format!(
+                        "Loaded {} ({} chunk(s), {} structures)", name, index.num_chunks(), index.num_structures()
+                    ));
+                }
+                index
+            }).collect::<Vec<_>>();
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap_or_else(|_| panic!(
+                "{}", log_msg(FAIL, "Failed to build thread pool for serving")
+            ));
+            let state = Arc::new(ServerState::new(
+                indices, query_dir.map(PathBuf::from), pool.current_num_threads() * MAX_CONNECTIONS_PER_THREAD, verbose));
+
+            match socket {
+                Some(socket_path) => serve_unix_socket(&socket_path, state, &pool),
+                None => {
+                    let address = format!("{}:{}", host, port);
+                    let listener = std::net::TcpListener::bind(&address).unwrap_or_else(|_| panic!(
+                        "{}", log_msg(FAIL, &format!("Failed to listen on {}", address))
+                    ));
+                    print_log_msg(INFO, &format!("Serving {} index(es) on http://{}", index_names.len(), address));
+                    for stream in listener.incoming() {
+                        spawn_connection(stream, &state, &pool);
+                    }
+                }
+            }
+        }
+        _ => {
+            eprintln!("{}", HELP_SERVE);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn serve_unix_socket(socket_path: &str, state: Arc<ServerState>, pool: &rayon::ThreadPool) {
+    use std::os::unix::fs::FileTypeExt;
+    // Socket left behind by a previous server
+    if let Ok(metadata) = std::fs::metadata(socket_path) {
+        if !metadata.file_type().is_socket() {
+            print_log_msg(FAIL, &format!("{} exists and is not a socket", socket_path));
+            std::process::exit(1);
+        }
+        let _ = std::fs::remove_file(socket_path);
+    }
+    let listener = std::os::unix::net::UnixListener::bind(socket_path).unwrap_or_else(|_| panic!(
+        "{}", log_msg(FAIL, &format!("Failed to listen on {}", socket_path))
+    ));
+    print_log_msg(INFO, &format!("Serving {} index(es) on {}", state.indices.len(), socket_path));
+    for stream in listener.incoming() {
+        spawn_connection(stream, &state, pool);
+    }
+}
+
+#[cfg(not(unix))]
+fn serve_unix_socket(_socket_path: &str, _state: Arc<ServerState>, _pool: &rayon::ThreadPool) {
+    print_log_msg(FAIL, "Unix sockets are not supported on this platform. Use --port");
+    std::process::exit(1);
+}
+
+// Accepted connections whose reads and writes time out
+trait TimeoutStream: Read + Write + Send + 'static {
+    fn set_timeouts(&self, timeout: Duration) -> std::io::Result<()>;
+}
+
+impl TimeoutStream for TcpStream {
+    fn set_timeouts(&self, timeout: Duration) -> std::io::Result<()> {
+        self.set_read_timeout(Some(timeout))?;
+        self.set_write_timeout(Some(timeout))
+    }
+}
+
+#[cfg(unix)]
+impl TimeoutStream for std::os::unix::net::UnixStream {
+    fn set_timeouts(&self, timeout: Duration) -> std::io::Result<()> {
+        self.set_read_timeout(Some(timeout))?;
+        self.set_write_timeout(Some(timeout))
+    }
+}
+
+fn spawn_connection<S: TimeoutStream>(stream: std::io::Result<S>, state: &Arc<ServerState>, pool: &rayon::ThreadPool) {
+    let stream = match stream.and_then(|stream| stream.set_timeouts(IO_TIMEOUT).map(|_| stream)) {
+        Ok(stream) => stream,
+        Err(e) => {
+            print_log_msg(WARN, &format!("Failed to accept connection: {}", e));
+            return;
+        }
+    };
+    match ConnectionSlot::acquire(state) {
+        Some(slot) => pool.spawn(move || handle_connection(stream, &slot.0)),
+        None => {
+            let mut stream = stream;
+            write_http_response(&mut stream, 503, &error_json("Server is busy. Try again later"));
+            if state.verbose {
+                print_log_msg(WARN, "Rejected a connection with 503: too many connections");
+            }
+        }
+    }
+}
+
+struct ServerState {
+    indices: Vec<Index>,
+    // Directory that paths given with pdb are resolved in. pdb is refused without it
+    query_dir: Option<PathBuf>,
+    num_requests: AtomicUsize,
+    // Accepted connections that are not closed yet
+    num_connections: AtomicUsize,
+    max_connections: usize,
+    verbose: bool,
+}
+
+impl ServerState {
+    fn new(indices: Vec<Index>, query_dir: Option<PathBuf>, max_connections: usize, verbose: bool) -> Self {
+        ServerState {
+            indices,
+            query_dir,
+            num_requests: AtomicUsize::new(0),
+            num_connections: AtomicUsize::new(0),
+            max_connections,
+            verbose,
+        }
+    }
+}
+
+// Place of an accepted connection among max_connections, released when the connection is closed
+struct ConnectionSlot(Arc<ServerState>);
+
+impl ConnectionSlot {
+    fn acquire(state: &Arc<ServerState>) -> Option<Self> {
+        if state.num_connections.fetch_add(1, Ordering::AcqRel) >= state.max_connections {
+            state.num_connections.fetch_sub(1, Ordering::AcqRel);
+            return None;
+        }
+        Some(ConnectionSlot(state.clone()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.num_connections.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+fn index_to_json(index: &Index) -> JsonValue {
+    let config = index.config();
+    json_object!{
+        "name" => index.name(),
+        "num_chunks" => index.num_chunks(),
+        "num_structures" => index.num_structures(),
+        "mode" => config.mode.to_string(),
+        "hash_type" => config.hash_type.to_string(),
+        "extra_hash_types" => config.extra_hash_sets.iter().map(|(hash_type, _, _)| hash_type.to_string()).collect::<Vec<_>>(),
+    }
+}
+
+// Query request. Options have the defaults of `folddisco query`
+struct QueryRequest {
+    index: Option<String>,
+    pdb_path: Option<String>,
+    pdb_content: Option<String>,
+    query_string: String,
+    per_structure: bool,
+    superposition: bool,
+    options: QueryOptions,
+}
+
+impl QueryRequest {
+    fn from_json(value: &JsonValue) -> Result<Self, String> {
+        if !matches!(value, JsonValue::Object(_)) {
+            return Err("Request body should be a JSON object".to_string());
+        }
+        let string = |key: &str| -> Result<Option<String>, String> {
+            match value.get(key) {
+                None | Some(JsonValue::Null) => Ok(None),
+                Some(JsonValue::String(s)) => Ok(Some(s.clone())),
+                Some(JsonValue::Number(n)) => Ok(Some(n.to_string())),
+                Some(_) => Err(format!("{} should be a string", key)),
+            }
+        };
+        let number = |key: &str| -> Result<Option<f64>, String> {
+            match value.get(key) {
+                None | Some(JsonValue::Null) => Ok(None),
+                Some(JsonValue::Number(n)) => Ok(Some(*n)),
+                Some(_) => Err(format!("{} should be a number", key)),
+            }
+        };
+        let count = |key: &str| -> Result<Option<usize>, String> {
+            match number(key)? {
+                Some(n) if n < 0.0 || n.fract() != 0.0 => Err(format!("{} should be a non-negative integer", key)),
+                n => Ok(n.map(|n| n as usize)),
+            }
+        };
+        let flag = |key: &str| -> Result<bool, String> {
+            match value.get(key) {
+                None | Some(JsonValue::Null) => Ok(false),
+                Some(JsonValue::Bool(b)) => Ok(*b),
+                Some(_) => Err(format!("{} should be true or false", key)),
+            }
+        };
+        let request = QueryRequest {
+            index: string("index")?,
+            pdb_path: string("pdb")?,
+            pdb_content: string("pdb_content")?,
+            query_string: string("query")?.unwrap_or_default(),
+            per_structure: flag("per_structure")?,
+            superposition: flag("superposition")?,
+            options: QueryOptions {
+                dist_thresholds: parse_thresholds("distance", string("distance")?)?,
+                angle_thresholds: parse_thresholds("angle", string("angle")?)?,
+                ca_dist_threshold: number("ca_distance")?.unwrap_or(1.5) as f32,
+                sampling_count: count("sampling_count")?,
+                sampling_ratio: number("sampling_ratio")?.map(|x| x as f32),
+                freq_filter: number("freq_filter")?.map(|x| x as f32),
+                length_penalty: number("length_penalty")?.map(|x| x as f32),
+                skip_match: flag("skip_match")?,
+                serial_query: flag("serial_index")?,
+                total_match_count: count("total_match")?.unwrap_or(0),
+                covered_node_count: count("covered_node")?.unwrap_or(0),
+                covered_node_ratio: number("covered_node_ratio")?.unwrap_or(0.0) as f32,
+                covered_edge_count: count("covered_edge")?.unwrap_or(0),
+                covered_edge_ratio: number("covered_edge_ratio")?.unwrap_or(0.0) as f32,
+                max_matching_node_count: count("max_node")?.unwrap_or(0),
+                max_matching_node_ratio: number("max_node_ratio")?.unwrap_or(0.0) as f32,
+                idf_score_cutoff: number("score")?.unwrap_or(0.0) as f32,
+                connected_node_count: count("connected_node")?.unwrap_or(0),
+                connected_node_ratio: number("connected_node_ratio")?.unwrap_or(0.0) as f32,
+                num_res_cutoff: count("num_residue")?.unwrap_or(50000),
+                plddt_cutoff: number("plddt")?.unwrap_or(0.0) as f32,
+                rmsd_cutoff: number("rmsd")?.unwrap_or(0.0) as f32,
+                top_n: count("top")?.unwrap_or(usize::MAX),
+                sort_by_score: flag("sort_by_score")?,
+                skip_ca_match: flag("skip_ca_match")?,
+                ..QueryOptions::default()
+            },
+        };
+        if request.pdb_path.is_some() == request.pdb_content.is_some() {
+            return Err("Give either pdb or pdb_content".to_string());
+        }
+        Ok(request)
+    }
+}
+
+// Thresholds as a comma separated string (e.g. "0.5,1.0") or a single number
+fn parse_thresholds(key: &str, thresholds: Option<String>) -> Result<Vec<f32>, String> {
+    match thresholds {
+        None => Ok(Vec::new()),
+        Some(thresholds) => thresholds.split(',').map(|x| {
+            x.trim().parse::<f32>().map_err(|_| format!("{} should be numbers separated by comma", key))
+        }).collect(),
+    }
+}
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+// Errors are returned with the status of the response
+fn read_http_request<S: Read>(stream: &mut S) -> Result<HttpRequest, (u16, String)> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).map_err(read_error)?;
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => return Err((400, "Malformed request line".to_string())),
+    };
+    let mut content_length = 0usize;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).map_err(read_error)? == 0 {
+            break;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().map_err(|_| (400, "Invalid Content-Length".to_string()))?;
+            }
+        }
+    }
+    if content_length > MAX_BODY_SIZE {
+        return Err((413, format!("Request body is larger than {} bytes", MAX_BODY_SIZE)));
+    }
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body).map_err(read_error)?;
+    // Query strings are ignored
+    let path = path.split('?').next().unwrap_or("").to_string();
+    Ok(HttpRequest { method, path, body })
+}
+
+// Reads that hit the timeout of the stream fail with WouldBlock or TimedOut depending on the platform
+fn read_error(e: std::io::Error) -> (u16, String) {
+    match e.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => (408, "Timed out while reading the request".to_string()),
+        _ => (400, e.to_string()),
+    }
+}
+
+fn write_http_response<S: Write>(stream: &mut S, status: u16, body: &JsonValue) {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Payload Too Large",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    };
+    let body = format!("{}\n", body);
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, reason, body.len(), body
+    );
+    // Clients that went away are not an error of the server
+    let _ = stream.write_all(response.as_bytes()).and_then(|_| stream.flush());
+}
+
+fn error_json(message: &str) -> JsonValue {
+    json_object!{ "error" => message }
+}
+
+fn handle_connection<S: Read + Write>(mut stream: S, state: &ServerState) {
+    let start = Instant::now();
+    let request = match read_http_request(&mut stream) {
+        Ok(request) => request,
+        Err((status, e)) => {
+            write_http_response(&mut stream, status, &error_json(&e));
+            if state.verbose {
+                print_log_msg(WARN, &format!("Rejected a request with {}: {}", status, e));
+            }
+            return;
+        }
+    };
+    let request_id = state.num_requests.fetch_add(1, Ordering::Relaxed);
+    // A panic while reading a structure fails the request, not the server
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| route(&request, state)));
+    let (status, body) = result.unwrap_or_else(|_| (500, error_json("Query failed. See the server log")));
+    write_http_response(&mut stream, status, &body);
+    if state.verbose {
+        print_log_msg(INFO, &format!(
+            "#{} {} {} {} ({:.3} s)", request_id, request.method, request.path, status, start.elapsed().as_secs_f32()
+        ));
+    }
+}
+
+fn route(request: &HttpRequest, state: &ServerState) -> (u16, JsonValue) {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/health") => (200, json_object!{ "status" => "ok" }),
+        ("GET", "/indices") => (200, JsonValue::Array(state.indices.iter().map(index_to_json).collect())),
+        ("POST", "/query") => {
+            let body = match std::str::from_utf8(&request.body) {
+                Ok(body) => body,
+                Err(_) => return (400, error_json("Request body is not UTF-8")),
+            };
+            let mut query = match parse_json(body).and_then(|value| QueryRequest::from_json(&value)) {
+                Ok(query) => query,
+                Err(e) => return (400, error_json(&e)),
+            };
+            if let Some(pdb_path) = &query.pdb_path {
+                match resolve_query_path(state.query_dir.as_deref(), pdb_path) {
+                    Ok(path) => query.pdb_path = Some(path),
+                    Err(e) => return (400, error_json(&e)),
+                }
+            }
+            let index = match &query.index {
+                Some(name) => state.indices.iter().find(|index| index.name() == name),
+                None if state.indices.len() == 1 => state.indices.first(),
+                None => return (400, error_json("Give the index to search. More than one index is served")),
+            };
+            match index {
+                Some(index) => answer_query(index, &query),
+                None => (404, error_json(&format!("Index not served: {}", query.index.as_deref().unwrap_or("")))),
+            }
+        }
+        (_, "/health") | (_, "/indices") | (_, "/query") => (405, error_json("Method not allowed")),
+        _ => (404, error_json("Not found")),
+    }
+}
+
+// Path of a query structure named with pdb. Only relative paths that stay under the
+// query directory are accepted so that clients can't read other files of the server
+fn resolve_query_path(query_dir: Option<&Path>, pdb_path: &str) -> Result<String, String> {
+    let query_dir = query_dir.ok_or_else(|| {
+        "pdb is not accepted by this server. Give the structure as pdb_content".to_string()
+    })?;
+    let path = Path::new(pdb_path);
+    if pdb_path.is_empty() || !path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir)) {
+        return Err(format!("pdb must be a relative path under the query directory without '..': {}", pdb_path));
+    }
+    Ok(query_dir.join(path).to_string_lossy().into_owned())
+}
+
+// Query structures given inline are written to a temporary file for the readers
+static NUM_TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+fn answer_query(index: &Index, request: &QueryRequest) -> (u16, JsonValue) {
+    let (pdb_path, temp_path) = match (&request.pdb_path, &request.pdb_content) {
+        (Some(pdb_path), _) => (pdb_path.clone(), None),
+        (None, Some(content)) => {
+            let extension = if content.trim_start().starts_with("data_") { "cif" } else { "pdb" };
+            let temp_path = std::env::temp_dir().join(format!(
+                "folddisco_serve_{}_{}.{}", std::process::id(), NUM_TEMP_FILES.fetch_add(1, Ordering::Relaxed), extension
+            )).to_string_lossy().into_owned();
+            if let Err(e) = std::fs::write(&temp_path, content) {
+                return (500, error_json(&format!("Failed to write query structure: {}", e)));
+            }
+            (temp_path.clone(), Some(temp_path))
+        }
+        (None, None) => unreachable!(),
+    };
+    // The query structure is read again while searching, so the temporary file is kept until then
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        QueryBuilder::new(&pdb_path).residues(&request.query_string).options(request.options.clone()).build()
+            .map(|query| search(index, &query, request.per_structure, request.superposition))
+    }));
+    if let Some(temp_path) = temp_path {
+        let _ = std::fs::remove_file(temp_path);
+    }
+    match result {
+        Ok(Ok(response)) => (200, response),
+        Ok(Err(e)) => (400, error_json(&e)),
+        Err(_) => (400, error_json("Failed to read the query structure")),
+    }
+}
+
+fn search(index: &Index, query: &Query, per_structure: bool, superposition: bool) -> JsonValue {
+    let query_residues = query.residue_labels();
+    let per_structure = per_structure || query.options().skip_match;
+    let hits = if per_structure {
+        index.search_structures(query).iter().map(|(_, v)| v.to_json(query_residues, superposition)).collect::<Vec<_>>()
+    } else {
+        index.search_matches(query).iter().map(|(_, v)| v.to_json(query_residues, superposition)).collect::<Vec<_>>()
+    };
+    json_object!{
+        "index" => index.name(),
+        "query" => query_residues.join(","),
+        "per_structure" => per_structure,
+        "num_hits" => hits.len(),
+        "hits" => JsonValue::Array(hits),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::cli::workflows::build_index::build_index;
+    use crate::cli::workflows::test_index_args;
+
+    // Request and response bytes of a connection
+    struct TestStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+    impl Read for TestStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> { self.input.read(buf) }
+    }
+    impl Write for TestStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> { self.output.write(buf) }
+        fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+
+    fn send(state: &ServerState, method: &str, path: &str, body: &str) -> (u16, JsonValue) {
+        let request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}", method, path, body.len(), body);
+        let mut stream = TestStream { input: Cursor::new(request.into_bytes()), output: Vec::new() };
+        handle_connection(&mut stream, state);
+        let response = String::from_utf8(stream.output).unwrap();
+        let status = response.split_whitespace().nth(1).unwrap().parse().unwrap();
+        let body = response.split_once("\r\n\r\n").unwrap().1;
+        (status, parse_json(body.trim()).unwrap())
+    }
+
+    #[test]
+    fn test_serve_queries() {
+        let index_path = "data/serve_test";
+        build_index(test_index_args("data/serine_peptidases_filtered", index_path, "pdbtr"));
+        let state = ServerState::new(vec![Index::open(index_path).unwrap()], Some(PathBuf::from("query")), 1, false);
+        assert_eq!(send(&state, "GET", "/health", "").0, 200);
+        let (status, indices) = send(&state, "GET", "/indices", "");
+        assert_eq!(status, 200);
+        assert_eq!(indices, JsonValue::Array(vec![index_to_json(&state.indices[0])]));
+
+        let (status, response) = send(&state, "POST", "/query", r#"{"pdb": "4CHA.pdb", "query": "B57,B102,C195", "top": 5}"#);
+        assert_eq!(status, 200);
+        assert_eq!(response.get("query").and_then(|x| x.as_str()), Some("B57,B102,C195"));
+        let hits = match response.get("hits") { Some(JsonValue::Array(hits)) => hits.clone(), _ => panic!() };
+        assert!(!hits.is_empty() && hits.len() <= 5);
+        assert_eq!(hits[0].get("id").and_then(|x| x.as_str()), Some("data/serine_peptidases_filtered/4cha.pdb"));
+        assert_eq!(hits[0].get("rmsd").and_then(|x| x.as_f64()), Some(0.0));
+        let first_pair = match hits[0].get("residues") { Some(JsonValue::Array(pairs)) => pairs[0].clone(), _ => panic!() };
+        assert_eq!(first_pair.get("query").and_then(|x| x.as_str()), Some("B57"));
+        assert_eq!(first_pair.get("target").and_then(|x| x.as_str()), Some("B57"));
+
+        // Same hits with the structure given inline
+        let content = std::fs::read_to_string("query/4CHA.pdb").unwrap();
+        let body = json_object!{"pdb_content" => content, "query" => "B57,B102,C195", "top" => 5usize}.to_string();
+        let (status, inline_response) = send(&state, "POST", "/query", &body);
+        assert_eq!(status, 200);
+        assert_eq!(inline_response.get("hits"), response.get("hits"));
+
+        let (status, response) = send(&state, "POST", "/query", r#"{"pdb": "4CHA.pdb", "query": "B57,B102,C195", "per_structure": true, "skip_match": true}"#);
+        assert_eq!(status, 200);
+        assert!(response.get("num_hits").and_then(|x| x.as_f64()).unwrap() >= 1.0);
+
+        assert_eq!(send(&state, "POST", "/query", r#"{"pdb": "missing.pdb"}"#).0, 400);
+        // Paths outside the query directory are refused before they are opened
+        for pdb_path in ["../query/4CHA.pdb", "sub/../../Cargo.toml", "/etc/hostname", ""] {
+            let body = json_object!{"pdb" => pdb_path}.to_string();
+            let (status, response) = send(&state, "POST", "/query", &body);
+            assert_eq!(status, 400);
+            assert!(response.get("error").and_then(|x| x.as_str()).unwrap().starts_with("pdb must be a relative path"));
+        }
+        assert_eq!(send(&state, "POST", "/query", r#"{"pdb": "4CHA.pdb", "index": "other"}"#).0, 404);
+        assert_eq!(send(&state, "POST", "/query", "not json").0, 400);
+        assert_eq!(send(&state, "POST", "/query", &"[".repeat(1 << 16)).0, 400);
+        let request = format!("POST /query HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY_SIZE + 1);
+        let mut stream = TestStream { input: Cursor::new(request.into_bytes()), output: Vec::new() };
+        handle_connection(&mut stream, &state);
+        assert!(String::from_utf8(stream.output).unwrap().starts_with("HTTP/1.1 413 Payload Too Large"));
+        assert_eq!(send(&state, "GET", "/query", "").0, 405);
+        assert_eq!(send(&state, "GET", "/other", "").0, 404);
+        assert_eq!(state.num_requests.load(Ordering::Relaxed), 15);
+
+        // Without a query directory only pdb_content is accepted
+        let state = ServerState::new(state.indices, None, 1, false);
+        let (status, response) = send(&state, "POST", "/query", r#"{"pdb": "4CHA.pdb"}"#);
+        assert_eq!(status, 400);
+        assert!(response.get("error").and_then(|x| x.as_str()).unwrap().starts_with("pdb is not accepted"));
+        assert_eq!(send(&state, "POST", "/query", &body).0, 200);
+
+        drop(state);
+        for ext in ["offset", "value", "lookup", "type"] {
+            std::fs::remove_file(format!("{}.{}", index_path, ext)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_request_timeout() {
+        let state = ServerState::new(Vec::new(), None, 1, false);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        // Client stops in the middle of the body
+        client.write_all(b"POST /query HTTP/1.1\r\nContent-Length: 100\r\n\r\n{").unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_timeouts(Duration::from_millis(100)).unwrap();
+        handle_connection(stream, &state);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout"));
+        assert_eq!(state.num_requests.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_too_many_connections() {
+        let state = Arc::new(ServerState::new(Vec::new(), None, 1, false));
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        // The only slot is taken by a connection that is still open
+        let slot = ConnectionSlot::acquire(&state).unwrap();
+        assert!(ConnectionSlot::acquire(&state).is_none());
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        spawn_connection(listener.accept().map(|(stream, _)| stream), &state, &pool);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable"));
+        // Closing the connection frees its slot
+        drop(slot);
+        assert_eq!(state.num_connections.load(Ordering::Relaxed), 0);
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(b"GET /health HTTP/1.1\r\n\r\n").unwrap();
+        spawn_connection(listener.accept().map(|(stream, _)| stream), &state, &pool);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+    }
+}
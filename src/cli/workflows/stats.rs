@@ -0,0 +1,428 @@
+//! Workflow for summarizing the contents of an index.
+//! Reports structure and posting counts, the distribution of posting lengths,
+//! the most frequent hashes decoded into features, and nres/pLDDT
+//! distributions from the lookup. Chunked indices are summarized together.
+//! Structures marked as deleted are left out of everything but the counts of
+//! structures.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::cli::*;
+use crate::cli::config::{read_index_config_from_file, IndexConfig};
+use crate::controller::io::read_id_values;
+use crate::controller::map::SimpleHashMap;
+use crate::controller::mode::IndexMode;
+use crate::controller::query::check_and_get_indices;
+use crate::index::indextable::load_big_index;
+use crate::index::tombstone::{is_deleted, load_tombstone_from_file};
+use crate::prelude::*;
+use crate::utils::convert::map_u8_to_aa;
+
+pub const HELP_STATS: &str = "\
+usage: folddisco stats -i <i:INDEX_PATH> [OPTIONS]
+
+input/output:
+ -i, --index <PATH>               Path of the index table
+ -o, --output <PATH>              Output file path [stdout]
+
+options:
+ --top <INT>                      Number of most frequent hashes to report [20]
+ -v, --verbose                    Print verbose messages
+ -h, --help                       Print this help menu
+
+examples:
+folddisco stats -i index/h_sapiens_folddisco
+folddisco stats -i index/afdb --top 100 -o afdb_stats.txt
+";
+
+pub fn stats(env: AppArgs) {
+    match env {
+        AppArgs::Stats {
+            index_path,
+            top_n,
+            output,
+            verbose,
+            help: _,
+        } => {
+            if index_path.is_none() {
+                eprintln!("{}", HELP_STATS);
+                std::process::exit(1);
+            }
+            let index_paths = check_and_get_indices(index_path, verbose);
+            for index_prefix in index_paths.iter() {
+                if !PathBuf::from(format!("{}.type", index_prefix)).is_file() {
+                    print_log_msg(FAIL, &format!("Index not found: {}", index_prefix));
+                    std::process::exit(1);
+                }
+            }
+            // Hashes of all chunks are decoded with the config of the first one
+            let config = read_index_config_from_file(&format!("{}.type", index_paths[0]));
+            for index_prefix in index_paths.iter().skip(1) {
+                if !config.is_compatible_with(&read_index_config_from_file(&format!("{}.type", index_prefix))) {
+                    print_log_msg(FAIL, &format!(
+                        "{} was built with different parameters from {}", index_prefix, index_paths[0]
+                    ));
+                    std::process::exit(1);
+                }
+            }
+            let index_stats = collect_index_stats(&index_paths, &config, top_n, verbose);
+
+            let mut writer: Box<dyn Write> = match output {
+                Some(output) => Box::new(std::io::BufWriter::new(std::fs::File::create(&output).unwrap_or_else(|_| panic!(
+                    "{}", log_msg(FAIL, &format!("Failed to create output file: {}", output))
+                )))),
+                None => Box::new(std::io::BufWriter::new(std::io::stdout())),
+            };
+            write_index_stats(&mut writer, &index_stats, &config).unwrap_or_else(|_| panic!(
+                "{}", log_msg(FAIL, "Failed to write index statistics")
+            ));
+        }
+        _ => {
+            eprintln!("{}", HELP_STATS);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct IndexStats {
+    pub num_chunks: usize,
+    pub num_structures: usize,
+    pub num_deleted: usize,
+    pub num_distinct_hashes: usize,
+    pub total_postings: usize,
+    pub max_posting_length: usize,
+    // histogram[i] counts hashes with posting length in [2^i, 2^(i+1))
+    pub histogram: Vec<usize>,
+    // (hash, posting length), most frequent first
+    pub top_hashes: Vec<(u32, usize)>,
+    pub nres: Vec<usize>,
+    pub plddt: Vec<f32>,
+}
+
+impl IndexStats {
+    fn add_posting(&mut self, posting_length: usize) {
+        if posting_length == 0 {
+            return;
+        }
+        self.num_distinct_hashes += 1;
+        self.total_postings += posting_length;
+        self.max_posting_length = self.max_posting_length.max(posting_length);
+        let bucket = posting_length.ilog2() as usize;
+        if self.histogram.len() <= bucket {
+            self.histogram.resize(bucket + 1, 0);
+        }
+        self.histogram[bucket] += 1;
+    }
+}
+
+pub fn collect_index_stats(index_paths: &[String], config: &IndexConfig, top_n: usize, verbose: bool) -> IndexStats {
+    let mut index_stats = IndexStats { num_chunks: index_paths.len(), ..Default::default() };
+    let mut top_heap: BinaryHeap<Reverse<(usize, u32)>> = BinaryHeap::with_capacity(top_n + 1);
+    let mut visit = |index_stats: &mut IndexStats, hash: u32, posting_length: usize| {
+        index_stats.add_posting(posting_length);
+        if top_n > 0 && posting_length > 0 {
+            top_heap.push(Reverse((posting_length, hash)));
+            if top_heap.len() > top_n {
+                top_heap.pop();
+            }
+        }
+    };
+
+    let mut deleted_vec = Vec::with_capacity(index_paths.len());
+    for index_prefix in index_paths.iter() {
+        let lookup = load_lookup_from_file(&format!("{}.lookup", index_prefix));
+        let deleted = load_tombstone_from_file(&format!("{}.deleted", index_prefix), lookup.len());
+        index_stats.num_structures += lookup.len();
+        for (pos, (_, _, nres, plddt)) in lookup.into_iter().enumerate() {
+            if is_deleted(deleted.as_deref(), pos) {
+                index_stats.num_deleted += 1;
+                continue;
+            }
+            index_stats.nres.push(nres);
+            index_stats.plddt.push(plddt);
+        }
+        deleted_vec.push(deleted);
+    }
+
+    // The same hash can appear in multiple chunks, so lengths are summed before counting
+    let mut merged_lengths: HashMap<u32, usize> = HashMap::new();
+    for (index_prefix, deleted) in index_paths.iter().zip(deleted_vec.iter()) {
+        if verbose { print_log_msg(INFO, &format!("Reading {}", index_prefix)); }
+        let deleted = deleted.as_deref();
+        let mut add = |hash: u32, posting_length: usize| {
+            if index_paths.len() == 1 {
+                visit(&mut index_stats, hash, posting_length);
+            } else if posting_length > 0 {
+                *merged_lengths.entry(hash).or_insert(0) += posting_length;
+            }
+        };
+        match config.mode {
+            IndexMode::Id => {
+                let (offset_table, _offset_mmap) = SimpleHashMap::load_from_disk(
+                    &PathBuf::from(format!("{}.offset", index_prefix))
+                );
+                let offset_table = offset_table.unwrap_or_else(|_| panic!(
+                    "{}", log_msg(FAIL, "Failed to load offset table")
+                ));
+                // Values are read only to leave out deleted structures
+                let chunk_config = read_index_config_from_file(&format!("{}.type", index_prefix));
+                let (_value_mmap, values) = read_id_values(&format!("{}.value", index_prefix), chunk_config.value_bits)
+                    .unwrap_or_else(|_| panic!("{}", log_msg(FAIL, "Failed to load values")));
+                for (hash, &(offset, posting_length)) in offset_table.iter() {
+                    let posting_length = match deleted {
+                        Some(_) => values.get_values_with_offset(offset, posting_length).iter()
+                            .filter(|&id| !is_deleted(deleted, id)).count(),
+                        None => posting_length,
+                    };
+                    add(hash, posting_length);
+                }
+            }
+            IndexMode::Big => {
+                let (big_index, _offset_mmap) = load_big_index(index_prefix);
+                for hash in 0..big_index.total_hashes() as u32 {
+                    let posting_length = match deleted {
+                        Some(_) => big_index.get_entries(hash).into_iter().filter(|&id| !is_deleted(deleted, id)).count(),
+                        None => big_index.get_entry_count(hash),
+                    };
+                    add(hash, posting_length);
+                }
+            }
+        }
+    }
+    for (hash, posting_length) in merged_lengths.into_iter() {
+        visit(&mut index_stats, hash, posting_length);
+    }
+
+    index_stats.top_hashes = top_heap.into_sorted_vec().into_iter().map(|Reverse((len, hash))| (hash, len)).collect();
+    index_stats
+}
+
+// Feature names of reversed hashes, in the order returned by GeometricHash::reverse_hash
+fn feature_names(hash_type: HashType) -> &'static [&'static str] {
+    match hash_type {
+        HashType::PDBMotif | HashType::PDBMotifSinCos => &["res1", "res2", "ca_dist", "cb_dist", "angle"],
+        HashType::TrRosetta => &["res1", "res2", "cb_dist", "omega", "theta1", "theta2", "phi1", "phi2"],
+        HashType::PDBTrRosetta => &["res1", "res2", "ca_dist", "cb_dist", "ca_cb_angle", "phi1", "phi2"],
+        HashType::PointPairFeature => &["res1", "res2", "dist", "n1_d", "n2_d", "n1_n2"],
+        HashType::TertiaryInteraction => &[
+            "cos_phi_12", "cos_phi_34", "cos_phi_15", "cos_phi_35", "cos_phi_14", "cos_phi_23",
+            "cos_phi_13", "ca_dist", "seq_dist"
+        ],
+        HashType::Hybrid => &[
+            "res1_group", "res2_group", "ca_dist", "cb_dist", "ca_cb_angle", "phi1", "phi2", "bb_phi1", "bb_phi2"
+        ],
+        HashType::Other => &[],
+    }
+}
+
+pub fn describe_hash(hash: u32, config: &IndexConfig) -> String {
+    let hash = GeometricHash::from_u32(hash, config.hash_type);
+    let mut values = vec![0.0f32; 10];
+    if config.num_bin_dist == 0 || config.num_bin_angle == 0 {
+        hash.reverse_hash_default(&mut values);
+    } else {
+        hash.reverse_hash(config.num_bin_dist, config.num_bin_angle, &mut values);
+    }
+    feature_names(config.hash_type).iter().zip(values.iter()).map(|(name, value)| {
+        match *name {
+            "res1" | "res2" => format!("{}={}", name, map_u8_to_aa(*value as u8)),
+            "res1_group" | "res2_group" | "seq_dist" => format!("{}={}", name, *value as i32),
+            _ => format!("{}={:.2}", name, value),
+        }
+    }).collect::<Vec<_>>().join(",")
+}
+
+// Min, first quartile, median, third quartile, max and mean
+fn summarize(mut sorted: Vec<f64>) -> Option<[f64; 6]> {
+    if sorted.is_empty() {
+        return None;
+    }
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let quantile = |q: f64| sorted[((sorted.len() - 1) as f64 * q).round() as usize];
+    let mean = sorted.iter().sum::<f64>() / sorted.len() as f64;
+    Some([quantile(0.0), quantile(0.25), quantile(0.5), quantile(0.75), quantile(1.0), mean])
+}
+
+pub fn write_index_stats<W: Write>(writer: &mut W, index_stats: &IndexStats, config: &IndexConfig) -> std::io::Result<()> {
+    writeln!(writer, "# Index")?;
+    writeln!(writer, "hash_type\t{}", config.hash_type.to_string())?;
+    writeln!(writer, "mode\t{:?}", config.mode)?;
+    writeln!(writer, "num_bin_dist\t{}", config.num_bin_dist)?;
+    writeln!(writer, "num_bin_angle\t{}", config.num_bin_angle)?;
+    writeln!(writer, "chunks\t{}", index_stats.num_chunks)?;
+    writeln!(writer, "structures\t{}", index_stats.num_structures)?;
+    writeln!(writer, "deleted_structures\t{}", index_stats.num_deleted)?;
+    writeln!(writer, "distinct_hashes\t{}", index_stats.num_distinct_hashes)?;
+    writeln!(writer, "total_postings\t{}", index_stats.total_postings)?;
+    let mean_posting_length = if index_stats.num_distinct_hashes > 0 {
+        index_stats.total_postings as f64 / index_stats.num_distinct_hashes as f64
+    } else {
+        0.0
+    };
+    writeln!(writer, "mean_posting_length\t{:.4}", mean_posting_length)?;
+    writeln!(writer, "max_posting_length\t{}", index_stats.max_posting_length)?;
+
+    writeln!(writer, "# Posting length histogram")?;
+    writeln!(writer, "min_length\tmax_length\tnum_hashes")?;
+    for (bucket, count) in index_stats.histogram.iter().enumerate() {
+        writeln!(writer, "{}\t{}\t{}", 1usize << bucket, (1usize << (bucket + 1)) - 1, count)?;
+    }
+
+    writeln!(writer, "# Most frequent hashes")?;
+    writeln!(writer, "rank\thash\tposting_length\tstructure_ratio\tfeatures")?;
+    let num_alive = index_stats.num_structures - index_stats.num_deleted;
+    for (rank, (hash, posting_length)) in index_stats.top_hashes.iter().enumerate() {
+        let ratio = if num_alive > 0 {
+            *posting_length as f64 / num_alive as f64
+        } else {
+            0.0
+        };
+        writeln!(
+            writer, "{}\t{}\t{}\t{:.4}\t{}", rank + 1, hash, posting_length, ratio, describe_hash(*hash, config)
+        )?;
+    }
+
+    writeln!(writer, "# Structure distributions")?;
+    writeln!(writer, "field\tmin\tq1\tmedian\tq3\tmax\tmean")?;
+    if let Some(summary) = summarize(index_stats.nres.iter().map(|&x| x as f64).collect()) {
+        writeln!(
+            writer, "nres\t{}\t{}\t{}\t{}\t{}\t{:.2}",
+            summary[0], summary[1], summary[2], summary[3], summary[4], summary[5]
+        )?;
+    }
+    if let Some(summary) = summarize(index_stats.plddt.iter().map(|&x| x as f64).collect()) {
+        writeln!(
+            writer, "plddt\t{:.2}\t{:.2}\t{:.2}\t{:.2}\t{:.2}\t{:.2}",
+            summary[0], summary[1], summary[2], summary[3], summary[4], summary[5]
+        )?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::workflows::build_index::build_index;
+    use crate::cli::workflows::test_index_args;
+    use crate::cli::workflows::convert::convert_index;
+    use crate::index::tombstone::save_tombstone_to_file;
+
+    fn index_env(index_path: &str, hash_type: &str, chunk_size: usize) -> AppArgs {
+        let mut env = test_index_args("data/cys_his_asp", index_path, hash_type);
+        if let AppArgs::Index { chunk_size: env_chunk_size, .. } = &mut env {
+            *env_chunk_size = chunk_size;
+        }
+        env
+    }
+
+    #[test]
+    fn test_index_stats() {
+        build_index(index_env("data/stats_test", "pdbtr", 65536));
+        build_index(index_env("data/stats_test_chunk", "pdbtr", 4));
+        let config = read_index_config_from_file("data/stats_test.type");
+        let single = collect_index_stats(&check_and_get_indices(Some("data/stats_test".to_string()), false), &config, 5, false);
+        let chunked = collect_index_stats(&check_and_get_indices(Some("data/stats_test_chunk".to_string()), false), &config, 5, false);
+
+        let (offset_table, _offset_mmap) = SimpleHashMap::load_from_disk(&PathBuf::from("data/stats_test.offset"));
+        let offset_table = offset_table.unwrap();
+        assert_eq!(single.num_structures, 10);
+        assert_eq!(single.num_distinct_hashes, offset_table.len());
+        assert_eq!(single.total_postings, offset_table.iter().map(|(_, (_, len))| *len).sum::<usize>());
+        assert_eq!(single.histogram.iter().sum::<usize>(), single.num_distinct_hashes);
+        assert_eq!(single.top_hashes.len(), 5);
+        assert_eq!(single.top_hashes[0].1, single.max_posting_length);
+        assert!(single.top_hashes.windows(2).all(|w| w[0].1 >= w[1].1));
+        assert_eq!(single.nres.len(), 10);
+
+        // Chunks are summarized as one index
+        assert_eq!(chunked.num_chunks, 3);
+        assert_eq!(chunked.num_structures, single.num_structures);
+        assert_eq!(chunked.num_distinct_hashes, single.num_distinct_hashes);
+        assert_eq!(chunked.total_postings, single.total_postings);
+        assert_eq!(chunked.histogram, single.histogram);
+
+        let description = describe_hash(single.top_hashes[0].0, &config);
+        assert!(description.starts_with("res1="));
+        assert!(description.contains("phi2="));
+        let mut output = Vec::new();
+        write_index_stats(&mut output, &single, &config).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("structures\t10\n"));
+
+        // Postings of deleted structures are left out. Structure 1 is the second one of the first chunk
+        let mut deleted = vec![false; 10];
+        deleted[1] = true;
+        save_tombstone_to_file("data/stats_test.deleted", &deleted);
+        save_tombstone_to_file("data/stats_test_chunk_0.deleted", &deleted[..4]);
+        let single = collect_index_stats(&check_and_get_indices(Some("data/stats_test".to_string()), false), &config, 5, false);
+        let chunked = collect_index_stats(&check_and_get_indices(Some("data/stats_test_chunk".to_string()), false), &config, 5, false);
+        let (_value_mmap, values) = read_id_values("data/stats_test.value", config.value_bits).unwrap();
+        assert_eq!(single.num_deleted, 1);
+        assert_eq!(single.nres.len(), 9);
+        assert_eq!(single.total_postings, values.iter().filter(|&id| id != 1).count());
+        assert_eq!(single.histogram.iter().sum::<usize>(), single.num_distinct_hashes);
+        assert_eq!(chunked.num_deleted, 1);
+        assert_eq!(chunked.total_postings, single.total_postings);
+        assert_eq!(chunked.num_distinct_hashes, single.num_distinct_hashes);
+        assert_eq!(chunked.histogram, single.histogram);
+
+        std::fs::remove_file("data/stats_test.deleted").unwrap();
+        std::fs::remove_file("data/stats_test_chunk_0.deleted").unwrap();
+        for prefix in ["data/stats_test", "data/stats_test_chunk_0", "data/stats_test_chunk_1", "data/stats_test_chunk_2"] {
+            for ext in ["offset", "value", "lookup", "type"] {
+                std::fs::remove_file(format!("{}.{}", prefix, ext)).unwrap();
+            }
+        }
+
+    }
+
+    #[test]
+    fn test_big_index_stats() {
+        build_index(index_env("data/stats_big_test", "pdb", 65536));
+        build_index(index_env("data/stats_big_test_chunk", "pdb", 4));
+        // Big mode chunks come from converting the id mode chunks
+        for i in 0..3 {
+            convert_index(&format!("data/stats_big_test_chunk_{}", i), &format!("data/stats_big_test_big_{}", i), IndexMode::Big, 2, false);
+        }
+        let config = read_index_config_from_file("data/stats_big_test.type");
+        let big_config = read_index_config_from_file("data/stats_big_test_big_0.type");
+        let single = collect_index_stats(&check_and_get_indices(Some("data/stats_big_test".to_string()), false), &config, 5, false);
+        let big = collect_index_stats(&check_and_get_indices(Some("data/stats_big_test_big".to_string()), false), &big_config, 5, false);
+        // Hashes shared by chunks are counted once
+        assert_eq!(big.num_chunks, 3);
+        assert_eq!(big.num_distinct_hashes, single.num_distinct_hashes);
+        assert_eq!(big.total_postings, single.total_postings);
+        assert_eq!(big.histogram, single.histogram);
+
+        // Deleted structures are left out in big mode as well
+        let mut deleted = vec![false; 10];
+        deleted[2] = true;
+        save_tombstone_to_file("data/stats_big_test.deleted", &deleted);
+        save_tombstone_to_file("data/stats_big_test_big_0.deleted", &deleted[..4]);
+        let total_postings = single.total_postings;
+        let single = collect_index_stats(&check_and_get_indices(Some("data/stats_big_test".to_string()), false), &config, 5, false);
+        let big = collect_index_stats(&check_and_get_indices(Some("data/stats_big_test_big".to_string()), false), &big_config, 5, false);
+        assert_eq!(big.num_deleted, 1);
+        assert!(single.total_postings < total_postings);
+        assert_eq!(big.num_distinct_hashes, single.num_distinct_hashes);
+        assert_eq!(big.total_postings, single.total_postings);
+        assert_eq!(big.histogram, single.histogram);
+        std::fs::remove_file("data/stats_big_test.deleted").unwrap();
+        std::fs::remove_file("data/stats_big_test_big_0.deleted").unwrap();
+
+        for prefix in ["data/stats_big_test", "data/stats_big_test_chunk", "data/stats_big_test_big"] {
+            for i in 0..3 {
+                for ext in ["offset", "value", "lookup", "type"] {
+                    let _ = std::fs::remove_file(format!("{}_{}.{}", prefix, i, ext));
+                }
+            }
+            for ext in ["offset", "value", "lookup", "type"] {
+                let _ = std::fs::remove_file(format!("{}.{}", prefix, ext));
+            }
+        }
+    }
+}
@@ -0,0 +1,184 @@
+//! Workflow for checking the integrity of an index.
+//! Headers of offset and value files are checked against the index config
+//! and payload checksums are recomputed. Files written before the header
+//! was introduced are reported as legacy.
+
+use std::path::PathBuf;
+
+use crate::cli::*;
+use crate::cli::config::read_index_config_from_file;
+use crate::controller::mode::IndexMode;
+use crate::controller::query::check_and_get_indices;
+use crate::index::header::{index_file_kinds, verify_index_file, IndexFileKind, INDEX_FORMAT_VERSION};
+use crate::prelude::*;
+
+pub const HELP_VERIFY: &str = "\
+usage: folddisco verify -i <i:INDEX_PATH> [OPTIONS]
+
+input:
+ -i, --index <PATH>               Path of the index table. All chunks are checked
+
+options:
+ -v, --verbose                    Print verbose messages
+ -h, --help                       Print this help menu
+
+output:
+ One line per file: path, kind, format version, status (OK, LEGACY or FAIL) and message.
+ Exits with status 1 if any file fails the check.
+
+examples:
+folddisco verify -i index/h_sapiens_folddisco
+";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyStatus {
+    Ok,
+    Legacy,
+    Fail(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct VerifyRecord {
+    pub path: String,
+    pub kind: String,
+    pub version: u32,
+    pub status: VerifyStatus,
+}
+
+pub fn verify(env: AppArgs) {
+    match env {
+        AppArgs::Verify {
+            index_path,
+            verbose,
+            help: _,
+        } => {
+            if index_path.is_none() {
+                eprintln!("{}", HELP_VERIFY);
+                std::process::exit(1);
+            }
+            let index_paths = check_and_get_indices(index_path, verbose);
+            let mut num_failed = 0usize;
+            for index_prefix in index_paths.iter() {
+                if verbose { print_log_msg(INFO, &format!("Verifying {}", index_prefix)); }
+                for record in verify_index(index_prefix) {
+                    let (status, message) = match &record.status {
+                        VerifyStatus::Ok => ("OK", String::new()),
+                        VerifyStatus::Legacy => ("LEGACY", "No header. Rebuild the index to enable integrity checks".to_string()),
+                        VerifyStatus::Fail(message) => {
+                            num_failed += 1;
+                            ("FAIL", message.clone())
+                        }
+                    };
+                    println!("{}\t{}\t{}\t{}\t{}", record.path, record.kind, record.version, status, message);
+                }
+            }
+            if num_failed > 0 {
+                print_log_msg(FAIL, &format!("{} file(s) failed verification", num_failed));
+                std::process::exit(1);
+            }
+            if verbose {
+                print_log_msg(DONE, &format!("Verified {} index file(s)", index_paths.len()));
+            }
+        }
+        _ => {
+            eprintln!("{}", HELP_VERIFY);
+            std::process::exit(1);
+        }
+    }
+}
+
+pub fn verify_index(index_prefix: &str) -> Vec<VerifyRecord> {
+    let type_path = format!("{}.type", index_prefix);
+    if !PathBuf::from(&type_path).is_file() {
+        return vec![VerifyRecord {
+            path: type_path, kind: "type".to_string(), version: 0,
+            status: VerifyStatus::Fail("Index config not found".to_string()),
+        }];
+    }
+    let config = read_index_config_from_file(&type_path);
+    let verify_table_file = |path: String, kind: IndexFileKind, hash_type: HashType| {
+        let (version, status) = match verify_index_file(&path) {
+            Ok(Some(header)) => {
+                if header.kind != kind {
+                    (header.version, VerifyStatus::Fail(format!(
+                        "Expected {} file but found {} file", kind, header.kind
+                    )))
+                } else if header.hash_type != hash_type {
+                    (header.version, VerifyStatus::Fail(format!(
+                        "Built with {} hashes but the index config says {}",
+                        header.hash_type.to_string(), hash_type.to_string()
+                    )))
+                } else {
+                    (header.version, VerifyStatus::Ok)
+                }
+            }
+            Ok(None) => (0, VerifyStatus::Legacy),
+            Err(e) => (INDEX_FORMAT_VERSION, VerifyStatus::Fail(e.to_string())),
+        };
+        VerifyRecord { path, kind: kind.to_string(), version, status }
+    };
+    let mut records = index_file_kinds(&config.mode).into_iter().map(
+        |(ext, kind)| verify_table_file(format!("{}.{}", index_prefix, ext), kind, config.hash_type)
+    ).collect::<Vec<_>>();
+    // Tables of extra hash sets are id mode tables of their own hash type
+    for (k, (hash_type, _, _)) in config.extra_hash_sets.iter().enumerate() {
+        for (ext, kind) in index_file_kinds(&IndexMode::Id) {
+            records.push(verify_table_file(format!("{}.hash{}.{}", index_prefix, k + 1, ext), kind, *hash_type));
+        }
+    }
+    // Coordinate cache and binary lookup are optional and don't depend on the hash type
+    for (ext, kind, name) in [
+        ("coords", IndexFileKind::Coordinates, "Coordinate cache"), ("blookup", IndexFileKind::Lookup, "Binary lookup"),
+    ] {
+        let path = format!("{}.{}", index_prefix, ext);
+        if !PathBuf::from(&path).is_file() {
+            continue;
+        }
+        let (version, status) = match verify_index_file(&path) {
+            Ok(Some(header)) if header.kind != kind => (header.version, VerifyStatus::Fail(format!(
+                "Expected {} file but found {} file", kind, header.kind
+            ))),
+            Ok(Some(header)) => (header.version, VerifyStatus::Ok),
+            Ok(None) => (0, VerifyStatus::Fail(format!("{} has no header", name))),
+            Err(e) => (INDEX_FORMAT_VERSION, VerifyStatus::Fail(e.to_string())),
+        };
+        records.push(VerifyRecord { path, kind: kind.to_string(), version, status });
+    }
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::workflows::build_index::build_index;
+    use crate::cli::workflows::test_index_args;
+    use crate::index::header::HEADER_SIZE;
+
+    #[test]
+    fn test_verify_index() {
+        let index_path = "data/verify_test";
+        build_index(test_index_args("data/cys_his_asp", index_path, "pdbtr"));
+        let records = verify_index(index_path);
+        assert_eq!(records.len(), 2);
+        assert!(records.iter().all(|record| record.status == VerifyStatus::Ok));
+        assert!(records.iter().all(|record| record.version == INDEX_FORMAT_VERSION));
+
+        // Flip a byte in the payload of the value file
+        let value_path = format!("{}.value", index_path);
+        let mut bytes = std::fs::read(&value_path).unwrap();
+        bytes[HEADER_SIZE] ^= 0xFF;
+        std::fs::write(&value_path, &bytes).unwrap();
+        let records = verify_index(index_path);
+        assert_eq!(records[0].status, VerifyStatus::Ok);
+        assert!(matches!(&records[1].status, VerifyStatus::Fail(message) if message.contains("Checksum")));
+
+        // Truncated copy
+        std::fs::write(&value_path, &bytes[..bytes.len() - 2]).unwrap();
+        let records = verify_index(index_path);
+        assert!(matches!(&records[1].status, VerifyStatus::Fail(message) if message.contains("truncated")));
+
+        for ext in ["offset", "value", "lookup", "type"] {
+            std::fs::remove_file(format!("{}.{}", index_path, ext)).unwrap();
+        }
+    }
+}
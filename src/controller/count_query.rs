@@ -6,27 +6,48 @@ use rayon::prelude::*;  // Import rayon for parallel iterators
 use std::collections::HashMap;
 
 use crate::index::indextable::FolddiscoIndex;
+use crate::index::lookup::LookupTable;
+use crate::index::tombstone::{count_alive, is_deleted};
 use crate::prelude::GeometricHash;
 
-use super::io::get_values_with_offset_u16;
+use super::io::IdValues;
 use super::map::SimpleHashMap;
 use super::result::StructureResult;
 
+// Options of count_query_idmode and count_query_bigmode. Removed structures in `deleted`
+// are skipped. IDF uses `global_doc_freq` (document frequencies, number of structures)
+// summed over all loaded indices if given
+#[derive(Clone, Copy, Default)]
+pub struct CountOptions<'a> {
+    pub deleted: Option<&'a [bool]>,
+    pub global_doc_freq: Option<(&'a HashMap<GeometricHash, usize>, usize)>,
+    pub sampling_ratio: Option<f32>,
+    pub sampling_count: Option<usize>,
+    pub freq_filter: Option<f32>,
+    pub length_penalty_power: Option<f32>,
+}
+
 pub fn count_query_idmode<'a>(
     queries: &Vec<GeometricHash>, query_map: &HashMap<GeometricHash, ((usize, usize), bool)>,
-    offset_table: &SimpleHashMap, value_vec: &[u16], lookup: &'a Vec<(String, usize, usize, f32)>, 
-    sampling_ratio: Option<f32>, sampling_count: Option<usize>,
-    freq_filter: Option<f32>, length_penalty_power: Option<f32>,
+    offset_table: &SimpleHashMap, value_vec: IdValues, lookup: &'a LookupTable, 
+    options: CountOptions,
 ) -> DashMap<usize, StructureResult<'a>> {
+    let CountOptions {
+        deleted, global_doc_freq, sampling_ratio, sampling_count, freq_filter, length_penalty_power,
+    } = options;
     let query_count_map = DashMap::new();  // Use DashMap instead of HashMap
+    // Removed structures are not counted in IDF
+    let num_alive = count_alive(deleted, lookup.len());
     // Sampling query
     let queries_to_iter = sample_query_idmode(queries, offset_table, sampling_ratio, sampling_count);
     queries_to_iter.par_iter().for_each(|query| {  // Use parallel iterator
         if let Some(offset) = offset_table.get(query) {
-            let single_queried_values = get_values_with_offset_u16(value_vec, offset.0, offset.1);
-            let hash_count = offset.1;            
+            let single_queried_values = value_vec.get_values_with_offset(offset.0, offset.1);
+            let (hash_count, num_structures) = get_document_frequency(
+                query, count_alive_values(single_queried_values.iter(), deleted), num_alive, global_doc_freq
+            );
             if let Some(freq_filter) = freq_filter {
-                if hash_count as f32 / lookup.len() as f32 > freq_filter {
+                if hash_count as f32 / num_structures as f32 > freq_filter {
                     // If the hash count is too low, skip the query
                     return;
                 }
@@ -34,13 +55,14 @@ pub fn count_query_idmode<'a>(
             let edge_info = query_map.get(query).unwrap();
             let edge = edge_info.0;
 
-            for &value in single_queried_values.iter() {
-                let id = &lookup[value as usize].0;
-                let nid = lookup[value as usize].1;
-                let nres = lookup[value as usize].2;
-                let plddt = lookup[value as usize].3;
+            for value in single_queried_values.iter() {
+                // Removed structures stay in the posting lists until compaction
+                if is_deleted(deleted, value) {
+                    continue;
+                }
+                let (id, nid, nres, plddt) = lookup.get(value);
 
-                let idf = (lookup.len() as f32 / hash_count as f32).log2();
+                let idf = (num_structures as f32 / hash_count as f32).log2();
 
                 let mut is_new: bool = false;
                 let entry = query_count_map.entry(nid);
@@ -88,19 +110,25 @@ pub fn count_query_idmode<'a>(
 
 pub fn count_query_bigmode<'a>(
     queries: &Vec<GeometricHash>, query_map: &HashMap<GeometricHash, ((usize, usize), bool)>,
-    big_index: &FolddiscoIndex, lookup: &'a Vec<(String, usize, usize, f32)>, 
-    sampling_ratio: Option<f32>, sampling_count: Option<usize>,
-    freq_filter: Option<f32>, length_penalty_power: Option<f32>,
+    big_index: &FolddiscoIndex, lookup: &'a LookupTable, 
+    options: CountOptions,
 ) -> DashMap<usize, StructureResult<'a>> {
+    let CountOptions {
+        deleted, global_doc_freq, sampling_ratio, sampling_count, freq_filter, length_penalty_power,
+    } = options;
     let query_count_map = DashMap::new();  // Use DashMap instead of HashMap
-    
+    // Removed structures are not counted in IDF
+    let num_alive = count_alive(deleted, lookup.len());
+
     let queries_to_iter = sample_query_bigmode(queries, big_index, sampling_ratio, sampling_count);
 
     queries_to_iter.par_iter().for_each(|query| {  // Use parallel iterator
         let single_queried_values = big_index.get_entries(query.as_u32());
-        let hash_count = single_queried_values.len();
+        let (hash_count, num_structures) = get_document_frequency(
+            query, count_alive_values(single_queried_values.iter().copied(), deleted), num_alive, global_doc_freq
+        );
         if let Some(freq_filter) = freq_filter {
-            if hash_count as f32 / lookup.len() as f32 > freq_filter {
+            if hash_count as f32 / num_structures as f32 > freq_filter {
                 // If the hash count is too low, skip the query
                 return;
             }
@@ -114,12 +142,12 @@ pub fn count_query_bigmode<'a>(
                 println!("Error query: {:?}", query);
                 continue;
             }
-            let id = &lookup[value].0;
-            let nid = lookup[value].1;
-            let nres = lookup[value].2;
-            let plddt = lookup[value].3;
+            if is_deleted(deleted, value) {
+                continue;
+            }
+            let (id, nid, nres, plddt) = lookup.get(value);
 
-            let idf = (lookup.len() as f32 / hash_count as f32).log2();
+            let idf = (num_structures as f32 / hash_count as f32).log2();
             let mut is_new: bool = false;
             let entry = query_count_map.entry(nid);
             // Not consuming the entry, so we can modify it
@@ -162,6 +190,79 @@ pub fn count_query_bigmode<'a>(
     query_count_map
 }
 
+// Add the counts of another hash type of the same index into query_count_map.
+// Scores are summed and matched query residues and edges are unioned
+pub fn fuse_structure_results<'a>(
+    query_count_map: &DashMap<usize, StructureResult<'a>>, other: DashMap<usize, StructureResult<'a>>,
+) {
+    other.into_iter().for_each(|(nid, other_result)| {
+        match query_count_map.get_mut(&nid) {
+            Some(mut entry) => {
+                let result = entry.value_mut();
+                result.idf += other_result.idf;
+                result.total_match_count += other_result.total_match_count;
+                result.node_set.extend(other_result.node_set);
+                result.edge_set.extend(other_result.edge_set);
+                result.node_count = result.node_set.len();
+                result.edge_count = result.edge_set.len();
+            }
+            None => {
+                query_count_map.insert(nid, other_result);
+            }
+        }
+    });
+}
+
+// Number of structures containing each query hash, added to doc_freq.
+// Summed over all indices before counting so that IDF is computed over
+// the whole database instead of each chunk.
+pub fn add_document_frequency_idmode(
+    queries: &[GeometricHash], offset_table: &SimpleHashMap, value_vec: IdValues,
+    deleted: Option<&[bool]>, doc_freq: &mut HashMap<GeometricHash, usize>,
+) {
+    for query in queries.iter() {
+        if let Some(offset) = offset_table.get(query) {
+            let values = value_vec.get_values_with_offset(offset.0, offset.1);
+            *doc_freq.entry(*query).or_insert(0) += count_alive_values(values.iter(), deleted);
+        }
+    }
+}
+
+pub fn add_document_frequency_bigmode(
+    queries: &[GeometricHash], big_index: &FolddiscoIndex,
+    deleted: Option<&[bool]>, doc_freq: &mut HashMap<GeometricHash, usize>,
+) {
+    for query in queries.iter() {
+        let hash_count = count_alive_values(big_index.get_entries(query.as_u32()).into_iter(), deleted);
+        if hash_count > 0 {
+            *doc_freq.entry(*query).or_insert(0) += hash_count;
+        }
+    }
+}
+
+// Length of a posting list without the removed structures
+#[inline(always)]
+fn count_alive_values(values: impl ExactSizeIterator<Item = usize>, deleted: Option<&[bool]>) -> usize {
+    match deleted {
+        Some(_) => values.filter(|&value| !is_deleted(deleted, value)).count(),
+        None => values.len(),
+    }
+}
+
+// (hash count, number of structures) used for IDF. Local values if no global frequency is given
+#[inline(always)]
+fn get_document_frequency(
+    query: &GeometricHash, local_hash_count: usize, local_num_structures: usize,
+    global_doc_freq: Option<(&HashMap<GeometricHash, usize>, usize)>,
+) -> (usize, usize) {
+    match global_doc_freq {
+        Some((doc_freq, num_structures)) => (
+            *doc_freq.get(query).unwrap_or(&local_hash_count), num_structures
+        ),
+        None => (local_hash_count, local_num_structures),
+    }
+}
+
 fn sample_query_idmode(
     queries: &Vec<GeometricHash>, offset_table: &SimpleHashMap,
     sampling_ratio: Option<f32>, sampling_count: Option<usize>,
@@ -228,3 +329,99 @@ fn sample_query_bigmode(
         (Some(_), Some(_)) => queries.clone(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::map::convert_sorted_hash_vec_to_simplemap;
+    use crate::controller::mode::IndexMode;
+    use crate::controller::io::read_compact_structure;
+    use crate::controller::query::make_query_map;
+    use crate::prelude::*;
+
+    fn build_in_memory(paths: Vec<String>) -> (SimpleHashMap, Vec<u16>, LookupTable) {
+        let mut folddisco = FoldDisco::new(
+            paths.clone(), HashType::PDBTrRosetta, 2, 16, 4, String::new(),
+            20.0, IndexMode::Id, None, false,
+        );
+        folddisco.collect_hash_vec();
+        folddisco.sort_hash_vec();
+        let (offset_map, values) = convert_sorted_hash_vec_to_simplemap(folddisco.hash_id_vec.clone());
+        let values = values.iter().map(|&x| x as u16).collect::<Vec<u16>>();
+        let lookup = LookupTable::Text(paths.iter().enumerate().map(|(i, path)| {
+            (path.clone(), i, folddisco.nres_vec[i], folddisco.plddt_vec[i])
+        }).collect());
+        (offset_map, values, lookup)
+    }
+
+    #[test]
+    fn test_global_idf_across_chunks() {
+        let paths = load_path("data/cys_his_asp", false);
+        let (single_map, single_values, single_lookup) = build_in_memory(paths.clone());
+        let chunks = vec![build_in_memory(paths[..4].to_vec()), build_in_memory(paths[4..].to_vec())];
+
+        let (query_structure, _) = read_compact_structure(&paths[0]).unwrap();
+        let (query_map, _, _) = make_query_map(
+            &query_structure, &Vec::new(), HashType::PDBTrRosetta, 16, 4, &None,
+            &Vec::new(), &Vec::new(), &Vec::new(), 20.0, false,
+        );
+        let queries = query_map.keys().cloned().collect::<Vec<_>>();
+
+        let expected = count_query_idmode(
+            &queries, &query_map, &single_map, IdValues::U16(&single_values), &single_lookup,
+            CountOptions::default(),
+        );
+        let mut doc_freq = HashMap::new();
+        for (offset_map, values, _) in chunks.iter() {
+            add_document_frequency_idmode(&queries, offset_map, IdValues::U16(values), None, &mut doc_freq);
+        }
+        let mut num_compared = 0;
+        for (offset_map, values, lookup) in chunks.iter() {
+            let results = count_query_idmode(
+                &queries, &query_map, offset_map, IdValues::U16(values), lookup,
+                CountOptions { global_doc_freq: Some((&doc_freq, paths.len())), ..Default::default() },
+            );
+            for entry in results.iter() {
+                let expected_result = expected.iter().find(|x| x.value().id == entry.value().id).unwrap();
+                assert!((expected_result.value().idf - entry.value().idf).abs() < 1e-4);
+                num_compared += 1;
+            }
+        }
+        assert_eq!(num_compared, expected.len());
+    }
+
+    #[test]
+    fn test_deleted_structures_not_in_idf() {
+        let paths = load_path("data/cys_his_asp", false);
+        let (map, values, lookup) = build_in_memory(paths.clone());
+        let (kept_map, kept_values, kept_lookup) = build_in_memory(paths[1..].to_vec());
+        let mut deleted = vec![false; paths.len()];
+        deleted[0] = true;
+
+        let (query_structure, _) = read_compact_structure(&paths[1]).unwrap();
+        let (query_map, _, _) = make_query_map(
+            &query_structure, &Vec::new(), HashType::PDBTrRosetta, 16, 4, &None,
+            &Vec::new(), &Vec::new(), &Vec::new(), 20.0, false,
+        );
+        let queries = query_map.keys().cloned().collect::<Vec<_>>();
+        // Scores with a removed structure are the same as without indexing it
+        let expected = count_query_idmode(
+            &queries, &query_map, &kept_map, IdValues::U16(&kept_values), &kept_lookup,
+            CountOptions::default(),
+        );
+        let mut doc_freq = HashMap::new();
+        add_document_frequency_idmode(&queries, &map, IdValues::U16(&values), Some(&deleted), &mut doc_freq);
+        for global_doc_freq in [None, Some((&doc_freq, paths.len() - 1))] {
+            let results = count_query_idmode(
+                &queries, &query_map, &map, IdValues::U16(&values), &lookup,
+                CountOptions { deleted: Some(&deleted), global_doc_freq, ..Default::default() },
+            );
+            assert_eq!(results.len(), expected.len());
+            for entry in results.iter() {
+                assert_ne!(entry.value().id, paths[0]);
+                let expected_result = expected.iter().find(|x| x.value().id == entry.value().id).unwrap();
+                assert!((expected_result.value().idf - entry.value().idf).abs() < 1e-4);
+            }
+        }
+    }
+}
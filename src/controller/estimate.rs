@@ -0,0 +1,262 @@
+// Dry-run estimate of index size, memory and time. Structures sampled evenly
+// from the inputs are hashed with the indexing parameters and the counts are
+// extrapolated to the whole input. Distinct hashes grow slower than postings,
+// so their number is extrapolated with Heaps' law fitted on the first half
+// and the whole sample.
+
+use std::collections::HashMap;
+use std::io::{Error, Write};
+use std::time::{Duration, Instant};
+
+use rayon::prelude::*;
+
+use crate::controller::io::id_value_bits;
+use crate::controller::FoldDisco;
+use crate::index::header::HEADER_SIZE;
+use crate::utils::log::{log_msg, FAIL};
+
+// Fewer structures than this are not worth extrapolating from
+const MIN_SAMPLE_SIZE: usize = 100;
+
+#[derive(Debug, Clone, Default)]
+pub struct IndexEstimate {
+    pub num_inputs: usize,
+    pub num_sampled: usize,
+    pub num_skipped: usize,
+    pub sample_time: Duration,
+    // Extrapolated to the inputs that would be indexed
+    pub num_structures: usize,
+    pub num_postings: usize,
+    pub num_distinct_hashes: usize,
+    // Id mode, summed over chunks. Peak memory is of the largest chunk
+    pub chunk_size: usize,
+    pub num_chunks: usize,
+    pub id_offset_size: usize,
+    pub id_value_size: usize,
+    pub id_peak_memory: usize,
+    pub id_peak_memory_sorted: Option<usize>, // with --max-memory
+    pub id_time: Duration,
+    // Big mode
+    pub big_offset_size: usize,
+    pub big_value_size: usize,
+    pub big_peak_memory: usize,
+    pub big_peak_memory_on_disk: usize, // with --mmap-on-disk
+    pub big_time: Duration,
+}
+
+// Positions of num_sampled structures spread evenly over the inputs
+pub fn sample_positions(num_inputs: usize, sample_ratio: f32) -> Vec<usize> {
+    let num_sampled = ((num_inputs as f64 * sample_ratio as f64).ceil() as usize)
+        .max(MIN_SAMPLE_SIZE).min(num_inputs);
+    (0..num_sampled).map(|i| i * num_inputs / num_sampled).collect()
+}
+
+// Heaps' law D(m) = D(n) * (m / n)^beta with beta from D(n / 2) and D(n)
+fn extrapolate_distinct(half_distinct: usize, distinct: usize, num_sampled: usize, num_target: usize) -> f64 {
+    if distinct == 0 || num_sampled == 0 {
+        return 0.0;
+    }
+    let beta = if num_sampled >= 2 && half_distinct > 0 {
+        ((distinct as f64 / half_distinct as f64).ln() / (num_sampled as f64 / (num_sampled / 2) as f64).ln()).clamp(0.0, 1.0)
+    } else {
+        1.0
+    };
+    distinct as f64 * (num_target as f64 / num_sampled as f64).powf(beta)
+}
+
+// Bytes of an id mode offset file with num_distinct hashes. See SimpleHashMap::dump_to_disk
+fn id_offset_file_size(num_distinct: usize) -> usize {
+    let capacity = num_distinct.saturating_sub(1).max(1) * 3;
+    HEADER_SIZE + 16 + capacity * 4 + capacity.div_ceil(8) + num_distinct * (4 + 16)
+}
+
+// Hash a sample of folddisco.path_vec and extrapolate. chunk_size and max_memory are
+// the id mode options; big mode is always estimated as one chunk.
+pub fn estimate_index(folddisco: &FoldDisco, sample_ratio: f32, chunk_size: usize, max_memory: f64) -> IndexEstimate {
+    let num_inputs = folddisco.path_vec.len();
+    let positions = sample_positions(num_inputs, sample_ratio);
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(folddisco.num_threads)
+        .build()
+        .unwrap_or_else(|_| panic!("{}", log_msg(FAIL, "Failed to build thread pool for sampling")));
+    let start = Instant::now();
+    let hashed = pool.install(|| {
+        positions.par_iter().map(|&pos| folddisco.hash_structure(&folddisco.path_vec[pos])).collect::<Vec<_>>()
+    });
+    let sample_time = start.elapsed();
+    drop(pool);
+
+    // Document frequencies in the sample. Postings of a hash are counted once per structure
+    let hash_vecs = hashed.into_iter().filter_map(|result| result.ok().map(|(hash_vec, _, _, _)| hash_vec)).collect::<Vec<_>>();
+    let num_indexed = hash_vecs.len();
+    let mut doc_freq: HashMap<u32, usize> = HashMap::new();
+    let mut half_distinct = 0;
+    for (i, hash_vec) in hash_vecs.iter().enumerate() {
+        if i == num_indexed / 2 {
+            half_distinct = doc_freq.len();
+        }
+        for &hash in hash_vec.iter() {
+            *doc_freq.entry(hash).or_insert(0) += 1;
+        }
+    }
+    let sample_postings = hash_vecs.iter().map(|hash_vec| hash_vec.len()).sum::<usize>();
+    drop(hash_vecs);
+
+    let mut estimate = IndexEstimate {
+        num_inputs,
+        num_sampled: positions.len(),
+        num_skipped: positions.len() - num_indexed,
+        sample_time,
+        ..Default::default()
+    };
+    if num_indexed == 0 {
+        return estimate;
+    }
+    let hash_space = 2usize.pow(folddisco.hash_type.encoding_bits() as u32);
+    let indexed_ratio = num_indexed as f64 / positions.len() as f64;
+    let postings_per_structure = sample_postings as f64 / num_indexed as f64;
+    let time_per_input = sample_time.as_secs_f64() / positions.len() as f64;
+    let distinct_in = |num_structures: usize| {
+        extrapolate_distinct(half_distinct, doc_freq.len(), num_indexed, num_structures)
+            .min(postings_per_structure * num_structures as f64)
+            .min(hash_space as f64) as usize
+    };
+    estimate.num_structures = (num_inputs as f64 * indexed_ratio).round() as usize;
+    estimate.num_postings = (postings_per_structure * estimate.num_structures as f64) as usize;
+    estimate.num_distinct_hashes = distinct_in(estimate.num_structures);
+
+    // Id mode. Each chunk is a separate index
+    estimate.chunk_size = chunk_size.min(num_inputs).max(1);
+    estimate.num_chunks = num_inputs.div_ceil(estimate.chunk_size);
+    let mut peak_distinct = 0;
+    let mut peak_postings = 0;
+    for chunk_index in 0..estimate.num_chunks {
+        let chunk_inputs = estimate.chunk_size.min(num_inputs - chunk_index * estimate.chunk_size);
+        let chunk_structures = (chunk_inputs as f64 * indexed_ratio).round() as usize;
+        let chunk_postings = (postings_per_structure * chunk_structures as f64) as usize;
+        let chunk_distinct = distinct_in(chunk_structures);
+        estimate.id_offset_size += id_offset_file_size(chunk_distinct);
+        estimate.id_value_size += HEADER_SIZE + chunk_postings * id_value_bits(chunk_inputs) / 8;
+        peak_distinct = peak_distinct.max(chunk_distinct);
+        peak_postings = peak_postings.max(chunk_postings);
+    }
+    // Sorted (hash, id) pairs, then the value vector and the offset table next to them
+    let offset_table_memory = id_offset_file_size(peak_distinct) - HEADER_SIZE;
+    estimate.id_peak_memory = peak_postings * (16 + 8) + offset_table_memory;
+    // Sort buffer is half of the budget. Offsets are collected while runs are merged
+    estimate.id_peak_memory_sorted = (max_memory > 0.0).then(|| {
+        (max_memory * 1073741824.0) as usize / 2 + peak_distinct * 24 + offset_table_memory
+    });
+    estimate.id_time = Duration::from_secs_f64(time_per_input * num_inputs as f64);
+
+    // Big mode. Ids are delta encoded in 7-bit groups. A hash found in df sampled structures
+    // is expected every num_indexed / df structures in the whole input as well
+    let sample_value_size = doc_freq.values().map(|&df| {
+        let delta = (num_indexed / df).max(1);
+        df * (1 + delta.ilog2() as usize / 7)
+    }).sum::<usize>();
+    let offset_table = (hash_space + 1) * 8;
+    estimate.big_offset_size = HEADER_SIZE + offset_table;
+    estimate.big_value_size = HEADER_SIZE
+        + (sample_value_size as f64 * estimate.num_structures as f64 / num_indexed as f64) as usize;
+    // Offsets and last ids of all hashes, and pairs of the structures hashed at once
+    let batch_memory = (folddisco.num_threads * 128).min(num_inputs) * (postings_per_structure * 16.0) as usize;
+    estimate.big_peak_memory_on_disk = offset_table + hash_space * 8 + batch_memory;
+    estimate.big_peak_memory = estimate.big_peak_memory_on_disk + estimate.big_value_size - HEADER_SIZE;
+    // Structures are hashed twice, once for counting and once for adding
+    estimate.big_time = Duration::from_secs_f64(2.0 * time_per_input * num_inputs as f64);
+    estimate
+}
+
+fn format_size(bytes: usize) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{} B", bytes) } else { format!("{:.1} {}", size, UNITS[unit]) }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        0..=59 => format!("{:.1}s", duration.as_secs_f64()),
+        60..=3599 => format!("{}m {}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+pub fn write_index_estimate<W: Write>(writer: &mut W, estimate: &IndexEstimate) -> Result<(), Error> {
+    writeln!(writer, "# Sample")?;
+    writeln!(writer, "inputs\t{}", estimate.num_inputs)?;
+    writeln!(writer, "sampled\t{}", estimate.num_sampled)?;
+    writeln!(writer, "skipped_in_sample\t{}", estimate.num_skipped)?;
+    writeln!(writer, "sample_time\t{}", format_duration(estimate.sample_time))?;
+    writeln!(writer, "# Estimate")?;
+    writeln!(writer, "structures\t{}", estimate.num_structures)?;
+    writeln!(writer, "postings\t{}", estimate.num_postings)?;
+    writeln!(writer, "distinct_hashes\t{}", estimate.num_distinct_hashes)?;
+    writeln!(writer, "# Id mode ({} chunks of up to {} structures)", estimate.num_chunks, estimate.chunk_size)?;
+    writeln!(writer, "offset_size\t{}", format_size(estimate.id_offset_size))?;
+    writeln!(writer, "value_size\t{}", format_size(estimate.id_value_size))?;
+    writeln!(writer, "peak_memory\t{}", format_size(estimate.id_peak_memory))?;
+    if let Some(peak_memory) = estimate.id_peak_memory_sorted {
+        writeln!(writer, "peak_memory_max_memory\t{}", format_size(peak_memory))?;
+    }
+    writeln!(writer, "hashing_time\t{}", format_duration(estimate.id_time))?;
+    writeln!(writer, "# Big mode")?;
+    writeln!(writer, "offset_size\t{}", format_size(estimate.big_offset_size))?;
+    writeln!(writer, "value_size\t{}", format_size(estimate.big_value_size))?;
+    writeln!(writer, "peak_memory\t{}", format_size(estimate.big_peak_memory))?;
+    writeln!(writer, "peak_memory_mmap_on_disk\t{}", format_size(estimate.big_peak_memory_on_disk))?;
+    writeln!(writer, "hashing_time\t{}", format_duration(estimate.big_time))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::mode::IndexMode;
+    use crate::geometry::core::HashType;
+    use crate::utils::loader::load_path;
+
+    #[test]
+    fn test_sample_positions() {
+        assert_eq!(sample_positions(10, 0.01), (0..10).collect::<Vec<_>>());
+        let positions = sample_positions(100000, 0.01);
+        assert_eq!(positions.len(), 1000);
+        assert_eq!(positions[1] - positions[0], 100);
+        assert!(*positions.last().unwrap() < 100000);
+    }
+
+    #[test]
+    fn test_estimate_index() {
+        let path_vec = load_path("data/serine_peptidases_filtered", false);
+        let folddisco = FoldDisco::new(
+            path_vec.clone(), HashType::PDBTrRosetta, 2, 16, 4, "data/estimate_test".to_string(),
+            20.0, IndexMode::Id, None, false,
+        );
+        // Whole input is sampled, so id mode sizes of one chunk are exact
+        let estimate = estimate_index(&folddisco, 1.0, 65536, 0.0);
+        assert_eq!(estimate.num_sampled, path_vec.len());
+        assert_eq!(estimate.num_structures + estimate.num_skipped, path_vec.len());
+        let mut folddisco = folddisco;
+        folddisco.collect_hash_vec();
+        folddisco.sort_hash_vec();
+        let mut distinct = folddisco.hash_id_vec.iter().map(|(hash, _)| *hash).collect::<Vec<_>>();
+        distinct.dedup();
+        assert_eq!(estimate.num_postings, folddisco.hash_id_vec.len());
+        assert_eq!(estimate.num_distinct_hashes, distinct.len());
+        assert_eq!(estimate.id_value_size, HEADER_SIZE + folddisco.hash_id_vec.len() * 2);
+        assert_eq!(estimate.num_chunks, 1);
+        assert!(estimate.id_peak_memory_sorted.is_none());
+        assert_eq!(estimate.big_offset_size, HEADER_SIZE + (2usize.pow(30) + 1) * 8);
+
+        // Chunks are estimated separately
+        let estimate = estimate_index(&folddisco, 1.0, 10, 1.0);
+        assert_eq!(estimate.num_chunks, path_vec.len().div_ceil(10));
+        assert!(estimate.id_peak_memory_sorted.unwrap() > 1 << 29);
+    }
+}
@@ -0,0 +1,131 @@
+// Writing matched motifs of query results as structures for visual inspection.
+// Each hit is moved onto the query with the superposition of its match, so
+// that the files can be opened together with the query structure as they are.
+// Files are named by rank, and the top hits are also written as models of one file.
+
+use std::path::Path;
+
+use rayon::prelude::*;
+
+use crate::controller::io::split_chain_path;
+use crate::prelude::{print_log_msg, INFO, WARN};
+use crate::structure::core::Structure;
+use crate::structure::io::StructureFileFormat;
+use crate::structure::io::writer::write_structure_to_path;
+
+use super::result::MatchResult;
+
+pub const TOP_HITS_FILE_NAME: &str = "top_hits";
+pub const NUM_TOP_HIT_MODELS: usize = 10;
+
+// Name of a hit without directories and structure file extensions
+fn hit_name(id: &str) -> String {
+    if let Some((file_path, chain)) = split_chain_path(id) {
+        return format!("{}_{}", hit_name(file_path), chain as char);
+    }
+    let name = Path::new(id).file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or(id.to_string());
+    let mut name = name.as_str();
+    for extension in [".gz", ".pdb", ".cif", ".ent", ".fcz"] {
+        name = name.strip_suffix(extension).unwrap_or(name);
+    }
+    name.replace(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.'), "_")
+}
+
+// Matched residues of a hit and the residues within `environment` Å of them, moved onto the query
+pub fn superposed_motif(structure: &Structure, hit: &MatchResult, environment: f32) -> Structure {
+    let residues = hit.matching_residues.iter().flatten().cloned().collect::<Vec<_>>();
+    let (rotation, translation) = &hit.superposition;
+    structure.select_residues(&residues, environment).transform(rotation, translation)
+}
+
+// Write hits in the given order as {rank}_{name}.{pdb|cif} and the first NUM_TOP_HIT_MODELS of them
+// as models of top_hits.{pdb|cif}. Structures are read with the nid of each hit, and the ones
+// that can't be read are skipped with a warning.
+// Returns the number of hits written
+pub fn write_hits<F>(
+    dir: &str, hits: &[(usize, MatchResult)], environment: f32, format: &StructureFileFormat,
+    read_structure: F, verbose: bool,
+) -> Result<usize, String>
+where
+    F: Fn(usize, &MatchResult) -> Result<Structure, String> + Sync,
+{
+    let extension = match format {
+        StructureFileFormat::PDB => "pdb",
+        StructureFileFormat::CIF => "cif",
+        _ => return Err(format!("Writing hits as {} is not supported. Use pdb or cif", format)),
+    };
+    std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir, e))?;
+    let width = hits.len().to_string().len();
+    let written = hits.par_iter().enumerate().map(|(rank, (nid, hit))| {
+        let motif = match read_structure(*nid, hit) {
+            Ok(structure) => superposed_motif(&structure, hit, environment),
+            Err(e) => {
+                print_log_msg(WARN, &format!("Failed to read hit {}: {}", hit.id, e));
+                return None;
+            }
+        };
+        let path = Path::new(dir).join(format!("{:0width$}_{}.{}", rank + 1, hit_name(hit.id), extension, width = width));
+        match write_structure_to_path(&path.to_string_lossy(), &[&motif], format) {
+            Ok(_) => Some((rank, motif)),
+            Err(e) => {
+                print_log_msg(WARN, &format!("Failed to write {}: {}", path.display(), e));
+                None
+            }
+        }
+    }).collect::<Vec<_>>();
+
+    let top_hits = written.iter().flatten().filter(
+        |(rank, _)| *rank < NUM_TOP_HIT_MODELS
+    ).map(|(_, motif)| motif).collect::<Vec<_>>();
+    if !top_hits.is_empty() {
+        let path = Path::new(dir).join(format!("{}.{}", TOP_HITS_FILE_NAME, extension));
+        write_structure_to_path(&path.to_string_lossy(), &top_hits, format).map_err(
+            |e| format!("Failed to write {}: {}", path.display(), e)
+        )?;
+    }
+    let num_written = written.iter().flatten().count();
+    if verbose {
+        print_log_msg(INFO, &format!("Wrote {} of {} hits to {}", num_written, hits.len(), dir));
+    }
+    Ok(num_written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::io::{read_structure_from_path, try_read_structure_from_path};
+
+    #[test]
+    fn test_hit_name() {
+        assert_eq!(hit_name("data/serine_peptidases_filtered/4cha.pdb"), "4cha");
+        assert_eq!(hit_name("afdb/AF-P00766-F1-model_v4.cif.gz"), "AF-P00766-F1-model_v4");
+        assert_eq!(hit_name("archive.tar:inner/1a0j.pdb"), "1a0j");
+        assert_eq!(hit_name("data/4cha.pdb_B"), "4cha_B");
+        assert_eq!(hit_name("AF-Q9 name"), "AF-Q9_name");
+    }
+
+    #[test]
+    fn test_write_hits() {
+        let path = "data/serine_peptidases_filtered/4cha.pdb";
+        let rotation = [[0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]];
+        let residues = vec![Some((b'B', 57)), Some((b'B', 102)), None, Some((b'C', 195))];
+        let hits = vec![
+            (0, MatchResult::new(path, 0, 1.0, residues.clone(), 0.0, (rotation, [0.0; 3]))),
+            (1, MatchResult::new("missing.pdb", 1, 1.0, residues, 0.0, (rotation, [0.0; 3]))),
+        ];
+        let dir = std::env::temp_dir().join(format!("folddisco_test_write_hits_{}", std::process::id()));
+        let dir = dir.to_string_lossy().to_string();
+        let num_written = write_hits(&dir, &hits, 0.0, &StructureFileFormat::PDB, |_, hit| {
+            try_read_structure_from_path(hit.id)
+        }, false).unwrap();
+        assert_eq!(num_written, 1);
+        let written = read_structure_from_path(&format!("{}/1_4cha.pdb", dir)).unwrap();
+        assert_eq!(written.num_residues, 3);
+        let original = read_structure_from_path(path).unwrap().select_residues(&[(b'B', 57)], 0.0);
+        let (before, after) = (original.atom_vector.get(0), written.atom_vector.get(0));
+        assert!((after.x + before.y).abs() < 1e-2 && (after.y - before.x).abs() < 1e-2);
+        assert!(Path::new(&format!("{}/top_hits.pdb", dir)).exists());
+        assert!(!Path::new(&format!("{}/2_missing.pdb", dir)).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
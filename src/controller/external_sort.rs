@@ -0,0 +1,255 @@
+// External sort of (hash, id) pairs for id mode indexing with bounded memory.
+// Pairs are buffered up to a memory budget, sorted and spilled to run files
+// ({prefix}.run_N). Runs are k-way merged and written directly as offset and
+// value files, so the whole hash_id_vec is never held in memory.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use rayon::prelude::*;
+
+use crate::controller::map::convert_hash_offsets_to_simplemap;
+use crate::geometry::core::HashType;
+use crate::index::header::{IndexFileHeader, IndexFileKind, HEADER_SIZE};
+use crate::index::stoplist::StopList;
+
+// u32 hash + u64 id in a run file
+const RUN_PAIR_SIZE: usize = 12;
+
+pub struct ExternalSorter {
+    prefix: String,
+    buffer: Vec<(u32, usize)>,
+    max_buffer_len: usize,
+    runs: Vec<String>,
+    pool: rayon::ThreadPool,
+}
+
+impl ExternalSorter {
+    // Half of max_memory goes to the pair buffer. The rest is left for hashing
+    // structures of a chunk and for the offset table.
+    pub fn new(prefix: &str, max_memory: usize, num_threads: usize) -> Self {
+        let max_buffer_len = (max_memory / 2 / std::mem::size_of::<(u32, usize)>()).max(1);
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .build()
+            .expect("Failed to build thread pool for sorting");
+        ExternalSorter {
+            prefix: prefix.to_string(),
+            buffer: Vec::new(),
+            max_buffer_len,
+            runs: Vec::new(),
+            pool,
+        }
+    }
+
+    pub fn extend(&mut self, pairs: Vec<(u32, usize)>) -> Result<(), Error> {
+        let mut pairs = pairs.into_iter();
+        loop {
+            let space = self.max_buffer_len - self.buffer.len();
+            self.buffer.extend(pairs.by_ref().take(space));
+            if self.buffer.len() < self.max_buffer_len {
+                return Ok(());
+            }
+            self.spill()?;
+        }
+    }
+
+    pub fn num_runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    fn sort_buffer(&mut self) {
+        let buffer = &mut self.buffer;
+        self.pool.install(|| buffer.par_sort_unstable());
+    }
+
+    fn spill(&mut self) -> Result<(), Error> {
+        self.sort_buffer();
+        let run_path = format!("{}.run_{}", self.prefix, self.runs.len());
+        let mut writer = BufWriter::new(File::create(&run_path)?);
+        for (hash, id) in self.buffer.drain(..) {
+            writer.write_all(&hash.to_le_bytes())?;
+            writer.write_all(&(id as u64).to_le_bytes())?;
+        }
+        writer.flush()?;
+        self.runs.push(run_path);
+        Ok(())
+    }
+
+    // Visit all pairs sorted by hash and then by id. Run files are removed afterwards.
+    pub fn merge<F: FnMut(u32, usize) -> Result<(), Error>>(mut self, mut visit: F) -> Result<(), Error> {
+        if self.runs.is_empty() {
+            // Everything fit in the buffer
+            self.sort_buffer();
+            for &(hash, id) in self.buffer.iter() {
+                visit(hash, id)?;
+            }
+            return Ok(());
+        }
+        if !self.buffer.is_empty() {
+            self.spill()?;
+        }
+        self.buffer = Vec::new();
+        let mut readers = Vec::with_capacity(self.runs.len());
+        for run_path in self.runs.iter() {
+            readers.push(BufReader::new(File::open(run_path)?));
+        }
+        let mut heap = BinaryHeap::with_capacity(readers.len());
+        for (run_index, reader) in readers.iter_mut().enumerate() {
+            if let Some(pair) = read_run_pair(reader)? {
+                heap.push(Reverse((pair, run_index)));
+            }
+        }
+        while let Some(Reverse(((hash, id), run_index))) = heap.pop() {
+            visit(hash, id)?;
+            if let Some(pair) = read_run_pair(&mut readers[run_index])? {
+                heap.push(Reverse((pair, run_index)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ExternalSorter {
+    fn drop(&mut self) {
+        for run_path in self.runs.iter() {
+            let _ = std::fs::remove_file(run_path);
+        }
+    }
+}
+
+fn read_run_pair(reader: &mut BufReader<File>) -> Result<Option<(u32, usize)>, Error> {
+    let mut bytes = [0u8; RUN_PAIR_SIZE];
+    match reader.read_exact(&mut bytes) {
+        Ok(()) => Ok(Some((
+            u32::from_le_bytes(bytes[..4].try_into().unwrap()),
+            u64::from_le_bytes(bytes[4..].try_into().unwrap()) as usize,
+        ))),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Merge sorted runs into {offset_path} and {value_path}. Same output as
+// convert_sorted_hash_vec_to_simplemap followed by dump_to_disk and write_id_values.
+// With max_doc_freq, posting lists of hashes found in more structures are dropped
+// and returned as a stop-list.
+pub fn write_sorted_pairs_to_index(
+    sorter: ExternalSorter, offset_path: &str, value_path: &str, value_bits: usize, hash_type: HashType,
+    max_doc_freq: Option<usize>,
+) -> Result<StopList, Error> {
+    let mut writer = BufWriter::new(File::create(value_path)?);
+    // Header is written after the payload checksum is known
+    writer.write_all(&[0u8; HEADER_SIZE])?;
+    let mut postings = PostingWriter {
+        writer, crc: flate2::Crc::new(), value_bits, max_doc_freq: max_doc_freq.unwrap_or(usize::MAX),
+        hash_offsets: Vec::new(), num_values: 0, run: Vec::new(), stop_list: StopList::new(),
+    };
+    sorter.merge(|hash, id| {
+        if postings.run.last().is_some_and(|&(last_hash, _)| last_hash != hash) {
+            postings.flush_run()?;
+        }
+        postings.run.push((hash, id));
+        Ok(())
+    })?;
+    postings.flush_run()?;
+    let PostingWriter { writer, crc, num_values, hash_offsets, stop_list, .. } = postings;
+    let mut header = IndexFileHeader::new(IndexFileKind::IdValue, hash_type, num_values, value_bits, &[]);
+    header.payload_size = num_values * value_bits / 8;
+    header.checksum = crc.sum();
+    let mut file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&header.to_bytes())?;
+    file.flush()?;
+
+    let offset_map = convert_hash_offsets_to_simplemap(&hash_offsets);
+    drop(hash_offsets);
+    offset_map.dump_to_disk(&PathBuf::from(offset_path), hash_type)?;
+    Ok(stop_list)
+}
+
+// Values of one hash are buffered until the next hash shows up
+struct PostingWriter {
+    writer: BufWriter<File>,
+    crc: flate2::Crc,
+    value_bits: usize,
+    max_doc_freq: usize,
+    hash_offsets: Vec<(u32, usize, usize)>,
+    num_values: usize,
+    run: Vec<(u32, usize)>,
+    stop_list: StopList,
+}
+
+impl PostingWriter {
+    fn flush_run(&mut self) -> Result<(), Error> {
+        let hash = match self.run.first() {
+            Some(&(hash, _)) => hash,
+            None => return Ok(()),
+        };
+        if self.run.len() > self.max_doc_freq {
+            self.stop_list.insert(hash, self.run.len());
+            self.run.clear();
+            return Ok(());
+        }
+        self.hash_offsets.push((hash, self.num_values, self.run.len()));
+        self.num_values += self.run.len();
+        for &(_, id) in self.run.iter() {
+            match self.value_bits {
+                16 => {
+                    let bytes = (id as u16).to_ne_bytes();
+                    self.crc.update(&bytes);
+                    self.writer.write_all(&bytes)?;
+                }
+                32 => {
+                    let bytes = (id as u32).to_ne_bytes();
+                    self.crc.update(&bytes);
+                    self.writer.write_all(&bytes)?;
+                }
+                _ => { panic!("Invalid number of bits"); }
+            }
+        }
+        self.run.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::io::read_id_values;
+    use crate::controller::map::{convert_sorted_hash_vec_to_simplemap, SimpleHashMap};
+
+    #[test]
+    fn test_external_sort_matches_in_memory() {
+        let pairs: Vec<(u32, usize)> = (0..5000usize).map(|i| (((i * 7919) % 613) as u32, i % 1000)).collect();
+        // Buffer of 100 pairs gives 50 runs
+        let mut sorter = ExternalSorter::new("data/external_sort_test", 100 * 2 * 16, 2);
+        for chunk in pairs.chunks(333) {
+            sorter.extend(chunk.to_vec()).unwrap();
+        }
+        assert_eq!(sorter.num_runs(), 50);
+        let stop_list = write_sorted_pairs_to_index(
+            sorter, "data/external_sort_test.offset", "data/external_sort_test.value", 16, HashType::PDBTrRosetta, None
+        ).unwrap();
+        assert!(stop_list.is_empty());
+        assert!(!PathBuf::from("data/external_sort_test.run_0").exists());
+
+        let mut sorted = pairs.clone();
+        sorted.sort_unstable();
+        let (expected_map, expected_values) = convert_sorted_hash_vec_to_simplemap(sorted);
+        let (offset_map, _offset_mmap) = SimpleHashMap::load_from_disk(&PathBuf::from("data/external_sort_test.offset"));
+        let offset_map = offset_map.unwrap();
+        let (_value_mmap, values) = read_id_values("data/external_sort_test.value", 16).unwrap();
+        let as_hashmap = |map: &SimpleHashMap| -> std::collections::HashMap<u32, (usize, usize)> {
+            map.iter().map(|(hash, value)| (hash, *value)).collect()
+        };
+        assert_eq!(as_hashmap(&offset_map), as_hashmap(&expected_map));
+        assert_eq!(values.iter().collect::<Vec<_>>(), expected_values);
+        drop(offset_map);
+        std::fs::remove_file("data/external_sort_test.offset").unwrap();
+        std::fs::remove_file("data/external_sort_test.value").unwrap();
+    }
+}
@@ -4,12 +4,14 @@
 // Copyright © 2024 Hyunbin Kim, All rights reserved
 
 use dashmap::DashMap;
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
 use std::fs::{File, OpenOptions};
-use std::io::{BufWriter, Write, Error};
+use std::io::{BufWriter, Read, Write, Error};
 use memmap2::Mmap;
 use crate::prelude::{log_msg, GeometricHash, HashType, FAIL};
+use crate::index::header::{parse_index_file, IndexFileHeader, IndexFileKind};
 use crate::structure::core::{CompactStructure, Structure};
+use crate::structure::io::archive::{read_archive_member, split_archive_path};
 use crate::{CIFReader, PDBReader};
 use std::mem::size_of;
 
@@ -299,11 +301,88 @@ pub fn read_u32_vector(path: &str)-> Result<(Mmap, &'static [u32]), Error> {
     Ok((mmap, vec))
 }
 
+// Value vector of id-mode index. Numeric ids are stored with 16 bits if the
+// index has up to 65536 structures and with 32 bits otherwise.
+// The width is recorded as value_bits in IndexConfig.
+#[derive(Clone, Copy, Debug)]
+pub enum IdValues<'a> {
+    U16(&'a [u16]),
+    U32(&'a [u32]),
+}
+
+impl<'a> IdValues<'a> {
+    pub fn len(&self) -> usize {
+        match self {
+            IdValues::U16(vec) => vec.len(),
+            IdValues::U32(vec) => vec.len(),
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    #[inline(always)]
+    pub fn get(&self, index: usize) -> usize {
+        match self {
+            IdValues::U16(vec) => vec[index] as usize,
+            IdValues::U32(vec) => vec[index] as usize,
+        }
+    }
+    pub fn get_values_with_offset(&self, offset: usize, length: usize) -> IdValues<'a> {
+        match self {
+            IdValues::U16(vec) => IdValues::U16(get_values_with_offset_u16(vec, offset, length)),
+            IdValues::U32(vec) => IdValues::U32(get_values_with_offset_u32(vec, offset, length)),
+        }
+    }
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = usize> + 'a {
+        let values = *self;
+        (0..values.len()).map(move |i| values.get(i))
+    }
+}
+
+pub fn id_value_bits(num_ids: usize) -> usize {
+    if num_ids <= u16::MAX as usize + 1 { 16 } else { 32 }
+}
+
+pub fn write_id_values(path: &str, vec: &[usize], value_bits: usize, hash_type: HashType) -> Result<(), Error> {
+    let payload: Vec<u8> = match value_bits {
+        16 => vec.iter().flat_map(|&x| (x as u16).to_ne_bytes()).collect(),
+        32 => vec.iter().flat_map(|&x| (x as u32).to_ne_bytes()).collect(),
+        _ => { panic!("Invalid number of bits"); }
+    };
+    let header = IndexFileHeader::new(IndexFileKind::IdValue, hash_type, vec.len(), value_bits, &payload);
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(&header.to_bytes())?;
+    writer.write_all(&payload)?;
+    writer.flush()
+}
+
+// value_bits is used for legacy files without header. Otherwise the width in the header is used.
+pub fn read_id_values(path: &str, value_bits: usize) -> Result<(Mmap, IdValues<'static>), Error> {
+    let file = File::open(path)?;
+    let mmap = unsafe { Mmap::map(&file)? };
+    let (header, payload_start) = parse_index_file(&mmap, IndexFileKind::IdValue)?;
+    let (value_bits, payload_size) = match header {
+        Some(header) => (header.element_bits, header.payload_size),
+        None => (value_bits, mmap.len()),
+    };
+    let payload = unsafe { mmap.as_ptr().add(payload_start) };
+    let values = match value_bits {
+        16 => IdValues::U16(unsafe {
+            std::slice::from_raw_parts(payload as *const u16, payload_size / size_of::<u16>())
+        }),
+        32 => IdValues::U32(unsafe {
+            std::slice::from_raw_parts(payload as *const u32, payload_size / size_of::<u32>())
+        }),
+        _ => { panic!("Invalid number of bits"); }
+    };
+    Ok((mmap, values))
+}
+
 pub fn read_compact_structure(path: &str) -> Result<(CompactStructure, bool), ()> {
     #[cfg(not(feature="foldcomp"))]
     let use_foldcomp = false;
     #[cfg(feature="foldcomp")]
-    let use_foldcomp = if path.contains(':') { true } else { false };
+    let use_foldcomp = if path.contains(':') && split_archive_path(path).is_none() { true } else { false };
 
     
     #[cfg(not(feature="foldcomp"))]
@@ -331,8 +410,27 @@ pub fn read_compact_structure(path: &str) -> Result<(CompactStructure, bool), ()
     Ok((compact_structure, use_foldcomp))
 }
 
+// Same as read_compact_structure but gives the reason instead of panicking
+pub fn try_read_compact_structure(path: &str) -> Result<(CompactStructure, bool), String> {
+    #[cfg(feature="foldcomp")]
+    if path.contains(':') && split_archive_path(path).is_none() {
+        let (db_path, id) = path.split_once(':').unwrap();
+        let foldcomp_db_reader = FoldcompDbReader::try_new(db_path)?;
+        return Ok((foldcomp_db_reader.read_single_structure(id)?.to_compact(), true));
+    }
+    Ok((try_read_structure_from_path(path)?.to_compact(), false))
+}
 
 pub fn read_structure_from_path(path: &str) -> Option<Structure> {
+    if let Some((file_path, chain)) = split_chain_path(path) {
+        return read_structure_from_path(file_path).map(|structure| structure.get_chain(chain));
+    }
+    if let Some((archive, member)) = split_archive_path(path) {
+        let structure = try_read_structure_from_archive(archive, member).unwrap_or_else(|_| panic!(
+            "{}", log_msg(FAIL, &format!("Failed to read {} from {}", member, archive))
+        ));
+        return Some(structure);
+    }
     if path.ends_with(".gz") {
         if path.ends_with(".pdb.gz") || path.ends_with(".ent.gz") {
             let reader = PDBReader::from_file(path).expect(
@@ -376,6 +474,117 @@ pub fn read_structure_from_path(path: &str) -> Option<Structure> {
     }
 }
 
+// Same as read_structure_from_path but gives the reason instead of panicking.
+// Parsers panic on some malformed lines, so panics are turned into errors as well.
+pub fn try_read_structure_from_path(path: &str) -> Result<Structure, String> {
+    if let Some((file_path, chain)) = split_chain_path(path) {
+        return try_read_structure_from_path(file_path).map(|structure| structure.get_chain(chain));
+    }
+    if let Some((archive, member)) = split_archive_path(path) {
+        return try_read_structure_from_archive(archive, member);
+    }
+    let stem = path.strip_suffix(".gz").unwrap_or(path);
+    let is_gz = stem.len() < path.len();
+    if stem.ends_with(".pdb") || stem.ends_with(".ent") {
+        catch_read_panic(|| {
+            let reader = PDBReader::from_file(path)?;
+            let structure = if is_gz { reader.read_structure_from_gz() } else { reader.read_structure() };
+            structure.map_err(|e| e.to_string())
+        })
+    } else if stem.ends_with(".cif") {
+        catch_read_panic(|| {
+            let reader = CIFReader::from_file(path)?;
+            let structure = if is_gz { reader.read_structure_from_gz() } else { reader.read_structure() };
+            structure.map_err(|e| e.to_string())
+        })
+    } else {
+        Err("Unknown file extension".to_string())
+    }
+}
+
+// Chains indexed separately are addressed as {path}_{chain}
+pub fn chain_path(path: &str, chain: u8) -> String {
+    format!("{}_{}", path, chain as char)
+}
+
+pub fn split_chain_path(path: &str) -> Option<(&str, u8)> {
+    let bytes = path.as_bytes();
+    if bytes.len() < 3 || bytes[bytes.len() - 2] != b'_' || !bytes[bytes.len() - 1].is_ascii() {
+        return None;
+    }
+    let file_path = &path[..path.len() - 2];
+    let stem = file_path.strip_suffix(".gz").unwrap_or(file_path);
+    if [".pdb", ".ent", ".cif"].iter().any(|ext| stem.ends_with(ext)) {
+        Some((file_path, bytes[bytes.len() - 1]))
+    } else {
+        None
+    }
+}
+
+// One entry per chain in the order chains appear. Structures with only a blank
+// chain stay as they are, and so do unreadable ones, which are reported while indexing.
+pub fn split_path_vec_by_chain(path_vec: &[String], num_threads: usize) -> Vec<String> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build()
+        .unwrap_or_else(|_| panic!("{}", log_msg(FAIL, "Failed to build thread pool for reading chains")));
+    let chains: Vec<Vec<u8>> = pool.install(|| {
+        path_vec.par_iter().map(|path| {
+            try_read_structure_from_path(path).map(|structure| structure.unique_chains()).unwrap_or_default()
+        }).collect()
+    });
+    path_vec.iter().zip(chains).flat_map(|(path, chains)| {
+        if chains.is_empty() || chains == [b' '] {
+            vec![path.clone()]
+        } else {
+            chains.into_iter().map(|chain| chain_path(path, chain)).collect()
+        }
+    }).collect()
+}
+
+// Member of a tar archive. Gzipped members are decompressed in memory
+pub fn try_read_structure_from_archive(archive: &str, member: &str) -> Result<Structure, String> {
+    let bytes = read_archive_member(archive, member).map_err(|e| e.to_string())?;
+    let stem = member.strip_suffix(".gz").unwrap_or(member);
+    let bytes = if stem.len() < member.len() {
+        let mut decoded = Vec::new();
+        flate2::read::MultiGzDecoder::new(&bytes[..]).read_to_end(&mut decoded).map_err(|e| e.to_string())?;
+        decoded
+    } else {
+        bytes
+    };
+    if stem.ends_with(".pdb") || stem.ends_with(".ent") {
+        catch_read_panic(|| PDBReader::from_bytes(&bytes).read_structure().map_err(|e| e.to_string()))
+    } else if stem.ends_with(".cif") {
+        catch_read_panic(|| CIFReader::from_bytes(&bytes).read_structure().map_err(|e| e.to_string()))
+    } else {
+        Err("Unknown file extension".to_string())
+    }
+}
+
+pub fn catch_read_panic<F: FnOnce() -> Result<Structure, String>>(read: F) -> Result<Structure, String> {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(read)) {
+        Ok(result) => result,
+        Err(payload) => {
+            let message = payload.downcast_ref::<&str>().map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or("unknown error".to_string());
+            Err(format!("Failed to parse structure: {}", message))
+        }
+    }
+}
+
+// Structures left out of an index. One line per structure with path and reason.
+// With append, lines are added to an existing report instead of replacing it
+pub fn write_skipped_report(path: &str, skipped: &[(usize, String, String)], append: bool) -> Result<(), Error> {
+    let file = OpenOptions::new().create(true).write(true).append(append).truncate(!append).open(path)?;
+    let mut writer = BufWriter::new(file);
+    for (_, pdb_path, reason) in skipped {
+        writeln!(writer, "{}\t{}", pdb_path, reason)?;
+    }
+    writer.flush()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -402,4 +611,22 @@ mod tests {
         let (_mmap, vec) = read_usize_vector("test_usize_vector_io.value").unwrap();
         assert_eq!(vec, &[1, 2, 3, 4, 5]);
     }
+    #[test]
+    fn test_id_values_io() {
+        let vec = vec![0, 65535, 65536, 1_000_000, 7];
+        assert_eq!(id_value_bits(65536), 16);
+        assert_eq!(id_value_bits(65537), 32);
+        write_id_values("data/test_id_values_io.value", &vec, 32, HashType::PDBTrRosetta).unwrap();
+        // Width in the header takes precedence
+        let (_mmap, values) = read_id_values("data/test_id_values_io.value", 16).unwrap();
+        assert_eq!(values.iter().collect::<Vec<_>>(), vec);
+        // Legacy value files without header
+        write_usize_vector_in_bits("data/test_id_values_io.value", &vec, 32).unwrap();
+        let (_mmap, values) = read_id_values("data/test_id_values_io.value", 32).unwrap();
+        assert_eq!(values.iter().collect::<Vec<_>>(), vec);
+        let values = values.get_values_with_offset(1, 3);
+        assert_eq!(values.len(), 3);
+        assert_eq!(values.get(2), 1_000_000);
+        std::fs::remove_file("data/test_id_values_io.value").unwrap();
+    }
 }
\ No newline at end of file
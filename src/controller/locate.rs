@@ -0,0 +1,121 @@
+// Where structures of an index are read from at query time. The location of
+// a structure is its lookup ID, or the line of {index}.paths for IDs that
+// are not paths. Locations under the structure root recorded in the index
+// config are moved to the root given with --structure-root, so that an index
+// keeps working after it or the structures are moved to another machine.
+
+use std::path::Path;
+
+use crate::cli::config::IndexConfig;
+use crate::controller::io::split_chain_path;
+use crate::index::lookup::load_locations_from_file;
+use crate::structure::io::StructureFileFormat;
+use crate::structure::io::archive::{archive_member_position, split_archive_path};
+
+pub struct StructureLocator {
+    locations: Option<Vec<String>>,
+    indexed_root: Option<String>,
+    structure_root: Option<String>,
+}
+
+impl StructureLocator {
+    pub fn new(locations: Option<Vec<String>>, indexed_root: Option<String>, structure_root: Option<String>) -> Self {
+        StructureLocator { locations, indexed_root, structure_root }
+    }
+
+    // Foldcomp DB entries are located by name. The DB itself is moved with --foldcomp-db
+    pub fn from_index(index_prefix: &str, config: &IndexConfig, structure_root: Option<&str>) -> Self {
+        let structure_root = match config.input_format {
+            StructureFileFormat::FCZDB => None,
+            _ => structure_root.map(|root| root.to_string()),
+        };
+        StructureLocator::new(
+            load_locations_from_file(&format!("{}.paths", index_prefix)),
+            config.structure_root.clone(), structure_root,
+        )
+    }
+
+    pub fn locate(&self, nid: usize, id: &str) -> String {
+        let location = match &self.locations {
+            Some(locations) => locations.get(nid).map(|location| location.as_str()).unwrap_or(id),
+            None => id,
+        };
+        match &self.structure_root {
+            Some(structure_root) => relocate_path(location, self.indexed_root.as_deref(), structure_root),
+            None => location.to_string(),
+        }
+    }
+}
+
+// File or archive that a location is read from exists
+pub fn location_exists(location: &str) -> bool {
+    let path = split_chain_path(location).map(|(path, _)| path).unwrap_or(location);
+    let path = split_archive_path(path).map(|(archive, _)| archive).unwrap_or(path);
+    Path::new(path).is_file()
+}
+
+// Archive of a location and the position of its member in it. None outside archives
+pub fn archive_read_position(location: &str) -> Option<(&str, u64)> {
+    let path = split_chain_path(location).map(|(path, _)| path).unwrap_or(location);
+    let (archive, member) = split_archive_path(path)?;
+    Some((archive, archive_member_position(archive, member)?))
+}
+
+// Replace indexed_root at the start of location with new_root. Locations outside
+// the root, or of indices without a recorded root, keep only their file name.
+// Archive members (ARCHIVE:MEMBER) and chains (PATH_A) are kept as they are.
+pub fn relocate_path(location: &str, indexed_root: Option<&str>, new_root: &str) -> String {
+    let new_root = new_root.trim_end_matches('/');
+    if let Some(indexed_root) = indexed_root {
+        if let Some(rest) = location.strip_prefix(indexed_root.trim_end_matches('/')) {
+            if rest.starts_with('/') || rest.starts_with(':') {
+                return format!("{}{}", new_root, rest);
+            }
+        }
+    }
+    match Path::new(location).file_name() {
+        Some(file_name) => format!("{}/{}", new_root, file_name.to_string_lossy()),
+        None => location.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relocate_path() {
+        let root = Some("data/serine_peptidases_filtered");
+        assert_eq!(
+            relocate_path("data/serine_peptidases_filtered/4cha.pdb", root, "/mnt/structures/"),
+            "/mnt/structures/4cha.pdb"
+        );
+        // Subdirectories and chains are kept
+        assert_eq!(
+            relocate_path("data/serine_peptidases_filtered/a/4cha.pdb_B", root, "/mnt/structures"),
+            "/mnt/structures/a/4cha.pdb_B"
+        );
+        // Archive members
+        assert_eq!(
+            relocate_path("/old/db.tar.gz:pdb/1abc.pdb.gz", Some("/old/db.tar.gz"), "/new/db.tar.gz"),
+            "/new/db.tar.gz:pdb/1abc.pdb.gz"
+        );
+        // Root must end at a path boundary
+        assert_eq!(
+            relocate_path("data/serine_peptidases_filtered_2/1abc.pdb", root, "/mnt/structures"),
+            "/mnt/structures/1abc.pdb"
+        );
+        assert_eq!(relocate_path("/old/dir/1abc.pdb", None, "/mnt/structures"), "/mnt/structures/1abc.pdb");
+
+        let locator = StructureLocator::new(
+            Some(vec!["/old/dir/1abc.pdb".to_string(), "/old/dir/2xyz.pdb".to_string()]),
+            Some("/old/dir".to_string()), None,
+        );
+        assert_eq!(locator.locate(1, "2XYZ"), "/old/dir/2xyz.pdb");
+        let locator = StructureLocator::new(None, Some("/old/dir".to_string()), Some("/new/dir".to_string()));
+        assert_eq!(locator.locate(0, "/old/dir/1abc.pdb"), "/new/dir/1abc.pdb");
+        assert!(location_exists("query/4CHA.pdb_B"));
+        assert!(!location_exists("/new/dir/1abc.pdb"));
+        assert_eq!(archive_read_position("query/4CHA.pdb_B"), None);
+    }
+}
@@ -1,12 +1,14 @@
 use std::fs::OpenOptions;
-use std::io::{BufWriter, Write};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
 use std::mem::{self, ManuallyDrop};
 use std::path::Path;
 use std::slice;
 use memmap2::Mmap;
 
+use crate::controller::io::IdValues;
 use crate::index::alloc::estimate_hash_size;
-use crate::prelude::GeometricHash;
+use crate::index::header::{parse_index_file, IndexFileHeader, IndexFileKind, HEADER_SIZE};
+use crate::prelude::{GeometricHash, HashType};
 
 // const INITIAL_CAPACITY: usize = 16;
 
@@ -166,7 +168,20 @@ impl SimpleHashMap {
             }
         }
     }
-    
+
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    // Iterate over (key, (offset, length)) in insertion order
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &(usize, usize))> {
+        self.keys.iter().cloned().zip(self.values.iter())
+    }
+
     fn estimate_file_size(&self) -> usize {
         let mut size = 0;
         size += mem::size_of::<usize>() * 2; // size and capacity
@@ -177,28 +192,28 @@ impl SimpleHashMap {
         size
     }
     
-    pub fn dump_to_disk(&self, path: &Path) -> std::io::Result<()> {
+    pub fn dump_to_disk(&self, path: &Path, hash_type: HashType) -> std::io::Result<()> {
         // If file exists, make a new file
         let file = OpenOptions::new().read(true).write(true).create(true).open(path)?;
-        file.set_len(self.estimate_file_size() as u64)?;
+        file.set_len((HEADER_SIZE + self.estimate_file_size()) as u64)?;
         let mut writer = BufWriter::new(file);
+        // Header is written after the payload as it holds the checksum
+        writer.write_all(&[0u8; HEADER_SIZE])?;
+        let mut crc = flate2::Crc::new();
         // Serialize and write metadata
-        writer.write_all(&self.size.to_le_bytes())?;
-        writer.write_all(&self.capacity.to_le_bytes())?;
+        let size_bytes = self.size.to_le_bytes();
+        let capacity_bytes = self.capacity.to_le_bytes();
 
         // Serialize and write values
         let values_size = self.values.len() * mem::size_of::<(usize, usize)>();
         let values_bytes = unsafe {
             slice::from_raw_parts(self.values.as_ptr() as *const u8, values_size)
         };
-        writer.write_all(values_bytes)?;
         // Serialize and write keys
         let keys_size = self.keys.len() * mem::size_of::<u32>();
         let keys_bytes = unsafe {
             slice::from_raw_parts(self.keys.as_ptr() as *const u8, keys_size)
         };
-        // Append keys to the file
-        writer.write_all(keys_bytes)?;
 
         // Serialize and write buckets. 
         let buckets_size = self.buckets.len() * mem::size_of::<u32>();
@@ -206,15 +221,28 @@ impl SimpleHashMap {
         let buckets_bytes = unsafe {
             slice::from_raw_parts(self.buckets.as_ptr() as *const u8, buckets_size)
         };
-        writer.write_all(buckets_bytes)?;
 
         // Serialize and write occupancy
         let occupancy_size = self.occupancy.bits.len();
         let occupancy_bytes = unsafe {
             slice::from_raw_parts(self.occupancy.bits.as_ptr() as *const u8, occupancy_size)
         };
-        writer.write_all(occupancy_bytes)?;
-        
+        let mut payload_size = 0usize;
+        for bytes in [&size_bytes[..], &capacity_bytes[..], values_bytes, keys_bytes, buckets_bytes, occupancy_bytes] {
+            writer.write_all(bytes)?;
+            crc.update(bytes);
+            payload_size += bytes.len();
+        }
+
+        let header = IndexFileHeader {
+            payload_size,
+            checksum: crc.sum(),
+            ..IndexFileHeader::new(IndexFileKind::IdOffset, hash_type, self.size, 32, &[])
+        };
+        let mut file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.set_len((HEADER_SIZE + payload_size) as u64)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&header.to_bytes())?;
         Ok(())
     }
 
@@ -222,7 +250,11 @@ impl SimpleHashMap {
         // Open as read-only
         let file = OpenOptions::new().read(true).write(true).open(path).expect("Failed to open file");
         let mmap = unsafe { Mmap::map(&file).expect("Failed to map file") };
-        let mut offset = 0usize;
+        // Legacy files without header start with the payload
+        let mut offset = match parse_index_file(&mmap, IndexFileKind::IdOffset) {
+            Ok((_, payload_start)) => payload_start,
+            Err(e) => return (Err(e), mmap),
+        };
         // Deserialize and read metadata
         let mut size_bytes = [0u8; mem::size_of::<usize>()];
         size_bytes.copy_from_slice(&mmap[offset..offset + mem::size_of::<usize>()]);
@@ -360,6 +392,32 @@ pub fn convert_sorted_hash_vec_to_simplemap(
     (offset_map, vec)
 }
 
+// Offset table from (hash, offset, length) collected while values were written in hash order
+pub fn convert_hash_offsets_to_simplemap(hash_offsets: &[(u32, usize, usize)]) -> SimpleHashMap {
+    // Same capacity as convert_sorted_hash_vec_to_simplemap, where estimate_hash_size
+    // counts one less than the number of distinct hashes, so that offset files are identical
+    let mut offset_map = SimpleHashMap::new((hash_offsets.len().saturating_sub(1) * 3).max(3));
+    for &(hash, offset, length) in hash_offsets {
+        offset_map.insert_u32(hash, (offset, length));
+    }
+    offset_map
+}
+
+// Inverse of convert_sorted_hash_vec_to_simplemap.
+// Numeric ids are passed through id_map; ids mapped to None are dropped.
+pub fn convert_simplemap_to_hash_vec<F: Fn(usize) -> Option<usize>>(
+    offset_map: &SimpleHashMap, value_vec: IdValues, id_map: F,
+) -> Vec<(u32, usize)> {
+    let mut hash_vec: Vec<(u32, usize)> = Vec::with_capacity(value_vec.len());
+    for (key, &(offset, length)) in offset_map.iter() {
+        for value in value_vec.get_values_with_offset(offset, length).iter() {
+            if let Some(id) = id_map(value) {
+                hash_vec.push((key, id));
+            }
+        }
+    }
+    hash_vec
+}
 
 
 #[cfg(test)]
@@ -383,7 +441,7 @@ mod tests {
         println!("MAP: {:?}", map);
         let path = PathBuf::from("hashmap.dat");
 
-        map.dump_to_disk(&path).expect("Failed to dump to disk");
+        map.dump_to_disk(&path, crate::prelude::HashType::PDBTrRosetta).expect("Failed to dump to disk");
         // Change the permissions of the file to allow read and write access
 
         let (loaded_map, mmap) = SimpleHashMap::load_from_disk(&path);
@@ -410,7 +468,7 @@ mod tests {
         let map = SimpleHashMap::_new_from_dashmap(std_map, test_size);
         let path = PathBuf::from("hashmap.dat");
 
-        map.dump_to_disk(&path).expect("Failed to dump to disk");
+        map.dump_to_disk(&path, crate::prelude::HashType::PDBTrRosetta).expect("Failed to dump to disk");
         let (loaded_map, mmap) = measure_time!(SimpleHashMap::load_from_disk(&path));
         if let Ok(loaded_map) = loaded_map {
             for i in 0..test_size {
@@ -441,4 +499,21 @@ mod tests {
         assert_eq!(map.get(&GeometricHash::from_u32(22345234u32, crate::prelude::HashType::PDBTrRosetta)), Some(&(200usize, 200usize)));
         assert_eq!(map.get(&GeometricHash::from_u32(3u32, crate::prelude::HashType::PDBTrRosetta)), None);
     }
+
+    #[test]
+    fn test_conversion_to_hash_vec() {
+        let sorted_vec = vec![(1u32, 0usize), (1, 2), (5, 1), (5, 2), (9, 0), (9, 3)];
+        let (map, values) = convert_sorted_hash_vec_to_simplemap(sorted_vec.clone());
+        let values = values.iter().map(|&x| x as u16).collect::<Vec<u16>>();
+        let mut hash_vec = convert_simplemap_to_hash_vec(&map, IdValues::U16(&values), Some);
+        hash_vec.sort();
+        assert_eq!(hash_vec, sorted_vec);
+        // Shift ids and drop id 2. 32-bit values behave the same
+        let values = values.iter().map(|&x| x as u32).collect::<Vec<u32>>();
+        let mut hash_vec = convert_simplemap_to_hash_vec(
+            &map, IdValues::U32(&values), |id| if id == 2 { None } else { Some(id + 10) }
+        );
+        hash_vec.sort();
+        assert_eq!(hash_vec, vec![(1u32, 10usize), (5, 11), (9, 10), (9, 13)]);
+    }
 }
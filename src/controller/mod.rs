@@ -13,22 +13,36 @@ pub mod query;
 pub mod result;
 pub mod retrieve;
 pub mod count_query;
+pub mod estimate;
+pub mod external_sort;
+pub mod locate;
 pub mod map;
 pub mod mode;
+pub mod search;
+pub mod export;
 
-use std::cell::UnsafeCell;
+use std::fmt;
 use std::io::Write;
-use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use feature::get_geometric_hash_as_u32_from_structure;
-use io::read_structure_from_path;
+use io::try_read_structure_from_path;
+#[cfg(feature = "foldcomp")]
+use io::catch_read_panic;
+use external_sort::ExternalSorter;
 use mode::IndexMode;
 // External imports
 use rayon::prelude::*;
 
+use crate::index::checkpoint::{
+    input_checksum, read_raw_array_into, read_skipped_list, write_raw_array, write_skipped_list,
+    IndexCheckpoint, IndexingPhase,
+};
+use crate::index::coords::{encode_record, CoordinateCacheWriter};
 use crate::index::indextable::FolddiscoIndex;
 // Internal imports
-use crate::PDBReader;
 use crate::geometry::core::HashType;
+use crate::structure::core::CompactStructure;
 use crate::utils::log::{ print_log_msg, log_msg, FAIL, WARN, INFO };
 
 #[cfg(feature = "foldcomp")]
@@ -42,6 +56,47 @@ const DEFAULT_DIST_CUTOFF: f32 = 20.0;
 
 // Module specific types
 pub type ResidueMatch = Option<(u8, u64)>;
+// Rotation and translation moving a matched motif onto the query (x_query = rot * x + tran)
+pub type Superposition = ([[f32; 3]; 3], [f32; 3]);
+// Matched residues in query order, RMSD and superposition of a match
+pub type MotifMatch = (Vec<ResidueMatch>, f32, Superposition);
+// Hashes, nres, pLDDT and coordinate cache record (empty without a cache) of a structure
+// or the reason it was skipped
+type HashedStructure = Result<(Vec<u32>, usize, f32, Vec<u8>), SkipReason>;
+
+// Reasons for leaving a structure out of an index
+#[derive(Debug, Clone, PartialEq)]
+pub enum SkipReason {
+    ReadFailure(String),
+    NoResidues,
+    MissingBackbone,
+    TooManyResidues(usize),
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SkipReason::ReadFailure(message) => write!(f, "{}", message),
+            SkipReason::NoResidues => write!(f, "No residues"),
+            SkipReason::MissingBackbone => write!(f, "No residue with N and CA atoms"),
+            SkipReason::TooManyResidues(nres) => write!(f, "Too many residues ({})", nres),
+        }
+    }
+}
+
+// Generation and time of the last checkpoint saved in an indexing phase
+struct CheckpointProgress {
+    generation: usize,
+    last_saved: Instant,
+}
+
+impl CheckpointProgress {
+    // Continue numbering from the last checkpoint so that it is never overwritten in place
+    fn start(output_path: &str) -> Self {
+        let generation = IndexCheckpoint::load(output_path).map_or(0, |checkpoint| checkpoint.generation);
+        CheckpointProgress { generation, last_saved: Instant::now() }
+    }
+}
 
 unsafe impl Send for FoldDisco {}
 unsafe impl Sync for FoldDisco {}
@@ -68,6 +123,16 @@ pub struct FoldDisco {
     #[cfg(feature = "foldcomp")]
     pub foldcomp_db_reader: FoldcompDbReader,
     pub is_foldcomp_enabled: bool,
+    pub id_offset: usize,
+    // Big mode only. Progress is saved after a chunk once the interval has passed
+    pub checkpoint_interval: Option<Duration>,
+    pub resume_checkpoint: Option<IndexCheckpoint>,
+    // Structures left out of the index: position in path_vec, path and reason
+    pub skipped: Vec<(usize, String, String)>,
+    // Stop at the first structure that can't be indexed
+    pub strict: bool,
+    // Coordinates of hashed structures are written here in id order if set
+    pub coord_cache_writer: Option<Mutex<CoordinateCacheWriter>>,
 }
 
 impl FoldDisco {
@@ -89,13 +154,19 @@ impl FoldDisco {
             max_residue: DEFAULT_MAX_RESIDUE,
             dist_cutoff: DEFAULT_DIST_CUTOFF,
             index_mode: IndexMode::Id,
-            fold_disco_index: FolddiscoIndex::new(0usize, String::new(), false),
+            fold_disco_index: FolddiscoIndex::new(0usize, String::new(), false, hash_type),
             foldcomp_db_path: String::new(),
             #[cfg(not(feature = "foldcomp"))]
             foldcomp_db_reader: false,
             #[cfg(feature = "foldcomp")]
             foldcomp_db_reader: FoldcompDbReader::empty(),
             is_foldcomp_enabled: false,
+            id_offset: 0,
+            checkpoint_interval: None,
+            resume_checkpoint: None,
+            skipped: Vec::new(),
+            strict: false,
+            coord_cache_writer: None,
         }
     }
 
@@ -125,13 +196,19 @@ impl FoldDisco {
             max_residue: DEFAULT_MAX_RESIDUE,
             dist_cutoff: dist_cutoff,
             index_mode: index_mode,
-            fold_disco_index: FolddiscoIndex::new(total_hashes, output_path.clone(), mmap_on_disk),
+            fold_disco_index: FolddiscoIndex::new(total_hashes, output_path.clone(), mmap_on_disk, hash_type),
             foldcomp_db_path: String::new(),
             #[cfg(not(feature = "foldcomp"))]
             foldcomp_db_reader: false,
             #[cfg(feature = "foldcomp")]
             foldcomp_db_reader: FoldcompDbReader::empty(),
             is_foldcomp_enabled: false,
+            id_offset: 0,
+            checkpoint_interval: None,
+            resume_checkpoint: None,
+            skipped: Vec::new(),
+            strict: false,
+            coord_cache_writer: None,
         }
     }
 
@@ -165,10 +242,16 @@ impl FoldDisco {
             max_residue: DEFAULT_MAX_RESIDUE,
             dist_cutoff: dist_cutoff,
             index_mode: index_mode,
-            fold_disco_index: FolddiscoIndex::new(total_hashes, output_path.clone(), mmap_on_disk),
+            fold_disco_index: FolddiscoIndex::new(total_hashes, output_path.clone(), mmap_on_disk, hash_type),
             foldcomp_db_path: foldcomp_db_path.to_string(),
             foldcomp_db_reader: foldcomp_db_reader,
             is_foldcomp_enabled: true,
+            id_offset: 0,
+            checkpoint_interval: None,
+            resume_checkpoint: None,
+            skipped: Vec::new(),
+            strict: false,
+            coord_cache_writer: None,
         }
     }
 
@@ -194,250 +277,427 @@ impl FoldDisco {
     pub fn set_max_residue(&mut self, max_residue: usize) {
         self.max_residue = max_residue;
     }
+    // Numeric ids start from id_offset. Used when appending to an existing index
+    pub fn set_id_offset(&mut self, id_offset: usize) {
+        self.id_offset = id_offset;
+    }
     
+    // Cache the coordinates of structures while they are hashed. Records are encoded
+    // from the structures read for hashing, so they are not read again
+    pub fn set_coordinate_cache_writer(&mut self, writer: CoordinateCacheWriter) {
+        self.coord_cache_writer = Some(Mutex::new(writer));
+    }
+
+    // Finish the coordinate cache once all structures are hashed. Hashing after this
+    // (e.g. adding entries in big mode) doesn't encode records
+    pub fn finish_coordinate_cache(&mut self) {
+        if let Some(writer) = self.coord_cache_writer.take() {
+            let writer = writer.into_inner().unwrap_or_else(|e| e.into_inner());
+            writer.finish().unwrap_or_else(|_| panic!("{}", log_msg(FAIL, "Failed to write coordinate cache")));
+        }
+    }
+
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    pub fn set_checkpoint_interval(&mut self, checkpoint_interval: Duration) {
+        self.checkpoint_interval = Some(checkpoint_interval);
+    }
+
+    // Load progress of an interrupted big mode run from {output_path}.checkpoint.
+    // Returns the phase to continue from. Inputs and hashing parameters should match.
+    pub fn resume_from_checkpoint(&mut self) -> Result<IndexingPhase, String> {
+        let checkpoint = IndexCheckpoint::load(&self.output_path).ok_or(
+            format!("No checkpoint found at {}.checkpoint", self.output_path)
+        )?;
+        if checkpoint.num_structures != self.path_vec.len() || checkpoint.input_checksum != input_checksum(&self.path_vec) {
+            return Err("Input structures differ from the interrupted run".to_string());
+        }
+        if checkpoint.hash_type != self.hash_type || checkpoint.num_bin_dist != self.num_bin_dist
+            || checkpoint.num_bin_angle != self.num_bin_angle {
+            return Err("Hashing parameters differ from the interrupted run".to_string());
+        }
+        let mut nres_vec = vec![0usize; self.path_vec.len()];
+        let mut plddt_vec = vec![0.0f32; self.path_vec.len()];
+        read_raw_array_into(&checkpoint.array_path(&self.output_path, "nres"), &mut nres_vec)
+            .and_then(|_| read_raw_array_into(&checkpoint.array_path(&self.output_path, "plddt"), &mut plddt_vec))
+            .and_then(|_| self.fold_disco_index.load_state(&checkpoint, &self.output_path))
+            .map_err(|e| format!("Failed to load checkpoint: {}", e))?;
+        self.skipped = read_skipped_list(&checkpoint.array_path(&self.output_path, "skipped"))
+            .map_err(|e| format!("Failed to load checkpoint: {}", e))?;
+        self.nres_vec = nres_vec;
+        self.plddt_vec = plddt_vec;
+        let phase = checkpoint.phase;
+        self.resume_checkpoint = Some(checkpoint);
+        Ok(phase)
+    }
+
+    pub fn remove_checkpoint(&self) {
+        if let Some(checkpoint) = IndexCheckpoint::load(&self.output_path) {
+            checkpoint.remove(&self.output_path);
+        }
+    }
+
+    // Chunk size of collect_and_count and add_entries. Kept from the checkpoint
+    // when resuming so that completed chunks stay the same.
+    fn indexing_chunk_size(&self) -> usize {
+        match &self.resume_checkpoint {
+            Some(checkpoint) => checkpoint.chunk_size,
+            None => self.num_threads * 128,
+        }
+    }
+
+    // Save progress if checkpointing is enabled and the interval has passed since the last one
+    fn checkpoint_if_due(
+        &self, phase: IndexingPhase, completed_chunks: usize,
+        nres_vec: &[usize], plddt_vec: &[f32], skipped: &[(usize, String, String)],
+        progress: &mut CheckpointProgress,
+    ) {
+        let interval = match self.checkpoint_interval {
+            Some(interval) => interval,
+            None => return,
+        };
+        if progress.last_saved.elapsed() < interval {
+            return;
+        }
+        let checkpoint = IndexCheckpoint {
+            phase,
+            completed_chunks,
+            chunk_size: self.indexing_chunk_size(),
+            num_structures: self.path_vec.len(),
+            input_checksum: input_checksum(&self.path_vec),
+            hash_type: self.hash_type,
+            num_bin_dist: self.num_bin_dist,
+            num_bin_angle: self.num_bin_angle,
+            generation: progress.generation + 1,
+        };
+        write_raw_array(&checkpoint.array_path(&self.output_path, "nres"), nres_vec)
+            .and_then(|_| write_raw_array(&checkpoint.array_path(&self.output_path, "plddt"), plddt_vec))
+            .and_then(|_| write_skipped_list(&checkpoint.array_path(&self.output_path, "skipped"), skipped))
+            .and_then(|_| self.fold_disco_index.save_state(&checkpoint, &self.output_path))
+            .unwrap_or_else(|_| panic!("{}", log_msg(FAIL, "Failed to save checkpoint")));
+        checkpoint.save(&self.output_path);
+        // Previous generation is not needed once the new one is referenced
+        IndexCheckpoint { generation: progress.generation, ..checkpoint.clone() }.remove_arrays(&self.output_path);
+        print_log_msg(INFO, &format!(
+            "Checkpoint saved ({} phase, {} chunks done)", phase, completed_chunks
+        ));
+        progress.generation = checkpoint.generation;
+        progress.last_saved = Instant::now();
+    }
+
     // Main methods
     pub fn fill_numeric_id_vec(&mut self) {
-        string_vec_to_numeric_id_vec(&self.path_vec, &mut self.numeric_id_vec);
+        string_vec_to_numeric_id_vec(&self.path_vec, &mut self.numeric_id_vec, self.id_offset);
     }
 
     pub fn collect_hash_vec(&mut self) { // THISONE
-        // Mutex free version
-        let shared_data = SharedData::new(self.path_vec.len());
-        
         // Set file threads
         let pool = rayon::ThreadPoolBuilder::new()
             .num_threads(self.num_threads)
             .build()
             .expect("Failed to build thread pool for iterating files");
         // For iterating files, apply multi-threading with num_threads_for_file
-
-        let collected: Vec<(u32, usize)> = pool.install(|| {
+        let hashed: Vec<HashedStructure> = pool.install(|| {
             // Preserve locality for multi-threading
-            self.path_vec
-                .par_iter()
-                .enumerate()
-                .map(|(pdb_pos, pdb_path)| {
-                    #[cfg(not(feature = "foldcomp"))]
-                    let compact = read_structure_from_path(pdb_path).expect(
-                        log_msg(FAIL, "Failed to read structure").as_str()
-                    );
-
-                    #[cfg(feature = "foldcomp")]
-                    let compact = if self.is_foldcomp_enabled {
-                        self.foldcomp_db_reader.read_single_structure(pdb_path).expect(
-                            log_msg(FAIL, "Failed to read structure").as_str()
-                        )
-                    } else {
-                        read_structure_from_path(pdb_path).expect(
-                            log_msg(FAIL, "Failed to read structure").as_str()
-                        )
-                    };  
-
-                    if compact.num_residues > self.max_residue {
-                        print_log_msg(WARN, &format!("{} has too many residues. Skipping", pdb_path));
-                        // skip this file
-                        // Drop intermediate variables
-                        drop(compact);
-                        return Vec::new();
-                    }
-                    let compact = compact.to_compact();
-                    // Directly write num_residues and avg_plddt to the vectors
-                    let nres = compact.num_residues;
-                    let plddt = compact.get_avg_plddt();
-                    // Mutex free version
-                    unsafe {
-                        let nres_vec = shared_data.get_nres_vec();
-                        let plddt_vec = shared_data.get_plddt_vec();
-                        let nres_vec = &mut *nres_vec.get();
-                        let plddt_vec = &mut *plddt_vec.get();
-                        nres_vec[pdb_pos] = nres;
-                        plddt_vec[pdb_pos] = plddt;
-                    }
+            self.path_vec.par_iter().map(|pdb_path| self.hash_structure(pdb_path)).collect()
+        });
+        let mut nres_vec = vec![0usize; self.path_vec.len()];
+        let mut plddt_vec = vec![0.0f32; self.path_vec.len()];
+        let mut skipped = std::mem::take(&mut self.skipped);
+        let mut next_id = self.id_offset;
+        self.hash_id_vec = self.assign_ids(
+            hashed, 0, &mut next_id, &mut nres_vec, &mut plddt_vec, &mut skipped
+        );
+        self.nres_vec = nres_vec;
+        self.plddt_vec = plddt_vec;
+        self.skipped = skipped;
+        drop(pool);
+    }
+    
+    // Hash the indexed structures again with another hash type and bins. Pairs are
+    // sorted by hash. Call after exclude_skipped so that ids match the lookup
+    pub fn collect_hash_vec_for(&self, hash_type: HashType, num_bin_dist: usize, num_bin_angle: usize) -> Vec<(u32, usize)> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.num_threads)
+            .build()
+            .expect("Failed to build thread pool for iterating files");
+        let mut hash_id_vec: Vec<(u32, usize)> = pool.install(|| {
+            self.path_vec.par_iter().enumerate().flat_map_iter(|(pos, pdb_path)| {
+                let compact = self.load_compact_structure(pdb_path).unwrap_or_else(|reason| {
+                    panic!("{}", log_msg(FAIL, &format!(
+                        "{} could be hashed before but not now: {}", pdb_path, reason
+                    )))
+                });
+                let mut hash_vec = get_geometric_hash_as_u32_from_structure(
+                    &compact, hash_type, num_bin_dist, num_bin_angle, self.dist_cutoff, &None,
+                );
+                hash_vec.sort_unstable();
+                hash_vec.dedup();
+                let id = self.id_offset + pos;
+                hash_vec.into_iter().map(move |hash| (hash, id))
+            }).collect()
+        });
+        pool.install(|| hash_id_vec.par_sort_unstable_by(|a, b| a.0.cmp(&b.0)));
+        drop(pool);
+        hash_id_vec
+    }
 
-                    let mut hash_vec = get_geometric_hash_as_u32_from_structure(
-                        &compact, self.hash_type,
-                        self.num_bin_dist, self.num_bin_angle,
-                        self.dist_cutoff, &self.multiple_bins,
-                    );
-                    // Drop intermediate variables
-                    drop(compact);
-
-                    // If remove_redundancy is true, remove duplicates
-                    hash_vec.sort_unstable();
-                    hash_vec.dedup();
-                    hash_vec.iter().map(|x| (*x, pdb_pos)).collect()
-                }).flatten().collect()
+    // Same as collect_hash_vec, but structures are hashed in chunks and the pairs are
+    // passed to the sorter, which spills sorted runs to disk to stay within its budget.
+    // hash_id_vec is left empty.
+    pub fn collect_hash_runs(&mut self, sorter: &mut ExternalSorter) {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.num_threads)
+            .build()
+            .expect("Failed to build thread pool for iterating files");
+        let chunk_size = self.indexing_chunk_size();
+        let mut nres_vec = vec![0usize; self.path_vec.len()];
+        let mut plddt_vec = vec![0.0f32; self.path_vec.len()];
+        let mut skipped = std::mem::take(&mut self.skipped);
+        let mut next_id = self.id_offset;
+        let chunked_paths = self.path_vec.chunks(chunk_size);
+        let total_chunks = chunked_paths.len();
+        chunked_paths.enumerate().for_each(|(chunk_index, chunk)| {
+            print_log_msg(INFO, &format!("Processing chunk {}/{}", chunk_index, total_chunks));
+            let hashed: Vec<HashedStructure> = pool.install(|| {
+                chunk.par_iter().map(|pdb_path| self.hash_structure(pdb_path)).collect()
             });
-        self.hash_id_vec = collected;
-        self.nres_vec = shared_data.get_nres_vec_clone();
-        self.plddt_vec = shared_data.get_plddt_vec_clone();
+            let collected = self.assign_ids(
+                hashed, chunk_index * chunk_size, &mut next_id,
+                &mut nres_vec, &mut plddt_vec, &mut skipped,
+            );
+            sorter.extend(collected).unwrap_or_else(|_| panic!(
+                "{}", log_msg(FAIL, "Failed to write sorted hashes")
+            ));
+        });
+        self.nres_vec = nres_vec;
+        self.plddt_vec = plddt_vec;
+        self.skipped = skipped;
         drop(pool);
     }
-    
+
     pub fn collect_and_count(&mut self) {
-        let shared_data = SharedData::new(self.path_vec.len());
+        // Resuming from a checkpoint skips completed chunks
+        let start_chunk = match &self.resume_checkpoint {
+            Some(checkpoint) if checkpoint.phase == IndexingPhase::Counting => checkpoint.completed_chunks,
+            Some(_) => return, // Counting was already finished
+            None => 0,
+        };
+        let chunk_size = self.indexing_chunk_size();
+        let (mut nres_vec, mut plddt_vec) = if self.resume_checkpoint.is_some() {
+            (std::mem::take(&mut self.nres_vec), std::mem::take(&mut self.plddt_vec))
+        } else {
+            (vec![0usize; self.path_vec.len()], vec![0.0f32; self.path_vec.len()])
+        };
+        let mut skipped = std::mem::take(&mut self.skipped);
+        let mut next_id = self.next_id_at(&nres_vec, start_chunk * chunk_size);
+        let mut progress = CheckpointProgress::start(&self.output_path);
         // Set file threads
         let pool = rayon::ThreadPoolBuilder::new()
             .num_threads(self.num_threads)
             .build()
             .expect("Failed to build thread pool for iterating hashes");
-        // For iterating files, apply multi-threading with num_threads_for_file
-        let chunk_size = self.num_threads * 128;
         // Chunk pdb paths
         let chunked_paths = self.path_vec.chunks(chunk_size);
         let total_chunks = chunked_paths.len();
-        chunked_paths.enumerate().for_each(|(chunk_index, chunk)| {
+        chunked_paths.enumerate().skip(start_chunk).for_each(|(chunk_index, chunk)| {
             // Print percentage of completion
             print_log_msg(INFO, &format!("Processing chunk {}/{}", chunk_index, total_chunks));
-            let collected: Vec<(u32, usize)> = pool.install(|| {
-                chunk
-                    .par_iter()
-                    .enumerate()
-                    .map(|(local_pos, pdb_path)| {
-                        let pdb_pos = chunk_index * chunk_size + local_pos;
-                        // let pdb_pos = self.path_vec.iter().position(|x| x == pdb_path).unwrap();
-
-                        #[cfg(not(feature = "foldcomp"))]
-                        let compact = read_structure_from_path(pdb_path).expect(
-                            log_msg(FAIL, "Failed to read structure").as_str()
-                        );
-                        #[cfg(feature = "foldcomp")]
-                        let compact = if self.is_foldcomp_enabled {
-                            self.foldcomp_db_reader.read_single_structure(pdb_path).expect(
-                                log_msg(FAIL, "Failed to read structure").as_str()
-                            )
-                        } else {
-                            read_structure_from_path(pdb_path).expect(
-                                log_msg(FAIL, "Failed to read structure").as_str()
-                            )
-                        };
-                        if compact.num_residues > self.max_residue {
-                            print_log_msg(WARN, &format!("{} has too many residues. Skipping", pdb_path));
-                            // skip this file
-                            // Drop intermediate variables
-                            drop(compact);
-                            return Vec::new();
-                        }
-                        let compact = compact.to_compact();
-                        // Directly write num_residues and avg_plddt to the vectors
-                        let nres = compact.num_residues;
-                        let plddt = compact.get_avg_plddt();
-                        // Mutex free version
-                        unsafe {
-                            let nres_vec = shared_data.get_nres_vec();
-                            let plddt_vec = shared_data.get_plddt_vec();
-                            let nres_vec = &mut *nres_vec.get();
-                            let plddt_vec = &mut *plddt_vec.get();
-                            nres_vec[pdb_pos] = nres;
-                            plddt_vec[pdb_pos] = plddt;
-                        }
-
-                        let mut hash_vec = get_geometric_hash_as_u32_from_structure(
-                            &compact, self.hash_type, 
-                            self.num_bin_dist, self.num_bin_angle,
-                            self.dist_cutoff, &self.multiple_bins,
-                        );
-                        // Drop intermediate variables
-                        drop(compact);
-                        // If remove_redundancy is true, remove duplicates
-
-                        hash_vec.sort_unstable();
-                        hash_vec.dedup();
-                        hash_vec.iter().map(|x| (x.clone(), pdb_pos)).collect()
-
-                    }).flatten().collect()
-                });
+            let hashed: Vec<HashedStructure> = pool.install(|| {
+                chunk.par_iter().map(|pdb_path| self.hash_structure(pdb_path)).collect()
+            });
+            let collected = self.assign_ids(
+                hashed, chunk_index * chunk_size, &mut next_id,
+                &mut nres_vec, &mut plddt_vec, &mut skipped,
+            );
             pool.install(|| {
                 (0..self.num_threads).into_par_iter().for_each(| tid | {
                     // Thread only saves hashes with same modulos
-                    let _ = &collected.iter().for_each(|(hash, pdb_pos)| {
+                    let _ = &collected.iter().for_each(|(hash, id)| {
                         if hash % self.num_threads as u32 == tid as u32 {
-                            self.fold_disco_index.count_single_entry(*hash, *pdb_pos);
+                            self.fold_disco_index.count_single_entry(*hash, *id);
                         }
                     });
                 });
             });
             drop(collected);
+            self.checkpoint_if_due(
+                IndexingPhase::Counting, chunk_index + 1, &nres_vec, &plddt_vec, &skipped, &mut progress,
+            );
         });
 
-        self.nres_vec = shared_data.get_nres_vec_clone();
-        self.plddt_vec = shared_data.get_plddt_vec_clone();
+        self.nres_vec = nres_vec;
+        self.plddt_vec = plddt_vec;
+        self.skipped = skipped;
         drop(pool);
     }
     
     pub fn add_entries(&mut self) {
+        let start_chunk = match &self.resume_checkpoint {
+            Some(checkpoint) if checkpoint.phase == IndexingPhase::Adding => checkpoint.completed_chunks,
+            _ => 0,
+        };
+        let chunk_size = self.indexing_chunk_size();
+        // Ids are given in the same way as collect_and_count. Skipped structures have no residues
+        let mut next_id = self.next_id_at(&self.nres_vec, start_chunk * chunk_size);
+        let mut progress = CheckpointProgress::start(&self.output_path);
         // Set file threads
         let pool = rayon::ThreadPoolBuilder::new()
             .num_threads(self.num_threads)
             .build()
             .expect("Failed to build thread pool for iterating hashes");
-        // For iterating files, apply multi-threading with num_threads_for_file
-        let chunk_size = self.num_threads * 128;
         // Chunk pdb paths
         let chunked_paths = self.path_vec.chunks(chunk_size);
         let total_chunks = chunked_paths.len();
-        chunked_paths.enumerate().for_each(|(chunk_index, chunk)| {
+        chunked_paths.enumerate().skip(start_chunk).for_each(|(chunk_index, chunk)| {
             print_log_msg(INFO, &format!("Processing chunk {}/{}", chunk_index, total_chunks));
-            let collected: Vec<(u32, usize)> = pool.install(|| {
-                chunk
-                    .par_iter()
-                    .enumerate()
-                    .map(|(local_pos, pdb_path)| {
-                        let pdb_pos = chunk_index * chunk_size + local_pos;
-
-                        #[cfg(not(feature = "foldcomp"))]
-                        let compact = read_structure_from_path(pdb_path).expect(
-                            log_msg(FAIL, "Failed to read structure").as_str()
-                        );
-
-                        #[cfg(feature = "foldcomp")]
-                        let compact = if self.is_foldcomp_enabled {
-                            self.foldcomp_db_reader.read_single_structure(pdb_path).expect(
-                                log_msg(FAIL, "Failed to read structure").as_str()
-                            )
-                        } else {
-                            read_structure_from_path(pdb_path).expect(
-                                log_msg(FAIL, "Failed to read structure").as_str()
-                            )
-                        };
-
-                        if compact.num_residues > self.max_residue {
-                            print_log_msg(WARN, &format!("{} has too many residues. Skipping", pdb_path));
-                            // skip this file
-                            // Drop intermediate variables
-                            drop(compact);
-                            return Vec::new();
-                        }
-                        let compact = compact.to_compact();
-                        // Directly write num_residues and avg_plddt to the vectors
-                        let mut hash_vec = get_geometric_hash_as_u32_from_structure(
-                            &compact, self.hash_type, self.num_bin_dist, self.num_bin_angle,
-                            self.dist_cutoff, &self.multiple_bins,
-                        );
-                        // Drop intermediate variables
-                        drop(compact);
-                        // If remove_redundancy is true, remove duplicates
-                        hash_vec.sort_unstable();
-                        hash_vec.dedup();
-                        hash_vec.iter().map(|x| (x.clone(), pdb_pos)).collect()
-
-                    }).flatten().collect()
-                });
+            let first_pos = chunk_index * chunk_size;
+            let hashed: Vec<Option<Vec<u32>>> = pool.install(|| {
+                chunk.par_iter().enumerate().map(|(local_pos, pdb_path)| {
+                    if self.nres_vec[first_pos + local_pos] == 0 {
+                        return None;
+                    }
+                    // Counts are already fixed, so a structure can't be skipped from here
+                    let (hash_vec, _, _, _) = self.hash_structure(pdb_path).unwrap_or_else(|reason| {
+                        panic!("{}", log_msg(FAIL, &format!(
+                            "{} could be read while counting but not while adding: {}", pdb_path, reason
+                        )))
+                    });
+                    Some(hash_vec)
+                }).collect()
+            });
+            let mut collected = Vec::new();
+            for hash_vec in hashed.into_iter().flatten() {
+                collected.extend(hash_vec.into_iter().map(|hash| (hash, next_id)));
+                next_id += 1;
+            }
             pool.install(|| {
                 (0..self.num_threads).into_par_iter().for_each(| tid | {
                     // Thread only saves hashes with same modulos
                     let mut bit_containers = Vec::with_capacity(8);
-                    let _ = &collected.iter().for_each(|(hash, pdb_pos)| {
+                    let _ = &collected.iter().for_each(|(hash, id)| {
                         if hash % self.num_threads as u32 == tid as u32 {
-                            self.fold_disco_index.add_single_entry(*hash, *pdb_pos, &mut bit_containers);
+                            self.fold_disco_index.add_single_entry(*hash, *id, &mut bit_containers);
                         }
                     });
                 });
             });
             drop(collected);
+            self.checkpoint_if_due(
+                IndexingPhase::Adding, chunk_index + 1, &self.nres_vec, &self.plddt_vec, &self.skipped, &mut progress,
+            );
         });
         drop(pool);
     }
+
+    // Read a structure for indexing. Structures that can't be hashed are reported with the reason
+    fn load_compact_structure(&self, pdb_path: &str) -> Result<CompactStructure, SkipReason> {
+        #[cfg(not(feature = "foldcomp"))]
+        let structure = try_read_structure_from_path(pdb_path);
+        #[cfg(feature = "foldcomp")]
+        let structure = if self.is_foldcomp_enabled {
+            catch_read_panic(|| self.foldcomp_db_reader.read_single_structure(pdb_path))
+        } else {
+            try_read_structure_from_path(pdb_path)
+        };
+        let structure = structure.map_err(SkipReason::ReadFailure)?;
+        if structure.num_atoms == 0 || structure.num_residues == 0 {
+            return Err(SkipReason::NoResidues);
+        }
+        if structure.num_residues > self.max_residue {
+            return Err(SkipReason::TooManyResidues(structure.num_residues));
+        }
+        let compact = structure.to_compact();
+        // Residues without N or CA are dropped in the compact structure
+        if compact.num_residues == 0 {
+            return Err(SkipReason::MissingBackbone);
+        }
+        Ok(compact)
+    }
+
+    fn hash_structure(&self, pdb_path: &str) -> HashedStructure {
+        let result = self.load_compact_structure(pdb_path).map(|compact| {
+            let nres = compact.num_residues;
+            let plddt = compact.get_avg_plddt();
+            let mut record = Vec::new();
+            if self.coord_cache_writer.is_some() {
+                encode_record(&compact, &mut record);
+            }
+            let mut hash_vec = get_geometric_hash_as_u32_from_structure(
+                &compact, self.hash_type,
+                self.num_bin_dist, self.num_bin_angle,
+                self.dist_cutoff, &self.multiple_bins,
+            );
+            // Drop intermediate variables
+            drop(compact);
+            // If remove_redundancy is true, remove duplicates
+            hash_vec.sort_unstable();
+            hash_vec.dedup();
+            (hash_vec, nres, plddt, record)
+        });
+        if let Err(reason) = &result {
+            if self.strict {
+                panic!("{}", log_msg(FAIL, &format!("Failed to index {}: {}", pdb_path, reason)));
+            }
+            print_log_msg(WARN, &format!("{} skipped: {}", pdb_path, reason));
+        }
+        result
+    }
+
+    // Give ids in input order to the structures that could be hashed, starting from next_id.
+    // nres and pLDDT are recorded at the position of the structure in path_vec, and
+    // coordinates are added to the cache in the same order.
+    fn assign_ids(
+        &self, hashed: Vec<HashedStructure>, first_pos: usize, next_id: &mut usize,
+        nres_vec: &mut [usize], plddt_vec: &mut [f32], skipped: &mut Vec<(usize, String, String)>,
+    ) -> Vec<(u32, usize)> {
+        let mut collected = Vec::with_capacity(
+            hashed.iter().map(|result| result.as_ref().map_or(0, |(hash_vec, _, _, _)| hash_vec.len())).sum()
+        );
+        for (local_pos, result) in hashed.into_iter().enumerate() {
+            let pdb_pos = first_pos + local_pos;
+            match result {
+                Ok((hash_vec, nres, plddt, record)) => {
+                    nres_vec[pdb_pos] = nres;
+                    plddt_vec[pdb_pos] = plddt;
+                    if let Some(writer) = &self.coord_cache_writer {
+                        writer.lock().unwrap().push_raw(&record).unwrap_or_else(|_| panic!(
+                            "{}", log_msg(FAIL, "Failed to write coordinate cache")
+                        ));
+                    }
+                    collected.extend(hash_vec.into_iter().map(|hash| (hash, *next_id)));
+                    *next_id += 1;
+                }
+                Err(reason) => {
+                    skipped.push((pdb_pos, self.path_vec[pdb_pos].clone(), reason.to_string()));
+                }
+            }
+        }
+        collected
+    }
+
+    // Id of the structure at pdb_pos. Structures before it with no residues were skipped
+    fn next_id_at(&self, nres_vec: &[usize], pdb_pos: usize) -> usize {
+        self.id_offset + nres_vec[..pdb_pos.min(nres_vec.len())].iter().filter(|&&nres| nres > 0).count()
+    }
+
+    // Remove skipped structures from path_vec, nres_vec and plddt_vec so that
+    // positions match the ids given while hashing. Call after hashing is done.
+    pub fn exclude_skipped(&mut self) {
+        if self.skipped.is_empty() {
+            return;
+        }
+        let mut is_skipped = vec![false; self.path_vec.len()];
+        self.skipped.iter().for_each(|(pdb_pos, _, _)| is_skipped[*pdb_pos] = true);
+        let mut keep = is_skipped.iter().map(|skipped| !skipped);
+        self.path_vec.retain(|_| keep.next().unwrap());
+        let mut keep = is_skipped.iter().map(|skipped| !skipped);
+        self.nres_vec.retain(|_| keep.next().unwrap());
+        let mut keep = is_skipped.iter().map(|skipped| !skipped);
+        self.plddt_vec.retain(|_| keep.next().unwrap());
+    }
     
     pub fn sort_hash_vec(&mut self) {
         let pool = rayon::ThreadPoolBuilder::new()
@@ -450,20 +710,6 @@ impl FoldDisco {
         drop(pool);
     }
     
-    pub fn get_allocation_size(&self) -> usize {
-        let mut allocation_size = 0usize;
-        self.path_vec.iter().for_each(|pdb_path| {
-            let pdb_reader = PDBReader::from_file(pdb_path).expect(
-            log_msg(FAIL, "PDB file not found").as_str()
-            );
-            let compact = pdb_reader.read_structure().expect(
-                log_msg(FAIL, "Failed to read structure").as_str()
-            );
-            allocation_size += compact.num_residues * (compact.num_residues - 1);
-        });
-        allocation_size
-    }
-
     pub fn save_id_vec(&self, path: &str) {
         // Save numeric_id_vec & path_vec as headerless tsv
         let mut file = std::fs::File::create(path).expect("Unable to create file");
@@ -476,9 +722,9 @@ impl FoldDisco {
     }
 }
 
-fn string_vec_to_numeric_id_vec(string_vec: &Vec<String>, numeric_id_vec: &mut Vec<usize>) {
+fn string_vec_to_numeric_id_vec(string_vec: &Vec<String>, numeric_id_vec: &mut Vec<usize>, id_offset: usize) {
     for i in 0..string_vec.len() {
-        numeric_id_vec.push(i);
+        numeric_id_vec.push(id_offset + i);
     }
 }
 
@@ -490,39 +736,3 @@ fn numeric_id_vec_from_string_vec(string_vec: &Vec<String>) -> Vec<usize> {
     }
     numeric_id_vec
 }
-
-struct SharedData {
-    nres_vec: Arc<UnsafeCell<Vec<usize>>>,
-    plddt_vec: Arc<UnsafeCell<Vec<f32>>>,
-}
-
-unsafe impl Sync for SharedData {}
-
-impl SharedData {
-    fn new(size: usize) -> Self {
-        SharedData {
-            nres_vec: Arc::new(UnsafeCell::new(vec![0; size])),
-            plddt_vec: Arc::new(UnsafeCell::new(vec![0.0; size])),
-        }
-    }
-
-    fn get_nres_vec(&self) -> &UnsafeCell<Vec<usize>> {
-        &self.nres_vec
-    }
-
-    fn get_plddt_vec(&self) -> &UnsafeCell<Vec<f32>> {
-        &self.plddt_vec
-    }
-    
-    fn get_nres_vec_clone(&self) -> Vec<usize> {
-        unsafe {
-            (*self.nres_vec.get()).clone()
-        }
-    }
-    
-    fn get_plddt_vec_clone(&self) -> Vec<f32> {
-        unsafe {
-            (*self.plddt_vec.get()).clone()
-        }
-    }
-}
\ No newline at end of file
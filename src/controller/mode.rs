@@ -3,6 +3,8 @@ use std::collections::HashSet;
 use std::fs;
 use std::path::Path;
 
+use crate::controller::io::split_chain_path;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum IdType {
     Pdb,
@@ -52,6 +54,10 @@ impl IdType {
             _ => Self::Other,
         }
     }
+    // IDs of these types are the paths structures were read from
+    pub fn is_path(&self) -> bool {
+        matches!(self, Self::AbsPath | Self::RelPath | Self::Other)
+    }
     pub fn to_u8(&self) -> u8 {
         match self {
             Self::Pdb => 0,
@@ -68,6 +74,10 @@ impl IdType {
 
 #[inline]
 pub fn parse_path_by_id_type(path: &str, id_type: &IdType) -> String {
+    // Chains indexed separately keep the chain after the id
+    if let Some((file_path, chain)) = split_chain_path(path) {
+        return format!("{}_{}", parse_path_by_id_type(file_path, id_type), chain as char);
+    }
     // TODO: 2024-04-04 15:07:54 Fill in this function to ease benchmarking
     let afdb_regex = regex::Regex::new(r"AF-.+-model_v\d").unwrap();
     match id_type {
@@ -127,6 +137,12 @@ pub fn parse_path_by_id_type(path: &str, id_type: &IdType) -> String {
 
 #[inline]
 pub fn parse_path_by_id_type_with_string(path: &str, id_type: &IdType, string: &mut String) {
+    if let Some((file_path, chain)) = split_chain_path(path) {
+        parse_path_by_id_type_with_string(file_path, id_type, string);
+        string.push('_');
+        string.push(chain as char);
+        return;
+    }
     // TODO: 2024-04-04 15:07:54 Fill in this function to ease benchmarking
     string.clear();
     let afdb_regex = regex::Regex::new(r"AF-.+-model_v\d").unwrap();
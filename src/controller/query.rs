@@ -11,7 +11,7 @@ use crate::prelude::{print_log_msg, INFO};
 use crate::utils::combination::CombinationIterator;
 use crate::utils::log::{log_msg, FAIL};
 use super::feature::get_single_feature;
-use super::io::read_compact_structure;
+use crate::structure::core::CompactStructure;
 
 pub fn parse_threshold_string(threshold_string: Option<String>) -> Vec<f32> {
     if threshold_string.is_none() {
@@ -163,14 +163,12 @@ fn expand_and_insert(
 }
 
 pub fn make_query_map(
-    path: &String, query_residues: &Vec<(u8, u64)>, hash_type: HashType, 
+    compact: &CompactStructure, query_residues: &Vec<(u8, u64)>, hash_type: HashType, 
     nbin_dist: usize, nbin_angle: usize, multiple_bin: &Option<Vec<(usize, usize)>>,
     dist_thresholds: &Vec<f32>, angle_thresholds: &Vec<f32>,
     amino_acid_substitutions: &Vec<Option<Vec<u8>>>, distance_cutoff: f32, serial_query: bool,
 ) -> (HashMap<GeometricHash, ((usize, usize), bool)>, Vec<usize>, HashMap<(u8, u8), Vec<(f32, usize)>>) {
 
-    let (compact, _) = read_compact_structure(path).expect("Failed to read compact structure");
-    
     let mut hash_collection = HashMap::new();
     let mut observed_distance_map: HashMap<(u8, u8), Vec<(f32, usize)>> = HashMap::new();
     
@@ -214,7 +212,7 @@ pub fn make_query_map(
             return;
         }
         let is_feature = get_single_feature(
-            indices[i], indices[j], &compact, hash_type, distance_cutoff, &mut feature
+            indices[i], indices[j], compact, hash_type, distance_cutoff, &mut feature
         );
         
         if is_feature {
@@ -270,12 +268,20 @@ pub fn make_query_map(
     (hash_collection, indices, observed_distance_map)
 }
 
-pub fn parse_query_string(query_string: &str, mut default_chain: u8) -> (Vec<(u8, u64)>, Vec<Option<Vec<u8>>>) {
+// Chain and residue number (e.g. "B57") of the query residues at the indices returned by make_query_map
+pub fn query_residue_labels(compact: &CompactStructure, query_indices: &[usize]) -> Vec<String> {
+    query_indices.iter().map(|&i| {
+        format!("{}{}", compact.chain_per_residue[i] as char, compact.residue_serial[i])
+    }).collect()
+}
+
+// Residues and amino acid substitutions of a query string. Malformed residues or ranges are errors
+pub fn parse_query_string(query_string: &str, mut default_chain: u8) -> Result<(Vec<(u8, u64)>, Vec<Option<Vec<u8>>>), String> {
     let mut query_residues = Vec::new();
     let mut amino_acid_substitutions = Vec::new();
 
     if query_string.is_empty() {
-        return (query_residues, amino_acid_substitutions);
+        return Ok((query_residues, amino_acid_substitutions));
     }
     if !default_chain.is_ascii_alphabetic() {
         default_chain = b'A';
@@ -308,21 +314,21 @@ pub fn parse_query_string(query_string: &str, mut default_chain: u8) -> (Vec<(u8
         };
 
         if range_part.contains('-') {
-            let (start_str, end_str) = range_part.split_once('-').expect("Invalid range");
-            let start = start_str.parse::<u64>().expect("Invalid start residue");
-            let end = end_str.parse::<u64>().expect("Invalid end residue");
+            let (start_str, end_str) = range_part.split_once('-').ok_or(format!("Invalid range: {}", segment))?;
+            let start = start_str.parse::<u64>().map_err(|_| format!("Invalid start residue: {}", segment))?;
+            let end = end_str.parse::<u64>().map_err(|_| format!("Invalid end residue: {}", segment))?;
             for r in start..=end {
                 query_residues.push((chain, r));
                 amino_acid_substitutions.push(subst_part.clone());
             }
         } else {
-            let residue_num = range_part.parse::<u64>().expect("Invalid residue");
+            let residue_num = range_part.parse::<u64>().map_err(|_| format!("Invalid residue: {}", segment))?;
             query_residues.push((chain, residue_num));
             amino_acid_substitutions.push(subst_part);
         }
     }
 
-    (query_residues, amino_acid_substitutions)
+    Ok((query_residues, amino_acid_substitutions))
 }
 
 
@@ -338,6 +344,11 @@ pub fn get_offset_value_lookup_type(index_path: String) -> (String, String, Stri
     (offset_path, value_path, lookup_path, hash_type_path)
 }
 
+// Offset and value tables of extra hash set k (from 1). Set 0 is the main table
+pub fn get_hash_set_offset_value(index_path: &str, hash_set: usize) -> (String, String) {
+    (format!("{}.hash{}.offset", index_path, hash_set), format!("{}.hash{}.value", index_path, hash_set))
+}
+
 pub fn check_and_get_indices(index_path: Option<String>, verbose: bool) -> Vec<String> {
     // Get path. formatting without quotation marks
     let index_path = index_path.unwrap();
@@ -370,6 +381,7 @@ pub fn check_and_get_indices(index_path: Option<String>, verbose: bool) -> Vec<S
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::controller::io::read_compact_structure;
     
     #[test]
     fn test_make_query_map() {
@@ -383,8 +395,9 @@ mod tests {
         //     (b'A', 250), (b'A', 232), (b'A', 269)
         // ];
         let hash_type = HashType::PDBTrRosetta;
+        let (compact, _) = read_compact_structure(&path).unwrap();
         let (hash_collection, _index_found, _observed_dist_map) = make_query_map(
-            &path, &query_residues, hash_type, 16, 4, &None,
+            &compact, &query_residues, hash_type, 16, 4, &None,
             &vec![0.0], &vec![0.0], &amino_acid_substitutions, 20.0, false
         );
         let hash_key = hash_collection.keys().cloned().collect::<Vec<GeometricHash>>();
@@ -405,42 +418,48 @@ mod tests {
     #[test]
     fn test_parse_query_string() {
         let query_string = "A250,B232,C269";
-        let query_residues = parse_query_string(query_string, b'A');
+        let query_residues = parse_query_string(query_string, b'A').unwrap();
         assert_eq!(query_residues, (vec![(b'A', 250), (b'B', 232), (b'C', 269)], vec![None, None, None]));
     }
     #[test]
     fn test_parse_query_string_with_space() {
         let query_string = "A250, A232, A269";
-        let query_residues = parse_query_string(query_string, b'A');
+        let query_residues = parse_query_string(query_string, b'A').unwrap();
         assert_eq!(query_residues, (vec![(b'A', 250), (b'A', 232), (b'A', 269)], vec![None, None, None]));
     }
     
     #[test]
     fn test_parse_query_string_with_space_and_no_chain() {
         let query_string = "250, 232, 269";
-        let query_residues = parse_query_string(query_string, b'A');
+        let query_residues = parse_query_string(query_string, b'A').unwrap();
         assert_eq!(query_residues, (vec![(b'A', 250), (b'A', 232), (b'A', 269)], vec![None, None, None]));
     }
 
     #[test]
     fn test_parse_query_string_with_aa_substitution() {
         let query_string = "A250:R,B232:K,C269:QK";
-        let query_residues = parse_query_string(query_string, b'A');
+        let query_residues = parse_query_string(query_string, b'A').unwrap();
         // R = 1, K = 11, Q = 5
         assert_eq!(query_residues, (vec![(b'A', 250), (b'B', 232), (b'C', 269)], vec![Some(vec![1]), Some(vec![11]), Some(vec![5, 11])]));
         let query_string = "250:R,232:K,269:QK";
-        let query_residues = parse_query_string(query_string, b'A');
+        let query_residues = parse_query_string(query_string, b'A').unwrap();
         // R = 1, K = 11, Q = 5
         assert_eq!(query_residues, (vec![(b'A', 250), (b'A', 232), (b'A', 269)], vec![Some(vec![1]), Some(vec![11]), Some(vec![5, 11])]));
     }
     #[test]
     fn test_parse_query_string_with_range() {
         let query_string = "A250-252,B232-234,C269:Q";
-        let query_residues = parse_query_string(query_string, b'A');
+        let query_residues = parse_query_string(query_string, b'A').unwrap();
         assert_eq!(query_residues, (vec![
             (b'A', 250), (b'A', 251), (b'A', 252), 
             (b'B', 232), (b'B', 233), (b'B', 234), 
             (b'C', 269),
         ], vec![None, None, None, None, None, None, Some(vec![5])]));
     }
+    #[test]
+    fn test_parse_query_string_invalid() {
+        assert!(parse_query_string("B5x", b'A').is_err());
+        assert!(parse_query_string("A250-2x2", b'A').is_err());
+        assert!(parse_query_string("A250,", b'A').is_err());
+    }
 }
\ No newline at end of file
@@ -5,14 +5,44 @@ use std::collections::HashSet;
 use std::io::Write;
 use rayon::slice::ParallelSliceMut;
 
-use crate::measure_time;
+use crate::{json_object, measure_time};
 use crate::prelude::{log_msg, print_log_msg, FAIL, INFO};
+use crate::utils::json::JsonValue;
 
-use super::ResidueMatch;
+use super::{MotifMatch, ResidueMatch, Superposition};
 
 
 pub const STRUCTURE_QUERY_RESULT_HEADER: &str = "id\tidf_score\ttotal_match_count\tnode_count\tedge_count\tmax_node_cov\tmin_rmsd\tnres\tplddt\tmatching_residues";
 pub const MATCH_QUERY_RESULT_HEADER: &str = "id\tnode_count\tidf_score\trmsd\tmatching_residues\tquery_residues";
+// Appended with --superposition. Per structure, the values of each match are separated by semicolons
+pub const SUPERPOSITION_HEADER: &str = "rotation\ttranslation";
+
+// Format of query results. Json is one array per query, Jsonl one object per line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Tsv,
+    Json,
+    Jsonl,
+}
+
+impl OutputFormat {
+    pub fn get_with_str(format: &str) -> Option<Self> {
+        match format {
+            "tsv" | "TSV" | "default" => Some(Self::Tsv),
+            "json" | "JSON" => Some(Self::Json),
+            "jsonl" | "JSONL" | "ndjson" => Some(Self::Jsonl),
+            _ => None,
+        }
+    }
+}
+
+// How each result record is written. Query residues label the matches in json
+#[derive(Debug, Clone, Copy)]
+pub struct ResultFormat<'a> {
+    pub output_format: OutputFormat,
+    pub query_residues: &'a [String],
+    pub superposition: bool,
+}
 
 pub struct StructureResult<'a> {
     pub id: &'a str,
@@ -25,8 +55,8 @@ pub struct StructureResult<'a> {
     pub plddt: f32,
     pub node_set: HashSet<usize>,
     pub edge_set: HashSet<(usize, usize)>,
-    pub matching_residues: Vec<(Vec<ResidueMatch>, f32)>, // Match with connected components
-    pub matching_residues_processed: Vec<(Vec<ResidueMatch>, f32)>, // Match with c-alpha distances
+    pub matching_residues: Vec<MotifMatch>, // Match with connected components
+    pub matching_residues_processed: Vec<MotifMatch>, // Match with c-alpha distances
     pub max_matching_node_count: usize,
     pub min_rmsd_with_max_match: f32,
 }
@@ -59,18 +89,18 @@ impl<'a> StructureResult<'a> {
         }
     }
     
-    pub fn into_match_query_results(&self, skip_ca_dist: bool) -> Vec<MatchResult> {
+    pub fn into_match_query_results(&self, skip_ca_dist: bool) -> Vec<MatchResult<'a>> {
         match skip_ca_dist {
-            false => self.matching_residues_processed.iter().enumerate().map(|(i, (residues, rmsd))| {
+            false => self.matching_residues_processed.iter().enumerate().map(|(i, (residues, rmsd, superposition))| {
                 // WARNING: NOTE: Thinking of getting the match specific idf by saving the idf for each edge
                 MatchResult::new(
-                    self.id, i, self.idf, residues.clone(), *rmsd
+                    self.id, i, self.idf, residues.clone(), *rmsd, *superposition
                 )
             }).collect(),
-            true => self.matching_residues.iter().enumerate().map(|(i, (residues, rmsd))| {
+            true => self.matching_residues.iter().enumerate().map(|(i, (residues, rmsd, superposition))| {
                 // WARNING: NOTE: Thinking of getting the match specific idf by saving the idf for each edge
                 MatchResult::new(
-                    self.id, i, self.idf, residues.clone(), *rmsd
+                    self.id, i, self.idf, residues.clone(), *rmsd, *superposition
                 )
             }).collect(),
         }
@@ -78,7 +108,7 @@ impl<'a> StructureResult<'a> {
 }
 
 pub fn convert_structure_query_result_to_match_query_results<'a>(
-    results: &'a [(usize, StructureResult<'a>)], skip_ca_dist: bool
+    results: &[(usize, StructureResult<'a>)], skip_ca_dist: bool
 ) -> Vec<(usize, MatchResult<'a>)> {
     results
         .iter()
@@ -98,7 +128,7 @@ impl<'a> fmt::Display for StructureResult<'a> {
             self.matching_residues_processed.iter().map(
                 // Only print score with 4 decimal places
                 // Join with comma
-                |(x, y)| format!("{}:{:.4}", x.iter().map(|x| {
+                |(x, y, _)| format!("{}:{:.4}", x.iter().map(|x| {
                     match x {
                         // Convert u8 to char
                         Some((a, b)) => format!("{}{}", *a as char, b),
@@ -123,7 +153,7 @@ impl<'a> fmt::Debug for StructureResult<'a> {
         } else {
             self.matching_residues_processed.iter().map(
                 // Only print score with 4 decimal places
-                |(x, y)| format!("{}:{:.4}", x.iter().map(|x| {
+                |(x, y, _)| format!("{}:{:.4}", x.iter().map(|x| {
                     match x {
                         Some((a, b)) => format!("{}{}", *a as char, b),
                         None => "_".to_string()
@@ -147,7 +177,7 @@ impl<'a> StructureResult<'a> {
         } else {
             self.matching_residues_processed.iter().map(
                 // Only print score with 4 decimal places
-                |(x, y)| format!("{}:{:.4}", x.iter().map(|x| {
+                |(x, y, _)| format!("{}:{:.4}", x.iter().map(|x| {
                     match x {
                         Some((a, b)) => format!("{}{}", *a as char, b),
                         None => "_".to_string()
@@ -171,11 +201,13 @@ pub struct MatchResult<'a> {
     pub idf: f32,
     pub matching_residues: Vec<ResidueMatch>,
     pub rmsd: f32,
+    pub superposition: Superposition,
 }
 
 impl<'a> MatchResult<'a> {
     pub fn new(
-        id: &'a str, nid: usize, avg_idf: f32, matching_residues: Vec<ResidueMatch>, rmsd: f32
+        id: &'a str, nid: usize, avg_idf: f32, matching_residues: Vec<ResidueMatch>, rmsd: f32,
+        superposition: Superposition,
     ) -> Self {
         //
         let node_count = matching_residues.iter().map(|x| {
@@ -191,8 +223,91 @@ impl<'a> MatchResult<'a> {
             idf: avg_idf,
             matching_residues,
             rmsd,
+            superposition,
+        }
+    }
+}
+
+// Matched residues paired with the query residues they were matched to.
+// Unmatched query residues have null targets
+fn residue_pairs_to_json(query_residues: &[String], matching_residues: &[ResidueMatch]) -> JsonValue {
+    JsonValue::Array(query_residues.iter().zip(matching_residues.iter()).map(|(query, target)| {
+        json_object!{
+            "query" => query.as_str(),
+            "target" => target.map(|(chain, serial)| format!("{}{}", chain as char, serial)),
+        }
+    }).collect())
+}
+
+// Rotation (row-major) and translation as comma separated values for tsv
+fn superposition_to_tsv((rot, tran): &Superposition) -> (String, String) {
+    let values = |row: &[f32]| row.iter().map(|x| format!("{:.4}", x)).collect::<Vec<_>>().join(",");
+    (values(&rot.concat()), values(tran))
+}
+
+fn superposition_to_json(match_json: JsonValue, (rot, tran): &Superposition) -> JsonValue {
+    match match_json {
+        JsonValue::Object(mut fields) => {
+            fields.push(("rotation".to_string(), JsonValue::from(rot.iter().map(|row| row.to_vec()).collect::<Vec<_>>())));
+            fields.push(("translation".to_string(), JsonValue::from(tran.to_vec())));
+            JsonValue::Object(fields)
+        }
+        match_json => match_json,
+    }
+}
+
+impl<'a> StructureResult<'a> {
+    // Rotations and translations of the matches in two columns
+    pub fn superpositions_to_tsv(&self) -> String {
+        let (rotations, translations): (Vec<String>, Vec<String>) = self.matching_residues_processed.iter()
+            .map(|(_, _, superposition)| superposition_to_tsv(superposition)).unzip();
+        if rotations.is_empty() {
+            "NA\tNA".to_string()
+        } else {
+            format!("{}\t{}", rotations.join(";"), translations.join(";"))
         }
     }
+
+    // query_residues are the labels (chain and serial) of the residues in the query, in match order.
+    // With superposition, each match has the rotation and translation moving it onto the query
+    pub fn to_json(&self, query_residues: &[String], superposition: bool) -> JsonValue {
+        json_object!{
+            "id" => self.id,
+            "idf_score" => self.idf,
+            "total_match_count" => self.total_match_count,
+            "node_count" => self.node_count,
+            "edge_count" => self.edge_count,
+            "max_node_cov" => self.max_matching_node_count,
+            "min_rmsd" => self.min_rmsd_with_max_match,
+            "nres" => self.nres,
+            "plddt" => self.plddt,
+            "matches" => JsonValue::Array(self.matching_residues_processed.iter().map(|(residues, rmsd, rotran)| {
+                let match_json = json_object!{
+                    "rmsd" => *rmsd,
+                    "residues" => residue_pairs_to_json(query_residues, residues),
+                };
+                if superposition { superposition_to_json(match_json, rotran) } else { match_json }
+            }).collect()),
+        }
+    }
+}
+
+impl<'a> MatchResult<'a> {
+    pub fn superposition_to_tsv(&self) -> String {
+        let (rotation, translation) = superposition_to_tsv(&self.superposition);
+        format!("{}\t{}", rotation, translation)
+    }
+
+    pub fn to_json(&self, query_residues: &[String], superposition: bool) -> JsonValue {
+        let match_json = json_object!{
+            "id" => self.id,
+            "node_count" => self.node_count,
+            "idf_score" => self.idf,
+            "rmsd" => self.rmsd,
+            "residues" => residue_pairs_to_json(query_residues, &self.matching_residues),
+        };
+        if superposition { superposition_to_json(match_json, &self.superposition) } else { match_json }
+    }
 }
 
 impl<'a> fmt::Display for MatchResult<'a> {
@@ -241,84 +356,63 @@ impl<'a> MatchResult<'a> {
     }
 }
 
+// Primary by max_matching_node_count, secondary by min_rmsd
+pub fn sort_structure_query_result_by_rmsd(results: &mut [(usize, StructureResult)]) {
+    results.par_sort_by(|a, b| {
+        if a.1.max_matching_node_count != b.1.max_matching_node_count {
+            b.1.max_matching_node_count.partial_cmp(&a.1.max_matching_node_count).unwrap()
+        } else {
+            a.1.min_rmsd_with_max_match.partial_cmp(&b.1.min_rmsd_with_max_match).unwrap()
+        }
+    });
+}
+
+// Primary by node_count, secondary by rmsd
+pub fn sort_match_query_result(results: &mut [(usize, MatchResult)]) {
+    results.par_sort_by(|a, b| {
+        if a.1.node_count != b.1.node_count {
+            b.1.node_count.partial_cmp(&a.1.node_count).unwrap()
+        } else {
+            a.1.rmsd.partial_cmp(&b.1.rmsd).unwrap()
+        }
+    });
+}
+
 pub fn sort_and_print_structure_query_result(
     results: &mut Vec<(usize, StructureResult)>, do_sort_by_rmsd: bool, 
-    output_path: &str, query_string: &str, header: bool, verbose: bool,
+    output_path: &str, query_string: &str, format: &ResultFormat, header: bool, verbose: bool,
 ) {
+    let ResultFormat { output_format, query_residues, superposition } = *format;
     if do_sort_by_rmsd {
         if verbose {
-            measure_time!(results.par_sort_by(|a, b| {
-                // Primary by max_matching_node_count, secondary by min_rmsd
-                if a.1.max_matching_node_count != b.1.max_matching_node_count {
-                    b.1.max_matching_node_count.partial_cmp(&a.1.max_matching_node_count).unwrap()
-                } else {
-                    a.1.min_rmsd_with_max_match.partial_cmp(&b.1.min_rmsd_with_max_match).unwrap()
-                }
-            }));
+            measure_time!(sort_structure_query_result_by_rmsd(results));
         } else {
-            results.par_sort_by(|a, b| {
-                // Primary by max_matching_node_count, secondary by min_rmsd
-                if a.1.max_matching_node_count != b.1.max_matching_node_count {
-                    b.1.max_matching_node_count.partial_cmp(&a.1.max_matching_node_count).unwrap()
-                } else {
-                    a.1.min_rmsd_with_max_match.partial_cmp(&b.1.min_rmsd_with_max_match).unwrap()
-                }
-            });
-        }
-    }
-
-    // If output path is not empty, write to file
-    if !output_path.is_empty() {
-        // Create file
-        let file = std::fs::File::create(&output_path).expect(
-            &log_msg(FAIL, &format!("Failed to create file: {}", &output_path))
-        );
-        let mut writer = std::io::BufWriter::new(file);
-        if header {
-            writer.write_all(format!("{}\n", STRUCTURE_QUERY_RESULT_HEADER).as_bytes()).expect(
-                &log_msg(FAIL, &format!("Failed to write to file: {}", &output_path))
-            );
-        }
-        for (_k, v) in results.iter() {
-            writer.write_all(format!("{:?}\t{}\n", v, query_string).as_bytes()).expect(
-                &log_msg(FAIL, &format!("Failed to write to file: {}", &output_path))
-            );
-        }
-    } else {
-        if header {
-            println!("{}", STRUCTURE_QUERY_RESULT_HEADER);
-        }
-        // let mut id_container = String::new();
-        for (_k, v) in results.iter() {
-            println!("{:?}\t{}", v, query_string);
+            sort_structure_query_result_by_rmsd(results);
         }
     }
+    let lines = results.iter().map(|(_k, v)| match output_format {
+        OutputFormat::Tsv if superposition => format!("{:?}\t{}\t{}", v, query_string, v.superpositions_to_tsv()),
+        OutputFormat::Tsv => format!("{:?}\t{}", v, query_string),
+        _ => with_query(v.to_json(query_residues, superposition), query_string).to_string(),
+    });
+    let header = header.then(|| match superposition {
+        true => format!("{}\t{}", STRUCTURE_QUERY_RESULT_HEADER, SUPERPOSITION_HEADER),
+        false => STRUCTURE_QUERY_RESULT_HEADER.to_string(),
+    });
+    write_query_result(lines, output_path, output_format, header.as_deref());
 }
 
 pub fn sort_and_print_match_query_result(
     results: &mut Vec<(usize, MatchResult)>, top_n: usize, 
-    output_path: &str, query_string: &str, header: bool, verbose: bool,
+    output_path: &str, query_string: &str, format: &ResultFormat, header: bool, verbose: bool,
     // do_sort_by_rmsd: bool, WARNING: not implemented yetf
 ) {
+    let ResultFormat { output_format, query_residues, superposition } = *format;
     // Sort query_count_vec by rmsd
     if verbose {
-        measure_time!(results.par_sort_by(|a, b| {
-            // Primary by max_matching_node_count, secondary by min_rmsd
-            if a.1.node_count != b.1.node_count {
-                b.1.node_count.partial_cmp(&a.1.node_count).unwrap()
-            } else {
-                a.1.rmsd.partial_cmp(&b.1.rmsd).unwrap()
-            }
-        }));
+        measure_time!(sort_match_query_result(results));
     } else {
-        results.par_sort_by(|a, b| {
-            // Primary by max_matching_node_count, secondary by min_rmsd
-            if a.1.node_count != b.1.node_count {
-                b.1.node_count.partial_cmp(&a.1.node_count).unwrap()
-            } else {
-                a.1.rmsd.partial_cmp(&b.1.rmsd).unwrap()
-            }
-        });
+        sort_match_query_result(results);
     }
     // Apply top N filter if top_n is not usize::MAX
     if top_n != usize::MAX {
@@ -327,33 +421,119 @@ pub fn sort_and_print_match_query_result(
         }
         results.truncate(top_n);
     }
+    let lines = results.iter().map(|(_k, v)| match output_format {
+        OutputFormat::Tsv if superposition => format!("{:?}\t{}\t{}", v, query_string, v.superposition_to_tsv()),
+        OutputFormat::Tsv => format!("{:?}\t{}", v, query_string),
+        _ => with_query(v.to_json(query_residues, superposition), query_string).to_string(),
+    });
+    let header = header.then(|| match superposition {
+        true => format!("{}\t{}", MATCH_QUERY_RESULT_HEADER, SUPERPOSITION_HEADER),
+        false => MATCH_QUERY_RESULT_HEADER.to_string(),
+    });
+    write_query_result(lines, output_path, output_format, header.as_deref());
+}
 
-    // If output path is not empty, write to file
-    if !output_path.is_empty() {
-        // Create file
-        let file = std::fs::File::create(&output_path).expect(
-            &log_msg(FAIL, &format!("Failed to create file: {}", &output_path))
-        );
-        let mut writer = std::io::BufWriter::new(file);
-        if header {
-            writer.write_all(format!("{}\n", MATCH_QUERY_RESULT_HEADER).as_bytes()).expect(
-                &log_msg(FAIL, &format!("Failed to write to file: {}", &output_path))
-            );
-        }
-        for (_k, v) in results.iter() {
-            writer.write_all(format!("{:?}\t{}\n", v, query_string).as_bytes()).expect(
-                &log_msg(FAIL, &format!("Failed to write to file: {}", &output_path))
-            );
+// Query as given by the user, added to each JSON record like the last column of the tsv output
+fn with_query(record: JsonValue, query_string: &str) -> JsonValue {
+    match record {
+        JsonValue::Object(mut fields) => {
+            fields.push(("query".to_string(), JsonValue::from(query_string)));
+            JsonValue::Object(fields)
         }
+        record => record,
+    }
+}
+
+// Write lines to the output file, or stdout if output path is empty. Header is only for tsv
+fn write_query_result<I: Iterator<Item = String>>(
+    lines: I, output_path: &str, output_format: OutputFormat, header: Option<&str>,
+) {
+    let mut writer: Box<dyn Write> = if output_path.is_empty() {
+        Box::new(std::io::BufWriter::new(std::io::stdout().lock()))
     } else {
-        if header {
-            println!("{}", MATCH_QUERY_RESULT_HEADER);
+        let file = std::fs::File::create(output_path).unwrap_or_else(|_| panic!(
+            "{}", log_msg(FAIL, &format!("Failed to create file: {}", output_path))
+        ));
+        Box::new(std::io::BufWriter::new(file))
+    };
+    let mut write = |text: &str| {
+        writer.write_all(text.as_bytes()).unwrap_or_else(|_| panic!(
+            "{}", log_msg(FAIL, &format!("Failed to write to file: {}", output_path))
+        ));
+    };
+    match output_format {
+        OutputFormat::Tsv => {
+            if let Some(header) = header {
+                write(&format!("{}\n", header));
+            }
+            lines.for_each(|line| write(&format!("{}\n", line)));
         }
-        // let mut id_container = String::new();
-        for (_k, v) in results.iter() {
-            println!("{:?}\t{}", v, query_string);
+        OutputFormat::Jsonl => lines.for_each(|line| write(&format!("{}\n", line))),
+        OutputFormat::Json => {
+            // One record per line inside the array
+            write("[");
+            for (i, line) in lines.enumerate() {
+                write(if i == 0 { "\n" } else { ",\n" });
+                write(&line);
+            }
+            write("\n]\n");
         }
     }
 }
 
-// TODO: Need testing
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::json::parse_json;
+
+    #[test]
+    fn test_print_query_result_as_json() {
+        let mut result = StructureResult::new("1abc.pdb", 0, 3, 3, 3, 2.5, 120, 90.0, &(0, 1));
+        result.max_matching_node_count = 3;
+        result.min_rmsd_with_max_match = 0.25;
+        let superposition = ([[0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]], [1.0, 2.0, 3.0]);
+        result.matching_residues_processed = vec![(vec![Some((b'A', 10)), None, Some((b'B', 7))], 0.25, superposition)];
+        let query_residues = vec!["A1".to_string(), "A2".to_string(), "A3".to_string()];
+        let mut results = vec![(0, result)];
+        let format = |output_format, superposition| ResultFormat {
+            output_format, query_residues: &query_residues, superposition,
+        };
+
+        let output_path = "data/test_query_result.json";
+        sort_and_print_structure_query_result(
+            &mut results, true, output_path, "A1,A2,A3", &format(OutputFormat::Json, false), true, false
+        );
+        let records = parse_json(&std::fs::read_to_string(output_path).unwrap()).unwrap();
+        let record = match &records { JsonValue::Array(records) => records[0].clone(), _ => panic!() };
+        assert_eq!(record.get("id").and_then(|x| x.as_str()), Some("1abc.pdb"));
+        assert_eq!(record.get("nres").and_then(|x| x.as_f64()), Some(120.0));
+        assert_eq!(record.get("query").and_then(|x| x.as_str()), Some("A1,A2,A3"));
+        let expected_match = parse_json(r#"{"rmsd": 0.25, "residues": [
+            {"query": "A1", "target": "A10"}, {"query": "A2", "target": null}, {"query": "A3", "target": "B7"}
+        ]}"#).unwrap();
+        assert_eq!(record.get("matches"), Some(&JsonValue::Array(vec![expected_match])));
+
+        let mut match_results = convert_structure_query_result_to_match_query_results(&results, false);
+        sort_and_print_match_query_result(
+            &mut match_results, usize::MAX, output_path, "A1,A2,A3", &format(OutputFormat::Jsonl, true), true, false
+        );
+        let lines = std::fs::read_to_string(output_path).unwrap();
+        assert_eq!(lines.lines().count(), 1);
+        let record = parse_json(lines.trim()).unwrap();
+        assert_eq!(record.get("rmsd").and_then(|x| x.as_f64()), Some(0.25));
+        assert_eq!(record.get("translation"), Some(&JsonValue::from(vec![1.0f32, 2.0, 3.0])));
+        assert_eq!(record.get("rotation").map(|x| x.to_string()), Some("[[0,-1,0],[1,0,0],[0,0,1]]".to_string()));
+
+        sort_and_print_match_query_result(
+            &mut match_results, usize::MAX, output_path, "A1,A2,A3", &format(OutputFormat::Tsv, true), true, false
+        );
+        let lines = std::fs::read_to_string(output_path).unwrap();
+        assert_eq!(lines.lines().next(), Some(format!("{}\t{}", MATCH_QUERY_RESULT_HEADER, SUPERPOSITION_HEADER).as_str()));
+        assert!(lines.lines().nth(1).unwrap().ends_with(
+            "A1,A2,A3\t0.0000,-1.0000,0.0000,1.0000,0.0000,0.0000,0.0000,0.0000,1.0000\t1.0000,2.0000,3.0000"
+        ));
+        assert_eq!(OutputFormat::get_with_str("jsonl"), Some(OutputFormat::Jsonl));
+        assert_eq!(OutputFormat::get_with_str("xml"), None);
+        std::fs::remove_file(output_path).unwrap();
+    }
+}
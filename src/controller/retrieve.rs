@@ -9,7 +9,7 @@ use crate::structure::{coordinate::Coordinate, core::CompactStructure, qcp::QCPS
 use crate::utils::combination::{CombinationIterator, CombinationVecIterator};
 use crate::controller::graph::{connected_components_with_given_node_count, create_index_graph};
 use crate::controller::feature::get_single_feature;
-use crate::controller::ResidueMatch;
+use crate::controller::{MotifMatch, ResidueMatch, Superposition};
 use crate::controller::io::read_structure_from_path;
 
 #[cfg(feature = "foldcomp")]
@@ -145,18 +145,27 @@ pub fn res_index_to_char(chain: u8, res_ind: u64) -> String {
     format!("{}{}", chain as char, res_ind)
 }
 
+// Structure to retrieve motifs from. Read from path unless it is given already,
+// e.g. from the coordinate cache
+pub struct RetrievalTarget<'a> {
+    pub path: &'a str,
+    pub structure: Option<CompactStructure>,
+}
+
 #[cfg(feature = "foldcomp")]
 pub fn retrieval_wrapper_for_foldcompdb(
-    path: &str, node_count: usize, query_vector: &Vec<GeometricHash>,
+    target: RetrievalTarget, node_count: usize, query_vector: &Vec<GeometricHash>,
     _hash_type: HashType, _nbin_dist: usize, _nbin_angle: usize,
     multiple_bin: &Option<Vec<(usize, usize)>>, dist_cutoff: f32,
     query_map: &HashMap<GeometricHash, ((usize, usize), bool)>,
     query_structure: &CompactStructure, all_query_indices: &Vec<usize>,
     aa_dist_map: &HashMap<(u8, u8), Vec<(f32, usize)>>,
     ca_distance_cutoff: f32, foldcomp_db_reader: &FoldcompDbReader,
-) -> (Vec<(Vec<ResidueMatch>, f32)>, Vec<(Vec<ResidueMatch>, f32)>, usize, f32) {
-    let compact = foldcomp_db_reader.read_single_structure(path).expect("Error reading structure from foldcomp db");
-    let compact = compact.to_compact();
+) -> (Vec<MotifMatch>, Vec<MotifMatch>, usize, f32) {
+    let compact = match target.structure {
+        Some(compact) => compact,
+        None => foldcomp_db_reader.read_single_structure(target.path).expect("Error reading structure from foldcomp db").to_compact(),
+    };
 
     // let mut indices_found: Vec<Vec<(usize, usize)>> = Vec::new();
     // Iterate over query vector and retrieve indices
@@ -197,7 +206,7 @@ pub fn retrieval_wrapper_for_foldcompdb(
     let connected = connected_components_with_given_node_count(&graph, node_count);
     
     // Parallel
-    let output: Vec<(Vec<ResidueMatch>, f32, Vec<ResidueMatch>, f32)>  = connected.par_iter().map(|component| {
+    let output: Vec<(MotifMatch, MotifMatch)> = connected.par_iter().map(|component| {
         // Filter graph to get subgraph with component
         let subgraph: Graph<usize, GeometricHash> = graph.filter_map(
             |node, _| {
@@ -278,28 +287,26 @@ pub fn retrieval_wrapper_for_foldcompdb(
             }
         });
 
-        let rmsd_from_hash = rmsd_for_matched(
+        let (rmsd_from_hash, superposition_from_hash) = superpose_matched(
             query_structure, &compact, &query_indices, &retrieved_indices
         );
         
-        let rmsd = if res_vec == res_vec_from_hash {
-            rmsd_from_hash
+        let (rmsd, superposition) = if res_vec == res_vec_from_hash {
+            (rmsd_from_hash, superposition_from_hash)
         } else {
-            rmsd_for_matched(
+            superpose_matched(
                 query_structure, &compact, &query_indices_scanned, &retrieved_indices_scanned
             )
         };
         
-        (res_vec_from_hash, rmsd_from_hash, res_vec, rmsd)
+        ((res_vec_from_hash, rmsd_from_hash, superposition_from_hash), (res_vec, rmsd, superposition))
     }).collect();
     // Split 
-    let (result_from_hash, result): (Vec<(Vec<ResidueMatch>, f32)>, Vec<(Vec<ResidueMatch>, f32)>) = output.into_iter().map(|(a, b, c, d)| {
-        ((a, b), (c, d))
-    }).unzip();
+    let (result_from_hash, result): (Vec<MotifMatch>, Vec<MotifMatch>) = output.into_iter().unzip();
     // In result, find the maximum matching node count and minimum RMSD with max match
     let mut max_matching_node_count = 0;
     let mut min_rmsd_with_max_match = 0.0;
-    result.iter().for_each(|(res_vec, rmsd)| {
+    result.iter().for_each(|(res_vec, rmsd, _)| {
         // Count number of Some in res_vec
         let count = res_vec.iter().filter(|&x| x.is_some()).count();
         if count > max_matching_node_count {
@@ -319,17 +326,19 @@ pub fn retrieval_wrapper_for_foldcompdb(
 // 2025-01-08 10:51:23 
 // Return a vector of ResidueMatch and RMSD values
 pub fn retrieval_wrapper(
-    path: &str, node_count: usize, query_vector: &Vec<GeometricHash>,
+    target: RetrievalTarget, node_count: usize, query_vector: &Vec<GeometricHash>,
     _hash_type: HashType, _nbin_dist: usize, _nbin_angle: usize, 
     multiple_bin: &Option<Vec<(usize, usize)>>, dist_cutoff: f32,
     query_map: &HashMap<GeometricHash, ((usize, usize), bool)>,
     query_structure: &CompactStructure, all_query_indices: &Vec<usize>,
     aa_dist_map: &HashMap<(u8, u8), Vec<(f32, usize)>>,
     ca_distance_cutoff: f32,
-) -> (Vec<(Vec<ResidueMatch>, f32)>, Vec<(Vec<ResidueMatch>, f32)>, usize, f32) {
+) -> (Vec<MotifMatch>, Vec<MotifMatch>, usize, f32) {
     // Load structure to retrieve motif
-    let compact = read_structure_from_path(&path).expect("Error reading structure from path");
-    let compact = compact.to_compact();
+    let compact = match target.structure {
+        Some(compact) => compact,
+        None => read_structure_from_path(target.path).expect("Error reading structure from path").to_compact(),
+    };
     // let mut indices_found: Vec<Vec<(usize, usize)>> = Vec::new();
     // Iterate over query vector and retrieve indices
     // Parallel
@@ -370,7 +379,7 @@ pub fn retrieval_wrapper(
     let connected = connected_components_with_given_node_count(&graph, node_count);
     
     // Parallel
-    let output: Vec<(Vec<ResidueMatch>, f32, Vec<ResidueMatch>, f32)>  = connected.par_iter().map(|component| {
+    let output: Vec<(MotifMatch, MotifMatch)> = connected.par_iter().map(|component| {
         // Filter graph to get subgraph with component
         let subgraph: Graph<usize, GeometricHash> = graph.filter_map(
             |node, _| {
@@ -450,28 +459,26 @@ pub fn retrieval_wrapper(
             }
         });
 
-        let rmsd_from_hash = rmsd_for_matched(
+        let (rmsd_from_hash, superposition_from_hash) = superpose_matched(
             query_structure, &compact, &query_indices, &retrieved_indices
         );
         
-        let rmsd = if res_vec == res_vec_from_hash {
-            rmsd_from_hash
+        let (rmsd, superposition) = if res_vec == res_vec_from_hash {
+            (rmsd_from_hash, superposition_from_hash)
         } else {
-            rmsd_for_matched(
+            superpose_matched(
                 query_structure, &compact, &query_indices_scanned, &retrieved_indices_scanned
             )
         };
         
-        (res_vec_from_hash, rmsd_from_hash, res_vec, rmsd)
+        ((res_vec_from_hash, rmsd_from_hash, superposition_from_hash), (res_vec, rmsd, superposition))
     }).collect();
     // Split 
-    let (result_from_hash, result): (Vec<(Vec<ResidueMatch>, f32)>, Vec<(Vec<ResidueMatch>, f32)>) = output.into_iter().map(|(a, b, c, d)| {
-        ((a, b), (c, d))
-    }).unzip();
+    let (result_from_hash, result): (Vec<MotifMatch>, Vec<MotifMatch>) = output.into_iter().unzip();
     // In result, find the maximum matching node count and minimum RMSD with max match
     let mut max_matching_node_count = 0;
     let mut min_rmsd_with_max_match = 0.0;
-    result.iter().for_each(|(res_vec, rmsd)| {
+    result.iter().for_each(|(res_vec, rmsd, _)| {
         // Count number of Some in res_vec
         let count = res_vec.iter().filter(|&x| x.is_some()).count();
         if count > max_matching_node_count {
@@ -598,6 +605,14 @@ pub fn rmsd_for_matched(
     compact1: &CompactStructure, compact2: &CompactStructure, 
     index1: &Vec<usize>, index2: &Vec<usize>
 ) -> f32 {
+    superpose_matched(compact1, compact2, index1, index2).0
+}
+
+// RMSD of the matched CA and CB atoms and the superposition moving compact2 onto compact1
+pub fn superpose_matched(
+    compact1: &CompactStructure, compact2: &CompactStructure, 
+    index1: &Vec<usize>, index2: &Vec<usize>
+) -> (f32, Superposition) {
     let mut qcp = QCPSuperimposer::new();
     
     let coord_vec1: Vec<Coordinate> = index1.iter().map(
@@ -610,12 +625,24 @@ pub fn rmsd_for_matched(
 
     qcp.set_atoms(&coord_vec1, &coord_vec2);
     qcp.run();
-    qcp.get_rms()
+    // QCP moves the first set onto the second. Inverse of it moves the match onto the query
+    let (rot, tran) = qcp.get_rotran();
+    let mut inv_rot = [[0.0; 3]; 3];
+    let mut inv_tran = [0.0; 3];
+    for i in 0..3 {
+        for j in 0..3 {
+            inv_rot[i][j] = rot[j][i];
+            inv_tran[i] -= rot[j][i] * tran[j];
+        }
+    }
+    (qcp.get_rms(), (inv_rot, inv_tran))
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::controller::io::read_compact_structure;
     use crate::controller::query::make_query_map;
+    use crate::structure::coordinate::CarbonCoordinateVector;
 
     use super::*;
 
@@ -623,15 +650,16 @@ mod tests {
     fn test_retrieval_wrapper() {
         let path = String::from("data/serine_peptidases_filtered/4cha.pdb");
         let query_string = "B57,B102,C195";
-        let (query_residues, aa_substitutions) = parse_query_string(query_string, b'A');
+        let (query_residues, aa_substitutions) = parse_query_string(query_string, b'A').unwrap();
         let hash_type = HashType::PDBTrRosetta;
         let nbin_dist = 16;
         let nbin_angle = 4;
         let dist_thresholds: Vec<f32> = vec![0.5,1.0];
         let angle_thresholds: Vec<f32> = vec![5.0,10.0];
         let dist_cutoff = 20.0;
+        let (query_compact, _) = read_compact_structure(&path).unwrap();
         let (query_map, query_indices, aa_dist_map ) = make_query_map(
-            &path, &query_residues, hash_type, nbin_dist, nbin_angle, &None,
+            &query_compact, &query_residues, hash_type, nbin_dist, nbin_angle, &None,
             &dist_thresholds, &angle_thresholds, &aa_substitutions, dist_cutoff, false
         );
         let queries: Vec<GeometricHash> = query_map.keys().cloned().collect();
@@ -639,10 +667,35 @@ mod tests {
         let compact = compact.to_compact();
         let new_path = String::from("data/serine_peptidases_filtered/4cha.pdb");
         let output = measure_time!(retrieval_wrapper(
-            &new_path, query_residues.len(), &queries, hash_type, nbin_dist, nbin_angle, &None,
-            dist_cutoff, &query_map, &compact, &query_indices, &aa_dist_map, 1.5,
+            RetrievalTarget { path: &new_path, structure: None }, query_residues.len(), &queries, hash_type,
+            nbin_dist, nbin_angle, &None, dist_cutoff, &query_map, &compact, &query_indices, &aa_dist_map, 1.5,
         ));
         println!("{:?}", output);
     }
 
+    #[test]
+    fn test_superpose_matched() {
+        let query = read_structure_from_path("data/serine_peptidases_filtered/4cha.pdb")
+            .expect("Error reading structure from path").to_compact();
+        // Copy rotated by 90 degrees around z and translated
+        let move_coord = |c: Coordinate| Coordinate::new(-c.y + 5.0, c.x - 3.0, c.z + 10.0);
+        let mut moved = query.clone();
+        moved.ca_vector = CarbonCoordinateVector::new();
+        moved.cb_vector = CarbonCoordinateVector::new();
+        for i in 0..query.num_residues {
+            moved.ca_vector.push(&move_coord(query.ca_vector.get_coord(i).unwrap()));
+            moved.cb_vector.push(&move_coord(query.cb_vector.get_coord(i).unwrap()));
+        }
+        let indices = vec![10, 40, 100, 150];
+        let (rmsd, (rot, tran)) = superpose_matched(&query, &moved, &indices, &indices);
+        assert!(rmsd < 1e-2);
+        for &i in &indices {
+            let c = moved.ca_vector.get_coord(i).unwrap().to_array();
+            let expected = query.ca_vector.get_coord(i).unwrap().to_array();
+            for k in 0..3 {
+                let superposed = rot[k][0] * c[0] + rot[k][1] * c[1] + rot[k][2] * c[2] + tran[k];
+                assert!((superposed - expected[k]).abs() < 1e-2, "{} != {}", superposed, expected[k]);
+            }
+        }
+    }
 }
\ No newline at end of file
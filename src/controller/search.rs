@@ -0,0 +1,868 @@
+// Library interface for searching motifs without the CLI. An Index loads
+// the tables of an index (all chunks) once and answers any number of
+// Queries built with QueryBuilder. Searches run on the current rayon pool,
+// so callers choose the threads with ThreadPool::install.
+//
+//     let index = Index::open("index/h_sapiens_folddisco")?;
+//     let query = QueryBuilder::new("query/4CHA.pdb").residues("B57,B102,C195").top(10).build()?;
+//     for (_nid, hit) in index.search_matches(&query) { println!("{}", hit); }
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use memmap2::Mmap;
+use rayon::prelude::*;
+
+use crate::cli::config::{try_read_index_config_from_file, IndexConfig};
+use crate::controller::count_query::{
+    add_document_frequency_bigmode, add_document_frequency_idmode, count_query_bigmode, count_query_idmode,
+    fuse_structure_results, CountOptions,
+};
+use crate::controller::filter::{MatchFilter, StructureFilter};
+use crate::controller::io::{read_id_values, try_read_compact_structure, try_read_structure_from_path, IdValues};
+use crate::controller::locate::{archive_read_position, location_exists, StructureLocator};
+use crate::controller::map::SimpleHashMap;
+use crate::controller::mode::IndexMode;
+use crate::controller::query::{
+    check_and_get_indices, get_hash_set_offset_value, get_offset_value_lookup_type, make_query_map,
+    parse_query_string, query_residue_labels,
+};
+use crate::controller::result::{
+    convert_structure_query_result_to_match_query_results, sort_match_query_result,
+    sort_structure_query_result_by_rmsd, MatchResult, StructureResult,
+};
+use crate::controller::retrieve::{retrieval_wrapper, RetrievalTarget};
+use crate::geometry::core::{GeometricHash, HashType};
+use crate::index::coords::CoordinateCache;
+use crate::index::header::check_index_files;
+use crate::index::indextable::{try_load_big_index, FolddiscoIndex};
+use crate::index::lookup::{try_load_lookup_table, LookupTable};
+use crate::index::stoplist::{try_load_stop_list_from_file, StopList};
+use crate::index::tombstone::{count_alive, try_load_tombstone_from_file};
+use crate::prelude::{print_log_msg, INFO, WARN};
+use crate::structure::core::{CompactStructure, Structure};
+use crate::structure::io::StructureFileFormat;
+
+#[cfg(feature = "foldcomp")]
+use crate::structure::io::fcz::FoldcompDbReader;
+
+pub const MIN_CONNECTED_COMPONENT_SIZE: usize = 2;
+
+// Extra hash set table: offset table, its mmap, value mmap, values and (hash type, dist bins, angle bins)
+type ExtraTable = (SimpleHashMap, Mmap, Mmap, IdValues<'static>, (HashType, usize, usize));
+
+// One chunk of an index with everything a query reads from it
+struct IndexChunk {
+    name: String,
+    config: IndexConfig,
+    offset_table: SimpleHashMap,
+    _offset_mmap: Mmap,
+    // Id mode values
+    values: Option<(Mmap, IdValues<'static>)>,
+    // Big mode index
+    big_index: Option<(FolddiscoIndex, Mmap)>,
+    lookup: LookupTable,
+    deleted: Option<Vec<bool>>,
+    stop_list: Option<StopList>,
+    coord_cache: Option<CoordinateCache>,
+    locator: StructureLocator,
+    extra_tables: Vec<ExtraTable>,
+}
+
+impl IndexChunk {
+    fn open(index_prefix: &str, structure_root: Option<&str>) -> Result<Self, String> {
+        if !Path::new(&format!("{}.offset", index_prefix)).is_file() || !Path::new(&format!("{}.type", index_prefix)).is_file() {
+            return Err(format!("Index not found: {}", index_prefix));
+        }
+        let (offset_path, value_path, _lookup_path, hash_type_path) = get_offset_value_lookup_type(index_prefix.to_string());
+        let config = try_read_index_config_from_file(&hash_type_path)?;
+        check_index_files(index_prefix, &config.mode, config.hash_type)
+            .map_err(|e| format!("Cannot read index {}: {}", index_prefix, e))?;
+        let (offset_table, offset_mmap, values, big_index) = match config.mode {
+            IndexMode::Id => {
+                let (offset_table, offset_mmap) = SimpleHashMap::load_from_disk(&PathBuf::from(&offset_path));
+                let offset_table = offset_table.map_err(|e| format!("Failed to load offset table {}: {}", offset_path, e))?;
+                let values = read_id_values(&value_path, config.value_bits)
+                    .map_err(|e| format!("Failed to load value vector {}: {}", value_path, e))?;
+                (offset_table, offset_mmap, Some(values), None)
+            }
+            IndexMode::Big => {
+                let anon_mmap = memmap2::MmapMut::map_anon(0).and_then(|mmap| mmap.make_read_only())
+                    .map_err(|e| format!("Failed to map memory: {}", e))?;
+                let big_index = try_load_big_index(index_prefix)
+                    .map_err(|e| format!("Failed to load big index {}: {}", index_prefix, e))?;
+                (SimpleHashMap::new(0), anon_mmap, None, Some(big_index))
+            }
+        };
+        let lookup = try_load_lookup_table(index_prefix)
+            .map_err(|e| format!("Failed to load lookup of {}: {}", index_prefix, e))?;
+        let deleted = try_load_tombstone_from_file(&format!("{}.deleted", index_prefix), lookup.len())
+            .map_err(|e| format!("Failed to load tombstones of {}: {}", index_prefix, e))?;
+        let stop_list = try_load_stop_list_from_file(&format!("{}.stop", index_prefix))
+            .map_err(|e| format!("Failed to load stop-list of {}: {}", index_prefix, e))?;
+        // Candidates are read from the coordinate cache if it matches the lookup
+        let coord_path = format!("{}.coords", index_prefix);
+        let coord_cache = match CoordinateCache::open(&coord_path) {
+            Ok(cache) if cache.len() == lookup.len() => Some(cache),
+            Ok(_) => {
+                print_log_msg(WARN, &format!("Coordinate cache {} does not match the lookup. Ignoring it", &coord_path));
+                None
+            }
+            Err(_) => None,
+        };
+        let locator = StructureLocator::from_index(index_prefix, &config, structure_root);
+        let mut extra_tables = Vec::with_capacity(config.extra_hash_sets.len());
+        for (k, hash_set) in config.extra_hash_sets.iter().enumerate() {
+            let (extra_offset_path, extra_value_path) = get_hash_set_offset_value(index_prefix, k + 1);
+            let (extra_table, extra_mmap) = SimpleHashMap::load_from_disk(&PathBuf::from(&extra_offset_path));
+            let extra_table = extra_table.map_err(|e| format!("Failed to load offset table {}: {}", extra_offset_path, e))?;
+            let (value_mmap, values) = read_id_values(&extra_value_path, config.value_bits)
+                .map_err(|e| format!("Failed to load value vector {}: {}", extra_value_path, e))?;
+            extra_tables.push((extra_table, extra_mmap, value_mmap, values, *hash_set));
+        }
+        Ok(IndexChunk {
+            name: index_prefix.to_string(), config, offset_table, _offset_mmap: offset_mmap, values, big_index, lookup, deleted, stop_list,
+            coord_cache, locator, extra_tables,
+        })
+    }
+
+    // Nid of the structure with the given id
+    fn contains(&self, nid: usize, id: &str) -> bool {
+        nid < self.lookup.len() && self.lookup.get(nid).0 == id
+    }
+}
+
+/// Index loaded for searching. All chunks of a chunked index are loaded and
+/// searched together with document frequencies over the whole index.
+pub struct Index {
+    name: String,
+    chunks: Vec<IndexChunk>,
+    #[cfg(feature = "foldcomp")]
+    foldcomp_db_reader: Option<FoldcompDbReader>,
+}
+
+impl Index {
+    /// Open the index at `index_path` (prefix of the index files)
+    pub fn open(index_path: &str) -> Result<Self, String> {
+        Index::open_with_locations(index_path, None, None)
+    }
+
+    /// Open an index whose structures were moved. Same as `--structure-root`
+    /// and `--foldcomp-db` of `folddisco query`
+    pub fn open_with_locations(
+        index_path: &str, structure_root: Option<&str>, foldcomp_db: Option<&str>,
+    ) -> Result<Self, String> {
+        let chunk_paths = check_and_get_indices(Some(index_path.to_string()), false);
+        let chunks = chunk_paths.par_iter().map(|prefix| IndexChunk::open(prefix, structure_root))
+            .collect::<Result<Vec<_>, String>>()?;
+        #[cfg(not(feature = "foldcomp"))]
+        if foldcomp_db.is_some() {
+            return Err("Built without Foldcomp support".to_string());
+        }
+        #[cfg(feature = "foldcomp")]
+        let foldcomp_db_reader = match chunks[0].config.input_format {
+            StructureFileFormat::FCZDB => foldcomp_db.map(|x| x.to_string()).or(chunks[0].config.foldcomp_db.clone())
+                .map(|path| FoldcompDbReader::try_new(path.as_str())).transpose()?,
+            _ => None,
+        };
+        Ok(Index {
+            name: index_path.to_string(),
+            chunks,
+            #[cfg(feature = "foldcomp")]
+            foldcomp_db_reader,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn num_chunks(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn num_structures(&self) -> usize {
+        self.chunks.iter().map(|chunk| chunk.lookup.len()).sum()
+    }
+
+    /// Config of the first chunk. Chunks of an index share hash type and bins
+    pub fn config(&self) -> &IndexConfig {
+        &self.chunks[0].config
+    }
+
+    /// Check that the structures of the index can be read for residue matching.
+    /// Fails with a hint to `--structure-root` if they were moved
+    pub fn check_structure_locations(&self) -> Result<(), String> {
+        for chunk in self.chunks.iter() {
+            if chunk.coord_cache.is_some() || chunk.config.input_format == StructureFileFormat::FCZDB {
+                continue;
+            }
+            if let Some((id, nid, _, _)) = chunk.lookup.iter().next() {
+                let location = chunk.locator.locate(nid, id);
+                if !location_exists(&location) {
+                    return Err(format!(
+                        "Structure {} of {} not found at {}. Use --structure-root if the structures were moved",
+                        id, self.name, location
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the structure of a result with its nid and id, e.g. to write hits
+    pub fn read_structure(&self, nid: usize, id: &str) -> Result<Structure, String> {
+        let chunk = self.chunks.iter().find(|chunk| chunk.contains(nid, id))
+            .ok_or(format!("{} is not in {}", id, self.name))?;
+        self.read_structure_at(&chunk.locator.locate(nid, id))
+    }
+
+    // Structure at a location given by the locator of a chunk
+    fn read_structure_at(&self, location: &str) -> Result<Structure, String> {
+        #[cfg(feature = "foldcomp")]
+        if let Some(foldcomp_db_reader) = &self.foldcomp_db_reader {
+            return foldcomp_db_reader.read_single_structure(location);
+        }
+        try_read_structure_from_path(location)
+    }
+
+    /// Structures matching the query with their nids. Sorted by RMSD, or by score with
+    /// `skip_match` or `sort_by_score`. Residue matching is skipped with `skip_match`
+    pub fn search_structures(&self, query: &Query) -> Vec<(usize, StructureResult<'_>)> {
+        let mut results = self.count_and_match(query);
+        if query.options.skip_match || query.options.sort_by_score {
+            results.par_sort_by(|a, b| b.1.idf.partial_cmp(&a.1.idf).unwrap());
+        } else {
+            sort_structure_query_result_by_rmsd(&mut results);
+        }
+        results
+    }
+
+    /// Matching motifs, one per match in a structure, with the nids of their structures.
+    /// Filtered with the connected node and RMSD cutoffs and limited to `top`
+    pub fn search_matches(&self, query: &Query) -> Vec<(usize, MatchResult<'_>)> {
+        let options = &query.options;
+        let results = self.count_and_match(query);
+        let match_filter = MatchFilter::new(
+            options.connected_node_count, options.connected_node_ratio, options.idf_score_cutoff,
+            options.rmsd_cutoff, query.residue_count(),
+        );
+        let mut match_results = convert_structure_query_result_to_match_query_results(&results, options.skip_ca_match);
+        match_results.retain(|(_, v)| match_filter.filter(v));
+        sort_match_query_result(&mut match_results);
+        match_results.truncate(options.top_n);
+        match_results
+    }
+
+    // Counting, structure filters and residue matching of all chunks
+    fn count_and_match(&self, query: &Query) -> Vec<(usize, StructureResult<'_>)> {
+        let options = &query.options;
+        // Query hashes for the hash type of each chunk and of its extra hash sets
+        let query_maps = self.chunks.par_iter().map(|chunk| {
+            let config = &chunk.config;
+            let primary = make_query_map(
+                &query.structure, &query.residues, config.hash_type, config.num_bin_dist, config.num_bin_angle,
+                &config.multiple_bin, &options.dist_thresholds, &options.angle_thresholds, &query.aa_substitutions,
+                config.grid_width, options.serial_query,
+            );
+            let extra = chunk.extra_tables.iter().map(|(_, _, _, _, (hash_type, num_bin_dist, num_bin_angle))| {
+                make_query_map(
+                    &query.structure, &query.residues, *hash_type, *num_bin_dist, *num_bin_angle,
+                    &None, &options.dist_thresholds, &options.angle_thresholds, &query.aa_substitutions,
+                    config.grid_width, options.serial_query,
+                ).0
+            }).collect::<Vec<_>>();
+            (primary, extra)
+        }).collect::<Vec<_>>();
+
+        // Document frequencies over all chunks as in `folddisco query`. One map per hash set
+        // (0 for the primary hash, k for extra set k) as sets can share a hash type with other bins
+        let mut global_doc_freqs: Vec<HashMap<GeometricHash, usize>> = vec![HashMap::new(); 1 + self.chunks[0].extra_tables.len()];
+        let mut global_num_structures = 0usize;
+        if self.chunks.len() > 1 {
+            for (chunk, ((pdb_query_map, _, _), extra_query_maps)) in self.chunks.iter().zip(query_maps.iter()) {
+                let pdb_query = pdb_query_map.keys().cloned().collect::<Vec<_>>();
+                let deleted = chunk.deleted.as_deref();
+                match (&chunk.values, &chunk.big_index) {
+                    (Some((_, values)), _) => add_document_frequency_idmode(
+                        &pdb_query, &chunk.offset_table, *values, deleted, &mut global_doc_freqs[0]
+                    ),
+                    (None, Some((big_index, _))) => add_document_frequency_bigmode(
+                        &pdb_query, big_index, deleted, &mut global_doc_freqs[0]
+                    ),
+                    (None, None) => unreachable!(),
+                }
+                for (k, ((extra_table, _, _, extra_values, _), extra_query_map)) in chunk.extra_tables.iter().zip(extra_query_maps.iter()).enumerate() {
+                    let extra_query = extra_query_map.keys().cloned().collect::<Vec<_>>();
+                    add_document_frequency_idmode(&extra_query, extra_table, *extra_values, deleted, &mut global_doc_freqs[k + 1]);
+                }
+                // Removed structures are left out of N as well as of the document frequencies
+                global_num_structures += count_alive(deleted, chunk.lookup.len());
+            }
+        }
+        let global_doc_freq = |set: usize| (self.chunks.len() > 1).then_some((&global_doc_freqs[set], global_num_structures));
+
+        let residue_count = query.residue_count();
+        let structure_filter = StructureFilter::new(
+            options.total_match_count, options.covered_node_count, options.covered_node_ratio,
+            options.covered_edge_count, options.covered_edge_ratio, options.idf_score_cutoff, options.num_res_cutoff,
+            options.plddt_cutoff, options.max_matching_node_count, options.max_matching_node_ratio, options.rmsd_cutoff,
+            residue_count, residue_count * residue_count.saturating_sub(1),
+        );
+        self.chunks.par_iter().zip(query_maps.par_iter()).map(
+            |(chunk, ((pdb_query_map, query_indices, aa_dist_map), extra_query_maps))| {
+                let config = &chunk.config;
+                let pdb_query = pdb_query_map.keys().cloned().collect::<Vec<_>>();
+                if let Some(stop_list) = &chunk.stop_list {
+                    report_stopped_query_hashes(pdb_query_map, stop_list, &query.structure, &chunk.name);
+                }
+                let count_options = |set: usize| CountOptions {
+                    deleted: chunk.deleted.as_deref(),
+                    global_doc_freq: global_doc_freq(set),
+                    sampling_ratio: options.sampling_ratio,
+                    sampling_count: options.sampling_count,
+                    freq_filter: options.freq_filter,
+                    length_penalty_power: options.length_penalty,
+                };
+                let query_count_map = match (&chunk.values, &chunk.big_index) {
+                    (Some((_, values)), _) => count_query_idmode(
+                        &pdb_query, pdb_query_map, &chunk.offset_table, *values, &chunk.lookup, count_options(0),
+                    ),
+                    (None, Some((big_index, _))) => count_query_bigmode(
+                        &pdb_query, pdb_query_map, big_index, &chunk.lookup, count_options(0),
+                    ),
+                    (None, None) => unreachable!(),
+                };
+                // Scores of the extra hash sets are added to the scores of the primary one
+                for (k, ((extra_table, _, _, extra_values, (extra_hash_type, _, _)), extra_query_map)) in chunk.extra_tables.iter().zip(extra_query_maps.iter()).enumerate() {
+                    let extra_query = extra_query_map.keys().cloned().collect::<Vec<_>>();
+                    let extra_count_map = count_query_idmode(
+                        &extra_query, extra_query_map, extra_table, *extra_values, &chunk.lookup, count_options(k + 1),
+                    );
+                    if options.verbose {
+                        print_log_msg(INFO, &format!("Found {} structures with {}", extra_count_map.len(), extra_hash_type.to_string()));
+                    }
+                    fuse_structure_results(&query_count_map, extra_count_map);
+                }
+                let mut query_count_vec: Vec<(usize, StructureResult)> = query_count_map.into_par_iter().filter(
+                    |(_, v)| structure_filter.filter_before_matching(v)
+                ).collect();
+                if options.verbose {
+                    print_log_msg(INFO, &format!("Found {} structures from inverted index of {}", query_count_vec.len(), chunk.name));
+                }
+                query_count_vec.par_sort_by(|a, b| b.1.idf.partial_cmp(&a.1.idf).unwrap());
+                query_count_vec.truncate(options.top_n);
+                if options.skip_match {
+                    return query_count_vec;
+                }
+                // Candidates in an archive are read in archive order. A gzipped archive is decompressed
+                // again from the start for a member before the last one read. Results are sorted later
+                if chunk.coord_cache.is_none() {
+                    query_count_vec.sort_by_cached_key(|(_, v)| {
+                        archive_read_position(&chunk.locator.locate(v.nid, v.id))
+                            .map(|(archive, position)| (archive.to_string(), position))
+                    });
+                }
+                // Candidates are read here so that one that can't be read is left out with a warning
+                // instead of failing the whole search
+                let unreadable = query_count_vec.par_iter_mut().filter_map(|(nid, v)| {
+                    let location = chunk.locator.locate(v.nid, v.id);
+                    let target = match chunk.coord_cache.as_ref().and_then(|cache| cache.get(v.nid)) {
+                        Some(cached) => cached,
+                        None => match self.read_structure_at(&location) {
+                            Ok(structure) => structure.to_compact(),
+                            Err(e) => {
+                                print_log_msg(WARN, &format!("Skipping {} of {}: {}", v.id, chunk.name, e));
+                                return Some(*nid);
+                            }
+                        },
+                    };
+                    let retrieval_result = retrieval_wrapper(
+                        RetrievalTarget { path: &location, structure: Some(target) }, MIN_CONNECTED_COMPONENT_SIZE,
+                        &pdb_query, config.hash_type, config.num_bin_dist, config.num_bin_angle, &config.multiple_bin,
+                        config.grid_width, pdb_query_map, &query.structure, query_indices, aa_dist_map,
+                        options.ca_dist_threshold,
+                    );
+                    v.matching_residues = retrieval_result.0;
+                    v.matching_residues_processed = retrieval_result.1;
+                    v.max_matching_node_count = retrieval_result.2;
+                    v.min_rmsd_with_max_match = retrieval_result.3;
+                    None
+                }).collect::<HashSet<usize>>();
+                query_count_vec.retain(|(nid, _)| !unreadable.contains(nid));
+                match config.mode {
+                    IndexMode::Id => query_count_vec.retain(|(_, v)| structure_filter.filter_after_matching(v)),
+                    IndexMode::Big => query_count_vec.retain(|(_, v)| !v.matching_residues.is_empty()),
+                }
+                query_count_vec
+            }
+        ).flatten().collect()
+    }
+}
+
+// Warn about query hashes that can't be found because the index left them out
+fn report_stopped_query_hashes(
+    pdb_query_map: &HashMap<GeometricHash, ((usize, usize), bool)>, stop_list: &StopList,
+    query_structure: &CompactStructure, index_name: &str,
+) {
+    let mut stopped_pairs = pdb_query_map.iter().filter(|(hash, _)| {
+        stop_list.contains_key(&hash.as_u32())
+    }).map(|(_, &((i, j), _))| (i.min(j), i.max(j))).collect::<Vec<_>>();
+    if stopped_pairs.is_empty() {
+        return;
+    }
+    let num_stopped = stopped_pairs.len();
+    stopped_pairs.sort_unstable();
+    stopped_pairs.dedup();
+    let residue = |idx: usize| format!("{}{}", query_structure.chain_per_residue[idx] as char, query_structure.residue_serial[idx]);
+    let mut pair_strings = stopped_pairs.iter().take(10).map(
+        |&(i, j)| format!("{}-{}", residue(i), residue(j))
+    ).collect::<Vec<_>>();
+    if stopped_pairs.len() > 10 {
+        pair_strings.push(format!("... ({} pairs)", stopped_pairs.len()));
+    }
+    print_log_msg(WARN, &format!(
+        "{} of {} query hashes are stop-listed in {} and not used. Residue pairs: {}",
+        num_stopped, pdb_query_map.len(), index_name, pair_strings.join(", ")
+    ));
+}
+
+/// Search options with the defaults of `folddisco query`
+#[derive(Clone, Debug)]
+pub struct QueryOptions {
+    pub dist_thresholds: Vec<f32>,
+    pub angle_thresholds: Vec<f32>,
+    pub ca_dist_threshold: f32,
+    pub sampling_count: Option<usize>,
+    pub sampling_ratio: Option<f32>,
+    pub freq_filter: Option<f32>,
+    pub length_penalty: Option<f32>,
+    pub skip_match: bool,
+    pub serial_query: bool,
+    pub total_match_count: usize,
+    pub covered_node_count: usize,
+    pub covered_node_ratio: f32,
+    pub covered_edge_count: usize,
+    pub covered_edge_ratio: f32,
+    pub max_matching_node_count: usize,
+    pub max_matching_node_ratio: f32,
+    pub idf_score_cutoff: f32,
+    pub connected_node_count: usize,
+    pub connected_node_ratio: f32,
+    pub num_res_cutoff: usize,
+    pub plddt_cutoff: f32,
+    pub rmsd_cutoff: f32,
+    pub top_n: usize,
+    pub sort_by_score: bool,
+    pub skip_ca_match: bool,
+    // Print progress and warnings of each search
+    pub verbose: bool,
+}
+
+impl Default for QueryOptions {
+    fn default() -> Self {
+        QueryOptions {
+            dist_thresholds: Vec::new(),
+            angle_thresholds: Vec::new(),
+            ca_dist_threshold: 1.5,
+            sampling_count: None,
+            sampling_ratio: None,
+            freq_filter: None,
+            length_penalty: None,
+            skip_match: false,
+            serial_query: false,
+            total_match_count: 0,
+            covered_node_count: 0,
+            covered_node_ratio: 0.0,
+            covered_edge_count: 0,
+            covered_edge_ratio: 0.0,
+            max_matching_node_count: 0,
+            max_matching_node_ratio: 0.0,
+            idf_score_cutoff: 0.0,
+            connected_node_count: 0,
+            connected_node_ratio: 0.0,
+            num_res_cutoff: 50000,
+            plddt_cutoff: 0.0,
+            rmsd_cutoff: 0.0,
+            top_n: usize::MAX,
+            sort_by_score: false,
+            skip_ca_match: false,
+            verbose: false,
+        }
+    }
+}
+
+/// Query structure with its motif residues and search options. Build with QueryBuilder
+pub struct Query {
+    pdb_path: String,
+    structure: CompactStructure,
+    residues: Vec<(u8, u64)>,
+    aa_substitutions: Vec<Option<Vec<u8>>>,
+    residue_labels: Vec<String>,
+    options: QueryOptions,
+}
+
+impl Query {
+    /// Path of the query structure
+    pub fn pdb_path(&self) -> &str {
+        &self.pdb_path
+    }
+
+    /// Query residues found in the structure (e.g. "B57"), in the order of
+    /// the residues in matches. All residues for a whole-structure query
+    pub fn residue_labels(&self) -> &[String] {
+        &self.residue_labels
+    }
+
+    /// Residues as given, e.g. (b'B', 57). Empty for a whole-structure query
+    pub fn residues(&self) -> &[(u8, u64)] {
+        &self.residues
+    }
+
+    pub fn options(&self) -> &QueryOptions {
+        &self.options
+    }
+
+    fn residue_count(&self) -> usize {
+        if self.residues.is_empty() { self.structure.num_residues } else { self.residues.len() }
+    }
+}
+
+pub struct QueryBuilder {
+    pdb_path: String,
+    residues: String,
+    options: QueryOptions,
+}
+
+impl QueryBuilder {
+    /// Query with the structure at `pdb_path` (PDB, mmCIF or FCZ). The whole
+    /// structure is the query unless residues are given
+    pub fn new(pdb_path: &str) -> Self {
+        QueryBuilder { pdb_path: pdb_path.to_string(), residues: String::new(), options: QueryOptions::default() }
+    }
+
+    /// Motif residues as in `folddisco query -q` (e.g. "B57,B102,C195" or "A1-10,A20:HK")
+    pub fn residues(mut self, query: &str) -> Self {
+        self.residues = query.to_string();
+        self
+    }
+
+    pub fn options(mut self, options: QueryOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn distance_thresholds(mut self, thresholds: Vec<f32>) -> Self {
+        self.options.dist_thresholds = thresholds;
+        self
+    }
+
+    pub fn angle_thresholds(mut self, thresholds: Vec<f32>) -> Self {
+        self.options.angle_thresholds = thresholds;
+        self
+    }
+
+    pub fn ca_distance(mut self, threshold: f32) -> Self {
+        self.options.ca_dist_threshold = threshold;
+        self
+    }
+
+    pub fn sampling_count(mut self, count: usize) -> Self {
+        self.options.sampling_count = Some(count);
+        self
+    }
+
+    pub fn sampling_ratio(mut self, ratio: f32) -> Self {
+        self.options.sampling_ratio = Some(ratio);
+        self
+    }
+
+    pub fn freq_filter(mut self, ratio: f32) -> Self {
+        self.options.freq_filter = Some(ratio);
+        self
+    }
+
+    pub fn length_penalty(mut self, penalty: f32) -> Self {
+        self.options.length_penalty = Some(penalty);
+        self
+    }
+
+    pub fn skip_match(mut self, skip: bool) -> Self {
+        self.options.skip_match = skip;
+        self
+    }
+
+    /// Residues are 0-based indices in the structure instead of residue numbers
+    pub fn serial_index(mut self, serial: bool) -> Self {
+        self.options.serial_query = serial;
+        self
+    }
+
+    pub fn total_match(mut self, count: usize) -> Self {
+        self.options.total_match_count = count;
+        self
+    }
+
+    pub fn covered_node(mut self, count: usize) -> Self {
+        self.options.covered_node_count = count;
+        self
+    }
+
+    pub fn covered_node_ratio(mut self, ratio: f32) -> Self {
+        self.options.covered_node_ratio = ratio;
+        self
+    }
+
+    pub fn covered_edge(mut self, count: usize) -> Self {
+        self.options.covered_edge_count = count;
+        self
+    }
+
+    pub fn covered_edge_ratio(mut self, ratio: f32) -> Self {
+        self.options.covered_edge_ratio = ratio;
+        self
+    }
+
+    pub fn max_node(mut self, count: usize) -> Self {
+        self.options.max_matching_node_count = count;
+        self
+    }
+
+    pub fn max_node_ratio(mut self, ratio: f32) -> Self {
+        self.options.max_matching_node_ratio = ratio;
+        self
+    }
+
+    pub fn score(mut self, cutoff: f32) -> Self {
+        self.options.idf_score_cutoff = cutoff;
+        self
+    }
+
+    pub fn connected_node(mut self, count: usize) -> Self {
+        self.options.connected_node_count = count;
+        self
+    }
+
+    pub fn connected_node_ratio(mut self, ratio: f32) -> Self {
+        self.options.connected_node_ratio = ratio;
+        self
+    }
+
+    pub fn num_residue(mut self, cutoff: usize) -> Self {
+        self.options.num_res_cutoff = cutoff;
+        self
+    }
+
+    pub fn plddt(mut self, cutoff: f32) -> Self {
+        self.options.plddt_cutoff = cutoff;
+        self
+    }
+
+    pub fn rmsd(mut self, cutoff: f32) -> Self {
+        self.options.rmsd_cutoff = cutoff;
+        self
+    }
+
+    pub fn top(mut self, n: usize) -> Self {
+        self.options.top_n = n;
+        self
+    }
+
+    pub fn sort_by_score(mut self, sort: bool) -> Self {
+        self.options.sort_by_score = sort;
+        self
+    }
+
+    pub fn skip_ca_match(mut self, skip: bool) -> Self {
+        self.options.skip_ca_match = skip;
+        self
+    }
+
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.options.verbose = verbose;
+        self
+    }
+
+    /// Read the query structure and resolve the residues
+    pub fn build(self) -> Result<Query, String> {
+        if !location_exists(&self.pdb_path) {
+            return Err(format!("Query structure not found: {}", self.pdb_path));
+        }
+        let (structure, _) = try_read_compact_structure(&self.pdb_path)
+            .map_err(|e| format!("Failed to read query structure {}: {}", self.pdb_path, e))?;
+        if structure.num_residues == 0 {
+            return Err(format!("No residues in query structure: {}", self.pdb_path));
+        }
+        let (residues, aa_substitutions) = parse_query_string(&self.residues, structure.chains[0])?;
+        // Residues not in the structure are left out of the query as in make_query_map
+        let residue_indices: Vec<usize> = if residues.is_empty() {
+            (0..structure.num_residues).collect()
+        } else if self.options.serial_query {
+            residues.iter().map(|(_, ri)| *ri as usize).collect()
+        } else {
+            residues.iter().filter_map(|(chain, ri)| structure.get_index(chain, ri)).collect()
+        };
+        if residue_indices.iter().any(|&i| i >= structure.num_residues) {
+            return Err(format!("Residue index out of range in {}", self.pdb_path));
+        }
+        let residue_labels = query_residue_labels(&structure, &residue_indices);
+        Ok(Query {
+            pdb_path: self.pdb_path, structure, residues, aa_substitutions, residue_labels, options: self.options,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::AppArgs;
+    use crate::cli::workflows::build_index::build_index;
+    use crate::cli::workflows::test_index_args;
+
+    #[test]
+    fn test_search_index() {
+        let index_path = "data/search_test";
+        build_index(test_index_args("data/serine_peptidases_filtered", index_path, "pdbtr"));
+        assert!(Index::open("data/missing_index").is_err());
+        assert!(QueryBuilder::new("missing.pdb").build().is_err());
+        assert!(QueryBuilder::new("query/4CHA.pdb").residues("B5x").build().is_err());
+        let index = Index::open(index_path).unwrap();
+        assert_eq!(index.num_chunks(), 1);
+        assert_eq!(index.num_structures(), index.chunks[0].lookup.len());
+
+        let query = QueryBuilder::new("query/4CHA.pdb").residues("B57,B102,C195").top(5).build().unwrap();
+        assert_eq!(query.residue_labels(), ["B57", "B102", "C195"]);
+        // Searches run on the pool of the caller and can be repeated
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        let matches = pool.install(|| index.search_matches(&query));
+        assert!(!matches.is_empty() && matches.len() <= 5);
+        let (nid, best) = &matches[0];
+        assert_eq!(best.id, "data/serine_peptidases_filtered/4cha.pdb");
+        assert_eq!(best.rmsd, 0.0);
+        assert_eq!(best.matching_residues, vec![Some((b'B', 57)), Some((b'B', 102)), Some((b'C', 195))]);
+        assert!(index.read_structure(*nid, best.id).is_ok());
+        assert!(index.read_structure(*nid, "missing.pdb").is_err());
+        assert!(index.check_structure_locations().is_ok());
+        assert_eq!(pool.install(|| index.search_matches(&query)).len(), matches.len());
+
+        let structures = index.search_structures(&query);
+        assert!(structures.iter().any(|(_, result)| result.id == best.id));
+        assert!(structures.iter().all(|(_, result)| !result.matching_residues.is_empty()));
+        let query = QueryBuilder::new("query/4CHA.pdb").residues("B57,B102,C195").skip_match(true).build().unwrap();
+        let scored = index.search_structures(&query);
+        assert!(scored.windows(2).all(|pair| pair[0].1.idf >= pair[1].1.idf));
+        assert!(scored.iter().all(|(_, result)| result.matching_residues.is_empty()));
+
+        drop(index);
+        for ext in ["offset", "value", "lookup", "type"] {
+            std::fs::remove_file(format!("{}.{}", index_path, ext)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_broken_index_and_structures() {
+        let pdb_dir = "data/search_broken_test_pdbs";
+        let index_path = "data/search_broken_test";
+        std::fs::create_dir_all(pdb_dir).unwrap();
+        for entry in std::fs::read_dir("data/serine_peptidases_filtered").unwrap() {
+            let path = entry.unwrap().path();
+            std::fs::copy(&path, Path::new(pdb_dir).join(path.file_name().unwrap())).unwrap();
+        }
+        build_index(test_index_args(pdb_dir, index_path, "pdbtr"));
+
+        // A structure removed after indexing is left out of the hits instead of failing the search
+        std::fs::remove_file(format!("{}/4cha.pdb", pdb_dir)).unwrap();
+        let index = Index::open(index_path).unwrap();
+        let query = QueryBuilder::new("query/4CHA.pdb").residues("B57,B102,C195").build().unwrap();
+        let matches = index.search_matches(&query);
+        assert!(!matches.is_empty());
+        assert!(matches.iter().all(|(_, result)| !result.id.ends_with("4cha.pdb")));
+        drop(index);
+
+        // Malformed index files are errors instead of panics
+        let lookup = std::fs::read_to_string(format!("{}.lookup", index_path)).unwrap();
+        std::fs::write(format!("{}.lookup", index_path), format!("{}broken line\n", lookup)).unwrap();
+        assert!(Index::open(index_path).err().unwrap().contains("lookup"));
+        std::fs::write(format!("{}.lookup", index_path), lookup).unwrap();
+        std::fs::write(format!("{}.type", index_path), "hash_type = 3\n").unwrap();
+        assert!(Index::open(index_path).err().unwrap().contains("hash_type"));
+
+        std::fs::remove_dir_all(pdb_dir).unwrap();
+        for ext in ["offset", "value", "lookup", "type"] {
+            std::fs::remove_file(format!("{}.{}", index_path, ext)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_search_gzipped_archive() {
+        let archive = "data/search_archive_test.tar.gz";
+        let index_path = "data/search_archive_test";
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            std::fs::File::create(archive).unwrap(), flate2::Compression::default()
+        ));
+        let mut paths = std::fs::read_dir("data/serine_peptidases_filtered").unwrap()
+            .map(|entry| entry.unwrap().path()).collect::<Vec<_>>();
+        paths.sort();
+        for path in paths.iter() {
+            builder.append_path_with_name(path, path.file_name().unwrap()).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+        build_index(test_index_args(archive, index_path, "pdbtr"));
+
+        // Candidates are read in archive order but returned sorted by RMSD
+        let index = Index::open(index_path).unwrap();
+        let query = QueryBuilder::new("query/4CHA.pdb").residues("B57,B102,C195").build().unwrap();
+        let structures = index.search_structures(&query);
+        assert!(structures.len() > 1);
+        assert_eq!(structures[0].1.id, format!("{}:4cha.pdb", archive));
+        assert!(structures.windows(2).all(|pair| {
+            let (a, b) = (&pair[0].1, &pair[1].1);
+            a.max_matching_node_count > b.max_matching_node_count
+                || (a.max_matching_node_count == b.max_matching_node_count && a.min_rmsd_with_max_match <= b.min_rmsd_with_max_match)
+        }));
+
+        drop(structures);
+        drop(index);
+        std::fs::remove_file(archive).unwrap();
+        for ext in ["offset", "value", "lookup", "type"] {
+            std::fs::remove_file(format!("{}.{}", index_path, ext)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_search_chunked_index_idf() {
+        // Extra hash set with the same hash type as the primary one but different bins
+        let build = |index_path: &str, chunk_size: usize| {
+            let mut env = test_index_args("data/serine_peptidases_filtered", index_path, "pdbtr");
+            if let AppArgs::Index { extra_hashes, chunk_size: env_chunk_size, .. } = &mut env {
+                *extra_hashes = Some("pdbtr:16-8".to_string());
+                *env_chunk_size = chunk_size;
+            }
+            build_index(env)
+        };
+        build("data/search_idf_test", 65536);
+        build("data/search_idf_test_chunked", 10);
+        let whole = Index::open("data/search_idf_test").unwrap();
+        let chunked = Index::open("data/search_idf_test_chunked").unwrap();
+        assert_eq!((whole.num_chunks(), chunked.num_chunks()), (1, 2));
+
+        // IDF of each structure doesn't depend on the chunk it was indexed in
+        let query = QueryBuilder::new("query/4CHA.pdb").residues("B57,B102,C195").skip_match(true).build().unwrap();
+        let expected = whole.search_structures(&query).into_iter()
+            .map(|(_, result)| (result.id.to_string(), result.idf)).collect::<HashMap<_, _>>();
+        let scored = chunked.search_structures(&query);
+        assert_eq!(scored.len(), expected.len());
+        for (_, result) in scored.iter() {
+            assert!((result.idf - expected[result.id]).abs() < 1e-4, "{}: {} != {}", result.id, result.idf, expected[result.id]);
+        }
+
+        drop(scored);
+        drop((whole, chunked));
+        for entry in std::fs::read_dir("data").unwrap() {
+            let path = entry.unwrap().path();
+            if path.file_name().unwrap().to_string_lossy().starts_with("search_idf_test") {
+                std::fs::remove_file(path).unwrap();
+            }
+        }
+    }
+}
@@ -378,6 +378,29 @@ impl GeometricHash {
     }
 
 
+    // Same hash with different numbers of bins. None for hash types whose bins
+    // can't be changed without the original features
+    pub fn rebin(
+        &self, nbin_dist: usize, nbin_angle: usize, new_nbin_dist: usize, new_nbin_angle: usize
+    ) -> Option<Self> {
+        match self {
+            GeometricHash::PDBMotif(hash) => Some(GeometricHash::PDBMotif(
+                hash.rebin(nbin_dist, nbin_angle, new_nbin_dist, new_nbin_angle)
+            )),
+            GeometricHash::PDBMotifSinCos(hash) => Some(GeometricHash::PDBMotifSinCos(
+                hash.rebin(nbin_dist, nbin_angle, new_nbin_dist, new_nbin_angle)
+            )),
+            GeometricHash::PDBTrRosetta(hash) => Some(GeometricHash::PDBTrRosetta(
+                hash.rebin(nbin_dist, nbin_angle, new_nbin_dist, new_nbin_angle)
+            )),
+            GeometricHash::Hybrid(hash) => Some(GeometricHash::Hybrid(
+                hash.rebin(nbin_dist, nbin_angle, new_nbin_dist, new_nbin_angle)
+            )),
+            _ => None,
+        }
+    }
+
+
     pub fn hash_type(&self) -> HashType {
         match self {
             GeometricHash::PDBMotif(hash) => hash.hash_type(),
@@ -8,6 +8,7 @@ use std::fmt;
 use crate::geometry::core::HashType;
 use crate::utils::convert::discretize_f32_value_into_u32 as discretize_value;
 use crate::utils::convert::continuize_u32_value_into_f32 as continuize_value;
+use crate::utils::convert::rebin_u32_value as rebin_value;
 use crate::utils::convert::*;
 
 #[derive(Ord, PartialOrd, Eq, PartialEq, Clone, Copy, Hash)]
@@ -162,6 +163,20 @@ impl HashValue {
         [res1_group, res2_group, ca_dist, cb_dist, ca_cb_angle, phi1, phi2, bb_phi1, bb_phi2]
     }
     
+    // Move every distance and sin/cos bin to the bin of its centre with the new numbers of bins
+    pub fn rebin(&self, nbin_dist: usize, nbin_angle: usize, new_nbin_dist: usize, new_nbin_angle: usize) -> Self {
+        let dist = |shift: u32| rebin_value(
+            (self.0 >> shift) & BITMASK32_4BIT, MIN_DIST, MAX_DIST, nbin_dist as f32, new_nbin_dist as f32
+        ) << shift;
+        let sin_cos = |shift: u32| rebin_value(
+            (self.0 >> shift) & BITMASK32_2BIT, MIN_SIN_COS, MAX_SIN_COS, nbin_angle as f32, new_nbin_angle as f32
+        ) << shift;
+        HashValue(
+            (self.0 >> 28) << 28 | dist(24) | dist(20) | sin_cos(18) | sin_cos(16) | sin_cos(14)
+            | sin_cos(12) | sin_cos(10) | sin_cos(8) | sin_cos(6) | sin_cos(4) | sin_cos(2) | sin_cos(0)
+        )
+    }
+
     pub fn hash_type(&self) -> HashType {
         HashType::Hybrid
     }
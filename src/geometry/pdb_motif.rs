@@ -7,15 +7,16 @@ use std::fmt;
 use crate::geometry::core::HashType;
 use crate::utils::convert::discretize_f32_value_into_u32 as discretize_value;
 use crate::utils::convert::continuize_u32_value_into_f32 as continuize_value;
+use crate::utils::convert::rebin_u32_value as rebin_value;
 
 // Residue 1: 5 bits; Residue 2: 5 bits; Distances: 16 bins 4 bits; 
 // Angle: 32 bins 5 bits; total: 23 bits
 const MIN_DIST: f32 = 2.0;
 const MAX_DIST: f32 = 20.0;
-const NBIN_DIST: f32 = 18.0;
+pub const NBIN_DIST: f32 = 18.0;
 const MIN_ANGLE: f32 = 0.0;
 const MAX_ANGLE: f32 = 180.0;
-const NBIN_ANGLE: f32 = 9.0;
+pub const NBIN_ANGLE: f32 = 9.0;
 // Bitmasks
 const BITMASK32_5BIT: u32 = 0x0000001F;
 
@@ -75,6 +76,17 @@ impl HashValue {
         self.reverse_hash(NBIN_DIST as usize, NBIN_ANGLE as usize)
     }
     
+    // Move every distance and angle bin to the bin of its centre with the new numbers of bins
+    pub fn rebin(&self, nbin_dist: usize, nbin_angle: usize, new_nbin_dist: usize, new_nbin_angle: usize) -> Self {
+        let dist = |shift: u32| rebin_value(
+            (self.0 >> shift) & BITMASK32_5BIT, MIN_DIST, MAX_DIST, nbin_dist as f32, new_nbin_dist as f32
+        ) << shift;
+        let angle = rebin_value(
+            self.0 & BITMASK32_5BIT, MIN_ANGLE, MAX_ANGLE, nbin_angle as f32, new_nbin_angle as f32
+        );
+        HashValue((self.0 >> 15) << 15 | dist(10) | dist(5) | angle)
+    }
+
     pub fn hash_type(&self) -> HashType {
         HashType::PDBMotif
     }
@@ -7,6 +7,7 @@ use std::fmt;
 use crate::geometry::core::HashType;
 use crate::utils::convert::discretize_f32_value_into_u32 as discretize_value;
 use crate::utils::convert::continuize_u32_value_into_f32 as continuize_value;
+use crate::utils::convert::rebin_u32_value as rebin_value;
 use crate::utils::convert::*;
 
 #[derive(Ord, PartialOrd, Eq, PartialEq, Clone, Copy, Hash)]
@@ -83,6 +84,17 @@ impl HashValue {
         self.reverse_hash(NBIN_DIST as usize, NBIN_SIN_COS as usize)
     }
     
+    // Move every distance and sin/cos bin to the bin of its centre with the new numbers of bins
+    pub fn rebin(&self, nbin_dist: usize, nbin_angle: usize, new_nbin_dist: usize, new_nbin_angle: usize) -> Self {
+        let dist = |shift: u32| rebin_value(
+            (self.0 >> shift) & BITMASK32_4BIT, MIN_DIST, MAX_DIST, nbin_dist as f32, new_nbin_dist as f32
+        ) << shift;
+        let sin_cos = |shift: u32| rebin_value(
+            (self.0 >> shift) & BITMASK32_4BIT, MIN_SIN_COS, MAX_SIN_COS, nbin_angle as f32, new_nbin_angle as f32
+        ) << shift;
+        HashValue((self.0 >> 16) << 16 | dist(12) | dist(8) | sin_cos(4) | sin_cos(0))
+    }
+
     pub fn hash_type(&self) -> HashType {
         HashType::PDBMotifSinCos
     }
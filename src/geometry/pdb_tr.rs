@@ -8,6 +8,7 @@ use std::fmt;
 use crate::geometry::core::HashType;
 use crate::utils::convert::discretize_f32_value_into_u32 as discretize_value;
 use crate::utils::convert::continuize_u32_value_into_f32 as continuize_value;
+use crate::utils::convert::rebin_u32_value as rebin_value;
 use crate::utils::convert::*;
 
 #[derive(Ord, PartialOrd, Eq, PartialEq, Clone, Copy, Hash)]
@@ -125,6 +126,20 @@ impl HashValue {
         [res1, res2, ca_dist, cb_dist, ca_cb_angle, phi1, phi2]
     }
     
+    // Move every distance and sin/cos bin to the bin of its centre with the new numbers of bins
+    pub fn rebin(&self, nbin_dist: usize, nbin_angle: usize, new_nbin_dist: usize, new_nbin_angle: usize) -> Self {
+        let dist = |shift: u32| rebin_value(
+            (self.0 >> shift) & BITMASK32_4BIT, MIN_DIST, MAX_DIST, nbin_dist as f32, new_nbin_dist as f32
+        ) << shift;
+        let sin_cos = |shift: u32| rebin_value(
+            (self.0 >> shift) & BITMASK32_2BIT, MIN_SIN_COS, MAX_SIN_COS, nbin_angle as f32, new_nbin_angle as f32
+        ) << shift;
+        HashValue(
+            (self.0 >> 20) << 20 | dist(16) | dist(12) | sin_cos(10) | sin_cos(8)
+            | sin_cos(6) | sin_cos(4) | sin_cos(2) | sin_cos(0)
+        )
+    }
+
     pub fn hash_type(&self) -> HashType {
         HashType::PDBTrRosetta
     }
@@ -206,4 +221,22 @@ mod tests {
             _ => panic!("Invalid hash type"),
         }
     }
+
+    #[test]
+    fn test_rebin() {
+        // Distances at the ends of the range and sin/cos of diagonal angles are
+        // away from the edges of both fine and coarse bins
+        let feature = vec![
+            map_aa_to_u8(b"PHE") as f32, map_aa_to_u8(b"VAL") as f32, 2.0_f32, 20.0_f32,
+            45.0_f32.to_radians(), 135.0_f32.to_radians(), -45.0_f32.to_radians()
+        ];
+        let fine = HashValue(HashValue::perfect_hash(&feature, 16, 4));
+        let coarse = HashValue::perfect_hash(&feature, 8, 2);
+        assert_eq!(fine.rebin(16, 4, 16, 4), fine);
+        assert_eq!(fine.rebin(16, 4, 8, 2).as_u32(), coarse);
+        let rebinned = GeometricHash::PDBTrRosetta(fine).rebin(16, 4, 8, 2).unwrap();
+        assert_eq!(rebinned.as_u32(), coarse);
+        // Residue pair is kept
+        assert_eq!(rebinned.as_u32() >> 20, fine.as_u32() >> 20);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,202 @@
+// Checkpoints of big mode indexing
+// {index}.checkpoint holds the progress as TOML. Arrays of the index and the
+// lookup are written to {index}.checkpoint_{generation}.{name}.
+// A new generation is written completely before the TOML is replaced, so a
+// crash while checkpointing leaves the previous checkpoint usable.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+
+use toml::map::Map;
+
+use crate::geometry::core::HashType;
+use crate::utils::log::{log_msg, FAIL};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexingPhase {
+    Counting,
+    Adding,
+}
+
+impl fmt::Display for IndexingPhase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IndexingPhase::Counting => write!(f, "counting"),
+            IndexingPhase::Adding => write!(f, "adding"),
+        }
+    }
+}
+
+impl IndexingPhase {
+    pub fn get_with_str(phase: &str) -> Self {
+        match phase {
+            "adding" => IndexingPhase::Adding,
+            _ => IndexingPhase::Counting,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexCheckpoint {
+    pub phase: IndexingPhase,
+    // Number of structure chunks fully processed in the phase
+    pub completed_chunks: usize,
+    pub chunk_size: usize,
+    pub num_structures: usize,
+    // CRC-32 of the input paths to refuse resuming with different inputs
+    pub input_checksum: u32,
+    pub hash_type: HashType,
+    pub num_bin_dist: usize,
+    pub num_bin_angle: usize,
+    pub generation: usize,
+}
+
+impl IndexCheckpoint {
+    pub fn to_toml(&self) -> toml::Value {
+        let mut map = Map::new();
+        map.insert("phase".to_string(), toml::Value::String(self.phase.to_string()));
+        map.insert("completed_chunks".to_string(), toml::Value::Integer(self.completed_chunks as i64));
+        map.insert("chunk_size".to_string(), toml::Value::Integer(self.chunk_size as i64));
+        map.insert("num_structures".to_string(), toml::Value::Integer(self.num_structures as i64));
+        map.insert("input_checksum".to_string(), toml::Value::Integer(self.input_checksum as i64));
+        map.insert("hash_type".to_string(), toml::Value::String(self.hash_type.to_string()));
+        map.insert("num_bin_dist".to_string(), toml::Value::Integer(self.num_bin_dist as i64));
+        map.insert("num_bin_angle".to_string(), toml::Value::Integer(self.num_bin_angle as i64));
+        map.insert("generation".to_string(), toml::Value::Integer(self.generation as i64));
+        toml::Value::Table(map)
+    }
+
+    pub fn from_toml(toml: &toml::Value) -> Self {
+        Self {
+            phase: IndexingPhase::get_with_str(toml["phase"].as_str().unwrap()),
+            completed_chunks: toml["completed_chunks"].as_integer().unwrap() as usize,
+            chunk_size: toml["chunk_size"].as_integer().unwrap() as usize,
+            num_structures: toml["num_structures"].as_integer().unwrap() as usize,
+            input_checksum: toml["input_checksum"].as_integer().unwrap() as u32,
+            hash_type: HashType::get_with_str(toml["hash_type"].as_str().unwrap()),
+            num_bin_dist: toml["num_bin_dist"].as_integer().unwrap() as usize,
+            num_bin_angle: toml["num_bin_angle"].as_integer().unwrap() as usize,
+            generation: toml["generation"].as_integer().unwrap() as usize,
+        }
+    }
+
+    pub fn array_path(&self, index_path: &str, name: &str) -> String {
+        format!("{}.checkpoint_{}.{}", index_path, self.generation, name)
+    }
+
+    pub fn save(&self, index_path: &str) {
+        let tmp_path = format!("{}.checkpoint.tmp", index_path);
+        std::fs::write(&tmp_path, toml::to_string(&self.to_toml()).unwrap()).unwrap_or_else(|_| panic!(
+            "{}", log_msg(FAIL, "Failed to write checkpoint")
+        ));
+        std::fs::rename(&tmp_path, format!("{}.checkpoint", index_path)).unwrap_or_else(|_| panic!(
+            "{}", log_msg(FAIL, "Failed to write checkpoint")
+        ));
+    }
+
+    pub fn load(index_path: &str) -> Option<Self> {
+        let content = std::fs::read_to_string(format!("{}.checkpoint", index_path)).ok()?;
+        let toml = content.parse::<toml::Value>().unwrap_or_else(|_| panic!(
+            "{}", log_msg(FAIL, "Failed to parse checkpoint")
+        ));
+        Some(Self::from_toml(&toml))
+    }
+
+    // Remove arrays of this generation. The TOML is kept
+    pub fn remove_arrays(&self, index_path: &str) {
+        for name in CHECKPOINT_ARRAYS {
+            let _ = std::fs::remove_file(self.array_path(index_path, name));
+        }
+    }
+
+    pub fn remove(&self, index_path: &str) {
+        self.remove_arrays(index_path);
+        let _ = std::fs::remove_file(format!("{}.checkpoint", index_path));
+    }
+}
+
+pub const CHECKPOINT_ARRAYS: [&str; 6] = ["offsets", "last_id", "entries", "nres", "plddt", "skipped"];
+
+pub fn input_checksum(path_vec: &[String]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    for path in path_vec {
+        crc.update(path.as_bytes());
+        crc.update(b"\n");
+    }
+    crc.sum()
+}
+
+// Native byte dumps of plain arrays. Only read back on the machine that wrote them.
+pub fn write_raw_array<T: Copy>(path: &str, array: &[T]) -> std::io::Result<()> {
+    let bytes = unsafe {
+        std::slice::from_raw_parts(array.as_ptr() as *const u8, std::mem::size_of_val(array))
+    };
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(bytes)?;
+    writer.flush()
+}
+
+pub fn read_raw_array_into<T: Copy>(path: &str, array: &mut [T]) -> std::io::Result<()> {
+    let bytes = unsafe {
+        std::slice::from_raw_parts_mut(array.as_mut_ptr() as *mut u8, std::mem::size_of_val(array))
+    };
+    File::open(path)?.read_exact(bytes)
+}
+
+// Structures skipped so far. One line per structure: position, path and reason
+pub fn write_skipped_list(path: &str, skipped: &[(usize, String, String)]) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    for (pdb_pos, pdb_path, reason) in skipped {
+        writeln!(writer, "{}\t{}\t{}", pdb_pos, pdb_path, reason)?;
+    }
+    writer.flush()
+}
+
+pub fn read_skipped_list(path: &str) -> std::io::Result<Vec<(usize, String, String)>> {
+    let content = std::fs::read_to_string(path)?;
+    content.lines().map(|line| {
+        let mut fields = line.splitn(3, '\t');
+        match (fields.next().and_then(|pos| pos.parse().ok()), fields.next(), fields.next()) {
+            (Some(pdb_pos), Some(pdb_path), Some(reason)) => Ok((pdb_pos, pdb_path.to_string(), reason.to_string())),
+            _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Invalid skipped entry: {}", line))),
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checkpoint_io() {
+        let index_path = "data/checkpoint_test";
+        let checkpoint = IndexCheckpoint {
+            phase: IndexingPhase::Adding,
+            completed_chunks: 3,
+            chunk_size: 128,
+            num_structures: 1000,
+            input_checksum: input_checksum(&["a.pdb".to_string(), "b.pdb".to_string()]),
+            hash_type: HashType::PDBTrRosetta,
+            num_bin_dist: 16,
+            num_bin_angle: 4,
+            generation: 2,
+        };
+        checkpoint.save(index_path);
+        assert_eq!(IndexCheckpoint::load(index_path), Some(checkpoint.clone()));
+        assert_ne!(checkpoint.input_checksum, input_checksum(&["a.pdb".to_string()]));
+
+        let array = vec![1usize, 5, usize::MAX, 0];
+        write_raw_array(&checkpoint.array_path(index_path, "offsets"), &array).unwrap();
+        let mut loaded = vec![0usize; 4];
+        read_raw_array_into(&checkpoint.array_path(index_path, "offsets"), &mut loaded).unwrap();
+        assert_eq!(loaded, array);
+        let skipped = vec![(4usize, "data/broken.pdb".to_string(), "Failed to parse structure".to_string())];
+        write_skipped_list(&checkpoint.array_path(index_path, "skipped"), &skipped).unwrap();
+        assert_eq!(read_skipped_list(&checkpoint.array_path(index_path, "skipped")).unwrap(), skipped);
+
+        checkpoint.remove(index_path);
+        assert!(IndexCheckpoint::load(index_path).is_none());
+        assert!(!std::path::Path::new(&checkpoint.array_path(index_path, "offsets")).exists());
+    }
+}
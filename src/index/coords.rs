@@ -0,0 +1,252 @@
+// Coordinate cache for retrieval
+// Sidecar file next to the lookup: {index}.coords
+// Holds what retrieval needs from each indexed structure (chains, residue
+// serials and names, N/CA/CB coordinates and B-factors) so that candidates
+// are not read and parsed from their source files at query time.
+// Layout after the 64 byte index file header (kind "coordinates"):
+//   records, one per numeric id, in id order
+//   record offsets relative to the first record, num_elements + 1 u64 (le)
+// A record is little endian:
+//   num_residues n (u32), num_chains c (u32), chains [u8; c],
+//   chain_per_residue [u8; n], residue_name [[u8; 3]; n], residue_serial [u64; n],
+//   b_factors [f32; n], then x, y and z of N, CA and CB as [f32; n] each.
+//   Missing coordinates (CB of a residue without C) are stored as NaN.
+
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Seek, SeekFrom, Write};
+
+use memmap2::Mmap;
+
+use crate::geometry::core::HashType;
+use crate::index::header::{parse_index_file, IndexFileHeader, IndexFileKind, HEADER_SIZE};
+use crate::structure::coordinate::CarbonCoordinateVector;
+use crate::structure::core::CompactStructure;
+
+pub struct CoordinateCache {
+    mmap: Mmap,
+    num_entries: usize,
+    // Byte ranges of the records and offsets in the mapped file
+    records_start: usize,
+    offsets_start: usize,
+}
+
+impl CoordinateCache {
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        let header = match parse_index_file(&mmap, IndexFileKind::Coordinates)? {
+            (Some(header), _) => header,
+            (None, _) => return Err(Error::new(ErrorKind::InvalidData, "Coordinate cache has no header")),
+        };
+        let offsets_size = (header.num_elements + 1) * 8;
+        if header.payload_size < offsets_size {
+            return Err(Error::new(ErrorKind::InvalidData, "Coordinate cache is truncated"));
+        }
+        Ok(CoordinateCache {
+            mmap,
+            num_entries: header.num_elements,
+            records_start: HEADER_SIZE,
+            offsets_start: HEADER_SIZE + header.payload_size - offsets_size,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.num_entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_entries == 0
+    }
+
+    fn record_offset(&self, id: usize) -> usize {
+        let start = self.offsets_start + id * 8;
+        u64::from_le_bytes(self.mmap[start..start + 8].try_into().unwrap()) as usize
+    }
+
+    pub fn get_raw(&self, id: usize) -> Option<&[u8]> {
+        if id >= self.num_entries {
+            return None;
+        }
+        let start = self.records_start + self.record_offset(id);
+        let end = self.records_start + self.record_offset(id + 1);
+        self.mmap.get(start..end.min(self.offsets_start))
+    }
+
+    pub fn get(&self, id: usize) -> Option<CompactStructure> {
+        self.get_raw(id).and_then(decode_record)
+    }
+
+    // Append records of the ids for which keep returns true to writer, in id order
+    pub fn copy_to<F: Fn(usize) -> bool>(&self, writer: &mut CoordinateCacheWriter, keep: F) -> Result<(), Error> {
+        for id in 0..self.num_entries {
+            if keep(id) {
+                let record = self.get_raw(id).ok_or_else(|| Error::new(
+                    ErrorKind::InvalidData, format!("Coordinate cache record {} is out of bounds", id)
+                ))?;
+                writer.push_raw(record)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Records are streamed to the file and the offsets are appended at the end
+pub struct CoordinateCacheWriter {
+    writer: BufWriter<File>,
+    crc: flate2::Crc,
+    offsets: Vec<u64>,
+    buffer: Vec<u8>,
+}
+
+impl CoordinateCacheWriter {
+    pub fn create(path: &str) -> Result<Self, Error> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        // Header is written after the payload checksum is known
+        writer.write_all(&[0u8; HEADER_SIZE])?;
+        Ok(CoordinateCacheWriter { writer, crc: flate2::Crc::new(), offsets: vec![0], buffer: Vec::new() })
+    }
+
+    pub fn push(&mut self, compact: &CompactStructure) -> Result<(), Error> {
+        let mut buffer = std::mem::take(&mut self.buffer);
+        buffer.clear();
+        encode_record(compact, &mut buffer);
+        let result = self.push_raw(&buffer);
+        self.buffer = buffer;
+        result
+    }
+
+    pub fn push_raw(&mut self, record: &[u8]) -> Result<(), Error> {
+        self.writer.write_all(record)?;
+        self.crc.update(record);
+        let last = *self.offsets.last().unwrap();
+        self.offsets.push(last + record.len() as u64);
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), Error> {
+        let offset_bytes = self.offsets.iter().flat_map(|offset| offset.to_le_bytes()).collect::<Vec<u8>>();
+        self.writer.write_all(&offset_bytes)?;
+        self.crc.update(&offset_bytes);
+        let num_entries = self.offsets.len() - 1;
+        let mut header = IndexFileHeader::new(IndexFileKind::Coordinates, HashType::Other, num_entries, 8, &[]);
+        header.payload_size = *self.offsets.last().unwrap() as usize + offset_bytes.len();
+        header.checksum = self.crc.sum();
+        let mut file = self.writer.into_inner().map_err(|e| e.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&header.to_bytes())?;
+        file.flush()
+    }
+}
+
+// Record of a structure as written by push. Records can be encoded in parallel and pushed in order with push_raw
+pub fn encode_record(compact: &CompactStructure, bytes: &mut Vec<u8>) {
+    let n = compact.num_residues;
+    bytes.extend_from_slice(&(n as u32).to_le_bytes());
+    bytes.extend_from_slice(&(compact.chains.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&compact.chains);
+    bytes.extend_from_slice(&compact.chain_per_residue[..n]);
+    compact.residue_name[..n].iter().for_each(|name| bytes.extend_from_slice(name));
+    compact.residue_serial[..n].iter().for_each(|serial| bytes.extend_from_slice(&serial.to_le_bytes()));
+    compact.b_factors[..n].iter().for_each(|b_factor| bytes.extend_from_slice(&b_factor.to_le_bytes()));
+    for vector in [&compact.n_vector, &compact.ca_vector, &compact.cb_vector] {
+        for axis in [&vector.x, &vector.y, &vector.z] {
+            (0..n).for_each(|i| {
+                let value = axis.get(i).copied().flatten().unwrap_or(f32::NAN);
+                bytes.extend_from_slice(&value.to_le_bytes());
+            });
+        }
+    }
+}
+
+fn decode_record(bytes: &[u8]) -> Option<CompactStructure> {
+    let mut pos = 0usize;
+    let mut take = |len: usize| {
+        let slice = bytes.get(pos..pos + len)?;
+        pos += len;
+        Some(slice)
+    };
+    let n = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+    let num_chains = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+    let chains = take(num_chains)?.to_vec();
+    let chain_per_residue = take(n)?.to_vec();
+    let residue_name = take(n * 3)?.chunks_exact(3).map(|name| [name[0], name[1], name[2]]).collect();
+    let residue_serial = take(n * 8)?.chunks_exact(8).map(|x| u64::from_le_bytes(x.try_into().unwrap())).collect();
+    let b_factors = take(n * 4)?.chunks_exact(4).map(|x| f32::from_le_bytes(x.try_into().unwrap())).collect();
+    let mut read_axis = || -> Option<Vec<Option<f32>>> {
+        Some(take(n * 4)?.chunks_exact(4).map(|x| {
+            let value = f32::from_le_bytes(x.try_into().unwrap());
+            if value.is_nan() { None } else { Some(value) }
+        }).collect())
+    };
+    let mut read_vector = || -> Option<CarbonCoordinateVector> {
+        Some(CarbonCoordinateVector { x: read_axis()?, y: read_axis()?, z: read_axis()? })
+    };
+    let n_vector = read_vector()?;
+    let ca_vector = read_vector()?;
+    let cb_vector = read_vector()?;
+    Some(CompactStructure {
+        num_chains,
+        chains,
+        chain_per_residue,
+        num_residues: n,
+        residue_serial,
+        residue_name,
+        n_vector,
+        ca_vector,
+        cb_vector,
+        b_factors,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::io::read_structure_from_path;
+    use crate::index::header::verify_index_file;
+
+    #[test]
+    fn test_coordinate_cache() {
+        let path = "data/coords_test.coords";
+        let structures = ["data/homeobox/1akha-.pdb", "data/homeobox/1b72b-.pdb", "query/4CHA.pdb"].iter().map(
+            |pdb_path| read_structure_from_path(pdb_path).unwrap().to_compact()
+        ).collect::<Vec<_>>();
+        let mut writer = CoordinateCacheWriter::create(path).unwrap();
+        for compact in structures.iter() {
+            writer.push(compact).unwrap();
+        }
+        writer.finish().unwrap();
+        assert!(verify_index_file(path).unwrap().is_some());
+
+        let cache = CoordinateCache::open(path).unwrap();
+        assert_eq!(cache.len(), 3);
+        for (id, expected) in structures.iter().enumerate() {
+            let compact = cache.get(id).unwrap();
+            assert_eq!(compact.num_residues, expected.num_residues);
+            assert_eq!(compact.chains, expected.chains);
+            assert_eq!(compact.chain_per_residue, expected.chain_per_residue);
+            assert_eq!(compact.residue_serial, expected.residue_serial);
+            assert_eq!(compact.residue_name, expected.residue_name);
+            assert_eq!(compact.b_factors, expected.b_factors);
+            for (vector, expected_vector) in [
+                (&compact.n_vector, &expected.n_vector), (&compact.ca_vector, &expected.ca_vector),
+                (&compact.cb_vector, &expected.cb_vector),
+            ] {
+                assert_eq!(vector.x, expected_vector.x);
+                assert_eq!(vector.y, expected_vector.y);
+                assert_eq!(vector.z, expected_vector.z);
+            }
+        }
+        assert!(cache.get(3).is_none());
+
+        // Copy without the second structure
+        let copy_path = "data/coords_test_copy.coords";
+        let mut writer = CoordinateCacheWriter::create(copy_path).unwrap();
+        cache.copy_to(&mut writer, |id| id != 1).unwrap();
+        writer.finish().unwrap();
+        let copied = CoordinateCache::open(copy_path).unwrap();
+        assert_eq!(copied.len(), 2);
+        assert_eq!(copied.get_raw(1), cache.get_raw(2));
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(copy_path).unwrap();
+    }
+}
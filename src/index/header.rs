@@ -0,0 +1,305 @@
+// Binary header for .offset, .value, .coords and .blookup files of an index
+// 64 bytes at the start of the file, followed by the payload.
+//   0..8   magic "FDISCOIX"
+//   8..12  format version (u32 le)
+//  12..16  byte order marker 0x01020304 written in the byte order of the payload
+//  16..20  file kind (u32 le)
+//  20..24  hash type (u32 le)
+//  24..32  number of elements in the payload (u64 le)
+//  32..40  payload size in bytes (u64 le)
+//  40..44  width of an element in bits (u32 le)
+//  44..48  CRC-32 of the payload (u32 le)
+//  48..64  reserved
+// The header size keeps the payload aligned for zero-copy mmap access.
+// Files without the magic number were written before the header existed and
+// are read as format version 0 without integrity checks.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+
+use crate::controller::mode::IndexMode;
+use crate::geometry::core::HashType;
+
+pub const INDEX_MAGIC: [u8; 8] = *b"FDISCOIX";
+pub const INDEX_FORMAT_VERSION: u32 = 1;
+pub const HEADER_SIZE: usize = 64;
+const BYTE_ORDER_MARKER: u32 = 0x01020304;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexFileKind {
+    IdOffset,
+    IdValue,
+    BigOffset,
+    BigValue,
+    Coordinates,
+    Lookup,
+}
+
+impl IndexFileKind {
+    fn to_u32(self) -> u32 {
+        match self {
+            IndexFileKind::IdOffset => 1,
+            IndexFileKind::IdValue => 2,
+            IndexFileKind::BigOffset => 3,
+            IndexFileKind::BigValue => 4,
+            IndexFileKind::Coordinates => 5,
+            IndexFileKind::Lookup => 6,
+        }
+    }
+    fn from_u32(kind: u32) -> Option<Self> {
+        match kind {
+            1 => Some(IndexFileKind::IdOffset),
+            2 => Some(IndexFileKind::IdValue),
+            3 => Some(IndexFileKind::BigOffset),
+            4 => Some(IndexFileKind::BigValue),
+            5 => Some(IndexFileKind::Coordinates),
+            6 => Some(IndexFileKind::Lookup),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for IndexFileKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IndexFileKind::IdOffset => write!(f, "id_offset"),
+            IndexFileKind::IdValue => write!(f, "id_value"),
+            IndexFileKind::BigOffset => write!(f, "big_offset"),
+            IndexFileKind::BigValue => write!(f, "big_value"),
+            IndexFileKind::Coordinates => write!(f, "coordinates"),
+            IndexFileKind::Lookup => write!(f, "lookup"),
+        }
+    }
+}
+
+fn hash_type_to_u32(hash_type: HashType) -> u32 {
+    match hash_type {
+        HashType::PDBMotif => 0,
+        HashType::PDBMotifSinCos => 1,
+        HashType::TrRosetta => 2,
+        HashType::PDBTrRosetta => 3,
+        HashType::PointPairFeature => 4,
+        HashType::TertiaryInteraction => 5,
+        HashType::Hybrid => 6,
+        // append new hash type here
+        HashType::Other => u32::MAX,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IndexFileHeader {
+    pub version: u32,
+    pub kind: IndexFileKind,
+    pub hash_type: HashType,
+    pub num_elements: usize,
+    pub payload_size: usize,
+    pub element_bits: usize,
+    pub checksum: u32,
+}
+
+impl IndexFileHeader {
+    pub fn new(kind: IndexFileKind, hash_type: HashType, num_elements: usize, element_bits: usize, payload: &[u8]) -> Self {
+        Self {
+            version: INDEX_FORMAT_VERSION,
+            kind,
+            hash_type,
+            num_elements,
+            payload_size: payload.len(),
+            element_bits,
+            checksum: payload_checksum(payload),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+        bytes[0..8].copy_from_slice(&INDEX_MAGIC);
+        bytes[8..12].copy_from_slice(&self.version.to_le_bytes());
+        bytes[12..16].copy_from_slice(&BYTE_ORDER_MARKER.to_ne_bytes());
+        bytes[16..20].copy_from_slice(&self.kind.to_u32().to_le_bytes());
+        bytes[20..24].copy_from_slice(&hash_type_to_u32(self.hash_type).to_le_bytes());
+        bytes[24..32].copy_from_slice(&(self.num_elements as u64).to_le_bytes());
+        bytes[32..40].copy_from_slice(&(self.payload_size as u64).to_le_bytes());
+        bytes[40..44].copy_from_slice(&(self.element_bits as u32).to_le_bytes());
+        bytes[44..48].copy_from_slice(&self.checksum.to_le_bytes());
+        bytes
+    }
+
+    // Returns Ok(None) for files written before the header was introduced
+    pub fn from_bytes(bytes: &[u8]) -> Result<Option<Self>, Error> {
+        if bytes.len() < INDEX_MAGIC.len() || bytes[0..8] != INDEX_MAGIC {
+            return Ok(None);
+        }
+        if bytes.len() < HEADER_SIZE {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Index file header is truncated"));
+        }
+        let u32_at = |start: usize| u32::from_le_bytes(bytes[start..start + 4].try_into().unwrap());
+        let u64_at = |start: usize| u64::from_le_bytes(bytes[start..start + 8].try_into().unwrap());
+        let version = u32_at(8);
+        if version > INDEX_FORMAT_VERSION {
+            return Err(Error::new(ErrorKind::InvalidData, format!(
+                "Index format version {} is newer than the supported version {}. Update folddisco or rebuild the index",
+                version, INDEX_FORMAT_VERSION
+            )));
+        }
+        if u32::from_ne_bytes(bytes[12..16].try_into().unwrap()) != BYTE_ORDER_MARKER {
+            return Err(Error::new(ErrorKind::InvalidData,
+                "Index was written on a machine with different byte order. Rebuild the index on this machine"
+            ));
+        }
+        let kind = IndexFileKind::from_u32(u32_at(16)).ok_or_else(|| Error::new(
+            ErrorKind::InvalidData, format!("Unknown index file kind: {}", u32_at(16))
+        ))?;
+        let hash_type = match u32_at(20) {
+            u32::MAX => HashType::Other,
+            index => HashType::get_with_index(index as usize),
+        };
+        Ok(Some(Self {
+            version,
+            kind,
+            hash_type,
+            num_elements: u64_at(24) as usize,
+            payload_size: u64_at(32) as usize,
+            element_bits: u32_at(40) as usize,
+            checksum: u32_at(44),
+        }))
+    }
+
+    pub fn check_kind(&self, kind: IndexFileKind) -> Result<(), Error> {
+        if self.kind != kind {
+            return Err(Error::new(ErrorKind::InvalidData, format!(
+                "Expected {} file but found {} file", kind, self.kind
+            )));
+        }
+        Ok(())
+    }
+
+    // Cheap check done on every load. Checksums are only verified by `folddisco verify`
+    pub fn check_file_size(&self, file_size: usize) -> Result<(), Error> {
+        if file_size < HEADER_SIZE + self.payload_size {
+            return Err(Error::new(ErrorKind::UnexpectedEof, format!(
+                "Index file is truncated: expected {} bytes but found {} bytes",
+                HEADER_SIZE + self.payload_size, file_size
+            )));
+        }
+        Ok(())
+    }
+}
+
+pub fn payload_checksum(payload: &[u8]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(payload);
+    crc.sum()
+}
+
+// Parse the header of a mapped index file of the given kind.
+// Returns the header (None for legacy files) and the start of the payload.
+pub fn parse_index_file(bytes: &[u8], kind: IndexFileKind) -> Result<(Option<IndexFileHeader>, usize), Error> {
+    match IndexFileHeader::from_bytes(bytes)? {
+        Some(header) => {
+            header.check_kind(kind)?;
+            header.check_file_size(bytes.len())?;
+            Ok((Some(header), HEADER_SIZE))
+        }
+        None => Ok((None, 0)),
+    }
+}
+
+pub fn read_index_file_header(path: &str) -> Result<Option<IndexFileHeader>, Error> {
+    let file = File::open(path)?;
+    let mut bytes = Vec::with_capacity(HEADER_SIZE);
+    file.take(HEADER_SIZE as u64).read_to_end(&mut bytes)?;
+    IndexFileHeader::from_bytes(&bytes)
+}
+
+pub fn index_file_kinds(mode: &IndexMode) -> [(&'static str, IndexFileKind); 2] {
+    match mode {
+        IndexMode::Id => [("offset", IndexFileKind::IdOffset), ("value", IndexFileKind::IdValue)],
+        IndexMode::Big => [("offset", IndexFileKind::BigOffset), ("value", IndexFileKind::BigValue)],
+    }
+}
+
+// Check headers of the offset and value files against the index config
+// before loading them. Errors mention the offending file.
+pub fn check_index_files(index_prefix: &str, mode: &IndexMode, hash_type: HashType) -> Result<(), Error> {
+    for (ext, kind) in index_file_kinds(mode) {
+        let path = format!("{}.{}", index_prefix, ext);
+        let with_path = |e: Error| Error::new(e.kind(), format!("{}: {}", path, e));
+        if let Some(header) = read_index_file_header(&path).map_err(with_path)? {
+            header.check_kind(kind).map_err(with_path)?;
+            if header.hash_type != hash_type {
+                return Err(with_path(Error::new(ErrorKind::InvalidData, format!(
+                    "File was built with {} hashes but the index config says {}",
+                    header.hash_type.to_string(), hash_type.to_string()
+                ))));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Overwrite the header of a file whose payload was already written after HEADER_SIZE bytes
+pub fn write_index_file_header(path: &str, header: &IndexFileHeader) -> Result<(), Error> {
+    let mut file = std::fs::OpenOptions::new().write(true).open(path)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&header.to_bytes())?;
+    Ok(())
+}
+
+// Recompute the checksum of the payload. Returns Ok(None) for legacy files.
+pub fn verify_index_file(path: &str) -> Result<Option<IndexFileHeader>, Error> {
+    let file = File::open(path)?;
+    let mmap = unsafe { memmap2::Mmap::map(&file)? };
+    let header = match IndexFileHeader::from_bytes(&mmap)? {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    header.check_file_size(mmap.len())?;
+    if mmap.len() != HEADER_SIZE + header.payload_size {
+        return Err(Error::new(ErrorKind::InvalidData, format!(
+            "Index file has {} trailing bytes", mmap.len() - HEADER_SIZE - header.payload_size
+        )));
+    }
+    let checksum = payload_checksum(&mmap[HEADER_SIZE..]);
+    if checksum != header.checksum {
+        return Err(Error::new(ErrorKind::InvalidData, format!(
+            "Checksum mismatch: expected {:08x} but computed {:08x}", header.checksum, checksum
+        )));
+    }
+    Ok(Some(header))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index_file_header() {
+        let payload = vec![1u8, 2, 3, 4, 5, 6, 7, 8];
+        let header = IndexFileHeader::new(IndexFileKind::IdValue, HashType::PDBTrRosetta, 4, 16, &payload);
+        let bytes = header.to_bytes();
+        assert_eq!(IndexFileHeader::from_bytes(&bytes).unwrap(), Some(header));
+
+        // Legacy files have no magic number
+        assert_eq!(IndexFileHeader::from_bytes(&payload).unwrap(), None);
+        // Newer versions are rejected with a clear message
+        let mut newer = bytes;
+        newer[8..12].copy_from_slice(&(INDEX_FORMAT_VERSION + 1).to_le_bytes());
+        let error = IndexFileHeader::from_bytes(&newer).unwrap_err();
+        assert!(error.to_string().contains("newer than the supported version"));
+
+        let path = "data/test_index_header.value";
+        let mut file_bytes = bytes.to_vec();
+        file_bytes.extend_from_slice(&payload);
+        std::fs::write(path, &file_bytes).unwrap();
+        assert_eq!(verify_index_file(path).unwrap(), Some(header));
+        assert_eq!(parse_index_file(&file_bytes, IndexFileKind::IdValue).unwrap().1, HEADER_SIZE);
+        assert!(parse_index_file(&file_bytes, IndexFileKind::IdOffset).is_err());
+        // Truncated and corrupted copies
+        assert!(parse_index_file(&file_bytes[..HEADER_SIZE + 4], IndexFileKind::IdValue).is_err());
+        file_bytes[HEADER_SIZE] ^= 0xFF;
+        std::fs::write(path, &file_bytes).unwrap();
+        assert!(verify_index_file(path).unwrap_err().to_string().contains("Checksum mismatch"));
+        std::fs::remove_file(path).unwrap();
+    }
+}
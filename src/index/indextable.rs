@@ -5,8 +5,14 @@ use std::io::Write;
 use std::mem::ManuallyDrop;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
-use memmap2::{Mmap, MmapMut};
-// use rayon::iter::{IndexedParallelIterator, ParallelIterator};
+use memmap2::{Mmap, MmapMut, MmapOptions};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::geometry::core::HashType;
+use crate::index::checkpoint::{read_raw_array_into, write_raw_array, IndexCheckpoint, IndexingPhase};
+use crate::index::header::{
+    parse_index_file, write_index_file_header, IndexFileHeader, IndexFileKind, HEADER_SIZE
+};
 
 
 pub struct FolddiscoIndex {
@@ -17,12 +23,13 @@ pub struct FolddiscoIndex {
     entries: UnsafeCell<MmapMut>,
     index_path: String,
     mmap_on_disk: bool,
+    hash_type: HashType,
 }
 
 unsafe impl Sync for FolddiscoIndex {}
 
 impl FolddiscoIndex {
-    pub fn new(total_hashes: usize, path: String, mmap_on_disk: bool) -> Self {
+    pub fn new(total_hashes: usize, path: String, mmap_on_disk: bool, hash_type: HashType) -> Self {
         let offsets = vec![0usize; total_hashes + 1];
         let last_id = vec![usize::MAX; total_hashes];
         let entries = MmapMut::map_anon(1024).unwrap();
@@ -36,6 +43,7 @@ impl FolddiscoIndex {
             entries: UnsafeCell::new(entries),
             index_path: path,
             mmap_on_disk,
+            hash_type,
         }
     }
 
@@ -177,8 +185,9 @@ impl FolddiscoIndex {
                 .create(true)
                 .open(index_path)
                 .unwrap();
-            index_file.set_len(total_entries as u64).unwrap();
-            let mmap = unsafe { MmapMut::map_mut(&index_file).unwrap() };
+            // Space for the header is reserved and filled in finish_index
+            index_file.set_len((HEADER_SIZE + total_entries) as u64).unwrap();
+            let mmap = unsafe { MmapOptions::new().offset(HEADER_SIZE as u64).map_mut(&index_file).unwrap() };
             *entries = mmap;
         } else {
            // Allocate an anonymous memory map for total_entries in memory
@@ -200,23 +209,29 @@ impl FolddiscoIndex {
         }
         offsets[0] = 0;
 
+        let index_path = format!("{}.value", self.index_path);
+        let total_entries = offsets[self.total_hashes];
         if !self.mmap_on_disk {
             // Copy the data to a file
-            let index_path = format!("{}.value", self.index_path);
             let index_file = std::fs::OpenOptions::new()
                 .read(true)
                 .write(true)
                 .create(true)
-                .open(index_path)
+                .open(&index_path)
                 .unwrap();
-            let total_entries = offsets[self.total_hashes];
-            index_file.set_len(total_entries as u64).unwrap();
+            index_file.set_len((HEADER_SIZE + total_entries) as u64).unwrap();
             
             // Map the file and copy the data
-            let mut file_mmap = unsafe { memmap2::MmapMut::map_mut(&index_file).unwrap() };
+            let mut file_mmap = unsafe { MmapOptions::new().offset(HEADER_SIZE as u64).map_mut(&index_file).unwrap() };
             file_mmap.copy_from_slice(&entries[..total_entries]);
             file_mmap.flush().unwrap();
+        } else {
+            entries.flush().unwrap();
         }
+        let header = IndexFileHeader::new(
+            IndexFileKind::BigValue, self.hash_type, total_entries, 8, &entries[..total_entries]
+        );
+        write_index_file_header(&index_path, &header).expect("Unable to write index header");
     }
 
     pub fn get_raw_entries(&self, hash: usize) -> &[u8] {
@@ -237,7 +252,97 @@ impl FolddiscoIndex {
         let raw_entries = self.get_raw_entries(hash as usize);
         merge_usize_vec_from_bytes(raw_entries)
     }
-    
+
+    // Number of ids stored for the hash without decoding them.
+    // Each id ends with a byte whose first bit is not set.
+    pub fn get_entry_count(&self, hash: u32) -> usize {
+        self.get_raw_entries(hash as usize).iter().filter(|&&byte| byte & 0x80 == 0).count()
+    }
+
+    pub fn total_hashes(&self) -> usize {
+        self.total_hashes
+    }
+
+    // Counting step for copying entries of another index into this one.
+    // Ids are passed through id_map and dropped if None. Mapped ids should be
+    // smaller than any id counted afterwards to keep the delta encoding valid.
+    pub fn count_entries_from_index<F>(&self, other: &FolddiscoIndex, id_map: F)
+    where F: Fn(usize) -> Option<usize> + Sync {
+        let total_hashes = std::cmp::min(self.total_hashes, other.total_hashes);
+        (0..total_hashes).into_par_iter().for_each(|hash| {
+            if other.get_raw_entries(hash).is_empty() {
+                return;
+            }
+            for id in other.get_entries(hash as u32) {
+                if let Some(id) = id_map(id) {
+                    self.count_single_entry(hash as u32, id);
+                }
+            }
+        });
+    }
+
+    // Filling step matching count_entries_from_index. Call after allocate_entries
+    pub fn add_entries_from_index<F>(&self, other: &FolddiscoIndex, id_map: F)
+    where F: Fn(usize) -> Option<usize> + Sync {
+        let total_hashes = std::cmp::min(self.total_hashes, other.total_hashes);
+        (0..total_hashes).into_par_iter().for_each_init(
+            || Vec::with_capacity(8),
+            |bit_container, hash| {
+                if other.get_raw_entries(hash).is_empty() {
+                    return;
+                }
+                for id in other.get_entries(hash as u32) {
+                    if let Some(id) = id_map(id) {
+                        self.add_single_entry(hash as u32, id, bit_container);
+                    }
+                }
+            }
+        );
+    }
+
+    // Write counting or adding state to checkpoint arrays. Entries are only
+    // written in the adding phase. On-disk entries are flushed instead of copied.
+    pub fn save_state(&self, checkpoint: &IndexCheckpoint, index_path: &str) -> std::io::Result<()> {
+        let offsets = unsafe { &*self.offsets.get() };
+        let last_id = unsafe { &*self.last_id.get() };
+        let entries = unsafe { &*self.entries.get() };
+        write_raw_array(&checkpoint.array_path(index_path, "offsets"), offsets)?;
+        write_raw_array(&checkpoint.array_path(index_path, "last_id"), last_id)?;
+        if checkpoint.phase == IndexingPhase::Adding {
+            if self.mmap_on_disk {
+                entries.flush()?;
+            } else {
+                let total_entries = offsets[self.total_hashes];
+                write_raw_array(&checkpoint.array_path(index_path, "entries"), &entries[..total_entries])?;
+            }
+        }
+        Ok(())
+    }
+
+    // Restore state written by save_state into a freshly created index
+    pub fn load_state(&self, checkpoint: &IndexCheckpoint, index_path: &str) -> std::io::Result<()> {
+        let offsets = unsafe { &mut *self.offsets.get() };
+        let last_id = unsafe { &mut *self.last_id.get() };
+        let entries = unsafe { &mut *self.entries.get() };
+        read_raw_array_into(&checkpoint.array_path(index_path, "offsets"), offsets)?;
+        read_raw_array_into(&checkpoint.array_path(index_path, "last_id"), last_id)?;
+        if checkpoint.phase == IndexingPhase::Adding {
+            let total_entries = offsets[self.total_hashes];
+            if self.mmap_on_disk {
+                let index_file = std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(format!("{}.value", self.index_path))?;
+                *entries = unsafe { MmapOptions::new().offset(HEADER_SIZE as u64).map_mut(&index_file)? };
+            } else {
+                let mut mmap = MmapMut::map_anon(total_entries)?;
+                read_raw_array_into(&checkpoint.array_path(index_path, "entries"), &mut mmap[..])?;
+                *entries = mmap;
+            }
+        }
+        Ok(())
+    }
+
     pub fn save_offset_to_file(&self) {
         let offsets = unsafe { &*self.offsets.get() };
         let offset_path = format!("{}.offset", self.index_path);
@@ -247,33 +352,63 @@ impl FolddiscoIndex {
             std::slice::from_raw_parts(offsets.as_ptr() as *const u8,
             offsets.len() * std::mem::size_of::<usize>())
         };
+        let header = IndexFileHeader::new(
+            IndexFileKind::BigOffset, self.hash_type, offsets.len(), usize::BITS as usize, offset_bytes
+        );
+        writer.write_all(&header.to_bytes()).expect("Unable to write offset header");
         writer.write_all(offset_bytes).expect("Unable to write offset bytes");
     }
         
 }
 
 pub fn load_big_index(index_prefix: &str) -> (FolddiscoIndex, Mmap) {
+    try_load_big_index(index_prefix).unwrap_or_else(|e| panic!("Unable to load big index {}: {}", index_prefix, e))
+}
+
+// Same as load_big_index but gives an error for missing, truncated or mismatched files
+pub fn try_load_big_index(index_prefix: &str) -> std::io::Result<(FolddiscoIndex, Mmap)> {
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
     let offset_path = format!("{}.offset", index_prefix);
     let index_path = format!("{}.value", index_prefix);
-    let offset_file = std::fs::File::open(&offset_path).expect("Unable to open offset file");
-    let offset_mmap = unsafe { Mmap::map(&offset_file).expect("Unable to map offset file") };
+    let offset_file = std::fs::File::open(&offset_path)?;
+    let offset_mmap = unsafe { Mmap::map(&offset_file)? };
+    let (offset_header, offset_start) = parse_index_file(&offset_mmap, IndexFileKind::BigOffset)
+        .map_err(|e| invalid(format!("Unable to read offset file {}: {}", offset_path, e)))?;
+    let num_offsets = match offset_header {
+        Some(header) => {
+            if header.element_bits != usize::BITS as usize {
+                return Err(invalid(format!(
+                    "Offset file {} was written with {}-bit offsets", offset_path, header.element_bits
+                )));
+            }
+            header.num_elements
+        }
+        None => offset_mmap.len() / std::mem::size_of::<usize>(),
+    };
+    let offsets_end = num_offsets.checked_mul(std::mem::size_of::<usize>()).and_then(|x| x.checked_add(offset_start));
+    if num_offsets == 0 || offsets_end.is_none_or(|end| end > offset_mmap.len()) {
+        return Err(invalid(format!("Offset file {} is truncated", offset_path)));
+    }
     let offsets = unsafe {
-        let offsets_ptr = offset_mmap.as_ptr() as *const usize;
-        ManuallyDrop::new(Vec::from_raw_parts(
-            offsets_ptr as *mut usize, offset_mmap.len() / std::mem::size_of::<usize>(),
-            offset_mmap.len() / std::mem::size_of::<usize>()
-        ))
+        let offsets_ptr = offset_mmap.as_ptr().add(offset_start) as *const usize;
+        ManuallyDrop::new(Vec::from_raw_parts(offsets_ptr as *mut usize, num_offsets, num_offsets))
     };
     let total_hashes = offsets.len() - 1;
 
     let entries_file = std::fs::OpenOptions::new()
         .read(true)
         .write(true)
-        .open(&index_path)
-        .expect("Unable to open index file");
-    let entries_mmap = unsafe { MmapMut::map_mut(&entries_file).expect("Unable to map index file") };
+        .open(&index_path)?;
+    let entries_mmap = unsafe { Mmap::map(&entries_file)? };
+    let (entries_header, entries_start) = parse_index_file(&entries_mmap, IndexFileKind::BigValue)
+        .map_err(|e| invalid(format!("Unable to read index file {}: {}", index_path, e)))?;
+    let hash_type = entries_header.map_or(HashType::Other, |header| header.hash_type);
+    drop(entries_mmap);
+    let entries_mmap = unsafe {
+        MmapOptions::new().offset(entries_start as u64).map_mut(&entries_file)?
+    };
 
-    ( FolddiscoIndex {
+    Ok(( FolddiscoIndex {
         offsets: UnsafeCell::new(vec![]),
         last_id: UnsafeCell::new(vec![]),
         loaded_offsets: offsets,
@@ -281,7 +416,8 @@ pub fn load_big_index(index_prefix: &str) -> (FolddiscoIndex, Mmap) {
         entries: UnsafeCell::new(entries_mmap),
         index_path,
         mmap_on_disk: true,
-    }, offset_mmap )
+        hash_type,
+    }, offset_mmap ))
 }
 
 #[inline(always)]
@@ -362,7 +498,7 @@ mod tests {
     fn test_folddisco_index() {
         let total_hashes = 10;
         let start = std::time::Instant::now();
-        let index = FolddiscoIndex::new(total_hashes, "test.index".to_string(), false);
+        let index = FolddiscoIndex::new(total_hashes, "test.index".to_string(), false, HashType::PDBTrRosetta);
 
         let hashes1: Vec<u32> = (0u32..7).collect();
         let id1 = 0usize;
@@ -406,6 +542,92 @@ mod tests {
             println!("Entries for hash {}: {:?}", i, entries);
         }
     }
-    
+
+    #[test]
+    fn test_copy_entries_from_index() {
+        let total_hashes = 10;
+        let source_path = "data/test_copy_source.index".to_string();
+        let source = FolddiscoIndex::new(total_hashes, source_path.clone(), false, HashType::PDBTrRosetta);
+        let hashes1: Vec<u32> = vec![0, 2, 4, 6, 8];
+        let hashes2: Vec<u32> = vec![1, 2, 3];
+        source.count_entries(&hashes1, 0);
+        source.count_entries(&hashes2, 300);
+        source.allocate_entries();
+        let mut bit_container = Vec::with_capacity(8);
+        source.add_entries(&hashes1, 0, &mut bit_container);
+        source.add_entries(&hashes2, 300, &mut bit_container);
+        source.finish_index();
+        source.save_offset_to_file();
+        let (loaded, offset_mmap) = load_big_index(&source_path);
+
+        // Copy with shifted ids, then append a new entry after the copied ones
+        let merged = FolddiscoIndex::new(total_hashes, "data/test_copy_merged.index".to_string(), false, HashType::PDBTrRosetta);
+        let hashes3: Vec<u32> = vec![2, 9];
+        merged.count_entries_from_index(&loaded, |id| Some(id + 5));
+        merged.count_entries(&hashes3, 1000);
+        merged.allocate_entries();
+        merged.add_entries_from_index(&loaded, |id| Some(id + 5));
+        merged.add_entries(&hashes3, 1000, &mut bit_container);
+        merged.finish_index();
+        assert_eq!(merged.get_entries(0), vec![5]);
+        assert_eq!(merged.get_entries(1), vec![305]);
+        assert_eq!(merged.get_entries(2), vec![5, 305, 1000]);
+        assert_eq!(merged.get_entries(5), Vec::<usize>::new());
+        assert_eq!(merged.get_entries(9), vec![1000]);
+        assert_eq!(merged.get_entry_count(2), 3);
+        assert_eq!(merged.get_entry_count(5), 0);
+        drop(loaded);
+        drop(offset_mmap);
+        // Truncated offsets or a missing value file are errors instead of panics
+        let offset_bytes = std::fs::read("data/test_copy_source.index.offset").unwrap();
+        std::fs::write("data/test_copy_source.index.offset", &offset_bytes[..HEADER_SIZE + 8]).unwrap();
+        assert!(try_load_big_index(&source_path).is_err());
+        assert!(try_load_big_index("data/test_copy_missing.index").is_err());
+        std::fs::remove_file("data/test_copy_source.index.offset").unwrap();
+        std::fs::remove_file("data/test_copy_source.index.value").unwrap();
+        std::fs::remove_file("data/test_copy_merged.index.value").unwrap();
+    }
+
+    #[test]
+    fn test_resume_from_state() {
+        let total_hashes = 10;
+        let index_path = "data/test_resume.index";
+        let batches: Vec<(Vec<u32>, usize)> = vec![
+            (vec![0, 2, 4, 6, 8], 0), (vec![1, 2, 3], 300), (vec![2, 9], 1000), (vec![0, 9], 70000),
+        ];
+        let mut bit_container = Vec::with_capacity(8);
+        let reference = FolddiscoIndex::new(total_hashes, index_path.to_string(), false, HashType::PDBTrRosetta);
+        batches.iter().for_each(|(hashes, id)| reference.count_entries(hashes, *id));
+        reference.allocate_entries();
+        batches.iter().for_each(|(hashes, id)| reference.add_entries(hashes, *id, &mut bit_container));
+
+        let checkpoint = |phase, generation| IndexCheckpoint {
+            phase, completed_chunks: 2, chunk_size: 2, num_structures: 4, input_checksum: 0,
+            hash_type: HashType::PDBTrRosetta, num_bin_dist: 16, num_bin_angle: 4, generation,
+        };
+        // Interrupted while counting
+        let counting = checkpoint(IndexingPhase::Counting, 1);
+        let first = FolddiscoIndex::new(total_hashes, index_path.to_string(), false, HashType::PDBTrRosetta);
+        batches[..2].iter().for_each(|(hashes, id)| first.count_entries(hashes, *id));
+        first.save_state(&counting, index_path).unwrap();
+        // Interrupted while adding
+        let adding = checkpoint(IndexingPhase::Adding, 2);
+        let second = FolddiscoIndex::new(total_hashes, index_path.to_string(), false, HashType::PDBTrRosetta);
+        second.load_state(&counting, index_path).unwrap();
+        batches[2..].iter().for_each(|(hashes, id)| second.count_entries(hashes, *id));
+        second.allocate_entries();
+        batches[..2].iter().for_each(|(hashes, id)| second.add_entries(hashes, *id, &mut bit_container));
+        second.save_state(&adding, index_path).unwrap();
+
+        let third = FolddiscoIndex::new(total_hashes, index_path.to_string(), false, HashType::PDBTrRosetta);
+        third.load_state(&adding, index_path).unwrap();
+        batches[2..].iter().for_each(|(hashes, id)| third.add_entries(hashes, *id, &mut bit_container));
+        let (reference_entries, third_entries) = unsafe { (&*reference.entries.get(), &*third.entries.get()) };
+        assert_eq!(unsafe { &*reference.offsets.get() }, unsafe { &*third.offsets.get() });
+        assert_eq!(reference_entries[..], third_entries[..]);
+        counting.remove(index_path);
+        adding.remove(index_path);
+    }
+
 }
 
@@ -4,14 +4,17 @@
 // id\tpath\tinteger\tfloat
 // id\tpath\tn_res\tplddt
 
-use std::io::Write;
+use std::io::{Error, ErrorKind, Write};
 use std::fs::File;
 use std::io::BufWriter;
+use std::path::PathBuf;
 
 use memmap2::Mmap;
 use rayon::iter::ParallelIterator;
 use rayon::str::ParallelString;
 
+use crate::geometry::core::HashType;
+use crate::index::header::{parse_index_file, IndexFileHeader, IndexFileKind, HEADER_SIZE};
 use crate::utils::log::{log_msg, FAIL};
 
 pub fn save_lookup_to_file(
@@ -65,20 +68,217 @@ pub fn save_lookup_to_file(
 //     (path_vec, numeric_id_vec, integer_vec, float_vec)
 // }
 pub fn load_lookup_from_file(path: &str) -> Vec<(String, usize, usize, f32)> {
-    let file = std::fs::File::open(path).expect(&log_msg(FAIL, "Unable to open the lookup file"));
-    let mmap = unsafe { Mmap::map(&file).expect(&log_msg(FAIL, "Unable to mmap the lookup file")) };
-    let content = unsafe { std::str::from_utf8_unchecked(&mmap) };
-    let loaded_lookup = content.par_lines().map(|line| {
+    try_load_lookup_from_file(path).unwrap_or_else(|e| panic!(
+        "{}", log_msg(FAIL, &format!("Unable to read the lookup file {}: {}", path, e))
+    ))
+}
+
+// Same as load_lookup_from_file but gives an error for a missing file or a malformed line
+pub fn try_load_lookup_from_file(path: &str) -> Result<Vec<(String, usize, usize, f32)>, Error> {
+    let file = std::fs::File::open(path)?;
+    let mmap = unsafe { Mmap::map(&file)? };
+    let content = std::str::from_utf8(&mmap).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    let parse_line = |line: &str| {
         let mut split = line.split("\t");
-        let id = split.next().unwrap().parse::<usize>().unwrap();
-        let name = split.next().unwrap().to_string();
-        let nres = split.next().unwrap().parse::<usize>().unwrap();
-        let plddt = split.next().unwrap().parse::<f32>().unwrap();
-        (name, id, nres, plddt)
-    }).collect::<Vec<_>>();
-    loaded_lookup
+        let id = split.next()?.parse::<usize>().ok()?;
+        let name = split.next()?.to_string();
+        let nres = split.next()?.parse::<usize>().ok()?;
+        let plddt = split.next()?.parse::<f32>().ok()?;
+        Some((name, id, nres, plddt))
+    };
+    content.par_lines().map(|line| parse_line(line).ok_or_else(
+        || Error::new(ErrorKind::InvalidData, format!("Malformed lookup line: {}", line))
+    )).collect()
+}
+
+// Binary lookup ({index}.blookup), mapped and read lazily at query time
+// Layout after the 64 byte index file header (kind "lookup"):
+//   records, one per line of the text lookup: nid (u64), nres (u32), plddt (f32)
+//   name offsets relative to the first name, num_elements + 1 u64
+//   names in UTF-8 without separators
+// All numbers are little endian.
+const RECORD_SIZE: usize = 16;
+
+pub struct BinaryLookup {
+    mmap: Mmap,
+    num_entries: usize,
+    // Byte ranges of the records, offsets and names in the mapped file
+    records_start: usize,
+    offsets_start: usize,
+    names_start: usize,
 }
 
+impl BinaryLookup {
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        let header = match parse_index_file(&mmap, IndexFileKind::Lookup)? {
+            (Some(header), _) => header,
+            (None, _) => return Err(Error::new(ErrorKind::InvalidData, "Binary lookup has no header")),
+        };
+        let num_entries = header.num_elements;
+        let truncated = || Error::new(ErrorKind::InvalidData, "Binary lookup is truncated");
+        let offsets_start = num_entries.checked_mul(RECORD_SIZE).and_then(|x| x.checked_add(HEADER_SIZE)).ok_or_else(truncated)?;
+        let names_start = num_entries.checked_add(1).and_then(|x| x.checked_mul(8))
+            .and_then(|x| x.checked_add(offsets_start)).ok_or_else(truncated)?;
+        let end = HEADER_SIZE + header.payload_size;
+        if end < names_start {
+            return Err(truncated());
+        }
+        let lookup = BinaryLookup { mmap, num_entries, records_start: HEADER_SIZE, offsets_start, names_start };
+        lookup.check_names(end - names_start)?;
+        Ok(lookup)
+    }
+
+    // Name offsets must start at 0, never decrease and stay within the names,
+    // and each name must be valid UTF-8. Done once so that get can't fail
+    fn check_names(&self, names_len: usize) -> Result<(), Error> {
+        let invalid = |message: String| Error::new(ErrorKind::InvalidData, format!("Binary lookup is corrupted: {}", message));
+        if self.name_offset(0) != 0 {
+            return Err(invalid("first name offset is not 0".to_string()));
+        }
+        for index in 0..self.num_entries {
+            let (start, end) = (self.name_offset(index), self.name_offset(index + 1));
+            if end < start || end > names_len {
+                return Err(invalid(format!("name offset {} of entry {} is out of range", end, index)));
+            }
+            let name = &self.mmap[self.names_start + start..self.names_start + end];
+            std::str::from_utf8(name).map_err(|e| invalid(format!("name of entry {} is not UTF-8: {}", index, e)))?;
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.num_entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_entries == 0
+    }
+
+    fn name_offset(&self, index: usize) -> usize {
+        let start = self.offsets_start + index * 8;
+        u64::from_le_bytes(self.mmap[start..start + 8].try_into().unwrap()) as usize
+    }
+
+    // (name, nid, nres, plddt) of the index-th entry
+    #[inline(always)]
+    pub fn get(&self, index: usize) -> (&str, usize, usize, f32) {
+        let record = &self.mmap[self.records_start + index * RECORD_SIZE..self.records_start + (index + 1) * RECORD_SIZE];
+        let nid = u64::from_le_bytes(record[0..8].try_into().unwrap()) as usize;
+        let nres = u32::from_le_bytes(record[8..12].try_into().unwrap()) as usize;
+        let plddt = f32::from_le_bytes(record[12..16].try_into().unwrap());
+        let name = &self.mmap[self.names_start + self.name_offset(index)..self.names_start + self.name_offset(index + 1)];
+        // Offsets and names are checked on open
+        (std::str::from_utf8(name).expect("Binary lookup names are checked on open"), nid, nres, plddt)
+    }
+}
+
+pub fn save_binary_lookup(path: &str, lookup: &[(String, usize, usize, f32)]) -> Result<(), Error> {
+    let mut payload = Vec::with_capacity(lookup.len() * (RECORD_SIZE + 8) + 8);
+    for (_, nid, nres, plddt) in lookup.iter() {
+        payload.extend_from_slice(&(*nid as u64).to_le_bytes());
+        payload.extend_from_slice(&(*nres as u32).to_le_bytes());
+        payload.extend_from_slice(&plddt.to_le_bytes());
+    }
+    let mut name_offset = 0u64;
+    payload.extend_from_slice(&name_offset.to_le_bytes());
+    for (name, _, _, _) in lookup.iter() {
+        name_offset += name.len() as u64;
+        payload.extend_from_slice(&name_offset.to_le_bytes());
+    }
+    lookup.iter().for_each(|(name, _, _, _)| payload.extend_from_slice(name.as_bytes()));
+    let header = IndexFileHeader::new(IndexFileKind::Lookup, HashType::Other, lookup.len(), RECORD_SIZE * 8, &payload);
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(&header.to_bytes())?;
+    writer.write_all(&payload)?;
+    writer.flush()
+}
+
+// Lookup of an index as used by queries. The binary lookup is used if the index has one
+pub enum LookupTable {
+    Text(Vec<(String, usize, usize, f32)>),
+    Binary(BinaryLookup),
+}
+
+impl LookupTable {
+    pub fn len(&self) -> usize {
+        match self {
+            LookupTable::Text(lookup) => lookup.len(),
+            LookupTable::Binary(lookup) => lookup.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[inline(always)]
+    pub fn get(&self, index: usize) -> (&str, usize, usize, f32) {
+        match self {
+            LookupTable::Text(lookup) => {
+                let (name, nid, nres, plddt) = &lookup[index];
+                (name.as_str(), *nid, *nres, *plddt)
+            }
+            LookupTable::Binary(lookup) => lookup.get(index),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, usize, usize, f32)> + '_ {
+        (0..self.len()).map(move |index| self.get(index))
+    }
+}
+
+pub fn load_lookup_table(index_prefix: &str) -> LookupTable {
+    try_load_lookup_table(index_prefix).unwrap_or_else(|e| panic!(
+        "{}", log_msg(FAIL, &format!("Unable to load the lookup of {}: {}", index_prefix, e))
+    ))
+}
+
+// Same as load_lookup_table but gives an error for a missing or corrupted lookup
+pub fn try_load_lookup_table(index_prefix: &str) -> Result<LookupTable, Error> {
+    let binary_path = format!("{}.blookup", index_prefix);
+    if PathBuf::from(&binary_path).is_file() {
+        Ok(LookupTable::Binary(BinaryLookup::open(&binary_path)?))
+    } else {
+        Ok(LookupTable::Text(try_load_lookup_from_file(&format!("{}.lookup", index_prefix))?))
+    }
+}
+
+// Write {index}.blookup from the text lookup. Returns the number of entries
+pub fn convert_lookup_to_binary(index_prefix: &str) -> usize {
+    let binary_path = format!("{}.blookup", index_prefix);
+    let lookup = load_lookup_from_file(&format!("{}.lookup", index_prefix));
+    save_binary_lookup(&binary_path, &lookup).unwrap_or_else(|_| panic!(
+        "{}", log_msg(FAIL, &format!("Unable to write the binary lookup: {}", binary_path))
+    ));
+    lookup.len()
+}
+
+// Keep the binary lookup of an index in sync after its text lookup was rewritten
+pub fn refresh_binary_lookup(index_prefix: &str) {
+    if PathBuf::from(format!("{}.blookup", index_prefix)).is_file() {
+        convert_lookup_to_binary(index_prefix);
+    }
+}
+
+// Locations of structures whose IDs are not paths ({index}.paths)
+// One path per line in the order of numeric ids
+pub fn save_locations_to_file(path: &str, location_vec: &[String]) {
+    let mut file = BufWriter::new(File::create(path).unwrap_or_else(|_| panic!(
+        "{}", log_msg(FAIL, "Unable to create the path file")
+    )));
+    for location in location_vec.iter() {
+        writeln!(file, "{}", location).unwrap_or_else(|_| panic!(
+            "{}", log_msg(FAIL, "Unable to write the path file")
+        ));
+    }
+}
+
+pub fn load_locations_from_file(path: &str) -> Option<Vec<String>> {
+    let content = std::fs::read_to_string(path).ok()?;
+    Some(content.lines().map(|line| line.to_string()).collect())
+}
 
 #[cfg(test)]
 mod tests {
@@ -104,8 +304,46 @@ mod tests {
         let loaded_lookup = load_lookup_from_file(path);
         // Check that the loaded data is the same as the original data
         assert_eq!(loaded_lookup, expected_lookup);
+        // Malformed lines are errors instead of panics
+        std::fs::write(path, "0\tpath1.pdb\t100\t50\n1\tpath2.pdb\n").unwrap();
+        assert!(try_load_lookup_from_file(path).is_err());
 
         // Clean up the test file
         // std::fs::remove_file(path).unwrap();
     }
+
+    #[test]
+    fn test_binary_lookup() {
+        let path = "data/lookup_test.blookup";
+        let lookup = vec![
+            ("path1.pdb".to_string(), 0, 100, 50.0),
+            ("".to_string(), 1, 0, 0.0),
+            ("dir/path3.pdb_B".to_string(), 7, 5000, 70.5),
+        ];
+        save_binary_lookup(path, &lookup).unwrap();
+        assert!(crate::index::header::verify_index_file(path).unwrap().is_some());
+        let binary = LookupTable::Binary(BinaryLookup::open(path).unwrap());
+        let text = LookupTable::Text(lookup.clone());
+        assert_eq!(binary.len(), 3);
+        assert_eq!(binary.iter().collect::<Vec<_>>(), text.iter().collect::<Vec<_>>());
+        assert_eq!(binary.get(2), ("dir/path3.pdb_B", 7, 5000, 70.5));
+
+        // Offsets past the names or names that are not UTF-8 fail the open
+        let bytes = std::fs::read(path).unwrap();
+        let offsets_start = HEADER_SIZE + lookup.len() * RECORD_SIZE;
+        let names_start = offsets_start + (lookup.len() + 1) * 8;
+        let rewrite = |payload: &[u8]| {
+            let header = IndexFileHeader::new(IndexFileKind::Lookup, HashType::Other, lookup.len(), RECORD_SIZE * 8, payload);
+            std::fs::write(path, [header.to_bytes().as_slice(), payload].concat()).unwrap();
+        };
+        let mut payload = bytes[HEADER_SIZE..].to_vec();
+        payload[offsets_start + 8 - HEADER_SIZE..offsets_start + 16 - HEADER_SIZE].copy_from_slice(&1000u64.to_le_bytes());
+        rewrite(&payload);
+        assert!(BinaryLookup::open(path).is_err());
+        let mut payload = bytes[HEADER_SIZE..].to_vec();
+        payload[names_start - HEADER_SIZE] = 0xff;
+        rewrite(&payload);
+        assert!(BinaryLookup::open(path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
 }
\ No newline at end of file
@@ -2,4 +2,9 @@
 // Declare inner modules
 pub mod lookup;
 pub mod alloc;
-pub mod indextable;
\ No newline at end of file
+pub mod indextable;
+pub mod tombstone;
+pub mod header;
+pub mod checkpoint;
+pub mod stoplist;
+pub mod coords;
@@ -0,0 +1,104 @@
+// Stop-list of hashes left out of an index
+// Hashes found in more than stop_ratio of the structures of an index have
+// their posting lists dropped at build time. The sidecar {index}.stop keeps
+// each stopped hash with its document frequency, one "hash\tdoc_freq" line
+// per hash, so that queries can report query hashes that could not be used.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::utils::log::{log_msg, FAIL};
+
+pub type StopList = HashMap<u32, usize>;
+
+// Largest document frequency kept in the index
+pub fn max_doc_freq(num_structures: usize, stop_ratio: f32) -> usize {
+    (num_structures as f64 * stop_ratio as f64).floor() as usize
+}
+
+// Drop postings of hashes already in the stop-list and of hashes found in more than
+// max_doc_freq structures. hash_id_vec should be sorted by hash. Document frequencies
+// of dropped postings are added to the stop-list.
+pub fn apply_stop_list(hash_id_vec: &mut Vec<(u32, usize)>, stop_list: &mut StopList, max_doc_freq: usize) {
+    let mut kept = 0;
+    let mut start = 0;
+    while start < hash_id_vec.len() {
+        let hash = hash_id_vec[start].0;
+        let mut end = start + 1;
+        while end < hash_id_vec.len() && hash_id_vec[end].0 == hash {
+            end += 1;
+        }
+        let doc_freq = end - start;
+        if let Some(stopped) = stop_list.get_mut(&hash) {
+            *stopped += doc_freq;
+        } else if doc_freq > max_doc_freq {
+            stop_list.insert(hash, doc_freq);
+        } else {
+            hash_id_vec.copy_within(start..end, kept);
+            kept += doc_freq;
+        }
+        start = end;
+    }
+    hash_id_vec.truncate(kept);
+}
+
+pub fn save_stop_list_to_file(path: &str, stop_list: &StopList) {
+    let mut stopped = stop_list.iter().map(|(&hash, &doc_freq)| (hash, doc_freq)).collect::<Vec<_>>();
+    stopped.sort_unstable();
+    let mut file = BufWriter::new(File::create(path).unwrap_or_else(|_| panic!(
+        "{}", log_msg(FAIL, "Unable to create the stop-list file")
+    )));
+    for (hash, doc_freq) in stopped {
+        writeln!(file, "{}\t{}", hash, doc_freq).unwrap_or_else(|_| panic!(
+            "{}", log_msg(FAIL, "Unable to write the stop-list file")
+        ));
+    }
+    file.flush().unwrap_or_else(|_| panic!("{}", log_msg(FAIL, "Unable to write the stop-list file")));
+}
+
+// Returns None if the index has no stop-list
+pub fn load_stop_list_from_file(path: &str) -> Option<StopList> {
+    try_load_stop_list_from_file(path).unwrap_or_else(|e| panic!("{}", log_msg(FAIL, &e)))
+}
+
+// Same as load_stop_list_from_file but gives an error for a malformed entry
+pub fn try_load_stop_list_from_file(path: &str) -> Result<Option<StopList>, String> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(_) => return Ok(None),
+    };
+    let stop_list = content.lines().map(|line| {
+        let (hash, doc_freq) = line.split_once('\t').ok_or_else(|| format!("Invalid stop-list entry: {}", line))?;
+        Ok((
+            hash.parse().map_err(|_| format!("Invalid hash in the stop-list: {}", hash))?,
+            doc_freq.parse().map_err(|_| format!("Invalid document frequency in the stop-list: {}", doc_freq))?,
+        ))
+    }).collect::<Result<StopList, String>>()?;
+    Ok(Some(stop_list))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_stop_list() {
+        // 10 structures. Hash 3 is in 6 of them, hash 5 in 2 and hash 7 in 1
+        let mut hash_id_vec = vec![(1, 0), (3, 0), (3, 1), (3, 2), (3, 4), (3, 5), (3, 9), (5, 2), (5, 3), (7, 8)];
+        let mut stop_list = StopList::new();
+        stop_list.insert(7, 4);
+        apply_stop_list(&mut hash_id_vec, &mut stop_list, max_doc_freq(10, 0.5));
+        assert_eq!(hash_id_vec, vec![(1, 0), (5, 2), (5, 3)]);
+        assert_eq!(stop_list.len(), 2);
+        assert_eq!(stop_list[&3], 6);
+        assert_eq!(stop_list[&7], 5);
+
+        let path = "data/stoplist_test.stop";
+        save_stop_list_to_file(path, &stop_list);
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "3\t6\n7\t5\n");
+        assert_eq!(load_stop_list_from_file(path), Some(stop_list));
+        assert!(load_stop_list_from_file("data/stoplist_test.none").is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,92 @@
+// Tombstones for structures removed from an index
+// Sidecar file next to the lookup: {index}.deleted
+// One bit per numeric id, least significant bit first.
+// Bit set means the structure is deleted and should be skipped in queries.
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+
+use crate::utils::log::{log_msg, FAIL};
+
+pub fn save_tombstone_to_file(path: &str, deleted: &[bool]) {
+    let mut bytes = vec![0u8; deleted.len().div_ceil(8)];
+    for (id, &is_deleted) in deleted.iter().enumerate() {
+        if is_deleted {
+            bytes[id / 8] |= 1 << (id % 8);
+        }
+    }
+    let mut file = BufWriter::new(File::create(path).unwrap_or_else(|_| panic!(
+        "{}", log_msg(FAIL, "Unable to create the tombstone file")
+    )));
+    file.write_all(&bytes).unwrap_or_else(|_| panic!(
+        "{}", log_msg(FAIL, "Unable to write the tombstone file")
+    ));
+}
+
+// Returns None if there is no tombstone file.
+// The returned vector always has num_ids elements.
+pub fn load_tombstone_from_file(path: &str, num_ids: usize) -> Option<Vec<bool>> {
+    try_load_tombstone_from_file(path, num_ids).unwrap_or_else(|_| panic!(
+        "{}", log_msg(FAIL, "Unable to read the tombstone file")
+    ))
+}
+
+// Same as load_tombstone_from_file but gives an error if the file can't be read
+pub fn try_load_tombstone_from_file(path: &str, num_ids: usize) -> std::io::Result<Option<Vec<bool>>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return Ok(None),
+    };
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    let deleted = (0..num_ids).map(|id| {
+        bytes.get(id / 8).is_some_and(|byte| byte & (1 << (id % 8)) != 0)
+    }).collect();
+    Ok(Some(deleted))
+}
+
+#[inline(always)]
+pub fn is_deleted(deleted: Option<&[bool]>, id: usize) -> bool {
+    match deleted {
+        Some(deleted) => deleted.get(id).copied().unwrap_or(false),
+        None => false,
+    }
+}
+
+// Number of structures that are not deleted out of the first num_ids.
+// Used as the number of structures for IDF
+pub fn count_alive(deleted: Option<&[bool]>, num_ids: usize) -> usize {
+    match deleted {
+        Some(deleted) => num_ids - deleted.iter().take(num_ids).filter(|&&x| x).count(),
+        None => num_ids,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_and_load_tombstone() {
+        let path = "data/tombstone_test.deleted";
+        let deleted = vec![false, true, false, false, false, false, false, false, true, true];
+        save_tombstone_to_file(path, &deleted);
+        assert_eq!(std::fs::metadata(path).unwrap().len(), 2);
+
+        let loaded = load_tombstone_from_file(path, deleted.len()).unwrap();
+        assert_eq!(loaded, deleted);
+        // Ids added after the tombstone was written are not deleted
+        let loaded = load_tombstone_from_file(path, 20).unwrap();
+        assert_eq!(&loaded[..10], &deleted[..]);
+        assert!(loaded[10..].iter().all(|x| !x));
+        assert!(is_deleted(Some(&loaded), 9));
+        assert!(!is_deleted(Some(&loaded), 100));
+        assert!(!is_deleted(None, 1));
+        assert_eq!(count_alive(Some(&loaded), 20), 17);
+        assert_eq!(count_alive(Some(&deleted), 5), 4);
+        assert_eq!(count_alive(None, 20), 20);
+
+        assert!(load_tombstone_from_file("data/tombstone_test.none", 10).is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -46,10 +46,12 @@ pub mod prelude {
     pub use crate::controller::FoldDisco;
     pub use crate::controller::io::{read_offset_map, save_offset_map, write_usize_vector};
     pub use crate::controller::query::{make_query_map, parse_query_string};
+    pub use crate::controller::result::{MatchResult, StructureResult};
+    pub use crate::controller::search::{Index, Query, QueryBuilder, QueryOptions};
 
     pub use crate::geometry::core::{GeometricHash, HashType};
     
-    pub use crate::index::lookup::{save_lookup_to_file, load_lookup_from_file};
+    pub use crate::index::lookup::{save_lookup_to_file, load_lookup_from_file, load_lookup_table, LookupTable};
     pub use crate::index::alloc::IndexBuilder;
     pub use crate::index::alloc::convert_sorted_pairs_to_offset_and_values_vec;
     
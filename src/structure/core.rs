@@ -45,6 +45,70 @@ impl Structure {
     pub fn to_compact(&self) -> CompactStructure {
         CompactStructure::build(self)
     }
+
+    // Atoms of one chain as a separate structure
+    pub fn get_chain(&self, chain: u8) -> Structure {
+        let mut structure = Structure::new();
+        let mut record = (b' ', 0);
+        for i in 0..self.num_atoms {
+            if self.atom_vector.chain[i] == chain {
+                structure.update(self.atom_vector.get(i), &mut record);
+            }
+        }
+        structure
+    }
+
+    // Atoms moved by x' = rotation * x + translation
+    pub fn transform(&self, rotation: &[[f32; 3]; 3], translation: &[f32; 3]) -> Structure {
+        let mut structure = Structure::new();
+        let mut record = (b' ', 0);
+        for i in 0..self.num_atoms {
+            let mut atom = self.atom_vector.get(i);
+            let (x, y, z) = (atom.x, atom.y, atom.z);
+            atom.x = rotation[0][0] * x + rotation[0][1] * y + rotation[0][2] * z + translation[0];
+            atom.y = rotation[1][0] * x + rotation[1][1] * y + rotation[1][2] * z + translation[1];
+            atom.z = rotation[2][0] * x + rotation[2][1] * y + rotation[2][2] * z + translation[2];
+            structure.update(atom, &mut record);
+        }
+        structure
+    }
+
+    // Atoms of the given residues and of the residues with any atom within `environment` Å of them
+    pub fn select_residues(&self, residues: &[(u8, u64)], environment: f32) -> Structure {
+        let atoms = &self.atom_vector;
+        let residue_of = |i: usize| (atoms.chain[i], atoms.res_serial[i]);
+        let motif_atoms = (0..self.num_atoms).filter(
+            |&i| residues.contains(&residue_of(i))
+        ).map(|i| atoms.get_coordinates(i)).collect::<Vec<_>>();
+        let mut selected = residues.to_vec();
+        if environment > 0.0 {
+            for i in 0..self.num_atoms {
+                let coord = atoms.get_coordinates(i);
+                if !selected.contains(&residue_of(i)) && motif_atoms.iter().any(|m| m.distance(&coord) <= environment) {
+                    selected.push(residue_of(i));
+                }
+            }
+        }
+        let mut structure = Structure::new();
+        let mut record = (b' ', 0);
+        for i in 0..self.num_atoms {
+            if selected.contains(&residue_of(i)) {
+                structure.update(atoms.get(i), &mut record);
+            }
+        }
+        structure
+    }
+
+    // Chain ids in the order they first appear
+    pub fn unique_chains(&self) -> Vec<u8> {
+        let mut chains = Vec::new();
+        for chain in self.chains.iter() {
+            if !chains.contains(chain) {
+                chains.push(*chain);
+            }
+        }
+        chains
+    }
     pub fn get_torsion(&self) -> Torsion {
         //FIXME: Right now, only Psi is calculated
         Torsion::build(self, TorsionType::Psi)
@@ -221,6 +285,26 @@ impl CompactStructure {
         }
         None
     }
+
+    // N, CA and CB atoms as a Structure, e.g. for writing. Approximated CB of GLY is left out
+    pub fn to_structure(&self) -> Structure {
+        let mut structure = Structure::new();
+        let mut record = (b' ', 0);
+        let mut atom_serial = 0;
+        for i in 0..self.num_residues {
+            let is_gly = &self.residue_name[i] == b"GLY";
+            for (atom_name, coord) in [(b" N  ", self.get_n(i)), (b" CA ", self.get_ca(i)), (b" CB ", self.get_cb(i))] {
+                if let (Some(coord), false) = (coord, is_gly && atom_name == b" CB ") {
+                    atom_serial += 1;
+                    structure.update(Atom::new(
+                        coord.x, coord.y, coord.z, *atom_name, atom_serial,
+                        self.chain_per_residue[i], self.residue_name[i], self.residue_serial[i], self.b_factors[i],
+                    ), &mut record);
+                }
+            }
+        }
+        structure
+    }
     #[inline(always)]
     pub fn get_ca(&self, idx: usize) -> Option<Coordinate> {
         let (x, y, z) = self.ca_vector.get(idx);
@@ -517,4 +601,25 @@ mod structure_tests {
         println!("Average B-factor: {}", avg_bfactor);
         assert!(avg_bfactor > 0.0);
     }
+
+    #[test]
+    fn test_select_and_transform() {
+        let data = crate::structure::io::pdb::Reader::from_file("data/serine_peptidases_filtered/4cha.pdb")
+            .expect("Unable to read test file");
+        let structure = data.read_structure().expect("Unable to read structure");
+        let motif = structure.select_residues(&[(b'B', 57), (b'B', 102), (b'C', 195)], 0.0);
+        assert_eq!(motif.num_residues, 3);
+        assert_eq!(motif.unique_chains(), vec![b'B', b'C']);
+        let environment = structure.select_residues(&[(b'B', 57), (b'B', 102), (b'C', 195)], 5.0);
+        assert!(environment.num_residues > motif.num_residues);
+        // 90 degrees around z and shifted
+        let moved = motif.transform(&[[0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]], &[1.0, 2.0, 3.0]);
+        let (before, after) = (motif.atom_vector.get(0), moved.atom_vector.get(0));
+        assert!((after.x - (1.0 - before.y)).abs() < 1e-4);
+        assert!((after.y - (2.0 + before.x)).abs() < 1e-4);
+        assert!((after.z - (3.0 + before.z)).abs() < 1e-4);
+        let backbone = motif.to_compact().to_structure();
+        assert_eq!(backbone.num_residues, 3);
+        assert!(backbone.num_atoms <= 9);
+    }
 }
@@ -0,0 +1,200 @@
+// Structures stored in tar archives
+// A member of an archive is addressed as {archive}:{member}, like structures
+// in a Foldcomp DB are addressed as {db}:{id}. Members are read in place
+// without extracting the archive.
+// Each archive is scanned once per process to get the position and size of
+// its members. Members of a plain .tar are read with a seek. .tar.gz and .tgz
+// can't be seeked, so each thread keeps a decoder and moves forward from the
+// last member it read. Reading members in archive order stays linear, so
+// search reads candidates ordered by archive_member_position.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use flate2::read::MultiGzDecoder;
+
+pub const ARCHIVE_EXTENSIONS: [&str; 3] = [".tar", ".tar.gz", ".tgz"];
+
+pub fn is_tar_archive(path: &str) -> bool {
+    ARCHIVE_EXTENSIONS.iter().any(|ext| path.ends_with(ext))
+}
+
+pub fn archive_member_path(archive: &str, member: &str) -> String {
+    format!("{}:{}", archive, member)
+}
+
+// Split {archive}:{member}. None if the path is not in an archive
+pub fn split_archive_path(path: &str) -> Option<(&str, &str)> {
+    ARCHIVE_EXTENSIONS.iter().filter_map(|ext| {
+        let end = path.find(&format!("{}:", ext))? + ext.len();
+        Some((&path[..end], &path[end + 1..]))
+    }).filter(|(_, member)| !member.is_empty()).min_by_key(|(archive, _)| archive.len())
+}
+
+struct ArchiveIndex {
+    // Regular files in archive order
+    members: Vec<String>,
+    // Offset in the uncompressed stream and size of each member
+    positions: HashMap<String, (u64, u64)>,
+}
+
+fn is_gzipped(archive: &str) -> bool {
+    !archive.ends_with(".tar")
+}
+
+fn scan_archive(archive: &str) -> io::Result<ArchiveIndex> {
+    let file = File::open(archive)?;
+    if is_gzipped(archive) {
+        let mut tar = tar::Archive::new(MultiGzDecoder::new(BufReader::new(file)));
+        index_entries(tar.entries()?)
+    } else {
+        let mut tar = tar::Archive::new(file);
+        index_entries(tar.entries_with_seek()?)
+    }
+}
+
+fn index_entries<R: Read>(entries: tar::Entries<'_, R>) -> io::Result<ArchiveIndex> {
+    let mut index = ArchiveIndex { members: Vec::new(), positions: HashMap::new() };
+    for entry in entries {
+        let entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let member = entry.path()?.to_string_lossy().to_string();
+        index.positions.insert(member.clone(), (entry.raw_file_position(), entry.size()));
+        index.members.push(member);
+    }
+    Ok(index)
+}
+
+fn archive_index(archive: &str) -> io::Result<Arc<ArchiveIndex>> {
+    static INDICES: OnceLock<Mutex<HashMap<String, Arc<ArchiveIndex>>>> = OnceLock::new();
+    let mut indices = INDICES.get_or_init(|| Mutex::new(HashMap::new())).lock().unwrap();
+    if let Some(index) = indices.get(archive) {
+        return Ok(index.clone());
+    }
+    let index = Arc::new(scan_archive(archive)?);
+    indices.insert(archive.to_string(), index.clone());
+    Ok(index)
+}
+
+// Names of regular files in the archive in archive order
+pub fn list_archive_members(archive: &str) -> io::Result<Vec<String>> {
+    Ok(archive_index(archive)?.members.clone())
+}
+
+// Raw bytes of a member. Gzipped members are returned as they are
+pub fn read_archive_member(archive: &str, member: &str) -> io::Result<Vec<u8>> {
+    let index = archive_index(archive)?;
+    let &(position, size) = index.positions.get(member).ok_or_else(|| io::Error::new(
+        io::ErrorKind::NotFound, format!("{} not found in {}", member, archive)
+    ))?;
+    let mut bytes = vec![0u8; size as usize];
+    if is_gzipped(archive) {
+        read_gzipped_at(archive, position, &mut bytes)?;
+    } else {
+        let mut file = File::open(archive)?;
+        file.seek(SeekFrom::Start(position))?;
+        file.read_exact(&mut bytes)?;
+    }
+    Ok(bytes)
+}
+
+// Offset of a member in the uncompressed archive. None if it is not in the archive
+pub fn archive_member_position(archive: &str, member: &str) -> Option<u64> {
+    archive_index(archive).ok()?.positions.get(member).map(|&(position, _)| position)
+}
+
+struct GzCursor {
+    archive: String,
+    position: u64,
+    decoder: MultiGzDecoder<BufReader<File>>,
+}
+
+thread_local! {
+    static GZ_CURSOR: RefCell<Option<GzCursor>> = const { RefCell::new(None) };
+}
+
+fn read_gzipped_at(archive: &str, position: u64, bytes: &mut [u8]) -> io::Result<()> {
+    GZ_CURSOR.with(|cursor| {
+        let mut cursor = cursor.borrow_mut();
+        let reusable = matches!(&*cursor, Some(gz) if gz.archive == archive && gz.position <= position);
+        if !reusable {
+            *cursor = Some(GzCursor {
+                archive: archive.to_string(),
+                position: 0,
+                decoder: MultiGzDecoder::new(BufReader::new(File::open(archive)?)),
+            });
+        }
+        let gz = cursor.as_mut().unwrap();
+        let result = (|| {
+            let to_skip = position - gz.position;
+            if io::copy(&mut (&mut gz.decoder).take(to_skip), &mut io::sink())? < to_skip {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Archive ended before the member"));
+            }
+            gz.decoder.read_exact(bytes)
+        })();
+        match result {
+            Ok(()) => gz.position = position + bytes.len() as u64,
+            Err(_) => *cursor = None,
+        }
+        result
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_test_archive(path: &str, members: &[(&str, &[u8])]) {
+        let file = File::create(path).unwrap();
+        let mut builder = if path.ends_with(".tar") {
+            tar::Builder::new(Box::new(file) as Box<dyn io::Write>)
+        } else {
+            tar::Builder::new(Box::new(flate2::write::GzEncoder::new(file, flate2::Compression::default())) as Box<dyn io::Write>)
+        };
+        for (name, data) in members {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, *data).unwrap();
+        }
+        builder.into_inner().unwrap();
+    }
+
+    #[test]
+    fn test_read_archive_members() {
+        let long_name = format!("{}/b.pdb", "d".repeat(120));
+        let members: Vec<(&str, &[u8])> = vec![
+            ("a.pdb", b"first"), (long_name.as_str(), b"second member"), ("c/c.cif", b"third"),
+        ];
+        for archive in ["data/archive_test.tar", "data/archive_test.tar.gz"] {
+            write_test_archive(archive, &members);
+            let names = list_archive_members(archive).unwrap();
+            assert_eq!(names, members.iter().map(|(name, _)| name.to_string()).collect::<Vec<_>>());
+            // Backwards after forwards restarts the gz decoder
+            for (name, data) in members.iter().chain(members.iter().rev()) {
+                assert_eq!(read_archive_member(archive, name).unwrap(), data.to_vec());
+            }
+            assert!(read_archive_member(archive, "missing.pdb").is_err());
+            let positions = names.iter().map(|name| archive_member_position(archive, name).unwrap()).collect::<Vec<_>>();
+            assert!(positions.windows(2).all(|pair| pair[0] < pair[1]));
+            assert_eq!(archive_member_position(archive, "missing.pdb"), None);
+            std::fs::remove_file(archive).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_split_archive_path() {
+        assert_eq!(split_archive_path("db/afdb.tar:AF-P1-F1-model_v4.pdb.gz"), Some(("db/afdb.tar", "AF-P1-F1-model_v4.pdb.gz")));
+        assert_eq!(split_archive_path("a.tar.gz:x/b.tar:c.pdb"), Some(("a.tar.gz", "x/b.tar:c.pdb")));
+        assert_eq!(split_archive_path("a.tgz:c.cif"), Some(("a.tgz", "c.cif")));
+        assert_eq!(split_archive_path("data/homeobox/1akha-.pdb"), None);
+        assert_eq!(split_archive_path("data/foldcomp/example_db:d1asha_"), None);
+        assert_eq!(split_archive_path("a.tar:"), None);
+    }
+}
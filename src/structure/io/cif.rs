@@ -91,7 +91,30 @@ impl Reader<File> {
         drop(binary);
         Ok(structure)
     }
-    
+
+}
+
+// Uncompressed mmCIF in memory, e.g. a member of a tar archive
+impl<'a> Reader<&'a [u8]> {
+    pub fn from_bytes(bytes: &'a [u8]) -> Self {
+        Reader {
+            reader: bytes,
+            input_type: StructureFileFormat::CIF,
+        }
+    }
+
+    pub fn read_structure(&self) -> Result<Structure, &str> {
+        let mut structure = Structure::new();
+        let contents = std::str::from_utf8(self.reader).map_err(|_e| "Error reading file")?;
+        match pdbtbx_cif::lex_cif(contents) {
+            Ok(data_block) => parse_mmcif_block_into_structure(&data_block, &mut structure),
+            Err(e) => {
+                eprintln!("Error parsing CIF file: {:?}", e);
+                return Err("Error parsing CIF file");
+            }
+        }
+        Ok(structure)
+    }
 }
 
 
@@ -39,22 +39,27 @@ impl Drop for FoldcompDbReader {
 
 impl FoldcompDbReader {
     pub fn new(path: &str) -> Self {
-        let (db_mmap, db) = read_foldcomp_db(path).expect("Error reading foldcomp db file.");
-        let lookup = read_foldcomp_db_lookup(path).expect("Error reading foldcomp db lookup file.");
-        let index = read_foldcomp_db_index(path).expect("Error reading foldcomp db index file.");
+        Self::try_new(path).unwrap_or_else(|e| panic!("Error reading foldcomp db {}: {}", path, e))
+    }
+
+    // Same as new but gives an error for missing or unreadable db files
+    pub fn try_new(path: &str) -> Result<Self, String> {
+        let (db_mmap, db) = read_foldcomp_db(path).map_err(|e| format!("Error reading foldcomp db file: {}", e))?;
+        let lookup = read_foldcomp_db_lookup(path).map_err(|e| format!("Error reading foldcomp db lookup file: {}", e))?;
+        let index = read_foldcomp_db_index(path).map_err(|e| format!("Error reading foldcomp db index file: {}", e))?;
         let path_string_to_return = path.to_string();
         
         let mut lookup = lookup;
         lookup.par_sort_unstable_by(|a, b| a.1.cmp(&b.1));
         
-        FoldcompDbReader {
+        Ok(FoldcompDbReader {
             path: path_string_to_return,
             input_type: StructureFileFormat::FCZDB,
             db_mmap: db_mmap,
             db: db,
             lookup: lookup,
             index: index,
-        }
+        })
     }
     
     pub fn empty() -> Self {
@@ -4,6 +4,8 @@ use std::fmt;
 pub mod parser;
 pub mod pdb;
 pub mod cif;
+pub mod archive;
+pub mod writer;
 
 #[cfg(feature = "foldcomp")]
 pub mod fcz;
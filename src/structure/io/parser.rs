@@ -2,6 +2,10 @@ use crate::structure::atom::Atom;
 
 pub fn parse_line(line: &String) -> Result<Atom, &str> {
     // Not failing due to line length
+    // Coordinates are required. Shorter lines can't be sliced
+    if line.len() < 54 {
+        return Err("Atom line is too short");
+    }
     // Parse line
     let x = line[30..38].trim().parse::<f32>();
     let y = line[38..46].trim().parse::<f32>();
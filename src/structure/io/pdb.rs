@@ -35,44 +35,7 @@ impl Reader<File> {
     }
 
     pub fn read_structure(&self) -> Result<Structure, &str> {
-        let reader = BufReader::new(&self.reader);
-        let mut structure = Structure::new(); // revise
-        let mut record = (b' ', 0);
-        let mut model = 0;
-        // Reading each line of PDB, parse and build atomvector.
-        for (_idx, line) in reader.lines().enumerate() {
-            if let Ok(atomline) = line {
-                if model > 1 {
-                    // Current version does not support multiple models in one PDB file
-                    break;
-                }
-                // If line is less than 6 characters, skip the line
-                if atomline.len() < 6 {
-                    continue;
-                }
-                match &atomline[..6] {
-                    "MODEL " => {
-                        model += 1;
-                    }
-                    "ATOM  " => {
-                        let atom = parse_line(&atomline);
-                        match atom {
-                            Ok(atom) => {
-                                structure.update(atom, &mut record);
-                            }
-                            Err(_e) => {
-                                continue;
-                            }
-                        }
-                    }
-                    _ => continue,
-                }
-            } else {
-                return Err("Error reading line");
-            };
-        }
-        // println!("{structure:?}");
-        Ok(structure)
+        read_structure_from_lines(BufReader::new(&self.reader))
     }
 
     pub fn read_structure_from_gz(&self) -> Result<Structure, &str> {
@@ -122,6 +85,60 @@ impl Reader<File> {
     
 }
 
+// Uncompressed PDB in memory, e.g. a member of a tar archive
+impl<'a> Reader<&'a [u8]> {
+    pub fn from_bytes(bytes: &'a [u8]) -> Self {
+        Reader {
+            reader: bytes,
+            input_type: StructureFileFormat::PDB,
+        }
+    }
+
+    pub fn read_structure(&self) -> Result<Structure, &str> {
+        read_structure_from_lines(self.reader)
+    }
+}
+
+fn read_structure_from_lines<B: BufRead>(reader: B) -> Result<Structure, &'static str> {
+    let mut structure = Structure::new(); // revise
+    let mut record = (b' ', 0);
+    let mut model = 0;
+    // Reading each line of PDB, parse and build atomvector.
+    for (_idx, line) in reader.lines().enumerate() {
+        if let Ok(atomline) = line {
+            if model > 1 {
+                // Current version does not support multiple models in one PDB file
+                break;
+            }
+            // If line is less than 6 characters, skip the line
+            if atomline.len() < 6 {
+                continue;
+            }
+            match &atomline[..6] {
+                "MODEL " => {
+                    model += 1;
+                }
+                "ATOM  " => {
+                    let atom = parse_line(&atomline);
+                    match atom {
+                        Ok(atom) => {
+                            structure.update(atom, &mut record);
+                        }
+                        Err(_e) => {
+                            continue;
+                        }
+                    }
+                }
+                _ => continue,
+            }
+        } else {
+            return Err("Error reading line");
+        };
+    }
+    // println!("{structure:?}");
+    Ok(structure)
+}
+
 #[cfg(test)]
 mod tests {
     use crate::prelude::load_path;
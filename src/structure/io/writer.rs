@@ -0,0 +1,184 @@
+// Writing structures as PDB or mmCIF. Only the fields kept in Structure are written;
+// occupancy is always 1.00 and all atoms are ATOM records
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::structure::core::Structure;
+
+use super::StructureFileFormat;
+
+// Element symbol guessed from the atom name. The first two columns hold the element,
+// right-justified for one-letter elements; names like "HG12" start with the element
+pub fn element_from_atom_name(atom_name: &[u8; 4]) -> String {
+    let element = match atom_name[0] {
+        b' ' => &atom_name[1..2],
+        b'0'..=b'9' => &atom_name[1..2],
+        // Hydrogens of 4-character names
+        b'H' | b'D' if atom_name[3] != b' ' => &atom_name[0..1],
+        _ => &atom_name[0..2],
+    };
+    String::from_utf8_lossy(element).trim().to_string()
+}
+
+fn write_pdb_atoms<W: Write>(writer: &mut W, structure: &Structure) -> io::Result<()> {
+    let atoms = &structure.atom_vector;
+    let mut serial = 0;
+    for i in 0..structure.num_atoms {
+        serial += 1;
+        let atom = atoms.get(i);
+        writeln!(
+            writer,
+            "ATOM  {:>5} {} {} {}{:>4}    {:>8.3}{:>8.3}{:>8.3}{:>6.2}{:>6.2}          {:>2}",
+            serial % 100000,
+            String::from_utf8_lossy(&atom.atom_name),
+            String::from_utf8_lossy(&atom.res_name),
+            atom.chain as char,
+            atom.res_serial % 10000,
+            atom.x, atom.y, atom.z,
+            1.0, atom.b_factor,
+            element_from_atom_name(&atom.atom_name),
+        )?;
+        // Chain ends at the last atom or when the next atom is in another chain
+        if i + 1 == structure.num_atoms || atoms.chain[i + 1] != atom.chain {
+            serial += 1;
+            writeln!(
+                writer, "TER   {:>5}      {} {}{:>4}",
+                serial % 100000, String::from_utf8_lossy(&atom.res_name), atom.chain as char, atom.res_serial % 10000
+            )?;
+        }
+    }
+    Ok(())
+}
+
+pub fn write_pdb<W: Write>(writer: &mut W, structure: &Structure) -> io::Result<()> {
+    write_pdb_atoms(writer, structure)?;
+    writeln!(writer, "END")
+}
+
+// Each structure becomes a MODEL, numbered from 1
+pub fn write_pdb_models<W: Write>(writer: &mut W, models: &[&Structure]) -> io::Result<()> {
+    for (i, structure) in models.iter().enumerate() {
+        writeln!(writer, "MODEL     {:>4}", i + 1)?;
+        write_pdb_atoms(writer, structure)?;
+        writeln!(writer, "ENDMDL")?;
+    }
+    writeln!(writer, "END")
+}
+
+// mmCIF values can't contain whitespace or start with a quote unless quoted
+fn cif_value(value: &str) -> String {
+    if value.is_empty() {
+        ".".to_string()
+    } else if value.contains(char::is_whitespace) || value.starts_with(['\'', '"', '_', '#', '$', ';']) {
+        format!("\"{}\"", value)
+    } else {
+        value.to_string()
+    }
+}
+
+// Structures are written as models of a single data block
+pub fn write_cif_models<W: Write>(writer: &mut W, name: &str, models: &[&Structure]) -> io::Result<()> {
+    writeln!(writer, "data_{}", cif_value(name).replace(char::is_whitespace, "_"))?;
+    writeln!(writer, "#\nloop_")?;
+    for column in [
+        "group_PDB", "id", "type_symbol", "label_atom_id", "label_comp_id", "label_asym_id",
+        "label_seq_id", "Cartn_x", "Cartn_y", "Cartn_z", "occupancy", "B_iso_or_equiv",
+        "auth_seq_id", "auth_asym_id", "pdbx_PDB_model_num",
+    ] {
+        writeln!(writer, "_atom_site.{}", column)?;
+    }
+    let mut serial = 0;
+    for (model, structure) in models.iter().enumerate() {
+        for i in 0..structure.num_atoms {
+            serial += 1;
+            let atom = structure.atom_vector.get(i);
+            let chain = (atom.chain as char).to_string();
+            writeln!(
+                writer,
+                "ATOM {} {} {} {} {} {} {:.3} {:.3} {:.3} 1.00 {:.2} {} {} {}",
+                serial,
+                cif_value(&element_from_atom_name(&atom.atom_name)),
+                cif_value(String::from_utf8_lossy(&atom.atom_name).trim()),
+                cif_value(String::from_utf8_lossy(&atom.res_name).trim()),
+                cif_value(chain.trim()),
+                atom.res_serial,
+                atom.x, atom.y, atom.z,
+                atom.b_factor,
+                atom.res_serial,
+                cif_value(chain.trim()),
+                model + 1,
+            )?;
+        }
+    }
+    writeln!(writer, "#")
+}
+
+// Write to a file as PDB or mmCIF. The data block of mmCIF is named after the file
+pub fn write_structure_to_path(path: &str, models: &[&Structure], format: &StructureFileFormat) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    match format {
+        StructureFileFormat::PDB if models.len() == 1 => write_pdb(&mut writer, models[0])?,
+        StructureFileFormat::PDB => write_pdb_models(&mut writer, models)?,
+        StructureFileFormat::CIF => {
+            let name = std::path::Path::new(path).file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+            write_cif_models(&mut writer, &name, models)?
+        }
+        _ => return Err(io::Error::new(
+            io::ErrorKind::Unsupported, format!("Writing {} is not supported. Use PDB or CIF", format)
+        )),
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structure::io::{cif, pdb};
+
+    fn read_4cha() -> Structure {
+        pdb::Reader::from_file("data/serine_peptidases_filtered/4cha.pdb").unwrap().read_structure().unwrap()
+    }
+
+    #[test]
+    fn test_element_from_atom_name() {
+        assert_eq!(element_from_atom_name(b" CA "), "C");
+        assert_eq!(element_from_atom_name(b"CA  "), "CA");
+        assert_eq!(element_from_atom_name(b"HG12"), "H");
+        assert_eq!(element_from_atom_name(b"1HB "), "H");
+    }
+
+    #[test]
+    fn test_write_pdb_and_cif() {
+        let structure = read_4cha();
+        let mut pdb_bytes = Vec::new();
+        write_pdb(&mut pdb_bytes, &structure).unwrap();
+        let mut cif_bytes = Vec::new();
+        write_cif_models(&mut cif_bytes, "4cha", &[&structure]).unwrap();
+
+        let from_pdb = pdb::Reader::from_bytes(&pdb_bytes).read_structure().unwrap();
+        let from_cif = cif::Reader::from_bytes(&cif_bytes).read_structure().unwrap();
+        for written in [&from_pdb, &from_cif] {
+            assert_eq!(written.num_atoms, structure.num_atoms);
+            assert_eq!(written.num_residues, structure.num_residues);
+            assert_eq!(written.chains, structure.chains);
+            for i in [0, structure.num_atoms / 2, structure.num_atoms - 1] {
+                let (a, b) = (written.atom_vector.get(i), structure.atom_vector.get(i));
+                assert_eq!((a.atom_name, a.res_name, a.res_serial, a.chain), (b.atom_name, b.res_name, b.res_serial, b.chain));
+                assert!(a.get_coordinate().distance(&b.get_coordinate()) < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_pdb_models() {
+        let structure = read_4cha();
+        let mut bytes = Vec::new();
+        write_pdb_models(&mut bytes, &[&structure, &structure]).unwrap();
+        let text = String::from_utf8(bytes).unwrap();
+        assert_eq!(text.matches("ENDMDL").count(), 2);
+        // Only the first model is read back
+        let first = pdb::Reader::from_bytes(text.as_bytes()).read_structure().unwrap();
+        assert_eq!(first.num_atoms, structure.num_atoms);
+    }
+}
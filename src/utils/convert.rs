@@ -43,6 +43,13 @@ pub fn continuize_u32_value_into_f32(val: u32, min: f32, max: f32, num_bin: f32)
     let cont_f = (max - min) / (num_bin - 1.0_f32);
     (val as f32) * (cont_f) + min
 }
+// Bin of the centre of a bin after changing the number of bins
+#[inline(always)]
+pub fn rebin_u32_value(val: u32, min: f32, max: f32, num_bin: f32, new_num_bin: f32) -> u32 {
+    discretize_f32_value_into_u32(
+        continuize_u32_value_into_f32(val, min, max, num_bin), min, max, new_num_bin
+    )
+}
 #[inline(always)]
 pub fn normalize_f32_value(val: f32, min: f32, max: f32) -> f32 {
     (val - min) / (max - min)
@@ -0,0 +1,308 @@
+// Minimal JSON values for the query server. Requests are small objects of
+// strings, numbers and booleans; responses are built from JsonValue and
+// written with Display. Numbers are kept as f64.
+
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<JsonValue>),
+    // Keys keep their order
+    Object(Vec<(String, JsonValue)>),
+}
+
+impl JsonValue {
+    pub fn get(&self, key: &str) -> Option<&JsonValue> {
+        match self {
+            JsonValue::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            JsonValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            JsonValue::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            JsonValue::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for JsonValue {
+    fn from(s: &str) -> Self { JsonValue::String(s.to_string()) }
+}
+impl From<String> for JsonValue {
+    fn from(s: String) -> Self { JsonValue::String(s) }
+}
+impl From<bool> for JsonValue {
+    fn from(b: bool) -> Self { JsonValue::Bool(b) }
+}
+impl From<usize> for JsonValue {
+    fn from(n: usize) -> Self { JsonValue::Number(n as f64) }
+}
+impl From<f32> for JsonValue {
+    fn from(n: f32) -> Self { JsonValue::Number(n as f64) }
+}
+impl<T: Into<JsonValue>> From<Option<T>> for JsonValue {
+    fn from(value: Option<T>) -> Self {
+        value.map(|v| v.into()).unwrap_or(JsonValue::Null)
+    }
+}
+impl<T: Into<JsonValue>> From<Vec<T>> for JsonValue {
+    fn from(values: Vec<T>) -> Self {
+        JsonValue::Array(values.into_iter().map(|v| v.into()).collect())
+    }
+}
+
+// Object from (key, value) pairs
+#[macro_export]
+macro_rules! json_object {
+    ($($key:expr => $value:expr),* $(,)?) => {
+        $crate::utils::json::JsonValue::Object(vec![
+            $(($key.to_string(), $crate::utils::json::JsonValue::from($value))),*
+        ])
+    };
+}
+
+fn write_escaped(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl fmt::Display for JsonValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JsonValue::Null => write!(f, "null"),
+            JsonValue::Bool(b) => write!(f, "{}", b),
+            // NaN and infinity are not JSON
+            JsonValue::Number(n) if !n.is_finite() => write!(f, "null"),
+            JsonValue::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            // Scores are f32. Four decimals as in the tsv output
+            JsonValue::Number(n) => write!(f, "{}", (n * 1e4).round() / 1e4),
+            JsonValue::String(s) => write_escaped(f, s),
+            JsonValue::Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 { write!(f, ",")?; }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            JsonValue::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 { write!(f, ",")?; }
+                    write_escaped(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+// Arrays and objects nested deeper than this are rejected so that a request
+// of nested brackets can't overflow the stack of the parsing thread
+pub const MAX_JSON_DEPTH: usize = 64;
+
+pub fn parse_json(input: &str) -> Result<JsonValue, String> {
+    let mut parser = JsonParser { bytes: input.as_bytes(), pos: 0, depth: 0 };
+    let value = parser.parse_value()?;
+    parser.skip_whitespace();
+    if parser.pos != parser.bytes.len() {
+        return Err(format!("Unexpected trailing characters at {}", parser.pos));
+    }
+    Ok(value)
+}
+
+struct JsonParser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl<'a> JsonParser<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.bytes.len() && self.bytes[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), String> {
+        if self.bytes[self.pos..].starts_with(token.as_bytes()) {
+            self.pos += token.len();
+            Ok(())
+        } else {
+            Err(format!("Expected {} at {}", token, self.pos))
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<JsonValue, String> {
+        self.skip_whitespace();
+        match self.bytes.get(self.pos) {
+            Some(b'[') | Some(b'{') => {
+                if self.depth == MAX_JSON_DEPTH {
+                    return Err(format!("Nested deeper than {} at {}", MAX_JSON_DEPTH, self.pos));
+                }
+                self.depth += 1;
+                let value = self.parse_container();
+                self.depth -= 1;
+                value
+            }
+            Some(b'n') => self.expect("null").map(|_| JsonValue::Null),
+            Some(b't') => self.expect("true").map(|_| JsonValue::Bool(true)),
+            Some(b'f') => self.expect("false").map(|_| JsonValue::Bool(false)),
+            Some(b'"') => self.parse_string().map(JsonValue::String),
+            Some(b'-') | Some(b'0'..=b'9') => self.parse_number(),
+            Some(_) => Err(format!("Unexpected character at {}", self.pos)),
+            None => Err("Unexpected end of input".to_string()),
+        }
+    }
+
+    // Array or object starting at the current position
+    fn parse_container(&mut self) -> Result<JsonValue, String> {
+        match self.bytes[self.pos] {
+            b'[' => {
+                self.pos += 1;
+                let mut values = Vec::new();
+                self.skip_whitespace();
+                if self.bytes.get(self.pos) == Some(&b']') {
+                    self.pos += 1;
+                    return Ok(JsonValue::Array(values));
+                }
+                loop {
+                    values.push(self.parse_value()?);
+                    self.skip_whitespace();
+                    match self.bytes.get(self.pos) {
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => { self.pos += 1; return Ok(JsonValue::Array(values)); }
+                        _ => return Err(format!("Expected , or ] at {}", self.pos)),
+                    }
+                }
+            }
+            _ => {
+                self.pos += 1;
+                let mut fields = Vec::new();
+                self.skip_whitespace();
+                if self.bytes.get(self.pos) == Some(&b'}') {
+                    self.pos += 1;
+                    return Ok(JsonValue::Object(fields));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.parse_string()?;
+                    self.skip_whitespace();
+                    self.expect(":")?;
+                    fields.push((key, self.parse_value()?));
+                    self.skip_whitespace();
+                    match self.bytes.get(self.pos) {
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => { self.pos += 1; return Ok(JsonValue::Object(fields)); }
+                        _ => return Err(format!("Expected , or }} at {}", self.pos)),
+                    }
+                }
+            }
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<JsonValue, String> {
+        let start = self.pos;
+        while self.pos < self.bytes.len() && matches!(self.bytes[self.pos], b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') {
+            self.pos += 1;
+        }
+        let text = std::str::from_utf8(&self.bytes[start..self.pos]).unwrap();
+        text.parse::<f64>().map(JsonValue::Number).map_err(|_| format!("Invalid number at {}", start))
+    }
+
+    fn parse_string(&mut self) -> Result<String, String> {
+        self.expect("\"")?;
+        let mut bytes = Vec::new();
+        loop {
+            match self.bytes.get(self.pos) {
+                Some(b'"') => { self.pos += 1; break; }
+                Some(b'\\') => {
+                    let escaped = *self.bytes.get(self.pos + 1).ok_or("Unexpected end of input")?;
+                    self.pos += 2;
+                    match escaped {
+                        b'"' => bytes.push(b'"'),
+                        b'\\' => bytes.push(b'\\'),
+                        b'/' => bytes.push(b'/'),
+                        b'n' => bytes.push(b'\n'),
+                        b'r' => bytes.push(b'\r'),
+                        b't' => bytes.push(b'\t'),
+                        b'b' => bytes.push(0x08),
+                        b'f' => bytes.push(0x0c),
+                        b'u' => {
+                            let hex = self.bytes.get(self.pos..self.pos + 4).ok_or("Unexpected end of input")?;
+                            let code = u32::from_str_radix(std::str::from_utf8(hex).unwrap_or(""), 16)
+                                .map_err(|_| format!("Invalid escape at {}", self.pos))?;
+                            self.pos += 4;
+                            // Surrogate pairs are not needed for IDs and paths
+                            let c = char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER);
+                            bytes.extend_from_slice(c.to_string().as_bytes());
+                        }
+                        _ => return Err(format!("Invalid escape at {}", self.pos)),
+                    }
+                }
+                Some(&b) => { bytes.push(b); self.pos += 1; }
+                None => return Err("Unterminated string".to_string()),
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| "Invalid UTF-8 in string".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_write_json() {
+        let value = parse_json(r#" {"pdb": "query/4CHA.pdb", "query": "B57,B102,C195", "top": 10,
+            "distance": 0.5, "per_structure": true, "chains": ["A", null], "note": "tab\t\"q\"é"} "#).unwrap();
+        assert_eq!(value.get("pdb").and_then(|v| v.as_str()), Some("query/4CHA.pdb"));
+        assert_eq!(value.get("top").and_then(|v| v.as_f64()), Some(10.0));
+        assert_eq!(value.get("per_structure").and_then(|v| v.as_bool()), Some(true));
+        assert_eq!(value.get("chains"), Some(&JsonValue::Array(vec!["A".into(), JsonValue::Null])));
+        assert_eq!(value.get("note").and_then(|v| v.as_str()), Some("tab\t\"q\"é"));
+        assert!(value.get("missing").is_none());
+        // Written values parse back
+        assert_eq!(parse_json(&value.to_string()).unwrap(), value);
+        assert_eq!(
+            json_object!{"id" => "1abc", "rmsd" => 0.123456f32, "nres" => 120usize, "plddt" => f32::NAN}.to_string(),
+            r#"{"id":"1abc","rmsd":0.1235,"nres":120,"plddt":null}"#
+        );
+        assert!(parse_json("{\"a\": 1,}").is_err());
+        assert!(parse_json("[1, 2").is_err());
+        // Nesting is limited instead of recursing until the stack overflows
+        let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(parse_json(&nested(MAX_JSON_DEPTH)).is_ok());
+        assert!(parse_json(&nested(MAX_JSON_DEPTH + 1)).is_err());
+        assert!(parse_json(&"[".repeat(1 << 20)).is_err());
+    }
+}
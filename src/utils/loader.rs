@@ -3,6 +3,10 @@
 // Author: Hyunbin Kim (khb7840@gmail.com)
 // Copyright © 2024 Hyunbin Kim, All rights reserved
 
+use std::io::BufRead;
+
+use crate::structure::io::archive::{archive_member_path, list_archive_members};
+
 const ALLOWED_EXTENSIONS: [&str; 6] = [".pdb", ".ent", ".cif", ".pdb.gz", ".ent.gz", ".cif.gz"];
 
 
@@ -36,6 +40,36 @@ pub fn load_path(dir: &str, recursive: bool) -> Vec<String> {
     pdb_paths
 }
 
+// Structures in a tar archive as {archive}:{member}
+pub fn load_archive(archive: &str) -> Vec<String> {
+    let members = list_archive_members(archive).expect("Unable to read tar archive");
+    members.iter()
+        .filter(|member| ALLOWED_EXTENSIONS.iter().any(|&ext| member.ends_with(ext)))
+        .map(|member| archive_member_path(archive, member))
+        .collect()
+}
+
+// One path per line from a file or from stdin if list_path is "-".
+// Empty lines and lines starting with '#' are ignored
+pub fn load_path_list(list_path: &str) -> Vec<String> {
+    let reader: Box<dyn BufRead> = if list_path == "-" {
+        Box::new(std::io::stdin().lock())
+    } else {
+        let file = std::fs::File::open(list_path).expect("Unable to read path list");
+        Box::new(std::io::BufReader::new(file))
+    };
+    let mut pdb_paths = Vec::new();
+    for line in reader.lines() {
+        let line = line.expect("Unable to read path list");
+        let path = line.trim();
+        if path.is_empty() || path.starts_with('#') {
+            continue;
+        }
+        pdb_paths.push(path.to_string());
+    }
+    pdb_paths
+}
+
 pub fn load_homeobox_toy() -> Vec<String> {
     vec![
         "data/homeobox/1akha-.pdb".to_string(),
@@ -74,4 +108,13 @@ mod tests {
         assert_eq!(pdb_paths.len(), 14);
         println!("Recursive: {:?}", pdb_paths);
     }
+
+    #[test]
+    fn test_load_path_list() {
+        let list_path = "data/load_path_list_test.txt";
+        std::fs::write(list_path, "# homeobox\ndata/homeobox/1akha-.pdb\n\n  data/homeobox/1b72a-.pdb  \n").unwrap();
+        let pdb_paths = load_path_list(list_path);
+        assert_eq!(pdb_paths, vec!["data/homeobox/1akha-.pdb", "data/homeobox/1b72a-.pdb"]);
+        std::fs::remove_file(list_path).unwrap();
+    }
 }
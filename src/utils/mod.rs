@@ -5,4 +5,4 @@ pub mod loader;
 pub mod benchmark;
 pub mod log;
 pub mod combination;
-pub mod convert;
\ No newline at end of file
+pub mod convert;pub mod json;